@@ -1,41 +1,673 @@
-use anyhow::{Context as _, Result};
-use config::Config;
-use serenity::model::id::ChannelId;
-
-#[derive(Debug, Default, serde::Deserialize, PartialEq, Clone)]
-pub struct DiscordConfig {
-    /// Botが動作するカテゴリID
-    pub vc_category: ChannelId,
-    /// 無視するチャンネルID
-    pub vc_ignored_channels: Vec<ChannelId>,
-    /// スレッドを作成するチャンネルID
-    pub thread_channel: ChannelId,
-}
-
-/// アプリケーションの設定
-#[derive(Debug, Default, serde::Deserialize, PartialEq, Clone)]
-pub struct AppConfig {
-    /// Discordの設定
-    pub discord: DiscordConfig,
-}
-
-impl AppConfig {
-    /// 設定を読み込む
-    pub fn load_config(basedir: &str) -> Result<AppConfig> {
-        // 設定ファイルのパス
-        let path = format!("{}/config.toml", basedir);
-        // 設定ファイルを読み込む
-        let config = Config::builder()
-            // Add in `./Settings.toml`
-            .add_source(config::File::with_name(&path))
-            // Add in settings from the environment (with a prefix of APP)
-            // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
-            .add_source(config::Environment::with_prefix("APP"))
-            .build()?;
-        // 設定ファイルをパース
-        let app_config = config
-            .try_deserialize::<AppConfig>()
-            .context("設定ファイルの読み込みに失敗")?;
-        Ok(app_config)
-    }
-}
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use config::Config;
+use serenity::model::{
+    channel::ReactionType,
+    id::{ChannelId, GuildId, RoleId},
+};
+
+use crate::messages::DEFAULT_EMOJI;
+
+/// サーバーごとの設定
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Clone)]
+pub struct GuildConfig {
+    /// Botが動作するカテゴリID
+    pub vc_category: ChannelId,
+    /// 無視するチャンネルID
+    #[serde(default)]
+    pub vc_ignored_channels: Vec<ChannelId>,
+    /// スレッドを作成するチャンネルID (`thread_parent_channel` とも書ける)
+    #[serde(alias = "thread_parent_channel")]
+    pub thread_channel: ChannelId,
+    /// 新しいVCの告知 (議題メッセージ) を投稿するチャンネルID (未設定の場合はスレッドを作成するチャンネル)
+    #[serde(default)]
+    pub announce_channel: Option<ChannelId>,
+    /// スレッドの名前のテンプレート (`{name}` はVCの名前、未設定の場合はVCの名前のまま)
+    /// サーバーごとにカテゴリを設定するので、カテゴリ (例: ゲーム用・音楽用) ごとの名前の付け方になる
+    #[serde(default)]
+    pub thread_name_template: Option<String>,
+    /// メッセージのテンプレートの上書き (キーはロケールファイルと同じ、未設定のキーはロケールのまま)
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+    /// フォーラムの投稿に付けるタグ (キーワード→タグID、VCの名前かオーナーのアクティビティにキーワードが含まれると付ける)
+    #[serde(default)]
+    pub forum_tags: HashMap<String, u64>,
+    /// 週ごとの動作状況を投稿するチャンネルID (未設定の場合は投稿しない)
+    #[serde(default)]
+    pub report_channel: Option<ChannelId>,
+    /// このサーバーで起きたエラーを報告するチャンネルID (未設定の場合はログのみ)
+    #[serde(default)]
+    pub error_channel: Option<ChannelId>,
+    /// ボタンやメッセージの絵文字の上書き (絵文字の名前→絵文字、カスタム絵文字は `<:name:id>`、未設定の名前はデフォルトのまま)
+    #[serde(default)]
+    pub emoji: HashMap<String, String>,
+}
+
+/// Bot終了時のスレッドの扱い
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownAction {
+    /// 何もしない
+    #[default]
+    None,
+    /// スレッドに再起動中のお知らせを投稿する
+    Notify,
+    /// スレッドをアーカイブする (次にVCに参加した時に戻る)
+    Archive,
+}
+
+/// VC⇔スレッドの対応と通話時間の保存先
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// JSONファイル (`state_file`, `voice_time_file`)
+    #[default]
+    File,
+    /// SQLiteのデータベース (`sqlite_file`)
+    #[cfg(feature = "persistence-sqlite")]
+    Sqlite,
+    /// Redis (`redis_url`)
+    #[cfg(feature = "persistence-redis")]
+    Redis,
+}
+
+/// スレッドへの参加メッセージなどでメンションしたユーザーに通知する範囲
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum MentionPolicy {
+    /// 誰にも通知しない
+    None,
+    /// きっかけになったユーザー (VCに参加したユーザー) のみに通知する
+    AuthorOnly,
+    /// メンションしたユーザー全員に通知する
+    #[default]
+    All,
+}
+
+/// 作成するスレッドの種類
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadType {
+    /// 公開スレッド (誰でも見られる)
+    #[default]
+    Public,
+    /// プライベートスレッド (VCの参加者のみ招待する)
+    Private,
+}
+
+/// VCごとの会話の場所
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatMode {
+    /// スレッドを作成するチャンネルにスレッドを作成する
+    #[default]
+    Thread,
+    /// スレッドを作成せず、VCのチャットにボタン付きの参加メッセージを投稿する
+    VcText,
+}
+
+/// VC解散時のスレッドの扱い
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DisbandAction {
+    /// スレッドをアーカイブする (会話がなければ削除する)
+    #[default]
+    Archive,
+    /// スレッドを削除する (まとめは議題メッセージに残す)
+    Delete,
+}
+
+/// 通知を控える時間帯
+#[derive(Debug, serde::Deserialize, PartialEq, Clone)]
+pub struct QuietHours {
+    /// 開始時刻 (例: `"23:00"`)
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    /// 終了時刻 (例: `"07:00"`、開始時刻より前の場合は日付をまたぐ)
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+    /// 時刻のタイムゾーン (例: `"Asia/Tokyo"`)
+    pub timezone: Tz,
+    /// 時間帯内は、スレッドを作成するチャンネルへの告知を投稿しない
+    #[serde(default)]
+    pub skip_announce: bool,
+}
+
+impl QuietHours {
+    /// 指定した時刻が時間帯内か判定する
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let time = now.with_timezone(&self.timezone).time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // 日付をまたぐ場合 (例: 23:00〜07:00)
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// 週ごとの動作状況を投稿する日時
+#[derive(Debug, serde::Deserialize, PartialEq, Clone)]
+pub struct WeeklyReport {
+    /// 投稿する曜日 (例: `"mon"`)
+    #[serde(deserialize_with = "deserialize_weekday")]
+    pub weekday: Weekday,
+    /// 投稿する時刻 (例: `"09:00"`)
+    #[serde(deserialize_with = "deserialize_time")]
+    pub time: NaiveTime,
+    /// 日時のタイムゾーン (例: `"Asia/Tokyo"`)
+    pub timezone: Tz,
+}
+
+impl WeeklyReport {
+    /// 指定した時刻より後で、次に投稿する日時
+    pub fn next_after(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.with_timezone(&self.timezone).date_naive();
+        // 夏時間の切り替えで存在しない時刻の場合は、次の週にする
+        (0..14)
+            .map(|days| today + Duration::days(days))
+            .filter(|date| date.weekday() == self.weekday)
+            .filter_map(|date| {
+                self.timezone
+                    .from_local_datetime(&date.and_time(self.time))
+                    .earliest()
+            })
+            .map(|at| at.with_timezone(&Utc))
+            .find(|at| *at > now)
+            .unwrap_or(now + Duration::weeks(1))
+    }
+}
+
+/// `"mon"` または `"monday"` 形式の曜日を読み込む
+fn deserialize_weekday<'de, D>(deserializer: D) -> std::result::Result<Weekday, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let text = <String as serde::Deserialize>::deserialize(deserializer)?;
+    text.parse()
+        .map_err(|_| serde::de::Error::custom(format!("曜日の形式が正しくありません: {}", text)))
+}
+
+/// `"23:00"` または `"23:00:00"` 形式の時刻を読み込む
+fn deserialize_time<'de, D>(deserializer: D) -> std::result::Result<NaiveTime, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let text = <String as serde::Deserialize>::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&text, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(&text, "%H:%M:%S"))
+        .map_err(|_| serde::de::Error::custom(format!("時刻の形式が正しくありません: {}", text)))
+}
+
+/// スラッシュコマンドを登録する範囲
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandScope {
+    /// サーバーごとに登録する (すぐに反映される)
+    #[default]
+    Guild,
+    /// グローバルに登録する (反映に時間がかかる場合がある)
+    Global,
+}
+
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Clone)]
+pub struct DiscordConfig {
+    /// サーバーID→サーバーごとの設定のマップ
+    pub guilds: HashMap<GuildId, GuildConfig>,
+    /// VCごとの会話の場所 (スレッドかVCのチャットか)
+    #[serde(default)]
+    pub chat_mode: ChatMode,
+    /// 作成するスレッドの種類
+    #[serde(default)]
+    pub thread_type: ThreadType,
+    /// スレッドへの参加メッセージなどでメンションしたユーザーに通知する範囲
+    #[serde(default)]
+    pub mention_policy: MentionPolicy,
+    /// VCから全員退出したらスレッドをアーカイブするか
+    #[serde(default)]
+    pub archive_on_empty: bool,
+    /// スレッドを作成する前に、VCと同じ名前のアーカイブされたスレッドを探して使い回すか (状態ファイルが失われた場合に重複しないように)
+    #[serde(default)]
+    pub reuse_archived_threads: bool,
+    /// VC解散時のスレッドの扱い
+    #[serde(default)]
+    pub on_disband: DisbandAction,
+    /// メッセージの言語 (locales/{locale}.toml を読み込む)
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    /// スレッドが自動でアーカイブされるまでの時間 (分)
+    /// 未設定の場合はDiscordのデフォルト
+    #[serde(default)]
+    pub thread_auto_archive_duration: Option<u16>,
+    /// Botの参加・退出を無視するか
+    #[serde(default = "default_true")]
+    pub ignore_bots: bool,
+    /// スレッドを作成するのに必要なVCの参加人数
+    #[serde(default = "default_min_members_for_thread")]
+    pub min_members_for_thread: usize,
+    /// 退出してからこの秒数以内に再参加した場合は参加メッセージを出さない
+    #[serde(default)]
+    pub vc_join_cooldown_secs: i64,
+    /// VCが空になって削除するスレッドを、この秒数の間はアーカイブしておき、再参加された場合は同じスレッドを使う (0ですぐに削除する)
+    #[serde(default)]
+    pub recreate_cooldown_secs: i64,
+    /// 参加メッセージをまとめる時間 (ミリ秒)、この間に参加したメンバーは1つのメッセージにまとめる (0でまとめない)
+    #[serde(default = "default_join_burst_window_ms")]
+    pub join_burst_window_ms: u64,
+    /// スレッドの名前の末尾にVCの参加人数を付けるか (例: "しりとり (3)")
+    #[serde(default)]
+    pub name_with_count: bool,
+    /// スレッドの名前が変更されたらVCの名前も変更するか
+    #[serde(default)]
+    pub two_way_rename: bool,
+    /// VCのチャットのスレッドへの案内メッセージが削除されたら投稿し直すか
+    #[serde(default)]
+    pub repost_vc_guide: bool,
+    /// 最後のメッセージからこの時間 (時間) がたったスレッドをアーカイブする (未設定の場合はしない)
+    #[serde(default)]
+    pub inactivity_archive_hours: Option<u64>,
+    /// 放置されたスレッドでも、VCに人がいる間はアーカイブしないか
+    #[serde(default)]
+    pub inactivity_skip_populated: bool,
+    /// 参加・退出メッセージをWebhookでVCの名前として投稿するか
+    #[cfg(feature = "webhook")]
+    #[serde(default)]
+    pub use_webhook: bool,
+    /// Webhookで投稿する時のアイコンのURL (未設定の場合はWebhookのデフォルト)
+    #[cfg(feature = "webhook")]
+    #[serde(default)]
+    pub webhook_avatar_url: Option<String>,
+    /// Botのステータスに表示する文章 (`{count}` はスレッドのあるVCの数、未設定の場合はロケールのまま)
+    #[serde(default)]
+    pub presence_template: Option<String>,
+    /// Bot終了時のスレッドの扱い
+    #[serde(default)]
+    pub shutdown_action: ShutdownAction,
+    /// Bot終了時の後始末を待つ最大秒数
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// VCの名前を変更した時に、変更前後の名前をスレッドに記録する
+    #[serde(default)]
+    pub rename_log: bool,
+    /// VCの名前が変わった時に、新しい名前をスレッドで知らせる
+    #[serde(default)]
+    pub rename_announce: bool,
+    /// 名前の変更ダイアログの入力後に、確認のボタンを押してから名前を変更する
+    #[serde(default)]
+    pub confirm_rename: bool,
+    /// カテゴリ内のステージチャンネルもVCと同じように扱う
+    #[serde(default)]
+    pub include_stage_channels: bool,
+    /// 参加メッセージ・告知・案内メッセージを埋め込みで投稿する
+    #[serde(default)]
+    pub use_embeds: bool,
+    /// 埋め込みの色 (例: `0x5865F2`、未設定の場合はDiscordの青紫)
+    #[serde(default)]
+    pub embed_color: Option<u32>,
+    /// オーナーでなくても全てのVCを操作できるロールID (モデレーター向け)
+    #[serde(default)]
+    pub control_roles: Vec<RoleId>,
+    /// オーナーがVCに参加している時だけ操作できるようにする (VCから抜けたオーナーには操作させない)
+    #[serde(default)]
+    pub require_in_vc_for_controls: bool,
+    /// 新しいVCの告知でメンションするロールID (未設定の場合はメンションしない)
+    #[serde(default)]
+    pub new_vc_ping_role: Option<RoleId>,
+    /// VC⇔スレッドの対応を記憶する最大数 (超えたら最も長く使われていない対応から破棄する、未設定の場合は上限なし)
+    #[serde(default)]
+    pub max_links: Option<usize>,
+    /// 同時に対応を持てるスレッドの最大数 (達したら空きができるまで新しく作成しない、未設定の場合は上限なし)
+    #[serde(default)]
+    pub max_active_threads: Option<usize>,
+    /// 参加中のメンバーを表示するメッセージをスレッドにピン留めし、参加・退出のたびに編集する
+    #[serde(default)]
+    pub participant_panel: bool,
+    /// VCのステータスを、スレッドにピン留めしたメッセージに反映する
+    #[serde(default)]
+    pub sync_vc_status: bool,
+    /// 通知を控える時間帯 (未設定の場合は常に通知する)
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// 週ごとの動作状況を投稿する日時 (未設定の場合は投稿しない)
+    #[serde(default)]
+    pub weekly_report: Option<WeeklyReport>,
+    /// スラッシュコマンドを登録する範囲
+    #[serde(default)]
+    pub command_scope: CommandScope,
+    /// サーバーごとに登録する場合に、登録先を絞るテスト用のサーバーID (未設定の場合は設定された全サーバー)
+    #[serde(default)]
+    pub command_guild_id: Option<GuildId>,
+}
+
+impl DiscordConfig {
+    /// サーバーの設定を取得する (Botが動作しないサーバーの場合はNone)
+    pub fn guild(&self, guild_id: &GuildId) -> Option<&GuildConfig> {
+        self.guilds.get(guild_id)
+    }
+}
+
+/// スレッドを作成するのに必要なVCの参加人数のデフォルト
+fn default_min_members_for_thread() -> usize {
+    1
+}
+
+/// 参加メッセージをまとめる時間のデフォルト (ミリ秒)
+fn default_join_burst_window_ms() -> u64 {
+    2000
+}
+
+/// Bot終了時の後始末を待つ最大秒数のデフォルト
+fn default_shutdown_timeout_secs() -> u64 {
+    10
+}
+
+/// `#[serde(default)]` で true をデフォルトにする
+fn default_true() -> bool {
+    true
+}
+
+/// Discordが受け付けるスレッドの自動アーカイブ時間 (分)
+const THREAD_AUTO_ARCHIVE_DURATIONS: [u16; 4] = [60, 1440, 4320, 10080];
+
+/// デフォルトの言語
+fn default_locale() -> String {
+    "ja".to_string()
+}
+
+/// アプリケーションの設定
+#[derive(Debug, Default, serde::Deserialize, PartialEq, Clone)]
+pub struct AppConfig {
+    /// VC⇔スレッドの対応を保存するファイルのパス (設定ディレクトリからの相対パス)
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    /// ユーザーごとのVCにいた合計時間を保存するファイルのパス (設定ディレクトリからの相対パス)
+    #[serde(default = "default_voice_time_file")]
+    pub voice_time_file: String,
+    /// VC⇔スレッドの対応と通話時間の保存先
+    #[serde(default)]
+    pub storage: StorageKind,
+    /// `storage = "sqlite"` の場合に保存するデータベースのファイルのパス (設定ディレクトリからの相対パス)
+    #[cfg(feature = "persistence-sqlite")]
+    #[serde(default = "default_sqlite_file")]
+    pub sqlite_file: String,
+    /// `storage = "redis"` の場合に保存するRedisのURL (`redis://host:port/db`)
+    #[cfg(feature = "persistence-redis")]
+    #[serde(default)]
+    pub redis_url: Option<String>,
+    /// Redisに保存するキーの接頭辞 (複数のBotで同じRedisを使う場合に分ける)
+    #[cfg(feature = "persistence-redis")]
+    #[serde(default = "default_redis_key_prefix")]
+    pub redis_key_prefix: String,
+    /// Discordを操作せず、行う予定の操作をinfoレベルでログに出力する (設定を本番のサーバーで確かめる用)
+    #[serde(default)]
+    pub dry_run: bool,
+    /// 監視用のメトリクスをPrometheusの形式で公開するポート (未設定の場合は公開しない)
+    #[cfg(feature = "metrics")]
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+    /// Discordの設定
+    pub discord: DiscordConfig,
+}
+
+/// 設定の値が変わっていたら元の値に戻し、設定の名前を記録する
+fn keep_field<T: PartialEq + Clone>(
+    name: &'static str,
+    value: &mut T,
+    current: &T,
+    changed: &mut Vec<&'static str>,
+) {
+    if value != current {
+        *value = current.clone();
+        changed.push(name);
+    }
+}
+
+/// 設定の上書きに使う環境変数の接頭辞
+const DISCORD_ENV_PREFIX: &str = "DISCORD_";
+
+/// 環境変数 `DISCORD_<キー>` から、[discord] の設定を上書きする値を集める
+/// ネストしたキーは `__` で区切る (例: `DISCORD_GUILDS__123__THREAD_CHANNEL` → `discord.guilds.123.thread_channel`)
+/// Botのトークン (`DISCORD_TOKEN`) は設定ではないので除く
+fn discord_env_overrides() -> Vec<(String, String)> {
+    std::env::vars()
+        .filter(|(name, _)| name != "DISCORD_TOKEN")
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(DISCORD_ENV_PREFIX)?;
+            Some((
+                format!("discord.{}", key.to_lowercase().replace("__", ".")),
+                value,
+            ))
+        })
+        .collect()
+}
+
+/// 状態ファイルのデフォルトのパス
+fn default_state_file() -> String {
+    "state.json".to_string()
+}
+
+/// 通話時間のファイルのデフォルトのパス
+fn default_voice_time_file() -> String {
+    "voice_time.json".to_string()
+}
+
+/// SQLiteのデータベースのデフォルトのパス
+#[cfg(feature = "persistence-sqlite")]
+fn default_sqlite_file() -> String {
+    "state.db".to_string()
+}
+
+/// Redisのキーのデフォルトの接頭辞
+#[cfg(feature = "persistence-redis")]
+fn default_redis_key_prefix() -> String {
+    "vc-thread:".to_string()
+}
+
+impl AppConfig {
+    /// 設定を読み込む
+    /// 設定ファイル (config.toml または config.yaml) を読み込み、環境変数で上書きする
+    pub fn load_config(basedir: &str) -> Result<AppConfig> {
+        // 設定ファイルのパス (拡張子から形式を判定する)
+        let path = format!("{}/config", basedir);
+        // 設定ファイルを読み込む
+        let mut builder = Config::builder()
+            // 環境変数だけで設定する場合もあるので、ファイルがなくてもよい
+            .add_source(config::File::with_name(&path).required(false))
+            // Add in settings from the environment (with a prefix of APP)
+            // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
+            .add_source(config::Environment::with_prefix("APP"));
+        // [discord] の設定を環境変数で上書きする
+        for (key, value) in discord_env_overrides() {
+            builder = builder
+                .set_override(&key, value)
+                .with_context(|| format!("環境変数による設定の上書きに失敗: {}", key))?;
+        }
+        let config = builder.build()?;
+        // 設定ファイルをパース
+        let mut app_config = config
+            .try_deserialize::<AppConfig>()
+            .context("設定ファイルの読み込みに失敗")?;
+        // 状態ファイルのパスを設定ディレクトリ基準にする
+        app_config.state_file = Path::new(basedir)
+            .join(&app_config.state_file)
+            .to_string_lossy()
+            .to_string();
+        app_config.voice_time_file = Path::new(basedir)
+            .join(&app_config.voice_time_file)
+            .to_string_lossy()
+            .to_string();
+        #[cfg(feature = "persistence-sqlite")]
+        {
+            app_config.sqlite_file = Path::new(basedir)
+                .join(&app_config.sqlite_file)
+                .to_string_lossy()
+                .to_string();
+        }
+        // 設定値を検証
+        app_config.validate()?;
+        Ok(app_config)
+    }
+
+    /// 実行中には反映できない設定を、今の設定の値に戻す
+    /// 設定ファイルを読み込み直した時に使い、値が変わっていた設定の名前を返す
+    pub fn keep_fixed_fields(&mut self, current: &AppConfig) -> Vec<&'static str> {
+        let mut changed = vec![];
+        keep_field(
+            "state_file",
+            &mut self.state_file,
+            &current.state_file,
+            &mut changed,
+        );
+        keep_field(
+            "voice_time_file",
+            &mut self.voice_time_file,
+            &current.voice_time_file,
+            &mut changed,
+        );
+        // 保存先は起動時に開くため
+        keep_field("storage", &mut self.storage, &current.storage, &mut changed);
+        #[cfg(feature = "persistence-sqlite")]
+        keep_field(
+            "sqlite_file",
+            &mut self.sqlite_file,
+            &current.sqlite_file,
+            &mut changed,
+        );
+        #[cfg(feature = "persistence-redis")]
+        keep_field(
+            "redis_url",
+            &mut self.redis_url,
+            &current.redis_url,
+            &mut changed,
+        );
+        #[cfg(feature = "persistence-redis")]
+        keep_field(
+            "redis_key_prefix",
+            &mut self.redis_key_prefix,
+            &current.redis_key_prefix,
+            &mut changed,
+        );
+        #[cfg(feature = "metrics")]
+        keep_field(
+            "metrics_port",
+            &mut self.metrics_port,
+            &current.metrics_port,
+            &mut changed,
+        );
+        // コマンドは起動時に登録するため
+        keep_field(
+            "discord.command_scope",
+            &mut self.discord.command_scope,
+            &current.discord.command_scope,
+            &mut changed,
+        );
+        keep_field(
+            "discord.command_guild_id",
+            &mut self.discord.command_guild_id,
+            &current.discord.command_guild_id,
+            &mut changed,
+        );
+        // Webhookのアイコンは起動時に設定するため
+        #[cfg(feature = "webhook")]
+        keep_field(
+            "discord.webhook_avatar_url",
+            &mut self.discord.webhook_avatar_url,
+            &current.discord.webhook_avatar_url,
+            &mut changed,
+        );
+        changed
+    }
+
+    /// 設定値を検証する
+    fn validate(&self) -> Result<()> {
+        // 保存先のRedisがわからなければ状態を保存できない
+        #[cfg(feature = "persistence-redis")]
+        if self.storage == StorageKind::Redis && self.redis_url.is_none() {
+            anyhow::bail!("storage = \"redis\" の場合は redis_url を設定してください");
+        }
+        // Botが動作するサーバーが1つもなければ何もできない
+        if self.discord.guilds.is_empty() {
+            anyhow::bail!("discord.guilds にサーバーを1つ以上設定してください");
+        }
+        // デフォルトの設定ファイルのままのIDでは動かない
+        for (guild_id, guild_config) in &self.discord.guilds {
+            if guild_id.0 == 0 {
+                anyhow::bail!("discord.guilds にサーバーIDを設定してください");
+            }
+            if guild_config.vc_category.0 == 0 {
+                anyhow::bail!("discord.guilds.{}.vc_category を設定してください", guild_id);
+            }
+            if guild_config.thread_channel.0 == 0 {
+                anyhow::bail!(
+                    "discord.guilds.{}.thread_channel を設定してください",
+                    guild_id
+                );
+            }
+            // VCの名前が入らないと、どのVCのスレッドか分からなくなる
+            if let Some(template) = &guild_config.thread_name_template {
+                if !template.contains("{name}") {
+                    anyhow::bail!(
+                        "discord.guilds.{}.thread_name_template には {{name}} を含めてください",
+                        guild_id
+                    );
+                }
+            }
+            // 絵文字の名前の打ち間違いや、ボタンに使えないカスタム絵文字の書き方に気付けるように
+            for (name, emoji) in &guild_config.emoji {
+                if !DEFAULT_EMOJI.iter().any(|(default, _)| default == name) {
+                    anyhow::bail!(
+                        "discord.guilds.{}.emoji の {} は {:?} のいずれかを指定してください",
+                        guild_id,
+                        name,
+                        DEFAULT_EMOJI.map(|(name, _)| name)
+                    );
+                }
+                let valid = match emoji.parse::<ReactionType>() {
+                    Ok(ReactionType::Custom { .. }) => true,
+                    Ok(_) => !emoji.is_empty() && !emoji.starts_with('<'),
+                    Err(_) => false,
+                };
+                if !valid {
+                    anyhow::bail!(
+                        "discord.guilds.{}.emoji.{} の形式が正しくありません (カスタム絵文字は <:name:id> の形式): {}",
+                        guild_id,
+                        name,
+                        emoji
+                    );
+                }
+            }
+        }
+        // スレッドの自動アーカイブ時間はDiscordが受け付ける値のみ
+        if let Some(duration) = self.discord.thread_auto_archive_duration {
+            if !THREAD_AUTO_ARCHIVE_DURATIONS.contains(&duration) {
+                anyhow::bail!(
+                    "thread_auto_archive_duration は {:?} のいずれかを指定してください: {}",
+                    THREAD_AUTO_ARCHIVE_DURATIONS,
+                    duration
+                );
+            }
+        }
+        // 0時間だとスレッドがすぐにアーカイブされてしまう
+        if self.discord.inactivity_archive_hours == Some(0) {
+            anyhow::bail!("inactivity_archive_hours は1以上を指定してください");
+        }
+        // 0件だと作成したスレッドの対応をすぐに破棄してしまう
+        if self.discord.max_links == Some(0) {
+            anyhow::bail!("max_links は1以上を指定してください");
+        }
+        // 0件だとスレッドを1つも作成できない
+        if self.discord.max_active_threads == Some(0) {
+            anyhow::bail!("max_active_threads は1以上を指定してください");
+        }
+        // 開始と終了が同じだと時間帯が無くなってしまう
+        if let Some(quiet_hours) = &self.discord.quiet_hours {
+            if quiet_hours.start == quiet_hours.end {
+                anyhow::bail!("quiet_hours の start と end には別の時刻を指定してください");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{Context as _, Result};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// 保存中の書き込みをまとめて1回の変更として扱うため、最後の変更から待つ時間
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// 設定ファイルとロケールファイルの変更を監視する
+pub struct ConfigWatcher {
+    /// ファイルの監視 (止めないように持っておく)
+    _watcher: RecommendedWatcher,
+    /// 変更の通知
+    rx: UnboundedReceiver<()>,
+}
+
+impl ConfigWatcher {
+    /// 設定ディレクトリの監視を開始する
+    pub fn new(basedir: &str) -> Result<ConfigWatcher> {
+        let basedir = fs::canonicalize(basedir).context("設定ディレクトリが見つかりません")?;
+        let locales_dir = basedir.join("locales");
+
+        // 監視のコールバックは別スレッドで呼ばれるので、チャンネルでタスクに知らせる
+        let (tx, rx) = mpsc::unbounded_channel();
+        let watched_dir = basedir.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let event = match res {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+            if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                return;
+            }
+            // 状態ファイルなど、Bot自身が書き込むファイルの変更は無視する
+            let relevant = event.paths.iter().any(|path| {
+                is_config_file(&watched_dir, path) || path.parent() == Some(&locales_dir)
+            });
+            if relevant {
+                let _ = tx.send(());
+            }
+        })
+        .context("設定ファイルの監視の作成に失敗")?;
+        watcher
+            .watch(&basedir, RecursiveMode::Recursive)
+            .context("設定ディレクトリの監視に失敗")?;
+
+        Ok(ConfigWatcher {
+            _watcher: watcher,
+            rx,
+        })
+    }
+
+    /// ファイルが変更されるまで待つ
+    /// エディタの保存などで続けて変更された場合は、変更が落ち着いてから返す
+    pub async fn changed(&mut self) {
+        if self.rx.recv().await.is_none() {
+            // 監視が止まった場合は二度と変更を通知しない
+            std::future::pending::<()>().await;
+        }
+        loop {
+            tokio::time::sleep(SETTLE_DELAY).await;
+            let mut more = false;
+            while self.rx.try_recv().is_ok() {
+                more = true;
+            }
+            if !more {
+                return;
+            }
+        }
+    }
+}
+
+/// 設定ファイル (拡張子から形式を判定するため、`config.*`) かどうか
+fn is_config_file(basedir: &Path, path: &Path) -> bool {
+    path.parent() == Some(basedir)
+        && path.file_stem().and_then(|stem| stem.to_str()) == Some("config")
+}
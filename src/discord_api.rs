@@ -0,0 +1,1828 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{Context as _, Result};
+use log::info;
+use serde_json::json;
+use serenity::{
+    async_trait,
+    builder::{
+        CreateInteractionResponse, CreateMessage, CreateThread, EditChannel,
+        EditInteractionResponse, EditMessage, EditThread,
+    },
+    json::{self, JsonMap, Value},
+    model::{
+        id::{ChannelId, GuildId, InteractionId, MessageId, RoleId, UserId},
+        prelude::{
+            Channel, ChannelType, GuildChannel, Member, Message, MessageType, Permissions,
+            ThreadsData, User,
+        },
+        Timestamp,
+    },
+    prelude::Context,
+};
+
+use crate::rename_queue::{RenameOutcome, RenameQueue};
+#[cfg(feature = "webhook")]
+use crate::webhook::WebhookSender;
+
+/// イベント受信リスナーが使うDiscordの操作
+/// serenityのContextを直接使わずにこのトレイトを通すことで、処理を差し替えられるようにする
+#[async_trait]
+pub trait DiscordApi: Sync {
+    /// サーバーのチャンネルを取得する (キャッシュにあればキャッシュから)
+    async fn guild_channel(&self, channel_id: ChannelId) -> Result<GuildChannel>;
+
+    /// チャンネルを取得する (キャッシュにあればキャッシュから)
+    /// 存在しない場合のエラーを判定できるよう、serenityのエラーのまま返す
+    async fn channel(&self, channel_id: ChannelId) -> serenity::Result<Channel>;
+
+    /// サーバーのメンバーを取得する (キャッシュにあればキャッシュから)
+    async fn member(&self, guild_id: GuildId, user_id: UserId) -> Result<Member>;
+
+    /// メッセージを投稿する
+    /// `notify` がfalseの場合はメンションしても通知しない
+    async fn send_message(
+        &self,
+        channel_id: ChannelId,
+        content: String,
+        notify: bool,
+    ) -> serenity::Result<Message> {
+        self.send_message_with(channel_id, |m| {
+            m.content(content);
+            if !notify {
+                m.allowed_mentions(|m| m.empty_users());
+            }
+            m
+        })
+        .await
+    }
+
+    /// 埋め込みやボタンを付けたメッセージを投稿する
+    async fn send_message_with<F>(&self, channel_id: ChannelId, f: F) -> serenity::Result<Message>
+    where
+        F: for<'b> FnOnce(&'b mut CreateMessage<'static>) -> &'b mut CreateMessage<'static> + Send;
+
+    /// メッセージを編集する
+    async fn edit_message<F>(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        f: F,
+    ) -> serenity::Result<Message>
+    where
+        F: for<'b> FnOnce(&'b mut EditMessage<'static>) -> &'b mut EditMessage<'static> + Send;
+
+    /// メッセージを削除する
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<()>;
+
+    /// メッセージをピン留めする
+    async fn pin_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<()>;
+
+    /// メッセージを取得する
+    async fn message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<Message>;
+
+    /// 最近のメッセージを新しい順に取得する
+    async fn recent_messages(
+        &self,
+        channel_id: ChannelId,
+        limit: u64,
+    ) -> serenity::Result<Vec<Message>>;
+
+    /// メッセージに紐づく公開スレッドを作成する
+    async fn create_public_thread<F>(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        f: F,
+    ) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut CreateThread) -> &mut CreateThread + Send;
+
+    /// メッセージに紐づかないスレッドを作成する (種類を指定しなければプライベートスレッド)
+    async fn create_private_thread<F>(
+        &self,
+        channel_id: ChannelId,
+        f: F,
+    ) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut CreateThread) -> &mut CreateThread + Send;
+
+    /// フォーラムチャンネルに投稿を作成する
+    /// serenityにフォーラムの投稿を作成するAPIが無いため、スレッド作成と同じエンドポイントに送る内容をそのまま渡す
+    async fn create_forum_post(
+        &self,
+        channel_id: ChannelId,
+        post: &JsonMap,
+    ) -> serenity::Result<GuildChannel>;
+
+    /// スレッドを編集する
+    async fn edit_thread<F>(&self, thread_id: ChannelId, f: F) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut EditThread) -> &mut EditThread + Send;
+
+    /// スレッドをアーカイブ/アーカイブ解除する
+    async fn set_thread_archived(
+        &self,
+        thread_id: ChannelId,
+        archived: bool,
+    ) -> serenity::Result<()> {
+        self.edit_thread(thread_id, |t| {
+            t.archived(archived);
+            t
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// チャンネル (スレッド) を削除する
+    async fn delete_channel(&self, channel_id: ChannelId) -> serenity::Result<()>;
+
+    /// チャンネルの設定 (参加人数の上限など) を変更する
+    async fn edit_channel<F>(&self, channel_id: ChannelId, f: F) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut EditChannel) -> &mut EditChannel + Send;
+
+    /// アーカイブされたスレッドを、最近アーカイブされたものから取得する
+    async fn archived_threads(
+        &self,
+        channel_id: ChannelId,
+        private: bool,
+        limit: u64,
+    ) -> serenity::Result<ThreadsData>;
+
+    /// スレッドのメンバーのIDを取得する
+    async fn thread_member_ids(&self, thread_id: ChannelId) -> serenity::Result<Vec<UserId>>;
+
+    /// スレッドにメンバーを追加する
+    async fn add_thread_member(
+        &self,
+        thread_id: ChannelId,
+        user_id: UserId,
+    ) -> serenity::Result<()>;
+
+    /// ユーザーとのDMのチャンネルを取得する
+    async fn dm_channel(&self, user_id: UserId) -> serenity::Result<ChannelId>;
+
+    /// レート制限内に収まるよう、キューを通してチャンネル名を変更する
+    async fn rename_channel(
+        &self,
+        queue: &RenameQueue,
+        channel_id: ChannelId,
+        name: String,
+    ) -> Result<RenameOutcome>;
+
+    /// スレッドにWebhookでメッセージを投稿する
+    #[cfg(feature = "webhook")]
+    #[allow(clippy::too_many_arguments)]
+    async fn send_webhook_message(
+        &self,
+        webhooks: &WebhookSender,
+        parent_id: ChannelId,
+        thread_id: ChannelId,
+        username: &str,
+        content: &str,
+        notify: bool,
+    ) -> Result<()>;
+
+    /// インタラクションに応答する
+    async fn create_interaction_response<F>(
+        &self,
+        interaction_id: InteractionId,
+        token: &str,
+        f: F,
+    ) -> serenity::Result<()>
+    where
+        F: for<'b> FnOnce(
+                &'b mut CreateInteractionResponse<'static>,
+            ) -> &'b mut CreateInteractionResponse<'static>
+            + Send;
+
+    /// 保留したインタラクションの応答の内容を設定する
+    async fn edit_interaction_response<F>(&self, token: &str, f: F) -> serenity::Result<()>
+    where
+        F: FnOnce(&mut EditInteractionResponse) -> &mut EditInteractionResponse + Send;
+
+    /// ユーザーの各チャンネルでの権限をAPIから計算する (起動直後でキャッシュが無くても使える)
+    async fn permissions_in(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        channels: &[GuildChannel],
+    ) -> Result<Vec<Permissions>>;
+
+    /// ユーザーのチャンネルでの権限を取得する
+    fn user_permissions(&self, channel: &GuildChannel, user_id: UserId) -> Result<Permissions>;
+
+    /// ユーザーをキャッシュから取得する
+    fn cached_user(&self, user_id: UserId) -> Option<User>;
+
+    /// サーバーのメンバーをキャッシュから取得する
+    fn cached_member(&self, guild_id: GuildId, user_id: UserId) -> Option<Member>;
+
+    /// ユーザーが参加しているVCをキャッシュから取得する
+    fn user_voice_channel(&self, guild_id: GuildId, user_id: UserId) -> Option<ChannelId>;
+
+    /// VCに参加しているユーザーをキャッシュから取得する (サーバーのキャッシュが無い場合はNone)
+    /// ボイスステートの一覧を取得するREST APIは無いため、ゲートウェイで受信したキャッシュを使う
+    fn voice_channel_members(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> Option<Vec<UserId>>;
+
+    /// サーバーにロールがあるかキャッシュから判定する
+    fn has_role(&self, guild_id: GuildId, role_id: RoleId) -> bool;
+
+    /// サーバーのオーナーをキャッシュから取得する
+    fn guild_owner_id(&self, guild_id: GuildId) -> Option<UserId>;
+
+    /// ユーザーのアクティビティ名をキャッシュから取得する (プレゼンスを受信していなければ空)
+    fn presence_activities(&self, guild_id: GuildId, user_id: UserId) -> Vec<String>;
+
+    /// サーバーのAFKチャンネルをキャッシュから取得する
+    fn afk_channel_id(&self, guild_id: GuildId) -> Option<ChannelId>;
+}
+
+/// serenityのContextを使う実装
+#[async_trait]
+impl DiscordApi for Context {
+    async fn guild_channel(&self, channel_id: ChannelId) -> Result<GuildChannel> {
+        if let Some(channel) = self.cache.guild_channel(channel_id) {
+            return Ok(channel);
+        }
+        channel_id
+            .to_channel(self)
+            .await
+            .context("チャンネルの取得に失敗")?
+            .guild()
+            .ok_or(anyhow::anyhow!("サーバーのチャンネルではありません"))
+    }
+
+    async fn channel(&self, channel_id: ChannelId) -> serenity::Result<Channel> {
+        channel_id.to_channel(self).await
+    }
+
+    async fn member(&self, guild_id: GuildId, user_id: UserId) -> Result<Member> {
+        guild_id
+            .member(self, user_id)
+            .await
+            .context("メンバー取得失敗")
+    }
+
+    async fn send_message_with<F>(&self, channel_id: ChannelId, f: F) -> serenity::Result<Message>
+    where
+        F: for<'b> FnOnce(&'b mut CreateMessage<'static>) -> &'b mut CreateMessage<'static> + Send,
+    {
+        channel_id.send_message(self, f).await
+    }
+
+    async fn edit_message<F>(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        f: F,
+    ) -> serenity::Result<Message>
+    where
+        F: for<'b> FnOnce(&'b mut EditMessage<'static>) -> &'b mut EditMessage<'static> + Send,
+    {
+        channel_id.edit_message(self, message_id, f).await
+    }
+
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<()> {
+        channel_id.delete_message(self, message_id).await
+    }
+
+    async fn pin_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<()> {
+        channel_id.pin(self, message_id).await
+    }
+
+    async fn message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<Message> {
+        channel_id.message(self, message_id).await
+    }
+
+    async fn recent_messages(
+        &self,
+        channel_id: ChannelId,
+        limit: u64,
+    ) -> serenity::Result<Vec<Message>> {
+        channel_id
+            .messages(self, |f| {
+                f.limit(limit);
+                f
+            })
+            .await
+    }
+
+    async fn create_public_thread<F>(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        f: F,
+    ) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut CreateThread) -> &mut CreateThread + Send,
+    {
+        channel_id.create_public_thread(self, message_id, f).await
+    }
+
+    async fn create_private_thread<F>(
+        &self,
+        channel_id: ChannelId,
+        f: F,
+    ) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut CreateThread) -> &mut CreateThread + Send,
+    {
+        channel_id.create_private_thread(self, f).await
+    }
+
+    async fn create_forum_post(
+        &self,
+        channel_id: ChannelId,
+        post: &JsonMap,
+    ) -> serenity::Result<GuildChannel> {
+        self.http.create_private_thread(channel_id.0, post).await
+    }
+
+    async fn edit_thread<F>(&self, thread_id: ChannelId, f: F) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut EditThread) -> &mut EditThread + Send,
+    {
+        thread_id.edit_thread(self, f).await
+    }
+
+    async fn delete_channel(&self, channel_id: ChannelId) -> serenity::Result<()> {
+        channel_id.delete(self).await?;
+        Ok(())
+    }
+
+    async fn edit_channel<F>(&self, channel_id: ChannelId, f: F) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut EditChannel) -> &mut EditChannel + Send,
+    {
+        channel_id.edit(self, f).await
+    }
+
+    async fn archived_threads(
+        &self,
+        channel_id: ChannelId,
+        private: bool,
+        limit: u64,
+    ) -> serenity::Result<ThreadsData> {
+        if private {
+            channel_id
+                .get_archived_private_threads(self, None, Some(limit))
+                .await
+        } else {
+            channel_id
+                .get_archived_public_threads(self, None, Some(limit))
+                .await
+        }
+    }
+
+    async fn thread_member_ids(&self, thread_id: ChannelId) -> serenity::Result<Vec<UserId>> {
+        let members = thread_id.get_thread_members(self).await?;
+        Ok(members.iter().filter_map(|m| m.user_id).collect())
+    }
+
+    async fn add_thread_member(
+        &self,
+        thread_id: ChannelId,
+        user_id: UserId,
+    ) -> serenity::Result<()> {
+        thread_id.add_thread_member(self, user_id).await
+    }
+
+    async fn dm_channel(&self, user_id: UserId) -> serenity::Result<ChannelId> {
+        Ok(user_id.create_dm_channel(self).await?.id)
+    }
+
+    async fn rename_channel(
+        &self,
+        queue: &RenameQueue,
+        channel_id: ChannelId,
+        name: String,
+    ) -> Result<RenameOutcome> {
+        queue.rename(&self.http, channel_id, name).await
+    }
+
+    #[cfg(feature = "webhook")]
+    async fn send_webhook_message(
+        &self,
+        webhooks: &WebhookSender,
+        parent_id: ChannelId,
+        thread_id: ChannelId,
+        username: &str,
+        content: &str,
+        notify: bool,
+    ) -> Result<()> {
+        webhooks
+            .send(&self.http, parent_id, thread_id, username, content, notify)
+            .await
+    }
+
+    async fn create_interaction_response<F>(
+        &self,
+        interaction_id: InteractionId,
+        token: &str,
+        f: F,
+    ) -> serenity::Result<()>
+    where
+        F: for<'b> FnOnce(
+                &'b mut CreateInteractionResponse<'static>,
+            ) -> &'b mut CreateInteractionResponse<'static>
+            + Send,
+    {
+        // インタラクションの種類によらず応答できるよう、serenityと同じ内容をAPIに直接送る
+        let mut response = CreateInteractionResponse::default();
+        f(&mut response);
+        let CreateInteractionResponse(map, files) = response;
+        let map = Value::from(json::hashmap_to_json_map(map));
+        if files.is_empty() {
+            self.http
+                .create_interaction_response(interaction_id.0, token, &map)
+                .await
+        } else {
+            self.http
+                .create_interaction_response_with_files(interaction_id.0, token, &map, files)
+                .await
+        }
+    }
+
+    async fn edit_interaction_response<F>(&self, token: &str, f: F) -> serenity::Result<()>
+    where
+        F: FnOnce(&mut EditInteractionResponse) -> &mut EditInteractionResponse + Send,
+    {
+        let mut response = EditInteractionResponse::default();
+        f(&mut response);
+        let map = Value::from(json::hashmap_to_json_map(response.0));
+        self.http
+            .edit_original_interaction_response(token, &map)
+            .await?;
+        Ok(())
+    }
+
+    async fn permissions_in(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        channels: &[GuildChannel],
+    ) -> Result<Vec<Permissions>> {
+        // 起動直後はキャッシュにサーバーがないため、APIから取得する
+        let guild = guild_id
+            .to_partial_guild(self)
+            .await
+            .context("サーバーの取得に失敗")?;
+        let member = guild_id
+            .member(self, user_id)
+            .await
+            .context("メンバー情報の取得に失敗")?;
+        channels
+            .iter()
+            .map(|channel| {
+                guild
+                    .user_permissions_in(channel, &member)
+                    .context("権限の計算に失敗")
+            })
+            .collect()
+    }
+
+    fn user_permissions(&self, channel: &GuildChannel, user_id: UserId) -> Result<Permissions> {
+        channel
+            .permissions_for_user(self, user_id)
+            .context("権限の取得に失敗")
+    }
+
+    fn cached_user(&self, user_id: UserId) -> Option<User> {
+        self.cache.user(user_id)
+    }
+
+    fn cached_member(&self, guild_id: GuildId, user_id: UserId) -> Option<Member> {
+        self.cache.member(guild_id, user_id)
+    }
+
+    fn user_voice_channel(&self, guild_id: GuildId, user_id: UserId) -> Option<ChannelId> {
+        self.cache
+            .guild_field(guild_id, |guild| {
+                guild.voice_states.get(&user_id).and_then(|v| v.channel_id)
+            })
+            .flatten()
+    }
+
+    fn voice_channel_members(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> Option<Vec<UserId>> {
+        self.cache.guild_field(guild_id, |guild| {
+            guild
+                .voice_states
+                .values()
+                .filter(|v| v.channel_id == Some(channel_id))
+                .map(|v| v.user_id)
+                .collect()
+        })
+    }
+
+    fn has_role(&self, guild_id: GuildId, role_id: RoleId) -> bool {
+        self.cache.role(guild_id, role_id).is_some()
+    }
+
+    fn guild_owner_id(&self, guild_id: GuildId) -> Option<UserId> {
+        self.cache.guild_field(guild_id, |g| g.owner_id)
+    }
+
+    fn presence_activities(&self, guild_id: GuildId, user_id: UserId) -> Vec<String> {
+        self.cache
+            .guild_field(guild_id, |guild| {
+                guild
+                    .presences
+                    .get(&user_id)
+                    .map(|p| p.activities.iter().map(|a| a.name.clone()).collect())
+                    .unwrap_or_default()
+            })
+            .unwrap_or_default()
+    }
+
+    fn afk_channel_id(&self, guild_id: GuildId) -> Option<ChannelId> {
+        self.cache
+            .guild_field(guild_id, |guild| guild.afk_channel_id)
+            .flatten()
+    }
+}
+
+/// ドライランで使う実装
+/// 取得は包んだ実装でそのまま行い、Discordを変える操作は内容をinfoレベルでログに出力して、仮のIDで成功したことにする
+pub struct DryRunApi<'a, A> {
+    inner: &'a A,
+    /// 仮に作成したスレッド (同じイベントの処理の中では取得できるようにする)
+    threads: Mutex<HashMap<ChannelId, GuildChannel>>,
+}
+
+/// 次に払い出す仮のID (実際のIDと重ならないよう、Discordが払い出さない小さな値から使う)
+static NEXT_DRY_RUN_ID: AtomicU64 = AtomicU64::new(1);
+
+impl<'a, A: DiscordApi> DryRunApi<'a, A> {
+    pub fn new(inner: &'a A) -> Self {
+        DryRunApi {
+            inner,
+            threads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 仮のIDを払い出す
+    fn next_id() -> u64 {
+        NEXT_DRY_RUN_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// 仮に作成したスレッドを取得する
+    fn thread(&self, thread_id: ChannelId) -> Option<GuildChannel> {
+        self.threads.lock().unwrap().get(&thread_id).cloned()
+    }
+
+    /// 仮に投稿したメッセージを作る
+    fn message(
+        channel_id: ChannelId,
+        message_id: MessageId,
+        content: Option<&Value>,
+    ) -> serde_json::Result<Message> {
+        serde_json::from_value(json!({
+            "id": message_id.to_string(),
+            "channel_id": channel_id.to_string(),
+            "author": {
+                "id": "0",
+                "username": "dry-run",
+                "discriminator": "0000",
+                "avatar": null,
+                "bot": true,
+            },
+            "content": content.and_then(Value::as_str).unwrap_or_default(),
+            "attachments": [],
+            "embeds": [],
+            "mention_everyone": false,
+            "mention_roles": [],
+            "mentions": [],
+            "pinned": false,
+            "timestamp": Timestamp::now(),
+            "edited_timestamp": null,
+            "tts": false,
+            "type": MessageType::Regular,
+        }))
+    }
+
+    /// 仮にスレッドを作成し、同じイベントの処理の中で取得できるように覚えておく
+    async fn create_thread(
+        &self,
+        parent_id: ChannelId,
+        thread_id: ChannelId,
+        map: &JsonMap,
+    ) -> serenity::Result<GuildChannel> {
+        let guild_id = self
+            .inner
+            .channel(parent_id)
+            .await?
+            .guild()
+            .map(|c| c.guild_id);
+        let thread: GuildChannel = serde_json::from_value(json!({
+            "id": thread_id.to_string(),
+            "guild_id": guild_id.unwrap_or_default().to_string(),
+            "type": map.get("type").cloned().unwrap_or(json!(ChannelType::PublicThread)),
+            "name": map.get("name").cloned().unwrap_or_default(),
+            "parent_id": parent_id.to_string(),
+            "thread_metadata": {
+                "archived": false,
+                "auto_archive_duration": map.get("auto_archive_duration").cloned().unwrap_or(json!(1440)),
+                "archive_timestamp": Timestamp::now(),
+                "locked": false,
+            },
+        }))?;
+        self.threads
+            .lock()
+            .unwrap()
+            .insert(thread_id, thread.clone());
+        Ok(thread)
+    }
+}
+
+#[async_trait]
+impl<'a, A: DiscordApi> DiscordApi for DryRunApi<'a, A> {
+    async fn guild_channel(&self, channel_id: ChannelId) -> Result<GuildChannel> {
+        match self.thread(channel_id) {
+            Some(thread) => Ok(thread),
+            None => self.inner.guild_channel(channel_id).await,
+        }
+    }
+
+    async fn channel(&self, channel_id: ChannelId) -> serenity::Result<Channel> {
+        match self.thread(channel_id) {
+            Some(thread) => Ok(Channel::Guild(thread)),
+            None => self.inner.channel(channel_id).await,
+        }
+    }
+
+    async fn member(&self, guild_id: GuildId, user_id: UserId) -> Result<Member> {
+        self.inner.member(guild_id, user_id).await
+    }
+
+    async fn send_message_with<F>(&self, channel_id: ChannelId, f: F) -> serenity::Result<Message>
+    where
+        F: for<'b> FnOnce(&'b mut CreateMessage<'static>) -> &'b mut CreateMessage<'static> + Send,
+    {
+        let mut builder = CreateMessage::default();
+        f(&mut builder);
+        let map = json::hashmap_to_json_map(builder.0);
+        info!(
+            "[ドライラン] チャンネル {} に投稿: {}",
+            channel_id,
+            Value::from(map.clone())
+        );
+        Ok(Self::message(
+            channel_id,
+            MessageId(Self::next_id()),
+            map.get("content"),
+        )?)
+    }
+
+    async fn edit_message<F>(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        f: F,
+    ) -> serenity::Result<Message>
+    where
+        F: for<'b> FnOnce(&'b mut EditMessage<'static>) -> &'b mut EditMessage<'static> + Send,
+    {
+        let mut builder = EditMessage::default();
+        f(&mut builder);
+        let map = json::hashmap_to_json_map(builder.0);
+        info!(
+            "[ドライラン] メッセージ {} を編集: {}",
+            message_id,
+            Value::from(map.clone())
+        );
+        Ok(Self::message(channel_id, message_id, map.get("content"))?)
+    }
+
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<()> {
+        info!(
+            "[ドライラン] チャンネル {} のメッセージ {} を削除",
+            channel_id, message_id
+        );
+        Ok(())
+    }
+
+    async fn pin_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<()> {
+        info!(
+            "[ドライラン] チャンネル {} のメッセージ {} をピン留め",
+            channel_id, message_id
+        );
+        Ok(())
+    }
+
+    async fn message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> serenity::Result<Message> {
+        self.inner.message(channel_id, message_id).await
+    }
+
+    async fn recent_messages(
+        &self,
+        channel_id: ChannelId,
+        limit: u64,
+    ) -> serenity::Result<Vec<Message>> {
+        if self.thread(channel_id).is_some() {
+            return Ok(Vec::new());
+        }
+        self.inner.recent_messages(channel_id, limit).await
+    }
+
+    async fn create_public_thread<F>(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        f: F,
+    ) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut CreateThread) -> &mut CreateThread + Send,
+    {
+        let mut builder = CreateThread::default();
+        f(&mut builder);
+        let map = json::hashmap_to_json_map(builder.0);
+        info!(
+            "[ドライラン] チャンネル {} のメッセージ {} にスレッドを作成: {}",
+            channel_id,
+            message_id,
+            Value::from(map.clone())
+        );
+        // メッセージに紐づくスレッドは、メッセージと同じIDになる
+        self.create_thread(channel_id, ChannelId(message_id.0), &map)
+            .await
+    }
+
+    async fn create_private_thread<F>(
+        &self,
+        channel_id: ChannelId,
+        f: F,
+    ) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut CreateThread) -> &mut CreateThread + Send,
+    {
+        let mut builder = CreateThread::default();
+        builder.kind(ChannelType::PrivateThread);
+        f(&mut builder);
+        let map = json::hashmap_to_json_map(builder.0);
+        info!(
+            "[ドライラン] チャンネル {} にスレッドを作成: {}",
+            channel_id,
+            Value::from(map.clone())
+        );
+        self.create_thread(channel_id, ChannelId(Self::next_id()), &map)
+            .await
+    }
+
+    async fn create_forum_post(
+        &self,
+        channel_id: ChannelId,
+        post: &JsonMap,
+    ) -> serenity::Result<GuildChannel> {
+        info!(
+            "[ドライラン] フォーラム {} に投稿を作成: {}",
+            channel_id,
+            Value::from(post.clone())
+        );
+        self.create_thread(channel_id, ChannelId(Self::next_id()), post)
+            .await
+    }
+
+    async fn edit_thread<F>(&self, thread_id: ChannelId, f: F) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut EditThread) -> &mut EditThread + Send,
+    {
+        let mut builder = EditThread::default();
+        f(&mut builder);
+        info!(
+            "[ドライラン] スレッド {} を編集: {}",
+            thread_id,
+            Value::from(json::hashmap_to_json_map(builder.0))
+        );
+        match self.channel(thread_id).await?.guild() {
+            Some(thread) => Ok(thread),
+            None => Err(serenity::Error::Other("サーバーのチャンネルではありません")),
+        }
+    }
+
+    async fn delete_channel(&self, channel_id: ChannelId) -> serenity::Result<()> {
+        info!("[ドライラン] チャンネル {} を削除", channel_id);
+        self.threads.lock().unwrap().remove(&channel_id);
+        Ok(())
+    }
+
+    async fn edit_channel<F>(&self, channel_id: ChannelId, f: F) -> serenity::Result<GuildChannel>
+    where
+        F: FnOnce(&mut EditChannel) -> &mut EditChannel + Send,
+    {
+        let mut builder = EditChannel::default();
+        f(&mut builder);
+        info!(
+            "[ドライラン] チャンネル {} を編集: {}",
+            channel_id,
+            Value::from(json::hashmap_to_json_map(builder.0))
+        );
+        match self.channel(channel_id).await?.guild() {
+            Some(channel) => Ok(channel),
+            None => Err(serenity::Error::Other("サーバーのチャンネルではありません")),
+        }
+    }
+
+    async fn archived_threads(
+        &self,
+        channel_id: ChannelId,
+        private: bool,
+        limit: u64,
+    ) -> serenity::Result<ThreadsData> {
+        self.inner
+            .archived_threads(channel_id, private, limit)
+            .await
+    }
+
+    async fn thread_member_ids(&self, thread_id: ChannelId) -> serenity::Result<Vec<UserId>> {
+        if self.thread(thread_id).is_some() {
+            return Ok(Vec::new());
+        }
+        self.inner.thread_member_ids(thread_id).await
+    }
+
+    async fn add_thread_member(
+        &self,
+        thread_id: ChannelId,
+        user_id: UserId,
+    ) -> serenity::Result<()> {
+        info!(
+            "[ドライラン] スレッド {} にメンバー {} を追加",
+            thread_id, user_id
+        );
+        Ok(())
+    }
+
+    async fn dm_channel(&self, user_id: UserId) -> serenity::Result<ChannelId> {
+        self.inner.dm_channel(user_id).await
+    }
+
+    async fn rename_channel(
+        &self,
+        _queue: &RenameQueue,
+        channel_id: ChannelId,
+        name: String,
+    ) -> Result<RenameOutcome> {
+        info!(
+            "[ドライラン] チャンネル {} を「{}」にリネーム",
+            channel_id, name
+        );
+        Ok(RenameOutcome::Renamed)
+    }
+
+    #[cfg(feature = "webhook")]
+    async fn send_webhook_message(
+        &self,
+        _webhooks: &WebhookSender,
+        _parent_id: ChannelId,
+        thread_id: ChannelId,
+        username: &str,
+        content: &str,
+        _notify: bool,
+    ) -> Result<()> {
+        info!(
+            "[ドライラン] スレッド {} に「{}」としてWebhookで投稿: {}",
+            thread_id, username, content
+        );
+        Ok(())
+    }
+
+    async fn create_interaction_response<F>(
+        &self,
+        interaction_id: InteractionId,
+        _token: &str,
+        f: F,
+    ) -> serenity::Result<()>
+    where
+        F: for<'b> FnOnce(
+                &'b mut CreateInteractionResponse<'static>,
+            ) -> &'b mut CreateInteractionResponse<'static>
+            + Send,
+    {
+        let mut response = CreateInteractionResponse::default();
+        f(&mut response);
+        info!(
+            "[ドライラン] インタラクション {} に応答: {}",
+            interaction_id,
+            Value::from(json::hashmap_to_json_map(response.0))
+        );
+        Ok(())
+    }
+
+    async fn edit_interaction_response<F>(&self, _token: &str, f: F) -> serenity::Result<()>
+    where
+        F: FnOnce(&mut EditInteractionResponse) -> &mut EditInteractionResponse + Send,
+    {
+        let mut response = EditInteractionResponse::default();
+        f(&mut response);
+        info!(
+            "[ドライラン] 保留した応答を設定: {}",
+            Value::from(json::hashmap_to_json_map(response.0))
+        );
+        Ok(())
+    }
+
+    async fn permissions_in(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        channels: &[GuildChannel],
+    ) -> Result<Vec<Permissions>> {
+        self.inner.permissions_in(guild_id, user_id, channels).await
+    }
+
+    fn user_permissions(&self, channel: &GuildChannel, user_id: UserId) -> Result<Permissions> {
+        self.inner.user_permissions(channel, user_id)
+    }
+
+    fn cached_user(&self, user_id: UserId) -> Option<User> {
+        self.inner.cached_user(user_id)
+    }
+
+    fn cached_member(&self, guild_id: GuildId, user_id: UserId) -> Option<Member> {
+        self.inner.cached_member(guild_id, user_id)
+    }
+
+    fn user_voice_channel(&self, guild_id: GuildId, user_id: UserId) -> Option<ChannelId> {
+        self.inner.user_voice_channel(guild_id, user_id)
+    }
+
+    fn voice_channel_members(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> Option<Vec<UserId>> {
+        self.inner.voice_channel_members(guild_id, channel_id)
+    }
+
+    fn has_role(&self, guild_id: GuildId, role_id: RoleId) -> bool {
+        self.inner.has_role(guild_id, role_id)
+    }
+
+    fn guild_owner_id(&self, guild_id: GuildId) -> Option<UserId> {
+        self.inner.guild_owner_id(guild_id)
+    }
+
+    fn presence_activities(&self, guild_id: GuildId, user_id: UserId) -> Vec<String> {
+        self.inner.presence_activities(guild_id, user_id)
+    }
+
+    fn afk_channel_id(&self, guild_id: GuildId) -> Option<ChannelId> {
+        self.inner.afk_channel_id(guild_id)
+    }
+}
+
+/// テスト用の実装 (Discordに接続せず、メモリ上のチャンネルやメッセージを操作する)
+#[cfg(test)]
+pub mod mock {
+    use std::{
+        collections::HashMap,
+        sync::{Mutex, MutexGuard},
+        time::Duration,
+    };
+
+    use serde_json::{json, Value};
+    use serenity::http::{HttpError, StatusCode};
+
+    use super::*;
+    use serenity::model::{
+        channel::{ChannelType, MessageType},
+        guild::Member,
+    };
+
+    /// Botのユーザー (Botとして投稿したメッセージの投稿者)
+    pub const BOT_USER_ID: UserId = UserId(1);
+
+    /// Botとして投稿したメッセージ
+    #[derive(Debug, Clone)]
+    pub struct Post {
+        pub channel_id: ChannelId,
+        pub message_id: MessageId,
+        pub content: String,
+        /// ボタンが付いているか
+        pub has_components: bool,
+    }
+
+    /// Discordの状態と、行われた操作の記録
+    #[derive(Debug)]
+    pub struct MockState {
+        /// チャンネルID→チャンネル (スレッドを含む)
+        pub channels: HashMap<ChannelId, GuildChannel>,
+        /// キャッシュにあるユーザー
+        pub users: HashMap<UserId, User>,
+        /// ユーザー→参加しているVC
+        pub voice_states: HashMap<UserId, ChannelId>,
+        /// サーバーのキャッシュが無い (ボイスステートがわからない) 状態にする
+        pub voice_cache_missing: bool,
+        /// サーバーのAFKチャンネル
+        pub afk_channel_id: Option<ChannelId>,
+        /// チャンネルID→メッセージ (古い順)
+        pub messages: HashMap<ChannelId, Vec<Message>>,
+        /// Botとして投稿したメッセージ (投稿順)
+        pub posts: Vec<Post>,
+        /// 編集したメッセージ (編集順)
+        pub edited: Vec<MessageId>,
+        /// スレッドID→スレッドのメンバー
+        pub thread_members: HashMap<ChannelId, Vec<UserId>>,
+        /// スレッドのメンバーを取得した回数
+        pub thread_member_fetches: usize,
+        /// 作成したスレッドの数
+        pub threads_created: usize,
+        /// インタラクションに応答した回数
+        pub interaction_responses: usize,
+        /// 削除したチャンネル
+        pub deleted_channels: Vec<ChannelId>,
+        /// チャンネル名の変更
+        pub renames: Vec<(ChannelId, String)>,
+        /// ボタン付きのメッセージの投稿を失敗させる
+        pub fail_components: bool,
+        /// チャンネルID→そのチャンネルへの投稿で返すエラーのステータスコード
+        pub send_errors: HashMap<ChannelId, u16>,
+        /// チャンネルID→そのチャンネルの取得で返すエラーのステータスコード
+        pub channel_errors: HashMap<ChannelId, u16>,
+        /// スレッドの作成にかかる時間 (同時に処理された場合の確認用)
+        pub thread_create_delay: Duration,
+        /// スレッドのアーカイブにかかる時間 (同時に処理された場合の確認用)
+        pub archive_delay: Duration,
+        /// 次に払い出すID
+        next_id: u64,
+    }
+
+    impl Default for MockState {
+        fn default() -> Self {
+            MockState {
+                channels: HashMap::new(),
+                users: HashMap::new(),
+                voice_states: HashMap::new(),
+                voice_cache_missing: false,
+                afk_channel_id: None,
+                messages: HashMap::new(),
+                posts: Vec::new(),
+                edited: Vec::new(),
+                thread_members: HashMap::new(),
+                thread_member_fetches: 0,
+                threads_created: 0,
+                interaction_responses: 0,
+                deleted_channels: Vec::new(),
+                renames: Vec::new(),
+                fail_components: false,
+                send_errors: HashMap::new(),
+                channel_errors: HashMap::new(),
+                thread_create_delay: Duration::ZERO,
+                archive_delay: Duration::ZERO,
+                // テストで指定するIDと重ならないようにする
+                next_id: 1_000_000,
+            }
+        }
+    }
+
+    impl MockState {
+        fn next_id(&mut self) -> u64 {
+            self.next_id += 1;
+            self.next_id
+        }
+
+        /// チャンネルの取得で返すエラー
+        fn channel_error(&self, channel_id: ChannelId) -> Option<serenity::Error> {
+            if let Some(status) = self.channel_errors.get(&channel_id) {
+                return Some(http_error(*status));
+            }
+            if !self.channels.contains_key(&channel_id) {
+                return Some(http_error(404));
+            }
+            None
+        }
+
+        /// アーカイブ中のスレッドか (投稿もメッセージの編集もできない)
+        fn is_archived(&self, channel_id: ChannelId) -> bool {
+            self.channels
+                .get(&channel_id)
+                .and_then(|c| c.thread_metadata)
+                .is_some_and(|m| m.archived)
+        }
+
+        /// メッセージを記録する
+        fn push_message(
+            &mut self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+            author: &User,
+            content: &str,
+        ) -> Message {
+            let message = message(message_id, channel_id, author, content);
+            self.messages
+                .entry(channel_id)
+                .or_default()
+                .push(message.clone());
+            message
+        }
+
+        /// スレッドを作成する (親チャンネルが無ければNone)
+        fn create_thread(
+            &mut self,
+            parent_id: ChannelId,
+            thread_id: ChannelId,
+            map: &HashMap<&'static str, Value>,
+        ) -> Option<GuildChannel> {
+            let guild_id = self.channels.get(&parent_id)?.guild_id;
+            let name = map.get("name").and_then(Value::as_str).unwrap_or_default();
+            let kind = map
+                .get("type")
+                .and_then(|kind| serde_json::from_value(kind.clone()).ok())
+                .unwrap_or(ChannelType::PublicThread);
+            let thread = guild_channel(thread_id, guild_id, kind, name, Some(parent_id), false);
+            self.channels.insert(thread_id, thread.clone());
+            self.threads_created += 1;
+            Some(thread)
+        }
+    }
+
+    /// メモリ上のDiscord
+    #[derive(Debug, Default)]
+    pub struct MockApi {
+        state: Mutex<MockState>,
+    }
+
+    impl MockApi {
+        /// 状態を取得する (テストで状態を変えたり、操作の記録を確認したりする)
+        pub fn state(&self) -> MutexGuard<'_, MockState> {
+            self.state.lock().unwrap()
+        }
+
+        /// サーバーのチャンネルを追加する
+        pub fn add_channel(
+            &self,
+            guild_id: GuildId,
+            channel_id: ChannelId,
+            kind: ChannelType,
+            name: &str,
+            parent_id: Option<ChannelId>,
+        ) -> GuildChannel {
+            let channel = guild_channel(channel_id, guild_id, kind, name, parent_id, false);
+            self.state().channels.insert(channel_id, channel.clone());
+            channel
+        }
+
+        /// チャンネルを取得する
+        pub fn get_channel(&self, channel_id: ChannelId) -> Option<GuildChannel> {
+            self.state().channels.get(&channel_id).cloned()
+        }
+
+        /// スレッドがアーカイブされているか
+        pub fn is_archived(&self, thread_id: ChannelId) -> bool {
+            self.state().is_archived(thread_id)
+        }
+
+        /// キャッシュにユーザーを追加し、サーバーのメンバーとして返す
+        pub fn add_member(&self, guild_id: GuildId, user_id: UserId, bot: bool) -> Member {
+            let user = user(user_id, bot);
+            self.state().users.insert(user_id, user.clone());
+            member(guild_id, user)
+        }
+
+        /// ユーザーをVCに参加させる (ボイスステートを変えるだけでイベントは送らない)
+        pub fn join(&self, user_id: UserId, channel_id: ChannelId) {
+            self.state().voice_states.insert(user_id, channel_id);
+        }
+
+        /// ユーザーをVCから退出させる
+        pub fn leave(&self, user_id: UserId) {
+            self.state().voice_states.remove(&user_id);
+        }
+
+        /// ユーザーとしてメッセージを投稿する
+        pub fn say(&self, channel_id: ChannelId, user_id: UserId, content: &str) {
+            let mut state = self.state();
+            let author = state
+                .users
+                .get(&user_id)
+                .cloned()
+                .unwrap_or_else(|| user(user_id, false));
+            let message_id = MessageId(state.next_id());
+            state.push_message(channel_id, message_id, &author, content);
+        }
+
+        /// チャンネルにBotとして投稿したメッセージ
+        pub fn posts_in(&self, channel_id: ChannelId) -> Vec<Post> {
+            self.state()
+                .posts
+                .iter()
+                .filter(|p| p.channel_id == channel_id)
+                .cloned()
+                .collect()
+        }
+    }
+
+    /// 失敗したAPIのエラーを作成する
+    pub fn http_error(status: u16) -> serenity::Error {
+        let error = serde_json::from_value(json!({ "code": 0, "message": "" })).unwrap();
+        serenity::Error::Http(Box::new(HttpError::UnsuccessfulRequest(
+            serenity::http::error::ErrorResponse {
+                status_code: StatusCode::from_u16(status).unwrap(),
+                url: url::Url::parse("https://discord.com/api/v10").unwrap(),
+                error,
+            },
+        )))
+    }
+
+    fn user(user_id: UserId, bot: bool) -> User {
+        serde_json::from_value(json!({
+            "id": user_id.to_string(),
+            "username": format!("user{}", user_id),
+            "discriminator": "0001",
+            "avatar": null,
+            "bot": bot,
+        }))
+        .unwrap()
+    }
+
+    fn member(guild_id: GuildId, user: User) -> Member {
+        serde_json::from_value(json!({
+            "guild_id": guild_id.to_string(),
+            "user": user,
+            "roles": [],
+            "joined_at": "2022-01-01T00:00:00Z",
+            "deaf": false,
+            "mute": false,
+        }))
+        .unwrap()
+    }
+
+    fn guild_channel(
+        channel_id: ChannelId,
+        guild_id: GuildId,
+        kind: ChannelType,
+        name: &str,
+        parent_id: Option<ChannelId>,
+        archived: bool,
+    ) -> GuildChannel {
+        let is_thread = matches!(
+            kind,
+            ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread
+        );
+        serde_json::from_value(json!({
+            "id": channel_id.to_string(),
+            "guild_id": guild_id.to_string(),
+            "type": kind,
+            "name": name,
+            "parent_id": parent_id.map(|id| id.to_string()),
+            "thread_metadata": is_thread.then(|| json!({
+                "archived": archived,
+                "auto_archive_duration": 1440,
+                "archive_timestamp": "2022-01-01T00:00:00Z",
+                "locked": false,
+            })),
+        }))
+        .unwrap()
+    }
+
+    fn message(
+        message_id: MessageId,
+        channel_id: ChannelId,
+        author: &User,
+        content: &str,
+    ) -> Message {
+        serde_json::from_value(json!({
+            "id": message_id.to_string(),
+            "channel_id": channel_id.to_string(),
+            "author": author,
+            "content": content,
+            "attachments": [],
+            "embeds": [],
+            "mention_everyone": false,
+            "mention_roles": [],
+            "mentions": [],
+            "pinned": false,
+            "timestamp": "2022-01-01T00:00:00Z",
+            "edited_timestamp": null,
+            "tts": false,
+            "type": MessageType::Regular,
+        }))
+        .unwrap()
+    }
+
+    #[async_trait]
+    impl DiscordApi for MockApi {
+        async fn guild_channel(&self, channel_id: ChannelId) -> Result<GuildChannel> {
+            match self
+                .channel(channel_id)
+                .await
+                .context("チャンネルの取得に失敗")?
+            {
+                Channel::Guild(channel) => Ok(channel),
+                _ => Err(anyhow::anyhow!("サーバーのチャンネルではありません")),
+            }
+        }
+
+        async fn channel(&self, channel_id: ChannelId) -> serenity::Result<Channel> {
+            let state = self.state();
+            if let Some(why) = state.channel_error(channel_id) {
+                return Err(why);
+            }
+            Ok(Channel::Guild(state.channels[&channel_id].clone()))
+        }
+
+        async fn member(&self, guild_id: GuildId, user_id: UserId) -> Result<Member> {
+            let user = self
+                .state()
+                .users
+                .get(&user_id)
+                .cloned()
+                .ok_or_else(|| http_error(404))
+                .context("メンバー取得失敗")?;
+            Ok(member(guild_id, user))
+        }
+
+        async fn send_message_with<F>(
+            &self,
+            channel_id: ChannelId,
+            f: F,
+        ) -> serenity::Result<Message>
+        where
+            F: for<'b> FnOnce(&'b mut CreateMessage<'static>) -> &'b mut CreateMessage<'static>
+                + Send,
+        {
+            let mut builder = CreateMessage::default();
+            f(&mut builder);
+
+            let mut state = self.state();
+            if let Some(status) = state.send_errors.get(&channel_id) {
+                return Err(http_error(*status));
+            }
+            if !state.channels.contains_key(&channel_id) {
+                return Err(http_error(404));
+            }
+            if state.is_archived(channel_id) {
+                return Err(http_error(400));
+            }
+            let has_components = builder.0.contains_key("components");
+            if has_components && state.fail_components {
+                return Err(http_error(400));
+            }
+            let content = builder
+                .0
+                .get("content")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            let message_id = MessageId(state.next_id());
+            let message =
+                state.push_message(channel_id, message_id, &user(BOT_USER_ID, true), &content);
+            state.posts.push(Post {
+                channel_id,
+                message_id,
+                content,
+                has_components,
+            });
+            Ok(message)
+        }
+
+        async fn edit_message<F>(
+            &self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+            f: F,
+        ) -> serenity::Result<Message>
+        where
+            F: for<'b> FnOnce(&'b mut EditMessage<'static>) -> &'b mut EditMessage<'static> + Send,
+        {
+            let mut builder = EditMessage::default();
+            f(&mut builder);
+
+            let mut state = self.state();
+            if state.is_archived(channel_id) {
+                return Err(http_error(400));
+            }
+            let message = state
+                .messages
+                .get_mut(&channel_id)
+                .and_then(|messages| messages.iter_mut().find(|m| m.id == message_id))
+                .ok_or_else(|| http_error(404))?;
+            if let Some(content) = builder.0.get("content").and_then(Value::as_str) {
+                message.content = content.to_string();
+            }
+            let message = message.clone();
+            state.edited.push(message_id);
+            Ok(message)
+        }
+
+        async fn delete_message(
+            &self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+        ) -> serenity::Result<()> {
+            let mut state = self.state();
+            let messages = state.messages.entry(channel_id).or_default();
+            let index = messages
+                .iter()
+                .position(|m| m.id == message_id)
+                .ok_or_else(|| http_error(404))?;
+            messages.remove(index);
+            Ok(())
+        }
+
+        async fn pin_message(
+            &self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+        ) -> serenity::Result<()> {
+            self.message(channel_id, message_id).await?;
+            Ok(())
+        }
+
+        async fn message(
+            &self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+        ) -> serenity::Result<Message> {
+            self.state()
+                .messages
+                .get(&channel_id)
+                .and_then(|messages| messages.iter().find(|m| m.id == message_id))
+                .cloned()
+                .ok_or_else(|| http_error(404))
+        }
+
+        async fn recent_messages(
+            &self,
+            channel_id: ChannelId,
+            limit: u64,
+        ) -> serenity::Result<Vec<Message>> {
+            let state = self.state();
+            if let Some(why) = state.channel_error(channel_id) {
+                return Err(why);
+            }
+            Ok(state
+                .messages
+                .get(&channel_id)
+                .map(|messages| {
+                    messages
+                        .iter()
+                        .rev()
+                        .take(limit as usize)
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default())
+        }
+
+        async fn create_public_thread<F>(
+            &self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+            f: F,
+        ) -> serenity::Result<GuildChannel>
+        where
+            F: FnOnce(&mut CreateThread) -> &mut CreateThread + Send,
+        {
+            let mut builder = CreateThread::default();
+            f(&mut builder);
+
+            let delay = self.state().thread_create_delay;
+            tokio::time::sleep(delay).await;
+            // メッセージに紐づくスレッドは、メッセージと同じIDになる
+            self.state()
+                .create_thread(channel_id, ChannelId(message_id.0), &builder.0)
+                .ok_or_else(|| http_error(404))
+        }
+
+        async fn create_private_thread<F>(
+            &self,
+            channel_id: ChannelId,
+            f: F,
+        ) -> serenity::Result<GuildChannel>
+        where
+            F: FnOnce(&mut CreateThread) -> &mut CreateThread + Send,
+        {
+            let mut builder = CreateThread::default();
+            builder.kind(ChannelType::PrivateThread);
+            f(&mut builder);
+
+            let delay = self.state().thread_create_delay;
+            tokio::time::sleep(delay).await;
+            let mut state = self.state();
+            let thread_id = ChannelId(state.next_id());
+            state
+                .create_thread(channel_id, thread_id, &builder.0)
+                .ok_or_else(|| http_error(404))
+        }
+
+        async fn create_forum_post(
+            &self,
+            channel_id: ChannelId,
+            post: &JsonMap,
+        ) -> serenity::Result<GuildChannel> {
+            let mut state = self.state();
+            let thread_id = ChannelId(state.next_id());
+            let map = HashMap::from([
+                ("name", post.get("name").cloned().unwrap_or_default()),
+                ("type", json!(ChannelType::PublicThread)),
+            ]);
+            let thread = state
+                .create_thread(channel_id, thread_id, &map)
+                .ok_or_else(|| http_error(404))?;
+            // 最初のメッセージは投稿と同じIDになる
+            let content = post["message"]["content"].as_str().unwrap_or_default();
+            state.push_message(
+                thread_id,
+                MessageId(thread_id.0),
+                &user(BOT_USER_ID, true),
+                content,
+            );
+            Ok(thread)
+        }
+
+        async fn edit_thread<F>(&self, thread_id: ChannelId, f: F) -> serenity::Result<GuildChannel>
+        where
+            F: FnOnce(&mut EditThread) -> &mut EditThread + Send,
+        {
+            let mut builder = EditThread::default();
+            f(&mut builder);
+
+            if builder.0.get("archived").and_then(Value::as_bool) == Some(true) {
+                let delay = self.state().archive_delay;
+                tokio::time::sleep(delay).await;
+            }
+            let mut state = self.state();
+            if let Some(why) = state.channel_error(thread_id) {
+                return Err(why);
+            }
+            let thread = state.channels.get_mut(&thread_id).unwrap();
+            if let Some(name) = builder.0.get("name").and_then(Value::as_str) {
+                thread.name = name.to_string();
+            }
+            if let Some(archived) = builder.0.get("archived").and_then(Value::as_bool) {
+                if let Some(metadata) = &mut thread.thread_metadata {
+                    metadata.archived = archived;
+                }
+            }
+            Ok(thread.clone())
+        }
+
+        async fn delete_channel(&self, channel_id: ChannelId) -> serenity::Result<()> {
+            let mut state = self.state();
+            if let Some(why) = state.channel_error(channel_id) {
+                return Err(why);
+            }
+            state.channels.remove(&channel_id);
+            state.messages.remove(&channel_id);
+            state.deleted_channels.push(channel_id);
+            Ok(())
+        }
+
+        async fn edit_channel<F>(
+            &self,
+            channel_id: ChannelId,
+            f: F,
+        ) -> serenity::Result<GuildChannel>
+        where
+            F: FnOnce(&mut EditChannel) -> &mut EditChannel + Send,
+        {
+            let mut builder = EditChannel::default();
+            f(&mut builder);
+
+            let mut state = self.state();
+            if let Some(why) = state.channel_error(channel_id) {
+                return Err(why);
+            }
+            let channel = state
+                .channels
+                .get_mut(&channel_id)
+                .ok_or_else(|| http_error(404))?;
+            if let Some(user_limit) = builder.0.get("user_limit").and_then(Value::as_u64) {
+                channel.user_limit = Some(user_limit);
+            }
+            Ok(channel.clone())
+        }
+
+        async fn archived_threads(
+            &self,
+            channel_id: ChannelId,
+            private: bool,
+            limit: u64,
+        ) -> serenity::Result<ThreadsData> {
+            let kind = if private {
+                ChannelType::PrivateThread
+            } else {
+                ChannelType::PublicThread
+            };
+            let threads = self
+                .state()
+                .channels
+                .values()
+                .filter(|c| c.parent_id == Some(channel_id) && c.kind == kind)
+                .filter(|c| c.thread_metadata.is_some_and(|m| m.archived))
+                .take(limit as usize)
+                .cloned()
+                .collect::<Vec<_>>();
+            Ok(serde_json::from_value(
+                json!({ "threads": threads, "members": [] }),
+            )?)
+        }
+
+        async fn thread_member_ids(&self, thread_id: ChannelId) -> serenity::Result<Vec<UserId>> {
+            let mut state = self.state();
+            if let Some(why) = state.channel_error(thread_id) {
+                return Err(why);
+            }
+            state.thread_member_fetches += 1;
+            Ok(state
+                .thread_members
+                .get(&thread_id)
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        async fn add_thread_member(
+            &self,
+            thread_id: ChannelId,
+            user_id: UserId,
+        ) -> serenity::Result<()> {
+            let mut state = self.state();
+            if let Some(why) = state.channel_error(thread_id) {
+                return Err(why);
+            }
+            state
+                .thread_members
+                .entry(thread_id)
+                .or_default()
+                .push(user_id);
+            Ok(())
+        }
+
+        async fn dm_channel(&self, _user_id: UserId) -> serenity::Result<ChannelId> {
+            // DMを受け付けていないユーザーとして扱う
+            Err(http_error(403))
+        }
+
+        async fn rename_channel(
+            &self,
+            _queue: &RenameQueue,
+            channel_id: ChannelId,
+            name: String,
+        ) -> Result<RenameOutcome> {
+            let mut state = self.state();
+            if let Some(channel) = state.channels.get_mut(&channel_id) {
+                channel.name = name.clone();
+            }
+            state.renames.push((channel_id, name));
+            Ok(RenameOutcome::Renamed)
+        }
+
+        #[cfg(feature = "webhook")]
+        async fn send_webhook_message(
+            &self,
+            _webhooks: &WebhookSender,
+            _parent_id: ChannelId,
+            _thread_id: ChannelId,
+            _username: &str,
+            _content: &str,
+            _notify: bool,
+        ) -> Result<()> {
+            // Webhookが使えない場合として、Botとしての投稿に切り替えさせる
+            Err(anyhow::anyhow!("Webhookは使えません"))
+        }
+
+        async fn create_interaction_response<F>(
+            &self,
+            _interaction_id: InteractionId,
+            _token: &str,
+            f: F,
+        ) -> serenity::Result<()>
+        where
+            F: for<'b> FnOnce(
+                    &'b mut CreateInteractionResponse<'static>,
+                ) -> &'b mut CreateInteractionResponse<'static>
+                + Send,
+        {
+            let mut response = CreateInteractionResponse::default();
+            f(&mut response);
+            self.state().interaction_responses += 1;
+            Ok(())
+        }
+
+        async fn edit_interaction_response<F>(&self, _token: &str, f: F) -> serenity::Result<()>
+        where
+            F: FnOnce(&mut EditInteractionResponse) -> &mut EditInteractionResponse + Send,
+        {
+            let mut response = EditInteractionResponse::default();
+            f(&mut response);
+            Ok(())
+        }
+
+        async fn permissions_in(
+            &self,
+            _guild_id: GuildId,
+            _user_id: UserId,
+            channels: &[GuildChannel],
+        ) -> Result<Vec<Permissions>> {
+            Ok(vec![Permissions::all(); channels.len()])
+        }
+
+        fn user_permissions(
+            &self,
+            _channel: &GuildChannel,
+            _user_id: UserId,
+        ) -> Result<Permissions> {
+            Ok(Permissions::empty())
+        }
+
+        fn cached_user(&self, user_id: UserId) -> Option<User> {
+            self.state().users.get(&user_id).cloned()
+        }
+
+        fn cached_member(&self, guild_id: GuildId, user_id: UserId) -> Option<Member> {
+            self.cached_user(user_id).map(|user| member(guild_id, user))
+        }
+
+        fn user_voice_channel(&self, _guild_id: GuildId, user_id: UserId) -> Option<ChannelId> {
+            self.state().voice_states.get(&user_id).copied()
+        }
+
+        fn voice_channel_members(
+            &self,
+            _guild_id: GuildId,
+            channel_id: ChannelId,
+        ) -> Option<Vec<UserId>> {
+            let state = self.state();
+            if state.voice_cache_missing {
+                return None;
+            }
+            let mut members = state
+                .voice_states
+                .iter()
+                .filter(|(_, c)| **c == channel_id)
+                .map(|(user_id, _)| *user_id)
+                .collect::<Vec<_>>();
+            members.sort();
+            Some(members)
+        }
+
+        fn has_role(&self, _guild_id: GuildId, _role_id: RoleId) -> bool {
+            false
+        }
+
+        fn guild_owner_id(&self, _guild_id: GuildId) -> Option<UserId> {
+            None
+        }
+
+        fn presence_activities(&self, _guild_id: GuildId, _user_id: UserId) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn afk_channel_id(&self, _guild_id: GuildId) -> Option<ChannelId> {
+            self.state().afk_channel_id
+        }
+    }
+}
@@ -1,760 +1,7725 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context as _, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
 use hhmmss::Hhmmss;
-use log::{error, warn};
+use log::{error, info, warn};
+use serenity::builder::{CreateApplicationCommands, CreateComponents, CreateMessage, CreateThread};
+use serenity::http::Http;
+use serenity::model::Timestamp;
 use serenity::model::{
     application::interaction::{Interaction, InteractionResponseType},
-    gateway::Ready,
+    event::ResumedEvent,
+    gateway::{Activity, Ready},
     guild::Member,
-    id::ChannelId,
+    id::{ChannelId, GuildId},
     prelude::{
+        command::{Command, CommandOptionType},
         component::{ActionRowComponent, ButtonStyle, InputTextStyle},
         interaction::{
-            message_component::MessageComponentInteraction, modal::ModalSubmitInteraction,
+            application_command::{ApplicationCommandInteraction, CommandDataOptionValue},
+            message_component::MessageComponentInteraction,
+            modal::ModalSubmitInteraction,
         },
-        Channel, ChannelType, GuildChannel, Message, UserId,
+        AttachmentType, Channel, ChannelType, GuildChannel, Message, MessageId,
+        PartialGuildChannel, Permissions, ReactionType, RoleId, UserId,
     },
     voice::VoiceState,
 };
-use tokio::time::timeout;
+use serenity::utils::Colour;
+use tokio::{sync::OwnedMutexGuard, time::timeout};
 
-use crate::app_config::AppConfig;
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, MetricsText};
+#[cfg(feature = "webhook")]
+use crate::webhook::WebhookSender;
+use crate::{
+    app_config::{
+        AppConfig, ChatMode, CommandScope, DisbandAction, MentionPolicy, ShutdownAction, ThreadType,
+    },
+    config_watcher::ConfigWatcher,
+    discord_api::{DiscordApi, DryRunApi},
+    link_store::{ChannelKey, Link, LinkStore},
+    log_fields::LogFields,
+    messages::{Messages, DEFAULT_EMOJI},
+    rename_queue::{RenameOutcome, RenameQueue},
+    respond::{respond_ephemeral, DeferredResponse, EphemeralResponse},
+    retry::{is_forbidden, is_not_found, retry},
+    storage::{self, StateStorage},
+    voice_time::{VoiceTime, VoiceTimeStore},
+};
 
 use serenity::async_trait;
 use serenity::prelude::*;
 
+/// エラー報告の詳細に載せる最大文字数 (埋め込みの説明文は4096文字まで)
+const ERROR_REPORT_MAX_CHARS: usize = 4000;
+/// Discord APIの呼び出しが一時的に失敗した時に試す最大回数
+const API_RETRY_ATTEMPTS: u32 = 3;
+/// Discordのチャンネル名の最大文字数
+const CHANNEL_NAME_MAX_CHARS: usize = 100;
+/// Webhookの名前の最大文字数
+#[cfg(feature = "webhook")]
+const WEBHOOK_USERNAME_MAX_CHARS: usize = 80;
+/// VCの名前の変更を待つ最大時間 (応答を保留しているので3秒を超えてもよい)
+const RENAME_TIMEOUT: Duration = Duration::from_secs(10);
+/// Botのステータスを更新する間隔 (この間の対応の変化はまとめて反映する)
+const PRESENCE_UPDATE_INTERVAL: Duration = Duration::from_secs(30);
+/// 存在しないチャンネルの対応を掃除する間隔
+const LINK_SWEEP_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// 1回の掃除で存在を確認するVCの最大数
+const LINK_SWEEP_BATCH_SIZE: usize = 50;
+/// APIに負荷をかけないよう、チャンネルの存在を確認するごとに空ける間隔
+const LINK_SWEEP_CHECK_DELAY: Duration = Duration::from_secs(1);
+/// 放置されたスレッドを確認する間隔
+const INACTIVITY_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// スレッドを作成する権限がない時に、同じサーバーの管理者に再び知らせるまでの時間
+const PERMISSION_ALERT_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+/// 使い回すスレッドを探す時に取得する、アーカイブされたスレッドの最大数
+const ARCHIVED_THREAD_SEARCH_LIMIT: u64 = 100;
+/// 削除を保留しているスレッドを確認する間隔
+const DEFERRED_DELETE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// 参加中のメンバーの表示を更新する間隔 (この間の参加・退出はまとめて反映する)
+const PANEL_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+/// 週ごとの動作状況の投稿時刻の設定を確認し直す間隔
+const WEEKLY_REPORT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// 週ごとの動作状況に載せる、参加の多いVC・VCにいた時間の長いユーザーの数
+const WEEKLY_REPORT_TOP_COUNT: usize = 5;
+/// 名前の変更の確認を保留しておく時間 (インタラクションは15分で期限が切れる)
+const PENDING_RENAME_TTL: Duration = Duration::from_secs(15 * 60);
+/// プライベートスレッドにメンバーを続けて招待する時の間隔 (レート制限にかからないようにする)
+const THREAD_MEMBER_ADD_INTERVAL: Duration = Duration::from_millis(250);
+/// 解散時に、人間の発言があるかを確かめるために取得するスレッドのメッセージの数
+const DISBAND_MESSAGE_CHECK_LIMIT: u64 = 20;
+
+/// サーバーごとの週の動作状況 (前回投稿してから)
+#[derive(Debug, Default, Clone)]
+struct WeeklyStats {
+    /// 作成したスレッドの数
+    threads_created: usize,
+    /// VCの名前→参加の回数のマップ
+    vc_joins: HashMap<String, usize>,
+    /// ユーザー→カスタムVCにいた時間 (秒) のマップ
+    voice_seconds: HashMap<UserId, i64>,
+}
+
+/// サーバーごとのVC⇔スレッドの対応とオーナー (調査用に複製したもの)
+/// JSONのキーには文字列しか使えないため、IDを文字列にして並べ替えておく
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct StateSnapshot {
+    /// VC→スレッドのマップ
+    pub vc_to_thread: BTreeMap<String, String>,
+    /// スレッド→VCのマップ
+    pub thread_to_vc: BTreeMap<String, String>,
+    /// VC→オーナーのマップ
+    pub vc_owner: BTreeMap<String, String>,
+}
+
+/// VCの通話の記録 (スレッドの作成から解散まで)
+#[derive(Debug, Clone)]
+struct VcSession {
+    /// 通話の開始時刻
+    started_at: DateTime<Utc>,
+    /// 通話中に参加したユーザー (参加順)
+    participants: Vec<UserId>,
+}
+
+impl VcSession {
+    /// 参加者を記録する (既に記録されている場合は何もしない)
+    fn add_participant(&mut self, user_id: UserId) {
+        if !self.participants.contains(&user_id) {
+            self.participants.push(user_id);
+        }
+    }
+}
+
+/// 確認を待っている名前の変更
+#[derive(Debug, Clone)]
+struct PendingRename {
+    /// 名前を変更するVCのスレッドのチャンネルID
+    thread_id: ChannelId,
+    /// 名前を変更したユーザー
+    user_id: UserId,
+    /// 変更後の名前
+    name: String,
+    /// スレッドに投稿するステータス
+    status: Option<String>,
+    /// 名前の変更を入力した時刻
+    requested_at: Instant,
+}
+
 /// イベント受信リスナー
 pub struct Handler {
     /// Bot
     bot_user_id: Mutex<Option<UserId>>,
-    /// 設定
-    app_config: AppConfig,
+    /// 定期処理で使うコンテキスト (準備完了時に設定する)
+    context: Mutex<Option<Context>>,
+    /// 起動時の復元と再接続時の状態合わせを同時に行わないためのロック
+    resync_lock: Mutex<()>,
+    /// 状態の保存を同時に行わないためのロック
+    save_lock: Mutex<()>,
+    /// VC⇔スレッドの対応と通話時間の保存先
+    storage: Arc<dyn StateStorage>,
+    /// 設定 (設定ファイルが変更されたら読み込み直す)
+    app_config: RwLock<Arc<AppConfig>>,
+    /// メッセージ (設定ファイルが変更されたら読み込み直す)
+    messages: RwLock<Arc<Messages>>,
     /// VC→スレッドのマップ
-    vc_to_thread: Mutex<HashMap<ChannelId, ChannelId>>,
+    vc_to_thread: DashMap<ChannelKey, ChannelId>,
     /// スレッド→VCのマップ
-    thread_to_vc: Mutex<HashMap<ChannelId, ChannelId>>,
+    thread_to_vc: DashMap<ChannelKey, ChannelId>,
     /// スレッド→VC作成時のメッセージのIDのマップ
-    thread_to_agenda_message: Mutex<HashMap<ChannelId, Message>>,
+    thread_to_agenda_message: DashMap<ChannelKey, Message>,
+    /// スレッド→ボタン付きの参加メッセージのIDのマップ
+    thread_to_welcome_message: DashMap<ChannelKey, MessageId>,
+    /// VC→VCのチャットに投稿したスレッドへの案内メッセージのIDのマップ
+    vc_to_guide_message: DashMap<ChannelKey, MessageId>,
+    /// スレッド→VCのステータスを載せたメッセージのIDのマップ
+    thread_to_status_message: DashMap<ChannelKey, MessageId>,
+    /// スレッド→参加中のメンバーを表示するメッセージのIDのマップ
+    thread_to_panel_message: DashMap<ChannelKey, MessageId>,
+    /// 参加中のメンバーの表示を更新する必要があるVC
+    panels_outdated: DashSet<ChannelKey>,
+    /// VC→対応を最後に使った時刻のマップ (対応の数が上限を超えたら古いものから破棄する)
+    link_touched: DashMap<ChannelKey, Instant>,
+    /// VC→オーナー(最初に参加したユーザー)のマップ
+    vc_owner: DashMap<ChannelKey, UserId>,
+    /// VC→参加中のユーザーと参加時刻のマップ
+    vc_join_times: DashMap<ChannelKey, HashMap<UserId, DateTime<Utc>>>,
+    /// VC→退出したユーザーと退出時刻のマップ
+    vc_leave_times: DashMap<ChannelKey, HashMap<UserId, DateTime<Utc>>>,
+    /// VC→スレッドの削除を保留してアーカイブした時刻 (すぐに再参加された場合は同じスレッドを使う)
+    vc_archived_at: DashMap<ChannelKey, DateTime<Utc>>,
+    /// サーバー→スレッドを作成する権限がないことを管理者に知らせた時刻のマップ (繰り返し知らせないため)
+    permission_alerted_at: DashMap<GuildId, Instant>,
+    /// 公開スレッド→Botが把握しているスレッドのメンバーのマップ (参加のたびにAPIで確認しないため)
+    thread_members: DashMap<ChannelKey, HashSet<UserId>>,
+    /// (サーバー, ユーザー)→カスタムVCにいた合計時間 (秒) のマップ
+    voice_totals: DashMap<(GuildId, UserId), i64>,
+    /// (サーバー, ユーザー)→参加中のカスタムVCと参加した時刻のマップ (退出時に合計時間に加える)
+    voice_started_at: DashMap<(GuildId, UserId), (ChannelId, DateTime<Utc>)>,
+    /// サーバー→前回投稿してからの週の動作状況のマップ
+    weekly_stats: DashMap<GuildId, WeeklyStats>,
+    /// チャンネル名の変更キュー
+    rename_queue: RenameQueue,
+    /// VC→VCごとの処理のロックのマップ
+    vc_locks: DashMap<ChannelKey, Arc<Mutex<()>>>,
+    /// VC→通話の記録のマップ
+    vc_sessions: DashMap<ChannelKey, VcSession>,
+    /// サーバー→スレッドを作成するチャンネルの種類のマップ
+    thread_channel_kinds: DashMap<GuildId, ChannelType>,
+    /// 起動してから作成したスレッドの数
+    threads_created: AtomicUsize,
+    /// 起動してからアーカイブしたスレッドの数
+    threads_archived: AtomicUsize,
+    /// 起動してから上限を超えたため破棄したVC⇔スレッドの対応の数
+    links_evicted: AtomicUsize,
+    /// 起動してから報告したエラーの数
+    #[cfg(feature = "metrics")]
+    errors_reported: AtomicUsize,
+    /// 起動してから名前変更ダイアログでVCの名前を変更した回数
+    renames_applied: AtomicUsize,
+    /// 次の掃除で存在を確認し始めるVCの位置
+    sweep_cursor: AtomicUsize,
+    /// VCの名前で投稿するためのWebhook
+    #[cfg(feature = "webhook")]
+    webhooks: WebhookSender,
+    /// チャンネル→取得したチャンネル名のマップ (チャンネルの更新・削除時に更新する)
+    channel_names: DashMap<ChannelId, String>,
+    /// VC→参加メッセージをまとめるために待っているメンバーのマップ
+    pending_joins: DashMap<ChannelKey, Vec<UserId>>,
+    /// 名前の変更の入力→確認を待っている名前の変更のマップ
+    pending_renames: DashMap<u64, PendingRename>,
+    /// VC→Botが変更した後の名前と、変更したユーザーのマップ (名前の変更を知らせる時に使う)
+    bot_renames: DashMap<ChannelKey, (String, Option<UserId>)>,
+    /// VC⇔スレッドの対応が変わり、ステータスの更新が必要か
+    presence_outdated: AtomicBool,
+    /// スレッドの数が上限に達したことを知らせたか (空きができて作成できたら戻す)
+    thread_cap_alerted: AtomicBool,
 }
 
 impl Handler {
     /// コンストラクタ
-    pub fn new(app_config: AppConfig) -> Result<Self> {
-        Ok(Self {
-            bot_user_id: Mutex::new(None),
+    pub fn new(app_config: AppConfig, messages: Messages) -> Result<Self> {
+        // 設定された保存先を開く
+        let storage = storage::open(&app_config).context("状態の保存先を開けませんでした")?;
+
+        // 前回終了時のVC⇔スレッドの対応を読み込む
+        // 既に存在しないチャンネルはget_vcで取得する際に弾かれる
+        let links = storage
+            .load_links()
+            .context("VC⇔スレッドの対応の読み込みに失敗")?;
+
+        // ユーザーごとのVCにいた合計時間を読み込む
+        let voice_times = storage
+            .load_voice_times()
+            .context("通話時間の読み込みに失敗")?;
+
+        Ok(Self::with_state(
             app_config,
-            vc_to_thread: Mutex::new(HashMap::new()),
-            thread_to_vc: Mutex::new(HashMap::new()),
-            thread_to_agenda_message: Mutex::new(HashMap::new()),
-        })
+            messages,
+            storage,
+            links,
+            voice_times,
+        ))
     }
 
-    /// カスタムVCかどうか判定する
-    fn is_custom_vc(&self, channel: &GuildChannel) -> bool {
-        // チャンネルがVCでない場合は無視
-        if channel.kind != ChannelType::Voice {
-            return false;
+    /// 対応と通話時間を指定して構築する (保存先から読み込まない)
+    pub fn with_state(
+        app_config: AppConfig,
+        messages: Messages,
+        storage: Arc<dyn StateStorage>,
+        links: LinkStore,
+        voice_times: VoiceTimeStore,
+    ) -> Self {
+        // 対応の一覧をマップに展開する
+        let vc_to_thread = DashMap::new();
+        let thread_to_vc = DashMap::new();
+        let thread_to_welcome_message = DashMap::new();
+        let vc_to_guide_message = DashMap::new();
+        let thread_to_status_message = DashMap::new();
+        let thread_to_panel_message = DashMap::new();
+        let link_touched = DashMap::new();
+        let now = Instant::now();
+        for link in links.links {
+            link_touched.insert((link.guild_id, link.vc_channel_id), now);
+            vc_to_thread.insert((link.guild_id, link.vc_channel_id), link.thread_id);
+            thread_to_vc.insert((link.guild_id, link.thread_id), link.vc_channel_id);
+            if let Some(welcome_message_id) = link.welcome_message_id {
+                thread_to_welcome_message
+                    .insert((link.guild_id, link.thread_id), welcome_message_id);
+            }
+            if let Some(guide_message_id) = link.guide_message_id {
+                vc_to_guide_message.insert((link.guild_id, link.vc_channel_id), guide_message_id);
+            }
+            if let Some(status_message_id) = link.status_message_id {
+                thread_to_status_message.insert((link.guild_id, link.thread_id), status_message_id);
+            }
+            if let Some(panel_message_id) = link.panel_message_id {
+                thread_to_panel_message.insert((link.guild_id, link.thread_id), panel_message_id);
+            }
         }
 
-        // 親チャンネルID(≒カテゴリID)取得
-        let parent_channel_id = match channel.parent_id {
-            Some(id) => id,
-            None => return false,
-        };
+        // ユーザーごとのVCにいた合計時間
+        // Botの停止中にVCにいた時間はわからないので、起動前から参加しているメンバーは起動後の状態合わせの時点から数える
+        let voice_totals = voice_times
+            .totals
+            .into_iter()
+            .map(|t| ((t.guild_id, t.user_id), t.seconds))
+            .collect::<DashMap<_, _>>();
 
-        // 親チャンネルIDがカスタムVCカテゴリかどうか判定
-        if parent_channel_id != self.app_config.discord.vc_category {
-            return false;
-        }
+        // VCの名前で投稿するためのWebhook
+        #[cfg(feature = "webhook")]
+        let webhooks = WebhookSender::new(app_config.discord.webhook_avatar_url.clone());
 
-        // チャンネルが無視されるチャンネルかどうか判定
-        if self
-            .app_config
-            .discord
-            .vc_ignored_channels
-            .contains(&channel.id)
-        {
-            return false;
+        Self {
+            bot_user_id: Mutex::new(None),
+            context: Mutex::new(None),
+            resync_lock: Mutex::new(()),
+            save_lock: Mutex::new(()),
+            storage,
+            app_config: RwLock::new(Arc::new(app_config)),
+            messages: RwLock::new(Arc::new(messages)),
+            vc_to_thread,
+            thread_to_vc,
+            thread_to_welcome_message,
+            vc_to_guide_message,
+            thread_to_status_message,
+            thread_to_panel_message,
+            panels_outdated: DashSet::new(),
+            thread_to_agenda_message: DashMap::new(),
+            link_touched,
+            vc_owner: DashMap::new(),
+            vc_join_times: DashMap::new(),
+            vc_leave_times: DashMap::new(),
+            vc_archived_at: DashMap::new(),
+            thread_members: DashMap::new(),
+            voice_totals,
+            voice_started_at: DashMap::new(),
+            weekly_stats: DashMap::new(),
+            permission_alerted_at: DashMap::new(),
+            rename_queue: RenameQueue::default(),
+            vc_locks: DashMap::new(),
+            vc_sessions: DashMap::new(),
+            thread_channel_kinds: DashMap::new(),
+            threads_created: AtomicUsize::new(0),
+            threads_archived: AtomicUsize::new(0),
+            links_evicted: AtomicUsize::new(0),
+            #[cfg(feature = "metrics")]
+            errors_reported: AtomicUsize::new(0),
+            renames_applied: AtomicUsize::new(0),
+            sweep_cursor: AtomicUsize::new(0),
+            #[cfg(feature = "webhook")]
+            webhooks,
+            channel_names: DashMap::new(),
+            pending_joins: DashMap::new(),
+            bot_renames: DashMap::new(),
+            pending_renames: DashMap::new(),
+            // 起動時に一度表示する
+            presence_outdated: AtomicBool::new(true),
+            thread_cap_alerted: AtomicBool::new(false),
         }
-
-        true
     }
 
-    /// 参加時にスレッドを作成する
-    async fn create_or_mention_thread(
-        &self,
-        ctx: &Context,
-        vc_channel_id: &ChannelId,
-        member: &Member,
-    ) -> Result<()> {
-        // マップからスレッドのチャンネルIDを取得
-        let map = self
-            .vc_to_thread
-            .lock()
-            .await
-            .get(vc_channel_id)
-            .map(|c| c.clone());
-        // 一度変数に入れてからmatchにいれないとロックされっぱなしになる
-        match map {
-            // スレッドが作成済みの場合
-            Some(thread_id) => {
-                // スレッドのメンバーを取得
-                let members = thread_id
-                    .get_thread_members(ctx)
-                    .await
-                    .context("スレッドメンバーの取得に失敗")?;
-                // メンバーが存在しない場合
-                if !members
-                    .iter()
-                    .filter_map(|m| m.user_id)
-                    .any(|user_id| user_id == member.user.id)
-                {
-                    // 参加メッセージ
-                    thread_id
-                        .send_message(ctx, |m| {
-                            m.content(format!("{} さんが参加しました。", member.mention()));
-                            m
-                        })
-                        .await
-                        .context("参加メッセージの送信に失敗")?;
-                }
-            }
-            // スレッドが作成されていない場合
-            None => {
-                // チャンネル名を取得
-                let channel_name = vc_channel_id
-                    .name(&ctx)
-                    .await
-                    .unwrap_or("不明なVC".to_string());
-                // VCカテゴリチャンネルにメッセージを送信
-                let thread_channel = self.app_config.discord.thread_channel;
-                // 議題メッセージを送信
-                let message = thread_channel
-                    .send_message(ctx, |m| {
-                        m.content(format!(
-                            "{} さんが新しいVCを作成しました。\nVCに参加する→ {}",
-                            member.mention(),
-                            vc_channel_id.mention(),
-                        ));
-                        m.allowed_mentions(|m| m.empty_users());
-                        m
-                    })
-                    .await
-                    .context("議題メッセージの送信に失敗")?;
-                // スレッドを作成
-                let thread = thread_channel
-                    .create_public_thread(ctx, &message, |m| {
-                        m.name(&channel_name);
-                        m.kind(ChannelType::PublicThread);
-                        m
-                    })
-                    .await
-                    .context("スレッドの作成に失敗")?;
-                // VCのテキストにチャンネルメンションを追加
-                vc_channel_id
-                    .send_message(ctx, |m| {
-                        m.content(format!("VCチャット→ {}", thread.mention()));
-                        m
-                    })
-                    .await
-                    .context("VCチャットの案内メッセージ作成に失敗")?;
-                // 参加メッセージ
-                thread
-                    .send_message(ctx, |m| {
-                        m.content(format!("{} `{}`へようこそ。\n興味を引くチャンネル名に変えてみんなを呼び込もう！", member.mention(), &channel_name));
-                        m.components(|c| {
-                            c.create_action_row(|f| {
-                                f.create_button(|b| {
-                                    b.label("📝チャンネル名を変える");
-                                    b.style(ButtonStyle::Success);
-                                    b.custom_id("rename_button");
-                                    b
-                                });
-                                f
-                            });
-                            c
-                        });
-                        m
-                    })
-                    .await
-                    .context("参加メッセージの作成に失敗")?;
+    /// サーバーのVC⇔スレッドの対応とオーナーを複製する (他のサーバーの分は含めない)
+    pub fn snapshot_state(&self, guild_id: GuildId) -> StateSnapshot {
+        StateSnapshot {
+            vc_to_thread: guild_entries(&self.vc_to_thread, guild_id),
+            thread_to_vc: guild_entries(&self.thread_to_vc, guild_id),
+            vc_owner: guild_entries(&self.vc_owner, guild_id),
+        }
+    }
 
-                // スレッドID->VCを登録
-                self.thread_to_vc
-                    .lock()
-                    .await
-                    .insert(thread.id, vc_channel_id.clone());
+    /// 今の設定を取得する
+    /// 設定を読み込み直すと入れ替わるため、参照を持ち続ける場合は取得したものを変数に入れておく
+    fn app_config(&self) -> Arc<AppConfig> {
+        self.app_config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
 
-                // チャンネルID->スレッドを登録
-                self.vc_to_thread
-                    .lock()
-                    .await
-                    .insert(vc_channel_id.clone(), thread.id);
+    /// 今のメッセージを取得する
+    fn messages(&self) -> Arc<Messages> {
+        self.messages
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
 
-                // チャンネルID->スレッドを登録
-                self.thread_to_agenda_message
-                    .lock()
-                    .await
-                    .insert(thread.id, message);
+    /// 設定ファイルが変更されたら、設定とメッセージを読み込み直す
+    /// Bot終了時にタスクごと止める
+    pub async fn run_config_reloader(self: Arc<Self>, basedir: String) {
+        let mut watcher = match ConfigWatcher::new(&basedir) {
+            Ok(watcher) => watcher,
+            Err(why) => {
+                error!("設定ファイルの監視の開始に失敗: {:?}", why);
+                return;
             }
         };
-
-        Ok(())
+        loop {
+            watcher.changed().await;
+            match self.reload_config(&basedir) {
+                Ok(_) => info!("設定ファイルを読み込み直しました"),
+                Err(why) => error!(
+                    "設定ファイルの読み込み直しに失敗 (今の設定のまま動かします): {:?}",
+                    why
+                ),
+            }
+        }
     }
 
-    /// VC名前変更時にスレッドをリネームする
-    async fn rename_thread(&self, ctx: &Context, vc_channel_id: &ChannelId) -> Result<()> {
-        // マップからスレッドのチャンネルIDを取得
-        let channel_id = self
-            .vc_to_thread
-            .lock()
-            .await
-            .get(vc_channel_id)
-            .map(|c| c.clone());
-        // 一度変数に入れてからmatchにいれないとロックされっぱなしになる
-        match channel_id {
-            // スレッドが作成済みの場合
-            Some(thread_id) => {
-                // チャンネル名を取得
-                let channel_name = vc_channel_id
-                    .name(&ctx)
-                    .await
-                    .unwrap_or("不明なVC".to_string());
-                // スレッドをリネーム
-                thread_id
-                    .edit_thread(ctx, |t| {
-                        t.name(channel_name);
-                        t
-                    })
-                    .await
-                    .context("スレッドのリネームに失敗")?;
-            }
-            // スレッドが作成されていない場合
-            None => {}
-        };
+    /// 設定とメッセージを読み込み直す
+    /// 検証に通った場合だけ入れ替え、実行中に変更できない設定は今の値のままにする
+    fn reload_config(&self, basedir: &str) -> Result<()> {
+        let mut app_config =
+            AppConfig::load_config(basedir).context("設定ファイルの読み込みに失敗")?;
+        let messages = Messages::load(basedir, &app_config.discord.locale)
+            .context("ロケールファイルの読み込みに失敗")?;
+        for name in app_config.keep_fixed_fields(&self.app_config()) {
+            warn!(
+                "{} は実行中に変更できないため、再起動するまで反映しません",
+                name
+            );
+        }
 
+        *self
+            .app_config
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(app_config);
+        *self
+            .messages
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(messages);
         Ok(())
     }
 
-    /// VCを取得
-    async fn get_vc(&self, ctx: &Context, channel_id: &ChannelId) -> Result<GuildChannel> {
-        // マップからスレッドのチャンネルIDを取得
-        // 一度変数に入れてからmatchにいれないとロックされっぱなしになる
-        let vc_channel_id = self
-            .thread_to_vc
-            .lock()
-            .await
-            .get(channel_id)
-            .map(|c| c.clone())
-            .ok_or(anyhow::anyhow!("無効なVCチャンネル"))?;
-        let vc_channel = vc_channel_id
-            .to_channel(&ctx)
+    /// VC⇔スレッドの対応を保存先に保存する
+    /// 同時に呼ばれても古い対応で上書きしないよう、対応の取得から書き込みまでをロックする
+    async fn save_links(&self) {
+        // ドライランで仮に作成したスレッドとの対応は保存しない
+        if self.skip_in_dry_run(|| "VC⇔スレッドの対応を保存".to_string()) {
+            return;
+        }
+        let _guard = self.save_lock.lock().await;
+        let links = LinkStore {
+            links: self
+                .vc_to_thread
+                .iter()
+                .map(|entry| {
+                    let (&(guild_id, vc_channel_id), &thread_id) = entry.pair();
+                    Link {
+                        guild_id,
+                        vc_channel_id,
+                        thread_id,
+                        welcome_message_id: self
+                            .thread_to_welcome_message
+                            .get(&(guild_id, thread_id))
+                            .map(|r| *r),
+                        guide_message_id: self
+                            .vc_to_guide_message
+                            .get(&(guild_id, vc_channel_id))
+                            .map(|r| *r),
+                        status_message_id: self
+                            .thread_to_status_message
+                            .get(&(guild_id, thread_id))
+                            .map(|r| *r),
+                        panel_message_id: self
+                            .thread_to_panel_message
+                            .get(&(guild_id, thread_id))
+                            .map(|r| *r),
+                    }
+                })
+                .collect(),
+        };
+        // 書き込みで非同期の処理を止めないよう、別のスレッドで書き込む
+        let storage = self.storage.clone();
+        let result = tokio::task::spawn_blocking(move || storage.save_links(&links))
             .await
-            .context("チャンネルの取得に失敗")?;
-        let vc_channel = vc_channel
-            .guild()
-            .ok_or(anyhow::anyhow!("無効なVCチャンネルの種類"))?;
-        Ok(vc_channel)
+            .context("状態の書き込みの待機に失敗")
+            .and_then(|result| result);
+        // 保存に失敗してもBotの動作は継続したいので、ログを出力だけしておく
+        if let Err(why) = result {
+            error!("VC⇔スレッドの対応の保存に失敗: {:?}", why);
+        }
     }
 
-    /// VC名前変更時にスレッドをリネームする
-    async fn button_pressed(
-        &self,
-        ctx: &Context,
-        interaction: &MessageComponentInteraction,
-    ) -> Result<()> {
-        // VCチャンネルを取得
-        let vc_channel = match self.get_vc(ctx, &interaction.channel_id).await {
-            Ok(vc_channel) => vc_channel,
-            Err(_) => {
-                return {
-                    interaction
-                        .create_interaction_response(&ctx, |r| {
-                            r.kind(InteractionResponseType::ChannelMessageWithSource)
-                                .interaction_response_data(|d| {
-                                    d.content("❌そのVCは既に解散しています");
-                                    d.ephemeral(true);
-                                    d
-                                });
-                            r
-                        })
-                        .await
-                        .context("エラー内容の応答に失敗")?;
-
-                    Ok(())
-                }
-            }
+    /// ユーザーごとのVCにいた合計時間を保存先に保存する
+    /// 同時に呼ばれても古い時間で上書きしないよう、時間の取得から書き込みまでをロックする
+    async fn save_voice_times(&self) {
+        let _guard = self.save_lock.lock().await;
+        let store = VoiceTimeStore {
+            totals: self
+                .voice_totals
+                .iter()
+                .map(|entry| {
+                    let (&(guild_id, user_id), &seconds) = entry.pair();
+                    VoiceTime {
+                        guild_id,
+                        user_id,
+                        seconds,
+                    }
+                })
+                .collect(),
         };
+        // 書き込みで非同期の処理を止めないよう、別のスレッドで書き込む
+        let storage = self.storage.clone();
+        let result = tokio::task::spawn_blocking(move || storage.save_voice_times(&store))
+            .await
+            .context("通話時間の書き込みの待機に失敗")
+            .and_then(|result| result);
+        // 保存に失敗してもBotの動作は継続したいので、ログを出力だけしておく
+        if let Err(why) = result {
+            error!("通話時間の保存に失敗: {:?}", why);
+        }
+    }
 
-        // VCの権限をチェック
-        match vc_channel.permissions_for_user(&ctx, interaction.user.id) {
-            Ok(vc_permission) if vc_permission.manage_channels() => {}
-            _ => {
-                return {
-                    interaction
-                        .create_interaction_response(&ctx, |r| {
-                            r.kind(InteractionResponseType::ChannelMessageWithSource)
-                                .interaction_response_data(|d| {
-                                    d.content("❌VCのオーナーのみが名前を変更できます");
-                                    d.ephemeral(true);
-                                    d
-                                });
-                            r
-                        })
-                        .await
-                        .context("エラー内容の応答に失敗")?;
-
-                    Ok(())
-                }
-            }
-        };
+    /// カスタムVCに参加した時刻を記録する
+    fn start_voice_time(&self, guild_id: GuildId, user_id: UserId, vc_channel_id: ChannelId) {
+        self.voice_started_at
+            .insert((guild_id, user_id), (vc_channel_id, Utc::now()));
+    }
 
-        // モーダルダイアログを開く
-        interaction
-            .create_interaction_response(&ctx, |r| {
-                r.kind(InteractionResponseType::Modal)
-                    .interaction_response_data(|d| {
-                        d.custom_id("rename_title");
-                        d.title("✏️チャンネル名を変える");
-                        d.components(|c| {
-                            c.create_action_row(|f| {
-                                f.create_input_text(|t| {
-                                    t.custom_id("rename_text");
-                                    t.label("VCのテーマは？");
-                                    t.placeholder("フォートナイト, しりとり, カラオケ,...");
-                                    t.style(InputTextStyle::Short);
-                                    t
-                                });
-                                f
-                            });
-                            c
-                        });
-                        d
-                    });
-                r
+    /// カスタムVCから退出した時に、参加していた時間を合計時間に加える
+    async fn finish_voice_time(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        vc_channel_id: ChannelId,
+    ) {
+        let started_at = self
+            .voice_started_at
+            .remove_if(&(guild_id, user_id), |_, (channel_id, _)| {
+                *channel_id == vc_channel_id
             })
-            .await
-            .context("ダイアログの作成に失敗")?;
+            .map(|(_, (_, started_at))| started_at);
+        if let Some(started_at) = started_at {
+            let seconds = (Utc::now() - started_at).num_seconds().max(0);
+            *self.voice_totals.entry((guild_id, user_id)).or_default() += seconds;
+            *self
+                .weekly_stats
+                .entry(guild_id)
+                .or_default()
+                .voice_seconds
+                .entry(user_id)
+                .or_default() += seconds;
+            self.save_voice_times().await;
+        }
+    }
 
-        Ok(())
+    /// VCが無くなった時に、参加していた全員の時間を合計時間に加える
+    async fn finish_vc_voice_times(&self, vc_key: &ChannelKey) {
+        let user_ids = self
+            .voice_started_at
+            .iter()
+            .filter(|entry| entry.key().0 == vc_key.0 && entry.value().0 == vc_key.1)
+            .map(|entry| entry.key().1)
+            .collect::<Vec<_>>();
+        for user_id in user_ids {
+            self.finish_voice_time(vc_key.0, user_id, vc_key.1).await;
+        }
     }
 
-    /// VC名前変更時にスレッドをリネームする
-    async fn rename_vc(&self, ctx: &Context, interaction: &ModalSubmitInteraction) -> Result<()> {
-        // VCチャンネルを取得
-        let mut vc_channel = match self.get_vc(ctx, &interaction.channel_id).await {
-            Ok(vc_channel) => vc_channel,
-            Err(_) => {
-                return {
-                    interaction
-                        .create_interaction_response(&ctx, |r| {
-                            r.kind(InteractionResponseType::ChannelMessageWithSource)
-                                .interaction_response_data(|d| {
-                                    d.content("❌そのVCは既に解散しています");
-                                    d.ephemeral(true);
-                                    d
-                                });
-                            r
-                        })
-                        .await
-                        .context("エラー内容の応答に失敗")?;
+    /// ユーザーがVCにいた合計時間 (参加中の場合は今までの時間も含める)
+    fn voice_time(&self, guild_id: GuildId, user_id: UserId) -> chrono::Duration {
+        let total = self
+            .voice_totals
+            .get(&(guild_id, user_id))
+            .map(|r| *r)
+            .unwrap_or(0);
+        let current = self
+            .voice_started_at
+            .get(&(guild_id, user_id))
+            .map(|r| (Utc::now() - r.1).num_seconds().max(0))
+            .unwrap_or(0);
+        chrono::Duration::seconds(total + current)
+    }
 
-                    Ok(())
-                }
-            }
-        };
+    /// VC⇔スレッドの対応を登録する
+    async fn link(&self, guild_id: GuildId, vc_channel_id: ChannelId, thread_id: ChannelId) {
+        // スレッドID->VCを登録
+        self.thread_to_vc
+            .insert((guild_id, thread_id), vc_channel_id);
 
-        // VCの権限をチェック
-        match vc_channel
-            .permissions_for_user(&ctx, interaction.user.id)
-            .context("VCチャンネルのパーミッション取得に失敗")?
-        {
-            vc_permission if vc_permission.manage_channels() => {}
-            _ => {
-                return {
-                    interaction
-                        .create_interaction_response(&ctx, |r| {
-                            r.kind(InteractionResponseType::ChannelMessageWithSource)
-                                .interaction_response_data(|d| {
-                                    d.content("❌VCのオーナーのみが名前を変更できます");
-                                    d.ephemeral(true);
-                                    d
-                                });
-                            r
-                        })
-                        .await
-                        .context("エラー内容の応答に失敗")?;
+        // チャンネルID->スレッドを登録
+        self.vc_to_thread
+            .insert((guild_id, vc_channel_id), thread_id);
+        self.link_touched
+            .insert((guild_id, vc_channel_id), Instant::now());
 
-                    Ok(())
-                }
-            }
+        // 対応の数が上限を超えたら、最も長く使われていない対応を破棄する
+        self.evict_links(&(guild_id, vc_channel_id)).await;
+
+        self.presence_outdated.store(true, Ordering::Relaxed);
+        self.save_links().await;
+    }
+
+    /// 対応の数が上限を超えている間、最も長く使われていない対応から破棄する
+    /// 破棄したVCは、次に参加した時に新しくスレッドを作成する
+    async fn evict_links(&self, keep: &ChannelKey) {
+        let max_links = match self.app_config().discord.max_links {
+            Some(max_links) => max_links,
+            None => return,
         };
+        if self.vc_to_thread.len() <= max_links {
+            return;
+        }
 
-        // VCの名前を取得
-        let name = interaction
-            .data
-            .components
+        // 使った時刻が古い順に並べる (マップの参照を持ったまま待機しないよう、先に集める)
+        let mut candidates = self
+            .link_touched
             .iter()
-            .flat_map(|c| c.components.iter())
-            .find_map(|c| match c {
-                ActionRowComponent::InputText(t) if t.custom_id == "rename_text" => {
-                    Some(t.value.clone())
-                }
-                _ => None,
-            })
-            .ok_or(anyhow::anyhow!("コンポーネントが見つかりません"))?;
-
-        // VCの名前を変更
-        let future = vc_channel.edit(&ctx, |e| {
-            e.name(name);
-            e
-        });
-        // レートリミットがかかると遅いので、2秒でタイムアウト
-        let result = match timeout(Duration::from_secs(2), future).await {
-            Ok(result) => result,
-            Err(_) => {
-                return {
-                    interaction
-                        .create_interaction_response(&ctx, |r| {
-                            r.kind(InteractionResponseType::ChannelMessageWithSource)
-                                .interaction_response_data(|d| {
-                                    d.content("❌VCの名前の変更に失敗しました\n```\n短時間に名前変更をしすぎてDiscord APIのレート上限に引っかかった可能性があります\n10分ほど待って再度お試しください```");
-                                    d.ephemeral(true);
-                                    d
-                                });
-                            r
-                        })
-                        .await
-                        .context("エラー内容の応答に失敗")?;
+            .filter(|entry| entry.key() != keep)
+            .map(|entry| (*entry.value(), *entry.key()))
+            .collect::<Vec<_>>();
+        candidates.sort();
 
-                    Ok(())
-                }
+        for (_, vc_key) in candidates {
+            if self.vc_to_thread.len() <= max_links {
+                break;
             }
-        };
-        // 権限などで失敗した場合はエラーを返す
-        result.context("VCの名前変更に失敗")?;
+            // 処理中のVCは破棄しない (他のVCのロックを待つと、お互いに待ち合ってしまう)
+            let lock = self.vc_locks.entry(vc_key).or_default().value().clone();
+            let _guard = match lock.try_lock_owned() {
+                Ok(guard) => guard,
+                Err(_) => continue,
+            };
+            warn!(
+                "VC⇔スレッドの対応が上限 ({}件) を超えたため、古い対応を破棄します: {:?}",
+                max_links, vc_key
+            );
+            self.forget_vc(&vc_key).await;
+            self.links_evicted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-        // 返答
-        interaction
-            .create_interaction_response(&ctx, |r| {
-                r.kind(InteractionResponseType::ChannelMessageWithSource)
-                    .interaction_response_data(|d| {
-                        d.content(format!(
-                            "✅{} が名前を変更しました",
-                            interaction.user.mention()
-                        ));
-                        d.allowed_mentions(|m| m.empty_users());
-                        d
-                    });
-                r
-            })
-            .await
-            .context("結果の応答に失敗")?;
+    /// 対応を使った時刻を記録する (対応がなければ何もしない)
+    fn touch_link(&self, vc_key: &ChannelKey) {
+        if let Some(mut touched) = self.link_touched.get_mut(vc_key) {
+            *touched = Instant::now();
+        }
+    }
 
-        Ok(())
+    /// VC⇔スレッドの対応を解除する
+    async fn unlink(&self, guild_id: GuildId, vc_channel_id: &ChannelId) {
+        // チャンネルID->スレッドを削除
+        let thread_id = self
+            .vc_to_thread
+            .remove(&(guild_id, *vc_channel_id))
+            .map(|(_, thread_id)| thread_id);
+
+        // スレッドID->VCを削除
+        if let Some(thread_id) = thread_id {
+            let thread_key = (guild_id, thread_id);
+            self.thread_to_vc.remove(&thread_key);
+            self.rename_queue.forget(&thread_id).await;
+            self.thread_to_agenda_message.remove(&thread_key);
+            self.thread_to_welcome_message.remove(&thread_key);
+            self.thread_to_status_message.remove(&thread_key);
+            self.thread_to_panel_message.remove(&thread_key);
+            self.thread_members.remove(&thread_key);
+            self.presence_outdated.store(true, Ordering::Relaxed);
+        }
+        self.vc_to_guide_message.remove(&(guild_id, *vc_channel_id));
+        self.link_touched.remove(&(guild_id, *vc_channel_id));
+
+        self.save_links().await;
     }
 
-    /// スレッドの議題メッセージを後始末する
-    async fn finalize_agenda_message(
-        &self,
-        ctx: &Context,
-        thread_channel_id: &ChannelId,
-    ) -> Result<bool> {
-        // 最近5件のメッセージを取得
-        let messages = thread_channel_id
-            .messages(&ctx, |f| {
-                f.limit(5);
-                f
-            })
-            .await
-            .context("メッセージ取得に失敗")?;
+    /// カスタムVCかどうか判定する
+    fn is_custom_vc(&self, api: &impl DiscordApi, channel: &GuildChannel) -> bool {
+        // チャンネルがVCでない場合は無視 (設定されていればステージチャンネルもVCとして扱う)
+        let is_vc = match channel.kind {
+            ChannelType::Voice => true,
+            ChannelType::Stage => self.app_config().discord.include_stage_channels,
+            _ => false,
+        };
+        if !is_vc {
+            return false;
+        }
 
-        // チャンネルID->議題メッセージを取得
-        let mut message_map = self.thread_to_agenda_message.lock().await;
-        let message = match message_map.get_mut(&thread_channel_id) {
-            Some(message) => message,
-            None => return Ok(false),
+        // チャンネルのサーバーの設定を取得 (設定されていないサーバーは無視)
+        let app_config = self.app_config();
+        let guild_config = match app_config.discord.guild(&channel.guild_id) {
+            Some(guild_config) => guild_config,
+            None => return false,
         };
 
-        // メッセージが2件(Botが最初に投稿するメッセージ)以下だったらスレッドを削除するフラグ
-        let should_delete_thread = messages.len() <= 2;
-        // 最新の5件に人間のメッセージがなければ議題メッセージを削除するフラグ
-        let should_delete_agenda_message = !messages.iter().any(|m| !m.author.bot);
+        // 親チャンネルID(≒カテゴリID)取得
+        let parent_channel_id = match channel.parent_id {
+            Some(id) => id,
+            None => return false,
+        };
 
-        // スレッドを消す予定がない場合は、通話時間等を記録する
-        if !should_delete_thread {
-            // メンバー取得
-            let members = thread_channel_id
-                .get_thread_members(&ctx)
-                .await
-                .context("メンバー取得に失敗")?;
-            // スレッドの名前と作成時刻を取得
-            let (thread_name, thread_created_at) = match thread_channel_id.to_channel(&ctx).await? {
-                Channel::Guild(guild_channel) => {
-                    let thread_name = guild_channel.name.clone();
-                    let thread_created_at = guild_channel
-                        .thread_metadata
-                        .and_then(|m| m.create_timestamp);
-                    (thread_name, thread_created_at)
-                }
-                _ => ("不明なVC".to_string(), None),
-            };
-            // 通話時間を計算
-            let duration = thread_created_at
-                .map(|created_at| {
-                    let created_at = created_at.naive_utc();
-                    let now = Utc::now().naive_utc();
-                    let duration = now - created_at;
-                    duration.hhmmss()
-                })
-                .unwrap_or("--:--:--".to_string());
+        // 親チャンネルIDがカスタムVCカテゴリかどうか判定
+        if parent_channel_id != guild_config.vc_category {
+            return false;
+        }
+
+        // チャンネルが無視されるチャンネルかどうか判定
+        if guild_config.vc_ignored_channels.contains(&channel.id) {
+            return false;
+        }
+
+        // AFKチャンネルは自動で移動されるだけなので、カテゴリ内にあってもスレッドを作成しない
+        if api.afk_channel_id(channel.guild_id) == Some(channel.id) {
+            return false;
+        }
+
+        true
+    }
+
+    /// Botに足りない権限を、チャンネルごとに一覧にする
+    async fn missing_permissions(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        bot_user_id: UserId,
+    ) -> Result<Vec<String>> {
+        let app_config = self.app_config();
+        let guild_config = app_config
+            .discord
+            .guild(&guild_id)
+            .ok_or(anyhow::anyhow!("サーバーの設定がありません"))?;
+
+        // スレッドの作成・アーカイブ・メッセージの投稿に必要な権限
+        let mut thread_permissions = Permissions::VIEW_CHANNEL
+            | Permissions::SEND_MESSAGES
+            | Permissions::READ_MESSAGE_HISTORY
+            | Permissions::CREATE_PUBLIC_THREADS
+            | Permissions::SEND_MESSAGES_IN_THREADS
+            | Permissions::MANAGE_THREADS;
+        if self.app_config().discord.thread_type == ThreadType::Private {
+            thread_permissions |= Permissions::CREATE_PRIVATE_THREADS;
+        }
+        #[cfg(feature = "webhook")]
+        if self.app_config().discord.use_webhook {
+            thread_permissions |= Permissions::MANAGE_WEBHOOKS;
+        }
+        // VCの名前変更・人数制限とVCのチャットへの案内に必要な権限
+        let vc_permissions =
+            Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES | Permissions::MANAGE_CHANNELS;
+
+        // 告知を別のチャンネルに投稿する場合は、そのチャンネルにも投稿できる必要がある
+        let mut checks = vec![
+            (guild_config.thread_channel, thread_permissions),
+            (guild_config.vc_category, vc_permissions),
+        ];
+        if let Some(announce_channel) = guild_config
+            .announce_channel
+            .filter(|&c| c != guild_config.thread_channel)
+        {
+            checks.push((
+                announce_channel,
+                Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES,
+            ));
+        }
 
-            // Botを取得
-            let bot = &self
-                .bot_user_id
-                .lock()
+        let mut channels = Vec::new();
+        for (channel_id, _) in &checks {
+            let channel = self
+                .get_guild_channel(api, *channel_id)
                 .await
-                .context("自身のBotユーザーの取得に失敗")?;
+                .context("権限を確認するチャンネルの取得に失敗")?;
+            channels.push(channel);
+        }
+        let permissions = api
+            .permissions_in(guild_id, bot_user_id, &channels)
+            .await
+            .context("Botの権限の計算に失敗")?;
 
-            // 参加者リストを作成
-            let member_mentions = members
-                .iter()
-                .filter_map(|m| m.user_id)
-                .filter(|m| m != bot)
-                .map(|m| m.mention().to_string())
-                .collect::<Vec<_>>()
-                .join(" ");
+        let mut missing = Vec::new();
+        for ((channel, permissions), (_, required)) in channels.iter().zip(permissions).zip(checks)
+        {
+            let lacking = required - permissions;
+            if !lacking.is_empty() {
+                missing.push(format!("#{}: {}", channel.name, lacking));
+            }
+        }
+        Ok(missing)
+    }
 
-            // 議題メッセージを消すか
-            if should_delete_agenda_message {
-                // 議題メッセージを消す場合は新たに投稿する
-                match thread_channel_id
-                    .send_message(ctx, |m| {
-                        m.content("");
-                        m.embed(|f| {
-                            f.title("VCが終了しました");
-                            f.description(format!("`{}` のVCが終了しました", &thread_name));
-                            f.field("通話時間", duration, true);
-                            f.field("参加者", member_mentions, false);
-                            f
-                        });
-                        m.allowed_mentions(|m| m.empty_users());
-                        m
-                    })
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(why) => {
-                        // メッセージが編集できなくてもチャンネルをアーカイブしたいので、ログを出力だけしておく
-                        error!("VC解散時に議題メッセージを削除できませんでした: {:?}", why);
-                    }
+    /// スラッシュコマンドを登録する
+    /// 全てのコマンドを1回でまとめて設定するため、何度呼んでも重複しない
+    async fn register_commands(&self, ctx: &Context) -> Result<()> {
+        if self.skip_in_dry_run(|| {
+            format!(
+                "スラッシュコマンドを登録 ({:?})",
+                self.app_config().discord.command_scope
+            )
+        }) {
+            return Ok(());
+        }
+        match self.app_config().discord.command_scope {
+            CommandScope::Guild => {
+                // 登録するサーバー (テスト用のサーバーが指定されていればそのサーバーのみ)
+                let guild_ids = match self.app_config().discord.command_guild_id {
+                    Some(guild_id) => vec![guild_id],
+                    None => self.app_config().discord.guilds.keys().copied().collect(),
                 };
-            } else {
-                // 議題メッセージを編集
-                match message
-                    .edit(ctx, |m| {
-                        m.content("");
-                        m.embed(|f| {
-                            f.title(&thread_name);
-                            f.description(format!("`{}` のVCが終了しました", &thread_name));
-                            f.field("通話時間", duration, true);
-                            f.field("参加者", member_mentions, false);
-                            f
-                        });
-                        m.allowed_mentions(|m| m.empty_users());
-                        m
-                    })
+                for guild_id in guild_ids {
+                    guild_id
+                        .set_application_commands(ctx, |c| self.build_commands(c))
+                        .await
+                        .with_context(|| {
+                            format!("スラッシュコマンドの登録に失敗 (サーバー: {})", guild_id)
+                        })?;
+                }
+                // 以前グローバルに登録したコマンドが重複して表示されないよう消す
+                Command::set_global_application_commands(ctx, |c| c)
                     .await
-                {
-                    Ok(_) => {}
-                    Err(why) => {
-                        // メッセージが編集できなくてもチャンネルをアーカイブしたいので、ログを出力だけしておく
-                        error!("VC解散時に議題メッセージを削除できませんでした: {:?}", why);
-                    }
-                };
+                    .context("グローバルのスラッシュコマンドの削除に失敗")?;
             }
-        }
-
-        // 最新の5件に人間のメッセージがなければ議題メッセージを削除
-        if should_delete_agenda_message {
-            // メッセージがあれば議題メッセージを削除
-            match message.delete(&ctx).await {
-                Ok(_) => {}
-                Err(why) => {
-                    // メッセージが削除できなくてもチャンネルをアーカイブしたいので、ログを出力だけしておく
-                    error!("VC解散時に議題メッセージを削除できませんでした: {:?}", why);
+            CommandScope::Global => {
+                Command::set_global_application_commands(ctx, |c| self.build_commands(c))
+                    .await
+                    .context("スラッシュコマンドの登録に失敗")?;
+                // 以前サーバーに登録したコマンドが重複して表示されないよう消す
+                for &guild_id in self.app_config().discord.guilds.keys() {
+                    guild_id
+                        .set_application_commands(ctx, |c| c)
+                        .await
+                        .with_context(|| {
+                            format!(
+                                "サーバーのスラッシュコマンドの削除に失敗 (サーバー: {})",
+                                guild_id
+                            )
+                        })?;
                 }
-            };
+            }
         }
+        Ok(())
+    }
 
-        Ok(should_delete_thread)
+    /// 登録するスラッシュコマンドの一覧を作成する
+    /// サーバーのVCを操作するコマンドなので、どれもDMでは使えないようにする
+    fn build_commands<'a>(
+        &self,
+        c: &'a mut CreateApplicationCommands,
+    ) -> &'a mut CreateApplicationCommands {
+        c.create_application_command(|c| {
+            c.name("thread");
+            c.description(self.messages().get("command_thread_description"));
+            c.dm_permission(false);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("rename");
+            c.description(self.messages().get("command_rename_description"));
+            c.dm_permission(false);
+            c.create_option(|o| {
+                o.name("name");
+                o.description(self.messages().get("command_rename_name_description"));
+                o.kind(CommandOptionType::String);
+                o.max_length(CHANNEL_NAME_MAX_CHARS as u16);
+                o.required(true);
+                o
+            });
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("lock");
+            c.description(self.messages().get("command_lock_description"));
+            c.dm_permission(false);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("unlock");
+            c.description(self.messages().get("command_unlock_description"));
+            c.dm_permission(false);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("transfer");
+            c.description(self.messages().get("command_transfer_description"));
+            c.dm_permission(false);
+            c.create_option(|o| {
+                o.name("user");
+                o.description(self.messages().get("command_transfer_user_description"));
+                o.kind(CommandOptionType::User);
+                o.required(true);
+                o
+            });
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("voicetime");
+            c.description(self.messages().get("command_voicetime_description"));
+            c.dm_permission(false);
+            c.create_option(|o| {
+                o.name("user");
+                o.description(self.messages().get("command_voicetime_user_description"));
+                o.kind(CommandOptionType::User);
+                o.required(false);
+                o
+            });
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("members");
+            c.description(self.messages().get("command_members_description"));
+            c.dm_permission(false);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("help");
+            c.description(self.messages().get("command_help_description"));
+            c.dm_permission(false);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("archive");
+            c.description(self.messages().get("command_archive_description"));
+            c.dm_permission(false);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("relink");
+            c.description(self.messages().get("command_relink_description"));
+            c.dm_permission(false);
+            c.create_option(|o| {
+                o.name("vc");
+                o.description(self.messages().get("command_relink_vc_description"));
+                o.kind(CommandOptionType::Channel);
+                if self.app_config().discord.include_stage_channels {
+                    o.channel_types(&[ChannelType::Voice, ChannelType::Stage]);
+                } else {
+                    o.channel_types(&[ChannelType::Voice]);
+                }
+                o.required(true);
+                o
+            });
+            c.create_option(|o| {
+                o.name("thread");
+                o.description(self.messages().get("command_relink_thread_description"));
+                o.kind(CommandOptionType::Channel);
+                o.channel_types(&[ChannelType::PublicThread, ChannelType::PrivateThread]);
+                o.required(true);
+                o
+            });
+            // 対応の修復は管理者向けなので、サーバーの管理権限を持つユーザーのみに表示する
+            c.default_member_permissions(Permissions::MANAGE_GUILD);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("stats");
+            c.description(self.messages().get("command_stats_description"));
+            c.dm_permission(false);
+            // Botの運用者向けなので、サーバーの管理権限を持つユーザーのみに表示する
+            c.default_member_permissions(Permissions::MANAGE_GUILD);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("migrate");
+            c.description(self.messages().get("command_migrate_description"));
+            c.dm_permission(false);
+            c.create_option(|o| {
+                o.name("archive_old");
+                o.description(
+                    self.messages()
+                        .get("command_migrate_archive_old_description"),
+                );
+                o.kind(CommandOptionType::Boolean);
+                o.required(false);
+                o
+            });
+            // 全てのVCに投稿するため、サーバーの管理権限を持つユーザーのみに表示する
+            c.default_member_permissions(Permissions::MANAGE_GUILD);
+            c
+        });
+        c.create_application_command(|c| {
+            c.name("dump");
+            c.description(self.messages().get("command_dump_description"));
+            c.dm_permission(false);
+            // 調査用なので、サーバーの管理権限を持つユーザーのみに表示する
+            c.default_member_permissions(Permissions::MANAGE_GUILD);
+            c
+        });
+        c
     }
-}
 
-#[async_trait]
-impl EventHandler for Handler {
-    /// 準備完了時に呼ばれる
-    async fn ready(&self, _ctx: Context, data_about_bot: Ready) {
-        warn!("Bot準備完了: {}", data_about_bot.user.tag());
+    /// Discord上のVCとスレッドから対応を復元する
+    async fn recover_links(&self, ctx: &Context, guild_id: GuildId) -> Result<usize> {
+        // サーバーの設定を取得
+        let app_config = self.app_config();
+        let guild_config = app_config
+            .discord
+            .guild(&guild_id)
+            .ok_or(anyhow::anyhow!("サーバーの設定がありません"))?;
 
-        // Bot自身のIDを取得
-        let mut bot_user_id = self.bot_user_id.lock().await;
-        *bot_user_id = Some(data_about_bot.user.id.clone());
+        // カテゴリ内のカスタムVCを取得
+        let vc_channels = guild_id
+            .channels(ctx)
+            .await
+            .context("チャンネル一覧の取得に失敗")?
+            .into_values()
+            .filter(|c| self.is_custom_vc(ctx, c))
+            .collect::<Vec<_>>();
+
+        // スレッドチャンネル内のアクティブなスレッドを取得
+        let threads = guild_id
+            .get_active_threads(ctx)
+            .await
+            .context("アクティブなスレッドの取得に失敗")?
+            .threads
+            .into_iter()
+            .filter(|t| t.parent_id == Some(guild_config.thread_channel))
+            .collect::<Vec<_>>();
+
+        // VC名とスレッド名が一致するものを対応付ける
+        let mut recovered = 0;
+        for vc_channel in vc_channels {
+            // 既に対応がある場合はスキップ
+            if self.vc_to_thread.contains_key(&(guild_id, vc_channel.id)) {
+                continue;
+            }
+            // 同名のスレッドを探す (既に他のVCに対応付いているスレッドは除く)
+            let thread = threads.iter().find(|t| {
+                !self.thread_to_vc.contains_key(&(guild_id, t.id))
+                    && self.vc_name_of_thread(&guild_id, &t.name)
+                        == strip_member_count(&vc_channel.name)
+            });
+            if let Some(thread) = thread {
+                self.link(guild_id, vc_channel.id, thread.id).await;
+                recovered += 1;
+            }
+        }
+
+        Ok(recovered)
     }
 
-    /// VCで話すボタンが押された時
-    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
-        // 不明なインタラクションは無視
-        match interaction {
-            Interaction::MessageComponent(interaction)
-                if interaction.data.custom_id == "rename_button" =>
-            {
-                // 名前変更チェック&反応
-                match self.button_pressed(&ctx, &interaction).await {
-                    Ok(_) => {}
-                    Err(why) => {
-                        error!("インタラクションの処理に失敗: {:?}", why);
-                        return;
-                    }
+    /// 再接続した時に、切断中に取りこぼした参加・退出を反映する
+    /// 起動時もサーバーのキャッシュが揃ったら呼び、停止中の参加・退出を反映する
+    async fn resync(&self, ctx: &Context) {
+        let _guard = self.resync_lock.lock().await;
+        for &guild_id in self.app_config().discord.guilds.keys() {
+            // 準備完了時の判定がまだであれば、先にスレッドを作成するチャンネルの種類を判定する
+            if !self.thread_channel_kinds.contains_key(&guild_id) {
+                if let Err(why) = self.detect_thread_channel_kind(ctx, guild_id).await {
+                    self.report_error(
+                        ctx,
+                        Some(guild_id),
+                        "スレッドを作成するチャンネルの種類の判定に失敗",
+                        &why,
+                    )
+                    .await;
+                    continue;
                 }
             }
-            Interaction::ModalSubmit(interaction)
-                if interaction.data.custom_id == "rename_title" =>
-            {
-                // テキスト入力があったらVC名前変更
-                match self.rename_vc(&ctx, &interaction).await {
-                    Ok(_) => {}
-                    Err(why) => {
-                        error!("インタラクションの処理に失敗: {:?}", why);
-                        return;
-                    }
+            // 同じVCのスレッドを重複して作成しないよう、切断中に作られたスレッドとの対応を先に復元する
+            if let Err(why) = self.recover_links(ctx, guild_id).await {
+                self.report_error(ctx, Some(guild_id), "VC⇔スレッドの対応の復元に失敗", &why)
+                    .await;
+                continue;
+            }
+            match self.reconcile_voice_states(ctx, guild_id).await {
+                Ok(0) => {}
+                Ok(reconciled) => warn!(
+                    "取りこぼした参加・退出を{}件のVCに反映しました (サーバー: {})",
+                    reconciled, guild_id
+                ),
+                Err(why) => {
+                    self.report_error(ctx, Some(guild_id), "VCの参加・退出の反映に失敗", &why)
+                        .await
                 }
             }
-            _ => return,
-        };
+        }
     }
 
-    /// VC削除時
-    async fn channel_delete(&self, ctx: Context, vc_channel: &GuildChannel) {
-        // カスタムVCでない場合は無視
-        if !self.is_custom_vc(vc_channel) {
-            return;
-        }
+    /// キャッシュのボイスステートと参加の記録を照らし合わせ、取りこぼした参加・退出を反映する
+    /// 人がいるのにスレッドのないVCにはスレッドを作成し、削除されたVCや空になったVCのスレッドは後始末する
+    /// 反映したVCの数を返す
+    async fn reconcile_voice_states(&self, ctx: &Context, guild_id: GuildId) -> Result<usize> {
+        // カテゴリ内のカスタムVCを取得
+        let vc_channels = guild_id
+            .channels(ctx)
+            .await
+            .context("チャンネル一覧の取得に失敗")?
+            .into_values()
+            .filter(|c| self.is_custom_vc(ctx, c))
+            .collect::<Vec<_>>();
+        let mut reconciled = 0;
 
-        // マップからスレッドのチャンネルIDを取得
-        // 一度変数に入れてからmatchにいれないとロックされっぱなしになる
-        let thread_channel_id = self
+        // 切断中に削除されたVCのスレッドを後始末する
+        let deleted = self
             .vc_to_thread
-            .lock()
-            .await
-            .get(&vc_channel.id)
-            .map(|c| c.clone());
+            .iter()
+            .map(|entry| *entry.key())
+            .filter(|(g, vc_channel_id)| {
+                *g == guild_id && !vc_channels.iter().any(|c| c.id == *vc_channel_id)
+            })
+            .collect::<Vec<_>>();
+        for vc_key in deleted {
+            let _guard = self.lock_vc(&vc_key).await;
+            if !self.skip_in_dry_run(|| format!("削除されたVC {} のスレッドを後始末", vc_key.1))
+            {
+                if let Err(why) = self.archive_thread(ctx, &vc_key, false).await {
+                    self.report_error(
+                        ctx,
+                        Some(guild_id),
+                        "VCスレッドチャンネルの後始末に失敗",
+                        &why,
+                    )
+                    .await;
+                }
+            }
+            self.forget_vc(&vc_key).await;
+            reconciled += 1;
+        }
 
-        // チャンネルIDが見つけれなければ終了
-        let thread_channel_id = match thread_channel_id {
-            Some(channel_id) => channel_id,
-            None => return,
-        };
+        for vc_channel in vc_channels {
+            let vc_key = (guild_id, vc_channel.id);
+            // サーバーのキャッシュが無ければ、参加中のメンバーがわからない
+            let members = match self.vc_member_ids(ctx, &vc_channel) {
+                Some(members) => members,
+                None => continue,
+            };
+            let vc_lock = self.lock_vc(&vc_key).await;
+            let recorded = self
+                .vc_join_times
+                .get(&vc_key)
+                .map(|r| r.keys().copied().collect::<Vec<_>>())
+                .unwrap_or_default();
+            let left = recorded
+                .iter()
+                .copied()
+                .filter(|user_id| !members.contains(user_id))
+                .collect::<Vec<_>>();
+            let joined = members
+                .iter()
+                .copied()
+                .filter(|user_id| !recorded.contains(user_id))
+                .collect::<Vec<_>>();
+            let has_thread = self.vc_to_thread.contains_key(&vc_key);
+            if left.is_empty() && joined.is_empty() && (has_thread || members.is_empty()) {
+                continue;
+            }
+            reconciled += 1;
 
-        // VCで誰も喋ってなかったら議題メッセージを削除
-        let should_delete = match self.finalize_agenda_message(&ctx, &thread_channel_id).await {
-            Ok(del) => del,
-            Err(why) => {
-                error!(
-                    "VCチャンネルで会話がなかったが、議題メッセージ削除に失敗: {:?}",
-                    why
-                );
-                false
+            // 参加・退出の記録を合わせる (実際の時刻はわからないので今の時刻とする)
+            for user_id in &left {
+                if let Some(mut join_times) = self.vc_join_times.get_mut(&vc_key) {
+                    join_times.remove(user_id);
+                }
+                self.finish_voice_time(guild_id, *user_id, vc_channel.id)
+                    .await;
+            }
+            for user_id in &joined {
+                self.vc_join_times
+                    .entry(vc_key)
+                    .or_default()
+                    .insert(*user_id, Utc::now());
+                self.start_voice_time(guild_id, *user_id, vc_channel.id);
             }
-        };
 
-        // 2件以上のメッセージがなければスレッドを削除する
-        if should_delete {
-            // VCスレッドチャンネルを削除
-            match thread_channel_id.delete(&ctx).await {
-                Ok(_) => {}
-                Err(why) => {
-                    error!("VCスレッドチャンネルの削除に失敗: {:?}", why);
-                    return;
+            if self.skip_in_dry_run(|| {
+                format!(
+                    "VC「{}」の取りこぼした参加 {:?}・退出 {:?} を反映",
+                    vc_channel.name, joined, left
+                )
+            }) {
+                continue;
+            }
+
+            // 退出したメンバーにオーナーがいれば、残っているメンバーに引き継ぐ
+            for user_id in &left {
+                if let Err(why) = self.transfer_owner(ctx, &vc_channel, user_id).await {
+                    self.report_error(ctx, Some(guild_id), "VCのオーナーの引き継ぎに失敗", &why)
+                        .await;
                 }
             }
-        } else {
-            // VCスレッドチャンネルをアーカイブ
-            match thread_channel_id
-                .edit_thread(ctx, |t| {
-                    t.archived(true);
-                    t
-                })
-                .await
-            {
-                Ok(_) => {}
-                Err(why) => {
-                    error!("VCスレッドチャンネルのアーカイブに失敗: {:?}", why);
-                    return;
+
+            if !has_thread {
+                // 人がいるのにスレッドがなければ、参加した時と同じように作成する (作成中はVCをロックする)
+                drop(vc_lock);
+                if let Err(why) = self.adopt_vc(ctx, &vc_channel).await {
+                    self.report_error(
+                        ctx,
+                        Some(guild_id),
+                        "VCスレッドチャンネルの作成/投稿に失敗",
+                        &why,
+                    )
+                    .await;
+                }
+                continue;
+            }
+
+            // スレッドの名前の参加人数と、参加中のメンバーの表示を合わせる
+            if let Err(why) = self.update_thread_member_count(ctx, &vc_channel).await {
+                self.report_error(ctx, Some(guild_id), "スレッドの参加人数の更新に失敗", &why)
+                    .await;
+            }
+            self.request_panel_update(&vc_key);
+
+            // 空になったVCは、全員が退出した時と同じようにアーカイブする
+            if members.is_empty() && self.app_config().discord.archive_on_empty {
+                if let Err(why) = self.archive_thread(ctx, &vc_key, true).await {
+                    self.report_error(
+                        ctx,
+                        Some(guild_id),
+                        "VCスレッドチャンネルのアーカイブに失敗",
+                        &why,
+                    )
+                    .await;
                 }
             }
         }
+
+        Ok(reconciled)
     }
 
-    /// VC名更新時
-    async fn channel_update(&self, _ctx: Context, _old: Option<Channel>, new: Channel) {
-        // チャンネルを取得
-        let vc_channel = match new.guild() {
-            Some(guild) => guild,
-            None => return,
-        };
+    /// Botのステータスに、スレッドのあるVCの数を表示する
+    /// ステータスの更新を連発しないよう、対応が変わっていれば一定間隔ごとにまとめて更新する
+    pub async fn run_presence_updater(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PRESENCE_UPDATE_INTERVAL);
+        loop {
+            interval.tick().await;
 
-        // カスタムVCでない場合は無視
-        if !self.is_custom_vc(&vc_channel) {
-            return;
-        }
+            // 準備完了前はまだ何もできない
+            let ctx = match self.context.lock().await.clone() {
+                Some(ctx) => ctx,
+                None => continue,
+            };
+            if !self.presence_outdated.swap(false, Ordering::Relaxed) {
+                continue;
+            }
 
-        // VCスレッドチャンネルをリネーム
-        match self.rename_thread(&_ctx, &vc_channel.id).await {
-            Ok(_) => {}
-            Err(why) => {
-                error!("VCスレッドチャンネルのリネームに失敗: {:?}", why);
-                return;
+            let count = self.vc_to_thread.len().to_string();
+            let args = [("count", count.as_str())];
+            let text = match &self.app_config().discord.presence_template {
+                Some(template) => {
+                    let overrides = HashMap::from([("presence".to_string(), template.clone())]);
+                    self.messages().format_with(&overrides, "presence", &args)
+                }
+                None => self.messages().format("presence", &args),
+            };
+            if self.skip_in_dry_run(|| format!("ステータスを「{}」に更新", text)) {
+                continue;
             }
+            ctx.set_activity(Activity::watching(text)).await;
         }
     }
 
-    /// VCに参加/退出した時
-    async fn voice_state_update(&self, ctx: Context, _old: Option<VoiceState>, new: VoiceState) {
-        // チャンネルID、ユーザーが存在しない場合は無視
-        if let (Some(vc_channel_id), Some(member)) = (new.channel_id, new.member) {
-            // チャンネルを取得
-            let vc_channel = match vc_channel_id
-                .to_channel(&ctx)
-                .await
-                .context("チャンネル取得失敗")
-                .and_then(|c| c.guild().ok_or(anyhow::anyhow!("チャンネルが存在しません")))
-            {
-                Ok(channel) => channel,
-                Err(why) => {
-                    error!("チャンネルの取得に失敗: {:?}", why);
-                    return;
+    /// 設定した曜日・時刻に、週の動作状況をサーバーごとに投稿する
+    /// 設定の再読み込みに追従するよう、投稿時刻は一定間隔ごとに確認し直す
+    pub async fn run_weekly_reporter(self: Arc<Self>) {
+        loop {
+            let schedule = match &self.app_config().discord.weekly_report {
+                Some(schedule) => schedule.clone(),
+                None => {
+                    tokio::time::sleep(WEEKLY_REPORT_CHECK_INTERVAL).await;
+                    continue;
                 }
             };
-
-            // カスタムVCでない場合は無視
-            if !self.is_custom_vc(&vc_channel) {
-                return;
+            let now = Utc::now();
+            let wait = (schedule.next_after(now) - now)
+                .to_std()
+                .unwrap_or_default();
+            if wait > WEEKLY_REPORT_CHECK_INTERVAL {
+                tokio::time::sleep(WEEKLY_REPORT_CHECK_INTERVAL).await;
+                continue;
             }
+            tokio::time::sleep(wait).await;
 
-            // VCスレッドチャンネルを作成
-            match self
-                .create_or_mention_thread(&ctx, &vc_channel_id, &member)
-                .await
-            {
-                Ok(_) => {}
-                Err(why) => {
-                    error!("VCスレッドチャンネルの作成/投稿に失敗: {:?}", why);
-                    return;
+            // 準備完了前はまだ何もできない (週の動作状況は次の投稿に持ち越す)
+            let ctx = match self.context.lock().await.clone() {
+                Some(ctx) => ctx,
+                None => continue,
+            };
+            for (guild_id, guild_config) in &self.app_config().discord.guilds {
+                let report_channel = match guild_config.report_channel {
+                    Some(report_channel) => report_channel,
+                    None => continue,
+                };
+                // 投稿したら次の週の分を数え直す
+                let stats = self
+                    .weekly_stats
+                    .remove(guild_id)
+                    .map(|(_, stats)| stats)
+                    .unwrap_or_default();
+                if let Err(why) = self
+                    .post_weekly_report(&ctx, *guild_id, report_channel, &stats)
+                    .await
+                {
+                    self.report_error(&ctx, Some(*guild_id), "週の動作状況の投稿に失敗", &why)
+                        .await;
                 }
             }
         }
     }
+
+    /// 週の動作状況を埋め込みで投稿する
+    async fn post_weekly_report(
+        &self,
+        ctx: &Context,
+        guild_id: GuildId,
+        report_channel: ChannelId,
+        stats: &WeeklyStats,
+    ) -> Result<()> {
+        // 参加の多いVCの名前
+        let mut vc_joins = stats.vc_joins.iter().collect::<Vec<_>>();
+        vc_joins.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let top_vcs = vc_joins
+            .iter()
+            .take(WEEKLY_REPORT_TOP_COUNT)
+            .enumerate()
+            .map(|(i, (name, count))| {
+                self.guild_message(
+                    &guild_id,
+                    "weekly_report_vc",
+                    &[
+                        ("rank", &(i + 1).to_string()),
+                        ("name", name),
+                        ("count", &count.to_string()),
+                    ],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // VCにいた時間の長いユーザー (埋め込み内のメンションは通知されない)
+        let mut voice_seconds = stats.voice_seconds.iter().collect::<Vec<_>>();
+        voice_seconds.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let top_users = voice_seconds
+            .iter()
+            .take(WEEKLY_REPORT_TOP_COUNT)
+            .enumerate()
+            .map(|(i, (user_id, seconds))| {
+                self.guild_message(
+                    &guild_id,
+                    "weekly_report_user",
+                    &[
+                        ("rank", &(i + 1).to_string()),
+                        ("user", &user_id.mention().to_string()),
+                        ("duration", &chrono::Duration::seconds(**seconds).hhmmss()),
+                    ],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let none = self.guild_message(&guild_id, "weekly_report_none", &[]);
+        let join_or_none = |lines: Vec<String>| {
+            if lines.is_empty() {
+                none.clone()
+            } else {
+                lines.join("\n")
+            }
+        };
+        let title = self.guild_message(&guild_id, "weekly_report_title", &[]);
+        let threads_label = self.guild_message(&guild_id, "weekly_report_threads", &[]);
+        let vcs_label = self.guild_message(&guild_id, "weekly_report_top_vcs", &[]);
+        let users_label = self.guild_message(&guild_id, "weekly_report_top_users", &[]);
+        let top_vcs = join_or_none(top_vcs);
+        let top_users = join_or_none(top_users);
+
+        if self.skip_in_dry_run(|| {
+            format!(
+                "サーバー {} のチャンネル {} に週の動作状況を投稿",
+                guild_id, report_channel
+            )
+        }) {
+            return Ok(());
+        }
+        retry("週の動作状況の投稿", API_RETRY_ATTEMPTS, || {
+            report_channel.send_message(ctx, |m| {
+                m.embed(|e| {
+                    e.title(&title);
+                    e.field(&threads_label, stats.threads_created, true);
+                    e.field(&vcs_label, &top_vcs, false);
+                    e.field(&users_label, &top_users, false);
+                    e.colour(self.embed_colour());
+                    e
+                })
+            })
+        })
+        .await
+        .context("週の動作状況の投稿に失敗")?;
+
+        Ok(())
+    }
+
+    /// 参加中のメンバーの表示を、参加・退出があったVCごとに一定間隔でまとめて更新する
+    pub async fn run_panel_updater(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PANEL_UPDATE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            // 設定の再読み込みで無効にされていれば、溜まった更新を捨てる
+            if !self.app_config().discord.participant_panel {
+                self.panels_outdated.clear();
+                continue;
+            }
+
+            // 準備完了前はまだ何もできない
+            let ctx = match self.context.lock().await.clone() {
+                Some(ctx) => ctx,
+                None => continue,
+            };
+
+            // 更新が必要なVCを取り出す (マップの参照を持ったまま待機しないよう、先に集める)
+            let vc_keys = self.panels_outdated.iter().map(|k| *k).collect::<Vec<_>>();
+            for vc_key in vc_keys {
+                self.panels_outdated.remove(&vc_key);
+                // 参加・解散の処理と同時に動かないようにする
+                let _guard = self.lock_vc(&vc_key).await;
+                if let Err(why) = self.update_panel(&ctx, &vc_key, false).await {
+                    error!("参加中のメンバーの表示の更新に失敗: {:?}", why);
+                }
+            }
+        }
+    }
+
+    /// 削除を保留してアーカイブしたスレッドを、再参加されないまま一定時間たったら削除する
+    pub async fn run_deferred_deleter(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(DEFERRED_DELETE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            // 設定の再読み込みに追従するよう、待つ時間は毎回読み直す
+            // (0以下にされた場合も、保留中のスレッドはすぐに削除する)
+            let cooldown =
+                chrono::Duration::seconds(self.app_config().discord.recreate_cooldown_secs.max(0));
+
+            // 準備完了前はまだ何もできない
+            let ctx = match self.context.lock().await.clone() {
+                Some(ctx) => ctx,
+                None => continue,
+            };
+
+            // 削除を保留しているVCを集める (マップの参照を持ったまま待機しないよう、先に集める)
+            let vc_keys = self
+                .vc_archived_at
+                .iter()
+                .map(|entry| *entry.key())
+                .collect::<Vec<_>>();
+            for vc_key in vc_keys {
+                // 参加・解散の処理と同時に動かないようにする
+                let _guard = self.lock_vc(&vc_key).await;
+
+                // 待っている間に再参加されていれば削除しない
+                let now = Utc::now();
+                if self
+                    .vc_archived_at
+                    .remove_if(&vc_key, |_, archived_at| now - *archived_at >= cooldown)
+                    .is_none()
+                {
+                    continue;
+                }
+                let thread_id = match self.vc_to_thread.get(&vc_key).map(|r| *r) {
+                    Some(thread_id) => thread_id,
+                    None => continue,
+                };
+
+                // VCスレッドチャンネルを削除 (既に削除されていれば対応を解除するだけ)
+                match thread_id.delete(&ctx).await {
+                    Ok(_) => {}
+                    Err(why) if is_not_found(&why) => {}
+                    Err(why) => {
+                        let why = anyhow::Error::new(why);
+                        self.report_error(
+                            &ctx,
+                            Some(vc_key.0),
+                            "VCスレッドチャンネルの削除に失敗",
+                            &why,
+                        )
+                        .await;
+                        continue;
+                    }
+                }
+                self.unlink(vc_key.0, &vc_key.1).await;
+            }
+        }
+    }
+
+    /// Botが停止中やゲートウェイの切断中に削除されたチャンネルの対応を定期的に掃除する
+    /// Bot終了時にタスクごと止める
+    pub async fn run_link_sweeper(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(LINK_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            // 準備完了前はまだ何もできない
+            let ctx = match self.context.lock().await.clone() {
+                Some(ctx) => ctx,
+                None => continue,
+            };
+            let pruned = self.sweep_links(&ctx).await;
+            warn!("存在しないチャンネルの対応を{}件削除しました", pruned);
+            self.prune_vc_locks();
+        }
+    }
+
+    /// 記録のあるVCを少しずつ確認し、VCかスレッドが存在しなければ記録を削除する
+    /// 削除した件数を返す
+    async fn sweep_links(&self, ctx: &Context) -> usize {
+        // 記録のあるVCを集める (マップの参照を持ったまま待機しないよう、先に集める)
+        let mut vc_keys = self
+            .vc_to_thread
+            .iter()
+            .map(|entry| *entry.key())
+            .chain(self.vc_owner.iter().map(|entry| *entry.key()))
+            .chain(self.vc_join_times.iter().map(|entry| *entry.key()))
+            .collect::<Vec<_>>();
+        vc_keys.sort();
+        vc_keys.dedup();
+        if vc_keys.is_empty() {
+            return 0;
+        }
+
+        // 前回の続きから一定数だけ確認する
+        let start = self.sweep_cursor.load(Ordering::Relaxed) % vc_keys.len();
+        let batch = vc_keys
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(LINK_SWEEP_BATCH_SIZE.min(vc_keys.len()))
+            .copied()
+            .collect::<Vec<_>>();
+        self.sweep_cursor
+            .store(start + batch.len(), Ordering::Relaxed);
+
+        let mut pruned = 0;
+        for vc_key in batch {
+            // キャッシュは削除を取りこぼしている可能性があるので、APIで確認する
+            let vc_exists = self.channel_exists(ctx, &vc_key.1).await;
+            tokio::time::sleep(LINK_SWEEP_CHECK_DELAY).await;
+            if !vc_exists {
+                let _guard = self.lock_vc(&vc_key).await;
+                self.forget_vc(&vc_key).await;
+                pruned += 1;
+                continue;
+            }
+
+            // スレッドだけ削除されていれば対応を解除する (次にVCに参加した時に作り直す)
+            let thread_id = match self.vc_to_thread.get(&vc_key).map(|r| *r) {
+                Some(thread_id) => thread_id,
+                None => continue,
+            };
+            let thread_exists = self.channel_exists(ctx, &thread_id).await;
+            tokio::time::sleep(LINK_SWEEP_CHECK_DELAY).await;
+            if !thread_exists {
+                let _guard = self.lock_vc(&vc_key).await;
+                self.unlink(vc_key.0, &vc_key.1).await;
+                pruned += 1;
+            }
+        }
+        pruned
+    }
+
+    /// チャンネルが存在するか確認する
+    /// 一時的なエラーで記録を消さないよう、存在しないと分かった時のみfalseを返す
+    async fn channel_exists(&self, ctx: &Context, channel_id: &ChannelId) -> bool {
+        match ctx.http.get_channel(channel_id.0).await {
+            Ok(_) => true,
+            Err(why) if is_not_found(&why) => false,
+            Err(why) => {
+                error!("チャンネルの存在の確認に失敗: {:?}", why);
+                true
+            }
+        }
+    }
+
+    /// 一定時間メッセージのないスレッドを定期的にアーカイブする (設定がなければ何もしない)
+    /// Bot終了時にタスクごと止める
+    pub async fn run_inactivity_archiver(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(INACTIVITY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            // 設定の再読み込みに追従するよう、毎回読み直す
+            let limit = match self.app_config().discord.inactivity_archive_hours {
+                Some(hours) => chrono::Duration::hours(hours as i64),
+                None => continue,
+            };
+
+            // 準備完了前はまだ何もできない
+            let ctx = match self.context.lock().await.clone() {
+                Some(ctx) => ctx,
+                None => continue,
+            };
+            if let Err(why) = self.archive_inactive_threads(&ctx, limit).await {
+                self.report_error(&ctx, None, "放置されたスレッドのアーカイブに失敗", &why)
+                    .await;
+            }
+        }
+    }
+
+    /// 最後のメッセージから一定時間たったスレッドをアーカイブする
+    /// アーカイブしたスレッドは、次にVCに参加した時に戻る
+    async fn archive_inactive_threads(&self, ctx: &Context, limit: chrono::Duration) -> Result<()> {
+        // 対応のあるスレッドを取得 (マップの参照を持ったまま待機しないよう、先に集める)
+        let links = self
+            .vc_to_thread
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect::<Vec<_>>();
+
+        for (vc_key, thread_id) in links {
+            // 参加・解散の処理と同時に動かないようにする
+            let _guard = self.lock_vc(&vc_key).await;
+
+            // 既にアーカイブされていればスキップ
+            let thread = match self.get_guild_channel(ctx, thread_id).await {
+                Ok(thread) => thread,
+                Err(why) => {
+                    error!("放置されたか確認するスレッドの取得に失敗: {:?}", why);
+                    continue;
+                }
+            };
+            let metadata = match thread.thread_metadata {
+                Some(metadata) if !metadata.archived => metadata,
+                _ => continue,
+            };
+
+            // 最後のメッセージ (なければスレッドの作成) からの時間
+            let last_activity = thread
+                .last_message_id
+                .map(|id| id.created_at())
+                .or(metadata.create_timestamp);
+            let last_activity = match last_activity {
+                Some(last_activity) => last_activity,
+                None => continue,
+            };
+            let idle =
+                chrono::Duration::seconds(Utc::now().timestamp() - last_activity.unix_timestamp());
+            if idle < limit {
+                continue;
+            }
+
+            // 設定によっては、まだ人がいるVCのスレッドはアーカイブしない
+            if self.app_config().discord.inactivity_skip_populated {
+                let vc_channel = match self.get_guild_channel(ctx, vc_key.1).await {
+                    Ok(vc_channel) => vc_channel,
+                    Err(why) => {
+                        error!("放置されたか確認するVCの取得に失敗: {:?}", why);
+                        continue;
+                    }
+                };
+                if self.count_vc_members(ctx, &vc_channel).unwrap_or(0) > 0 {
+                    continue;
+                }
+            }
+
+            if self.skip_in_dry_run(|| format!("放置されたスレッド {} をアーカイブ", thread_id))
+            {
+                continue;
+            }
+
+            // アーカイブ後は編集できないので、先にボタンを押せないようにする
+            if let Err(why) = self
+                .set_welcome_buttons_disabled(ctx, vc_key.0, &thread_id, true)
+                .await
+            {
+                error!("参加メッセージのボタンの無効化に失敗: {:?}", why);
+            }
+            ctx.set_thread_archived(thread_id, true)
+                .await
+                .context("VCスレッドチャンネルのアーカイブに失敗")?;
+            self.threads_archived.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Bot終了時に、対応のあるスレッドを設定に応じて後始末する
+    pub async fn shutdown(&self, http: &Arc<Http>) {
+        // 参加中のメンバーの時間は終了までの分を記録しておく (再起動後は起動前からの参加として数えない)
+        let voice_keys = self
+            .voice_started_at
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().0))
+            .collect::<Vec<_>>();
+        for ((guild_id, user_id), vc_channel_id) in voice_keys {
+            self.finish_voice_time(guild_id, user_id, vc_channel_id)
+                .await;
+        }
+
+        let action = self.app_config().discord.shutdown_action;
+        if action == ShutdownAction::None {
+            return;
+        }
+        if self.skip_in_dry_run(|| format!("終了時にスレッドを後始末 ({:?})", action)) {
+            return;
+        }
+
+        // 対応のあるスレッドを取得 (マップの参照を持ったまま待機しないよう、先に集める)
+        // VCのチャットはアーカイブできないので、お知らせの投稿のみ行う
+        let thread_ids = self
+            .vc_to_thread
+            .iter()
+            .filter(|entry| action == ShutdownAction::Notify || entry.key().1 != *entry.value())
+            .map(|entry| (entry.key().0, *entry.value()))
+            .collect::<Vec<_>>();
+
+        // 終了が止まらないよう、時間内に終わらなければ諦める
+        let cleanup = async {
+            for (guild_id, thread_id) in thread_ids {
+                let result = match action {
+                    ShutdownAction::None => Ok(()),
+                    ShutdownAction::Notify => thread_id
+                        .send_message(http, |m| {
+                            m.content(self.guild_message(&guild_id, "shutdown_notice", &[]));
+                            m
+                        })
+                        .await
+                        .map(|_| ())
+                        .context("再起動中のお知らせの投稿に失敗"),
+                    ShutdownAction::Archive => thread_id
+                        .edit_thread(http, |t| {
+                            t.archived(true);
+                            t
+                        })
+                        .await
+                        .map(|_| ())
+                        .context("VCスレッドチャンネルのアーカイブに失敗"),
+                };
+                if let Err(why) = result {
+                    error!("終了時のスレッドの後始末に失敗: {:?}", why);
+                }
+            }
+        };
+        let limit = Duration::from_secs(self.app_config().discord.shutdown_timeout_secs);
+        if timeout(limit, cleanup).await.is_err() {
+            warn!("終了時のスレッドの後始末がタイムアウトしました");
+        }
+    }
+
+    /// VCごとのロックを取得する
+    async fn lock_vc(&self, vc_key: &ChannelKey) -> OwnedMutexGuard<()> {
+        let lock = self.vc_locks.entry(*vc_key).or_default().value().clone();
+        lock.lock_owned().await
+    }
+
+    /// 誰も使っていないVCごとのロックを削除する
+    /// ロック中や待機中のロックを削除すると、次に取得したタスクが別のロックを作って同時に動いてしまうため、
+    /// マップ以外から参照されていないものだけを削除する
+    fn prune_vc_locks(&self) {
+        self.vc_locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    }
+
+    /// エラーをログに出力し、サーバーのエラー報告チャンネルが設定されていれば投稿する (サーバーがわからない場合はログのみ)
+    async fn report_error(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: Option<GuildId>,
+        context_msg: &str,
+        why: &anyhow::Error,
+    ) {
+        error!("{}: {:?}", context_msg, why);
+        #[cfg(feature = "metrics")]
+        self.errors_reported.fetch_add(1, Ordering::Relaxed);
+
+        // サーバーのエラー報告チャンネルが設定されていなければログのみ
+        let error_channel = match guild_id.and_then(|guild_id| self.error_channel(&guild_id)) {
+            Some(error_channel) => error_channel,
+            None => return,
+        };
+        if self.skip_in_dry_run(|| format!("エラー報告チャンネル {} に投稿", error_channel))
+        {
+            return;
+        }
+
+        // 埋め込みの説明文の上限に収まるように切り詰める
+        let detail = format!("{:?}", why)
+            .chars()
+            .take(ERROR_REPORT_MAX_CHARS)
+            .collect::<String>();
+        if let Err(why) = api
+            .send_message_with(error_channel, |m| {
+                m.embed(|f| {
+                    f.title(context_msg);
+                    f.description(format!("```\n{}\n```", detail));
+                    f.colour(Colour::RED);
+                    f.timestamp(Timestamp::now());
+                    f
+                });
+                m
+            })
+            .await
+        {
+            error!("エラー報告チャンネルへの投稿に失敗: {:?}", why);
+        }
+    }
+
+    /// 参加時にスレッドを作成する
+    async fn create_or_mention_thread(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+        member: &Member,
+    ) -> Result<()> {
+        let vc_channel_id = &vc_channel.id;
+        let guild_id = vc_channel.guild_id;
+        let vc_key = (guild_id, *vc_channel_id);
+
+        // 同時に参加された時にスレッドが重複して作成されないよう、スレッドの登録が終わるまでVCをロックする
+        let vc_lock = self.lock_vc(&vc_key).await;
+
+        // マップからスレッドのチャンネルIDを取得
+        let map = self.vc_to_thread.get(&vc_key).map(|r| *r);
+        match map {
+            // スレッドが作成済みの場合
+            Some(thread_id) => {
+                self.touch_link(&vc_key);
+
+                // 削除を保留している間に再参加されたので、削除せずに同じスレッドを使う
+                self.vc_archived_at.remove(&vc_key);
+
+                // VCが一度空になってアーカイブされている場合は戻す
+                if self.unarchive_thread(api, guild_id, &thread_id).await? {
+                    // 解散後に再び使われるので、新しい通話として記録する
+                    self.vc_sessions.insert(
+                        vc_key,
+                        VcSession {
+                            started_at: Utc::now(),
+                            participants: Vec::new(),
+                        },
+                    );
+                }
+
+                // 通話の参加者を記録
+                if let Some(mut session) = self.vc_sessions.get_mut(&vc_key) {
+                    session.add_participant(member.user.id);
+                }
+
+                // 回線が不安定などで退出してすぐに戻ってきた場合は参加メッセージを出さない
+                if self
+                    .rejoined_within_cooldown(&vc_key, &member.user.id)
+                    .await
+                {
+                    return Ok(());
+                }
+
+                // スレッドのメンバーでなければ参加メッセージを出す
+                // 公開スレッドはBotが把握しているメンバーで判断する
+                // プライベートスレッドは招待が必要なので、まとめて参加したメンバーを後で一度に確認して招待する
+                // VCのチャットを使っている場合はスレッドではないので、Botが把握しているメンバーで判断する
+                let private_thread = self.app_config().discord.thread_type == ThreadType::Private
+                    && thread_id != *vc_channel_id;
+                let is_new_member = private_thread
+                    || self
+                        .remember_thread_member(api, (guild_id, thread_id), member.user.id)
+                        .await?;
+                // メンバーが存在しない場合
+                if is_new_member {
+                    // まとめて参加した場合は、少し待って参加メッセージを1つにまとめる
+                    let window =
+                        Duration::from_millis(self.app_config().discord.join_burst_window_ms);
+                    let joined = if window.is_zero() {
+                        vec![member.user.id]
+                    } else {
+                        // 待っている間に参加したメンバーは、最初のメンバーの参加メッセージに載せる
+                        let mut first = false;
+                        self.pending_joins
+                            .entry(vc_key)
+                            .or_insert_with(|| {
+                                first = true;
+                                Vec::new()
+                            })
+                            .push(member.user.id);
+                        if !first {
+                            return Ok(());
+                        }
+                        // 待っている間に他のメンバーの参加を処理できるよう、ロックを解除する
+                        drop(vc_lock);
+                        tokio::time::sleep(window).await;
+                        self.pending_joins
+                            .remove(&vc_key)
+                            .map(|(_, joined)| joined)
+                            .unwrap_or_default()
+                    };
+
+                    // プライベートスレッドの場合は、まだメンバーでないメンバーをまとめて招待
+                    // メンバーの一覧は参加した人数にかかわらず1回だけ取得する
+                    let joined = if private_thread {
+                        let members = api
+                            .thread_member_ids(thread_id)
+                            .await
+                            .context("スレッドメンバーの取得に失敗")?;
+                        let new_members = joined
+                            .into_iter()
+                            .filter(|user_id| !members.contains(user_id))
+                            .collect::<Vec<_>>();
+                        self.invite_thread_members(api, thread_id, &new_members)
+                            .await
+                    } else {
+                        joined
+                    };
+                    if joined.is_empty() {
+                        return Ok(());
+                    }
+
+                    let joined_mentions = joined
+                        .iter()
+                        .map(|m| m.mention().to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    // 参加メッセージ
+                    let content = self.guild_message(
+                        &guild_id,
+                        "join",
+                        &[
+                            ("user", &joined_mentions),
+                            ("thread", &thread_id.mention().to_string()),
+                        ],
+                    );
+                    // 参加メッセージでメンションするのは参加したメンバーのみ
+                    self.send_thread_message(
+                        api,
+                        guild_id,
+                        thread_id,
+                        &vc_channel.name,
+                        content,
+                        self.mention_policy() != MentionPolicy::None,
+                    )
+                    .await
+                    .context("参加メッセージの送信に失敗")?;
+                }
+            }
+            // スレッドが作成されていない場合
+            None => {
+                // VCに参加しているメンバー (キャッシュが無い場合は参加したメンバーのみとみなす)
+                let members = self
+                    .vc_member_ids(api, vc_channel)
+                    .filter(|members| !members.is_empty())
+                    .unwrap_or_else(|| vec![member.user.id]);
+                // 参加人数が設定値に届くまではスレッドを作成しない
+                if members.len() < self.app_config().discord.min_members_for_thread {
+                    return Ok(());
+                }
+                // 設定の誤りなどで作成し続けないよう、スレッドの数が上限に達したら空きができるまで作成しない
+                if let Some(max_active_threads) = self.app_config().discord.max_active_threads {
+                    let active = self.vc_to_thread.len();
+                    if active >= max_active_threads {
+                        warn!(
+                            "スレッドの数が上限に達したため作成しません ({}/{})",
+                            active, max_active_threads
+                        );
+                        if !self.thread_cap_alerted.swap(true, Ordering::Relaxed) {
+                            self.alert_thread_cap(
+                                api,
+                                vc_channel.guild_id,
+                                active,
+                                max_active_threads,
+                            )
+                            .await;
+                        }
+                        return Ok(());
+                    }
+                    self.thread_cap_alerted.store(false, Ordering::Relaxed);
+                }
+                // 最も早く参加したメンバーをVCの作成者とする
+                let owner = self
+                    .earliest_joined(&vc_key, &members)
+                    .await
+                    .unwrap_or(member.user.id);
+                // 参加しているメンバー全員にメンション
+                let member_mentions = members
+                    .iter()
+                    .map(|m| m.mention().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                // チャンネル名を取得
+                let channel_name = self
+                    .channel_name(api, *vc_channel_id)
+                    .await
+                    .unwrap_or(self.unknown_channel_name(&guild_id));
+                // スレッドの名前
+                let thread_name = self.thread_name(api, vc_channel, &channel_name);
+                // 通知を控える時間帯はメンションしても通知しない
+                let quiet = self.is_quiet_hours();
+                // 時間帯内に告知しない設定であれば、議題メッセージを送信しない
+                // フォーラムの投稿は議題メッセージが投稿そのものなので常に作成する
+                let announce = !quiet
+                    || !self
+                        .app_config()
+                        .discord
+                        .quiet_hours
+                        .as_ref()
+                        .is_some_and(|q| q.skip_announce);
+                // VCのチャットを使う場合は、スレッドを作成せずにVC自身をスレッドとして登録する
+                let vc_text = self.app_config().discord.chat_mode == ChatMode::VcText;
+                // 状態が失われて対応がわからなくなった、同じ名前のアーカイブ済みのスレッドがあれば使う
+                let reused = if self.app_config().discord.reuse_archived_threads && !vc_text {
+                    match self
+                        .find_archived_thread(api, guild_id, &channel_name)
+                        .await
+                    {
+                        Ok(reused) => reused,
+                        Err(why) => {
+                            error!("アーカイブされたスレッドの検索に失敗: {:?}", why);
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                let is_new_thread = reused.is_none();
+                // 議題メッセージとスレッドを作成
+                let created = if let Some(reused) = reused {
+                    Ok(reused)
+                } else if vc_text {
+                    Ok((vc_channel.clone(), None))
+                } else if self.is_forum(&guild_id) {
+                    self.create_forum_post(api, vc_channel, &owner, &thread_name)
+                        .await
+                } else {
+                    self.create_text_thread(
+                        api,
+                        vc_channel,
+                        &owner,
+                        &members,
+                        &thread_name,
+                        announce,
+                    )
+                    .await
+                };
+                let (thread, message) = match created {
+                    Ok(created) => created,
+                    Err(why) => {
+                        // 権限がなくて作成できない場合は、ログだけではわからないので管理者に知らせる
+                        let forbidden = why
+                            .chain()
+                            .filter_map(|e| e.downcast_ref::<serenity::Error>())
+                            .any(is_forbidden);
+                        if forbidden {
+                            self.alert_missing_permission(api, guild_id).await;
+                        }
+                        return Err(why);
+                    }
+                };
+
+                // 以降の投稿に失敗しても次の参加でスレッドが重複して作成されないよう、すぐに登録する
+                // VC⇔スレッドを登録
+                self.link(guild_id, *vc_channel_id, thread.id).await;
+
+                // スレッドID->議題メッセージを登録
+                if let Some(message) = message {
+                    self.thread_to_agenda_message
+                        .insert((guild_id, thread.id), message);
+                }
+
+                // VCを作成したユーザーをオーナーとして登録
+                self.vc_owner.insert(vc_key, owner);
+
+                // 参加メッセージでメンションしたメンバーはスレッドのメンバーになる
+                self.thread_members
+                    .insert((guild_id, thread.id), members.iter().copied().collect());
+
+                // 通話の開始時刻と参加者を記録
+                self.vc_sessions.insert(
+                    vc_key,
+                    VcSession {
+                        started_at: Utc::now(),
+                        participants: members,
+                    },
+                );
+
+                if is_new_thread {
+                    self.threads_created.fetch_add(1, Ordering::Relaxed);
+                    self.weekly_stats
+                        .entry(guild_id)
+                        .or_default()
+                        .threads_created += 1;
+                }
+
+                // VCのテキストにチャンネルメンションを追加 (VCのチャットを使う場合は参加メッセージを直接投稿するので不要)
+                if !vc_text {
+                    match self
+                        .post_vc_guide(api, guild_id, vc_channel_id, &thread.id)
+                        .await
+                    {
+                        // VC->案内メッセージを登録 (VC⇔スレッドと一緒に保存される)
+                        Ok(guide) => {
+                            self.vc_to_guide_message.insert(vc_key, guide.id);
+                        }
+                        Err(why) => error!("VCチャットの案内メッセージ作成に失敗: {:?}", why),
+                    }
+                }
+                // 参加メッセージ
+                let welcome_text = self.guild_message(
+                    &guild_id,
+                    "welcome",
+                    &[
+                        ("user", &member_mentions),
+                        ("channel", &channel_name),
+                        ("thread", &thread.mention().to_string()),
+                    ],
+                );
+                let welcome = self
+                    .send_welcome_message(
+                        api,
+                        guild_id,
+                        thread.id,
+                        &owner,
+                        &channel_name,
+                        &member_mentions,
+                        &welcome_text,
+                        Some(member.user.id),
+                    )
+                    .await;
+                match welcome {
+                    // スレッドID->参加メッセージを登録 (VC⇔スレッドと一緒に保存される)
+                    Ok(welcome) => {
+                        self.thread_to_welcome_message
+                            .insert((guild_id, thread.id), welcome.id);
+                    }
+                    Err(why) => {
+                        // ボタンを付けられなくても、空のスレッドにならないよう案内だけは投稿する
+                        error!("参加メッセージの作成に失敗: {:?}", why);
+                        let fallback_text = self.guild_message(
+                            &guild_id,
+                            "welcome_fallback",
+                            &[("user", &member_mentions)],
+                        );
+                        let fallback = api
+                            .send_message_with(thread.id, |m| {
+                                m.content(fallback_text);
+                                self.restrict_mentions(m, member.user.id)
+                            })
+                            .await;
+                        if let Err(why) = fallback {
+                            error!("参加メッセージの代わりの案内の投稿に失敗: {:?}", why);
+                        }
+                    }
+                }
+
+                // 案内メッセージと参加メッセージのIDを保存する
+                self.save_links().await;
+            }
+        };
+
+        Ok(())
+    }
+
+    /// ボタン付きの参加メッセージを投稿する
+    /// `author` (参加したユーザー) がNoneの場合は、メンションしても通知しない
+    #[allow(clippy::too_many_arguments)]
+    async fn send_welcome_message(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        owner: &UserId,
+        channel_name: &str,
+        member_mentions: &str,
+        welcome_text: &str,
+        author: Option<UserId>,
+    ) -> Result<Message> {
+        // 埋め込みのサムネイルにするオーナーのアイコン
+        let owner_avatar = api.cached_user(*owner).map(|user| user.face());
+        retry("参加メッセージの作成", API_RETRY_ATTEMPTS, || {
+            api.send_message_with(channel_id, |m| {
+                if self.app_config().discord.use_embeds {
+                    // 埋め込み内のメンションは通知されないので、本文でメンションする
+                    m.content(member_mentions);
+                    m.embed(|e| {
+                        e.title(channel_name);
+                        e.description(welcome_text);
+                        e.colour(self.embed_colour());
+                        if let Some(owner_avatar) = &owner_avatar {
+                            e.thumbnail(owner_avatar);
+                        }
+                        e
+                    });
+                } else {
+                    m.content(welcome_text);
+                }
+                m.components(|c| self.welcome_buttons(c, &guild_id, false));
+                match author {
+                    Some(author) => self.restrict_mentions(m, author),
+                    None => m.allowed_mentions(|m| m.empty_users()),
+                }
+            })
+        })
+        .await
+        .context("参加メッセージの作成に失敗")
+    }
+
+    /// スレッドを作成するチャンネルから、VCと同じ名前で対応のないアーカイブ済みのスレッドを探す
+    /// 見つかった場合はアーカイブを解除し、議題メッセージ (取得できれば) と一緒に返す
+    async fn find_archived_thread(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        vc_name: &str,
+    ) -> Result<Option<(GuildChannel, Option<Message>)>> {
+        let thread_channel = self.thread_channel(&guild_id)?;
+        // 最近アーカイブされたものから返される
+        let private = self.app_config().discord.thread_type == ThreadType::Private;
+        let archived = api
+            .archived_threads(thread_channel, private, ARCHIVED_THREAD_SEARCH_LIMIT)
+            .await
+            .context("アーカイブされたスレッドの取得に失敗")?;
+
+        // 名前の末尾の参加人数は変わっている可能性があるので除いて比べる
+        let vc_name = strip_member_count(vc_name);
+        let thread = archived.threads.into_iter().find(|thread| {
+            self.vc_name_of_thread(&guild_id, &thread.name) == vc_name
+                && !self.thread_to_vc.contains_key(&(guild_id, thread.id))
+        });
+        let thread = match thread {
+            Some(thread) => thread,
+            None => return Ok(None),
+        };
+
+        api.set_thread_archived(thread.id, false)
+            .await
+            .context("スレッドのアーカイブ解除に失敗")?;
+
+        // 告知を別のチャンネルに投稿している場合は、スレッドから議題メッセージをたどれない
+        if !self.is_forum(&guild_id) && self.announce_channel(&guild_id)? != thread_channel {
+            return Ok(Some((thread, None)));
+        }
+
+        // 議題メッセージはスレッドと同じIDを持つ (テキストチャンネルでは親チャンネルに、フォーラムでは投稿内にある)
+        let message_id = MessageId(thread.id.0);
+        let message = if self.is_forum(&guild_id) {
+            api.message(thread.id, message_id).await
+        } else {
+            api.message(thread_channel, message_id).await
+        };
+        let message = match message {
+            Ok(message) => Some(message),
+            Err(why) => {
+                warn!("使い回すスレッドの議題メッセージの取得に失敗: {:?}", why);
+                None
+            }
+        };
+
+        Ok(Some((thread, message)))
+    }
+
+    /// スレッドの数が上限に達したことを、サーバーのエラー報告チャンネルに知らせる (未設定の場合はログのみ)
+    async fn alert_thread_cap(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        active: usize,
+        max_active_threads: usize,
+    ) {
+        let error_channel = match self.error_channel(&guild_id) {
+            Some(error_channel) => error_channel,
+            None => return,
+        };
+        if self.skip_in_dry_run(|| format!("エラー報告チャンネル {} に投稿", error_channel))
+        {
+            return;
+        }
+        let content = self.messages().format(
+            "thread_cap_alert",
+            &[
+                ("active", &active.to_string()),
+                ("max", &max_active_threads.to_string()),
+            ],
+        );
+        if let Err(why) = api
+            .send_message_with(error_channel, |m| self.message_body(m, content))
+            .await
+        {
+            error!("スレッドの数の上限のお知らせの投稿に失敗: {:?}", why);
+        }
+    }
+
+    /// スレッドを作成する権限がないことを、エラー報告チャンネル (未設定の場合はサーバーのオーナーのDM) に知らせる
+    /// VCに参加されるたびに失敗するので、一定時間は繰り返し知らせない
+    async fn alert_missing_permission(&self, api: &impl DiscordApi, guild_id: GuildId) {
+        let now = Instant::now();
+        let recently_alerted = self
+            .permission_alerted_at
+            .get(&guild_id)
+            .is_some_and(|alerted_at| now.duration_since(*alerted_at) < PERMISSION_ALERT_COOLDOWN);
+        if recently_alerted {
+            return;
+        }
+        self.permission_alerted_at.insert(guild_id, now);
+
+        // 足りない権限がわかれば一緒に知らせる
+        let bot_user_id = *self.bot_user_id.lock().await;
+        let missing = match bot_user_id {
+            Some(bot_user_id) => match self.missing_permissions(api, guild_id, bot_user_id).await {
+                Ok(missing) => missing.join("\n"),
+                Err(why) => {
+                    error!("Botの権限の確認に失敗: {:?}", why);
+                    String::new()
+                }
+            },
+            None => String::new(),
+        };
+        let thread_channel = match self.thread_channel(&guild_id) {
+            Ok(thread_channel) => thread_channel,
+            Err(_) => return,
+        };
+        let content = self.guild_message(
+            &guild_id,
+            "permission_alert",
+            &[
+                ("channel", &thread_channel.mention().to_string()),
+                ("missing", &missing),
+            ],
+        );
+
+        let result = match self.error_channel(&guild_id) {
+            Some(error_channel) => api
+                .send_message_with(error_channel, |m| self.message_body(m, content))
+                .await
+                .map(|_| ()),
+            None => {
+                let owner_id = match api.guild_owner_id(guild_id) {
+                    Some(owner_id) => owner_id,
+                    None => {
+                        warn!("サーバーのキャッシュが無いため、権限の不足をオーナーに知らせられません");
+                        return;
+                    }
+                };
+                match api.dm_channel(owner_id).await {
+                    Ok(dm) => api
+                        .send_message_with(dm, |m| self.message_body(m, content))
+                        .await
+                        .map(|_| ()),
+                    Err(why) => Err(why),
+                }
+            }
+        };
+        if let Err(why) = result {
+            error!("権限の不足のお知らせの投稿に失敗: {:?}", why);
+        }
+    }
+
+    /// 公開スレッドのメンバーとして記録する (初めてのメンバーの場合はtrue)
+    /// 起動後に初めて参加されたスレッドのみ、それまでのメンバーをAPIで取得する
+    async fn remember_thread_member(
+        &self,
+        api: &impl DiscordApi,
+        thread_key: ChannelKey,
+        user_id: UserId,
+    ) -> Result<bool> {
+        if !self.thread_members.contains_key(&thread_key) {
+            let members = api
+                .thread_member_ids(thread_key.1)
+                .await
+                .context("スレッドメンバーの取得に失敗")?;
+            self.thread_members
+                .entry(thread_key)
+                .or_default()
+                .extend(members);
+        }
+        Ok(self
+            .thread_members
+            .entry(thread_key)
+            .or_default()
+            .insert(user_id))
+    }
+
+    /// VCのチャットにスレッドへの案内メッセージを投稿する
+    async fn post_vc_guide(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        vc_channel_id: &ChannelId,
+        thread_id: &ChannelId,
+    ) -> Result<Message> {
+        let guide = retry(
+            "VCチャットの案内メッセージ作成",
+            API_RETRY_ATTEMPTS,
+            || {
+                api.send_message_with(*vc_channel_id, |m| {
+                    self.message_body(
+                        m,
+                        self.guild_message(
+                            &guild_id,
+                            "vc_guide",
+                            &[
+                                ("channel", &thread_id.mention().to_string()),
+                                ("thread", &thread_id.mention().to_string()),
+                            ],
+                        ),
+                    )
+                })
+            },
+        )
+        .await
+        .context("VCチャットの案内メッセージ作成に失敗")?;
+        Ok(guide)
+    }
+
+    /// テキストチャンネルに議題メッセージを送信し、スレッドを作成する
+    async fn create_text_thread(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+        owner: &UserId,
+        members: &[UserId],
+        thread_name: &str,
+        announce: bool,
+    ) -> Result<(GuildChannel, Option<Message>)> {
+        // スレッドを作成するチャンネルと、告知を投稿するチャンネル
+        let thread_channel = self.thread_channel(&vc_channel.guild_id)?;
+        let announce_channel = self.announce_channel(&vc_channel.guild_id)?;
+        // 議題メッセージを送信 (告知しない場合は送信しない)
+        let message = if announce {
+            let text = self.guild_message(
+                &vc_channel.guild_id,
+                "create_announce",
+                &[
+                    ("user", &owner.mention().to_string()),
+                    ("channel", &vc_channel.id.mention().to_string()),
+                ],
+            );
+            let ping_role = self.new_vc_ping_role(api, &vc_channel.guild_id);
+            let message = retry("議題メッセージの送信", API_RETRY_ATTEMPTS, || {
+                api.send_message_with(announce_channel, |m| {
+                    match ping_role {
+                        // 埋め込み内のメンションは通知されないため、ロールのメンションは本文にする
+                        Some(role_id) if self.app_config().discord.use_embeds => {
+                            self.message_body(m, text.clone());
+                            m.content(role_id.mention());
+                        }
+                        Some(role_id) => {
+                            self.message_body(m, format!("{} {}", role_id.mention(), text));
+                        }
+                        None => {
+                            self.message_body(m, text.clone());
+                        }
+                    }
+                    m.allowed_mentions(|m| m.empty_users().roles(ping_role))
+                })
+            })
+            .await
+            .context("議題メッセージの送信に失敗")?;
+            Some(message)
+        } else {
+            None
+        };
+        // プライベートスレッドを作成 (作成できなければ公開スレッドにする)
+        let private_thread = match self.app_config().discord.thread_type {
+            ThreadType::Private => {
+                match retry(
+                    "プライベートスレッドの作成",
+                    API_RETRY_ATTEMPTS,
+                    || {
+                        api.create_private_thread(thread_channel, |m| {
+                            self.thread_builder(m, thread_name)
+                        })
+                    },
+                )
+                .await
+                {
+                    Ok(thread) => Some(thread),
+                    Err(why) => {
+                        // サーバーの設定や権限によってはプライベートスレッドを作成できない
+                        warn!(
+                            "プライベートスレッドを作成できないため、公開スレッドを作成します: {:?}",
+                            why
+                        );
+                        None
+                    }
+                }
+            }
+            ThreadType::Public => None,
+        };
+        // スレッドを作成
+        // 公開スレッドは同じチャンネルのメッセージにしか紐づけられないため、告知を別のチャンネルに投稿した場合は
+        // スレッドを作成するチャンネルにスレッドの目印のメッセージを投稿して紐づける (告知は議題メッセージのまま)
+        let starter_message = match (&private_thread, &message) {
+            (None, Some(message)) if announce_channel != thread_channel => {
+                let text = self.guild_message(
+                    &vc_channel.guild_id,
+                    "thread_anchor",
+                    &[("channel", &vc_channel.id.mention().to_string())],
+                );
+                let anchor = retry(
+                    "スレッドの目印のメッセージの送信",
+                    API_RETRY_ATTEMPTS,
+                    || {
+                        api.send_message_with(thread_channel, |m| {
+                            self.message_body(m, text.clone());
+                            m.allowed_mentions(|m| m.empty_users())
+                        })
+                    },
+                )
+                .await
+                .context("スレッドの目印のメッセージの送信に失敗")?;
+                Some(anchor.id)
+            }
+            (None, Some(message)) => Some(message.id),
+            _ => None,
+        };
+        let thread = match (private_thread, starter_message) {
+            (Some(thread), _) => thread,
+            (None, Some(message_id)) => retry("スレッドの作成", API_RETRY_ATTEMPTS, || {
+                api.create_public_thread(thread_channel, message_id, |m| {
+                    m.kind(ChannelType::PublicThread);
+                    self.thread_builder(m, thread_name)
+                })
+            })
+            .await
+            .context("スレッドの作成に失敗")?,
+            // 紐づける議題メッセージがない場合は、メッセージに紐づかない公開スレッドを作成する
+            (None, None) => retry("スレッドの作成", API_RETRY_ATTEMPTS, || {
+                api.create_private_thread(thread_channel, |m| {
+                    m.kind(ChannelType::PublicThread);
+                    self.thread_builder(m, thread_name)
+                })
+            })
+            .await
+            .context("スレッドの作成に失敗")?,
+        };
+        // プライベートスレッドの場合はVCのメンバーを招待
+        if thread.kind == ChannelType::PrivateThread {
+            self.invite_thread_members(api, thread.id, members).await;
+        }
+
+        Ok((thread, message))
+    }
+
+    /// プライベートスレッドにメンバーを招待し、招待できたメンバーを返す
+    /// まとめて招待するAPIはないため、大人数でもレート制限にかからないよう間隔を空けて順に招待する
+    async fn invite_thread_members(
+        &self,
+        api: &impl DiscordApi,
+        thread_id: ChannelId,
+        user_ids: &[UserId],
+    ) -> Vec<UserId> {
+        let mut invited = Vec::new();
+        for (i, user_id) in user_ids.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(THREAD_MEMBER_ADD_INTERVAL).await;
+            }
+            match retry(
+                "スレッドへのメンバーの追加",
+                API_RETRY_ATTEMPTS,
+                || api.add_thread_member(thread_id, *user_id),
+            )
+            .await
+            {
+                Ok(_) => invited.push(*user_id),
+                Err(why) => error!("スレッドへのメンバーの追加に失敗: {:?}", why),
+            }
+        }
+        invited
+    }
+
+    /// フォーラムチャンネルに、議題メッセージを最初のメッセージとした投稿を作成する
+    async fn create_forum_post(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+        owner: &UserId,
+        thread_name: &str,
+    ) -> Result<(GuildChannel, Option<Message>)> {
+        // 投稿を作成するフォーラムチャンネル
+        let forum_channel = self.thread_channel(&vc_channel.guild_id)?;
+
+        // serenityにフォーラムの投稿を作成するAPIが無いため、スレッド作成と同じエンドポイントに直接送信する
+        let mut post = serde_json::Map::new();
+        post.insert("name".to_string(), serde_json::json!(thread_name));
+        if let Some(duration) = self.app_config().discord.thread_auto_archive_duration {
+            post.insert(
+                "auto_archive_duration".to_string(),
+                serde_json::json!(duration),
+            );
+        }
+        let announce = self.guild_message(
+            &vc_channel.guild_id,
+            "create_announce",
+            &[
+                ("user", &owner.mention().to_string()),
+                ("channel", &vc_channel.id.mention().to_string()),
+            ],
+        );
+        let ping_role = self.new_vc_ping_role(api, &vc_channel.guild_id);
+        let allowed_mentions = serde_json::json!({
+            "parse": [],
+            "roles": ping_role.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+        });
+        let ping = ping_role.map(|r| r.mention().to_string());
+        let message = if self.app_config().discord.use_embeds {
+            // 埋め込み内のメンションは通知されないため、ロールのメンションは本文にする
+            serde_json::json!({
+                "content": ping.unwrap_or_default(),
+                "embeds": [{ "description": announce, "color": self.embed_colour().0 }],
+                "allowed_mentions": allowed_mentions,
+            })
+        } else {
+            let content = match ping {
+                Some(ping) => format!("{} {}", ping, announce),
+                None => announce,
+            };
+            serde_json::json!({
+                "content": content,
+                "allowed_mentions": allowed_mentions,
+            })
+        };
+        post.insert("message".to_string(), message);
+        // VCの名前やオーナーのアクティビティに合うタグを付ける
+        let tags = self.forum_tags(api, vc_channel, owner);
+        if !tags.is_empty() {
+            post.insert("applied_tags".to_string(), serde_json::json!(tags));
+        }
+        let thread = retry(
+            "フォーラムの投稿の作成",
+            API_RETRY_ATTEMPTS,
+            || api.create_forum_post(forum_channel, &post),
+        )
+        .await
+        .context("フォーラムの投稿の作成に失敗")?;
+
+        // 最初のメッセージは投稿と同じIDになる
+        let message = api
+            .message(thread.id, MessageId(thread.id.0))
+            .await
+            .context("フォーラムの投稿の最初のメッセージの取得に失敗")?;
+
+        Ok((thread, Some(message)))
+    }
+
+    /// フォーラムの投稿に付けるタグを選ぶ
+    /// 設定したキーワードがVCの名前かオーナーのアクティビティ (プレゼンスを受信している場合) に含まれるタグを付ける
+    fn forum_tags(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+        owner: &UserId,
+    ) -> Vec<String> {
+        let app_config = self.app_config();
+        let forum_tags = match app_config.discord.guild(&vc_channel.guild_id) {
+            Some(guild_config) => &guild_config.forum_tags,
+            None => return Vec::new(),
+        };
+        // オーナーのアクティビティ名
+        let activities = api.presence_activities(vc_channel.guild_id, *owner);
+        forum_tags
+            .iter()
+            .filter(|(keyword, _)| {
+                vc_channel.name.contains(keyword.as_str())
+                    || activities.iter().any(|a| a.contains(keyword.as_str()))
+            })
+            .map(|(_, tag_id)| tag_id.to_string())
+            .collect()
+    }
+
+    /// 参加中のメンバーの表示を更新するVCとして記録する (次の定期処理でまとめて更新する)
+    fn request_panel_update(&self, vc_key: &ChannelKey) {
+        if self.app_config().discord.participant_panel && self.vc_to_thread.contains_key(vc_key) {
+            self.panels_outdated.insert(*vc_key);
+        }
+    }
+
+    /// スレッドにピン留めした、参加中のメンバーの表示を編集する (なければ投稿する)
+    /// 解散した場合は、解散したことを表示する
+    async fn update_panel(
+        &self,
+        api: &impl DiscordApi,
+        vc_key: &ChannelKey,
+        disbanded: bool,
+    ) -> Result<()> {
+        // マップからスレッドのチャンネルIDを取得
+        let thread_id = match self.vc_to_thread.get(vc_key).map(|r| *r) {
+            Some(thread_id) => thread_id,
+            None => return Ok(()),
+        };
+        let thread_key = (vc_key.0, thread_id);
+
+        // 参加中のメンバー (解散した場合は誰もいない)
+        let members = if disbanded {
+            Vec::new()
+        } else {
+            let vc_channel = self.get_guild_channel(api, vc_key.1).await?;
+            self.vc_member_ids(api, &vc_channel).unwrap_or_default()
+        };
+        let (title, description, colour) = if disbanded {
+            (
+                self.guild_message(&vc_key.0, "panel_disbanded_title", &[]),
+                self.guild_message(&vc_key.0, "panel_disbanded", &[]),
+                Colour::LIGHT_GREY,
+            )
+        } else if members.is_empty() {
+            (
+                self.guild_message(&vc_key.0, "panel_title", &[]),
+                self.guild_message(&vc_key.0, "panel_empty", &[]),
+                self.embed_colour(),
+            )
+        } else {
+            (
+                self.guild_message(
+                    &vc_key.0,
+                    "panel_title_count",
+                    &[("count", &members.len().to_string())],
+                ),
+                // 埋め込み内のメンションは通知されない
+                members
+                    .iter()
+                    .map(|m| m.mention().to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                self.embed_colour(),
+            )
+        };
+
+        // 既にメッセージがあれば編集する
+        if let Some(message_id) = self.thread_to_panel_message.get(&thread_key).map(|r| *r) {
+            match api
+                .edit_message(thread_id, message_id, |m| {
+                    m.embed(|e| {
+                        e.title(&title);
+                        e.description(&description);
+                        e.colour(colour);
+                        e
+                    })
+                })
+                .await
+            {
+                Ok(_) => return Ok(()),
+                // メッセージが削除されていれば投稿し直す
+                Err(why) if is_not_found(&why) => {}
+                Err(why) => return Err(why).context("参加中のメンバーの表示の編集に失敗"),
+            }
+        }
+        // 解散した後に新しく投稿することはしない
+        if disbanded {
+            return Ok(());
+        }
+
+        // メッセージを投稿してピン留めする
+        let message = retry(
+            "参加中のメンバーの表示の投稿",
+            API_RETRY_ATTEMPTS,
+            || {
+                api.send_message_with(thread_id, |m| {
+                    m.embed(|e| {
+                        e.title(&title);
+                        e.description(&description);
+                        e.colour(colour);
+                        e
+                    })
+                })
+            },
+        )
+        .await
+        .context("参加中のメンバーの表示の投稿に失敗")?;
+        self.thread_to_panel_message.insert(thread_key, message.id);
+        self.save_links().await;
+        if let Err(why) = api.pin_message(thread_id, message.id).await {
+            error!("参加中のメンバーの表示のピン留めに失敗: {:?}", why);
+        }
+
+        Ok(())
+    }
+
+    /// VCのステータスを、スレッドにピン留めしたメッセージに反映する
+    /// ステータスが消された場合はメッセージも削除する
+    async fn sync_vc_status(&self, ctx: &Context, vc_key: &ChannelKey, status: &str) -> Result<()> {
+        // マップからスレッドのチャンネルIDを取得
+        let thread_id = match self.vc_to_thread.get(vc_key).map(|r| *r) {
+            Some(thread_id) => thread_id,
+            None => return Ok(()),
+        };
+        let thread_key = (vc_key.0, thread_id);
+        let message_id = self.thread_to_status_message.get(&thread_key).map(|r| *r);
+
+        // ステータスが消された場合
+        if status.is_empty() {
+            if let Some(message_id) = message_id {
+                self.thread_to_status_message.remove(&thread_key);
+                self.save_links().await;
+                match thread_id.delete_message(ctx, message_id).await {
+                    Ok(_) => {}
+                    // 既に削除されていれば何もしない
+                    Err(why) if is_not_found(&why) => {}
+                    Err(why) => return Err(why).context("VCのステータスのメッセージの削除に失敗"),
+                }
+            }
+            return Ok(());
+        }
+
+        let content = self.guild_message(&vc_key.0, "vc_status", &[("status", status)]);
+
+        // 既にメッセージがあれば編集する
+        if let Some(message_id) = message_id {
+            match thread_id
+                .edit_message(ctx, message_id, |m| {
+                    m.content(&content);
+                    m.allowed_mentions(|m| m.empty_parse());
+                    m
+                })
+                .await
+            {
+                Ok(_) => return Ok(()),
+                // メッセージが削除されていれば投稿し直す
+                Err(why) if is_not_found(&why) => {}
+                Err(why) => return Err(why).context("VCのステータスのメッセージの編集に失敗"),
+            }
+        }
+
+        // メッセージを投稿してピン留めする
+        let message = retry(
+            "VCのステータスのメッセージの投稿",
+            API_RETRY_ATTEMPTS,
+            || {
+                thread_id.send_message(ctx, |m| {
+                    m.content(&content);
+                    m.allowed_mentions(|m| m.empty_parse());
+                    m
+                })
+            },
+        )
+        .await
+        .context("VCのステータスのメッセージの投稿に失敗")?;
+        self.thread_to_status_message.insert(thread_key, message.id);
+        self.save_links().await;
+        if let Err(why) = message.pin(ctx).await {
+            error!("VCのステータスのメッセージのピン留めに失敗: {:?}", why);
+        }
+
+        Ok(())
+    }
+
+    /// 設定に応じて、メッセージの文章を本文か埋め込みにする
+    fn message_body<'a, 'b>(
+        &self,
+        m: &'b mut CreateMessage<'a>,
+        text: String,
+    ) -> &'b mut CreateMessage<'a> {
+        if self.app_config().discord.use_embeds {
+            m.embed(|e| {
+                e.description(text);
+                e.colour(self.embed_colour());
+                e
+            })
+        } else {
+            m.content(text)
+        }
+    }
+
+    /// 埋め込みの色
+    fn embed_colour(&self) -> Colour {
+        self.app_config()
+            .discord
+            .embed_color
+            .map_or(Colour::BLURPLE, Colour)
+    }
+
+    /// 監視用のメトリクスを公開するポート (未設定の場合はNone)
+    #[cfg(feature = "metrics")]
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.app_config().metrics_port
+    }
+
+    /// 監視用のメトリクスをPrometheusのテキスト形式で出力する
+    /// /stats と同じ値を出力する
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&self) -> String {
+        let mut metrics = MetricsText::default();
+        metrics
+            .counter(
+                "vc_thread_threads_created_total",
+                "Threads created since startup",
+                self.threads_created.load(Ordering::Relaxed),
+            )
+            .counter(
+                "vc_thread_threads_archived_total",
+                "Threads archived since startup",
+                self.threads_archived.load(Ordering::Relaxed),
+            )
+            .counter(
+                "vc_thread_renames_total",
+                "VC renames applied from the rename dialog since startup",
+                self.renames_applied.load(Ordering::Relaxed),
+            )
+            .counter(
+                "vc_thread_links_evicted_total",
+                "VC-thread links dropped over max_links since startup",
+                self.links_evicted.load(Ordering::Relaxed),
+            )
+            .counter(
+                "vc_thread_errors_reported_total",
+                "Errors reported since startup",
+                self.errors_reported.load(Ordering::Relaxed),
+            )
+            .gauge(
+                "vc_thread_active_links",
+                "Current number of VC-thread links",
+                self.vc_to_thread.len(),
+            );
+        metrics::api_metrics(&mut metrics).finish()
+    }
+
+    /// イベントの処理中のログに付ける情報を作る
+    /// チャンネルはVCかスレッドかを対応から判断し、対応するもう一方のチャンネルも付ける
+    fn log_fields(
+        &self,
+        action: &'static str,
+        guild_id: Option<GuildId>,
+        channel_id: Option<ChannelId>,
+        user_id: Option<UserId>,
+    ) -> LogFields {
+        let (vc_channel_id, thread_id) = match guild_id.zip(channel_id) {
+            Some(key) => {
+                if let Some(vc_channel_id) = self.thread_to_vc.get(&key).map(|r| *r) {
+                    (Some(vc_channel_id), Some(key.1))
+                } else {
+                    (Some(key.1), self.vc_to_thread.get(&key).map(|r| *r))
+                }
+            }
+            None => (None, None),
+        };
+        LogFields {
+            action,
+            guild_id,
+            vc_channel_id,
+            thread_id,
+            user_id,
+        }
+    }
+
+    /// ドライランの場合は、Discordへの操作の代わりにその内容をログに出力してtrueを返す
+    fn skip_in_dry_run(&self, action: impl FnOnce() -> String) -> bool {
+        if !self.app_config().dry_run {
+            return false;
+        }
+        info!("[ドライラン] {}", action());
+        true
+    }
+
+    /// 新しいVCの告知でメンションするロール
+    /// 通知を控える時間帯や、ロールがそのサーバーのものでない場合はメンションしない
+    fn new_vc_ping_role(&self, api: &impl DiscordApi, guild_id: &GuildId) -> Option<RoleId> {
+        let role_id = self.app_config().discord.new_vc_ping_role?;
+        if self.is_quiet_hours() {
+            return None;
+        }
+        api.has_role(*guild_id, role_id).then_some(role_id)
+    }
+
+    /// 通知を控える時間帯か判定する
+    fn is_quiet_hours(&self) -> bool {
+        self.app_config()
+            .discord
+            .quiet_hours
+            .as_ref()
+            .is_some_and(|q| q.contains(Utc::now()))
+    }
+
+    /// メンションしたユーザーに通知する範囲 (通知を控える時間帯は誰にも通知しない)
+    fn mention_policy(&self) -> MentionPolicy {
+        if self.is_quiet_hours() {
+            MentionPolicy::None
+        } else {
+            self.app_config().discord.mention_policy
+        }
+    }
+
+    /// メッセージでメンションしたユーザーのうち、設定された範囲のユーザーのみに通知する
+    /// `author` はメッセージのきっかけになったユーザー
+    fn restrict_mentions<'a, 'b>(
+        &self,
+        m: &'b mut CreateMessage<'a>,
+        author: UserId,
+    ) -> &'b mut CreateMessage<'a> {
+        match self.mention_policy() {
+            MentionPolicy::None => m.allowed_mentions(|m| m.empty_users()),
+            MentionPolicy::AuthorOnly => m.allowed_mentions(|m| m.users([author])),
+            MentionPolicy::All => m,
+        }
+    }
+
+    /// スレッドを作成するチャンネルを取得する
+    fn thread_channel(&self, guild_id: &GuildId) -> Result<ChannelId> {
+        Ok(self
+            .app_config()
+            .discord
+            .guild(guild_id)
+            .ok_or(anyhow::anyhow!("サーバーの設定がありません"))?
+            .thread_channel)
+    }
+
+    /// 新しいVCの告知を投稿するチャンネルを取得する (未設定の場合はスレッドを作成するチャンネル)
+    fn announce_channel(&self, guild_id: &GuildId) -> Result<ChannelId> {
+        let app_config = self.app_config();
+        let guild_config = app_config
+            .discord
+            .guild(guild_id)
+            .ok_or(anyhow::anyhow!("サーバーの設定がありません"))?;
+        Ok(guild_config
+            .announce_channel
+            .unwrap_or(guild_config.thread_channel))
+    }
+
+    /// サーバーのエラー報告チャンネルを取得する (未設定の場合はNone)
+    fn error_channel(&self, guild_id: &GuildId) -> Option<ChannelId> {
+        self.app_config()
+            .discord
+            .guild(guild_id)
+            .and_then(|guild_config| guild_config.error_channel)
+    }
+
+    /// スレッドを作成するチャンネルがフォーラムかどうか (起動時に判定した結果)
+    fn is_forum(&self, guild_id: &GuildId) -> bool {
+        self.thread_channel_kinds.get(guild_id).map(|r| *r) == Some(ChannelType::Forum)
+    }
+
+    /// スレッドを作成するチャンネルの種類を判定する
+    async fn detect_thread_channel_kind(&self, ctx: &Context, guild_id: GuildId) -> Result<()> {
+        let thread_channel = self.thread_channel(&guild_id)?;
+        let kind = self
+            .get_guild_channel(ctx, thread_channel)
+            .await
+            .context("スレッドを作成するチャンネルの取得に失敗")?
+            .kind;
+        self.thread_channel_kinds.insert(guild_id, kind);
+        Ok(())
+    }
+
+    /// スレッド作成時の共通の設定をする
+    fn thread_builder<'a>(&self, t: &'a mut CreateThread, name: &str) -> &'a mut CreateThread {
+        t.name(name);
+        if let Some(duration) = self.app_config().discord.thread_auto_archive_duration {
+            t.auto_archive_duration(duration);
+        }
+        t
+    }
+
+    /// サーバーごとのテンプレートと絵文字を優先してメッセージを作成する
+    fn guild_message(&self, guild_id: &GuildId, key: &str, args: &[(&str, &str)]) -> String {
+        match self.app_config().discord.guild(guild_id) {
+            Some(guild_config) => {
+                // サーバーごとの絵文字は `{emoji_名前}` の値として渡す (未設定の絵文字はデフォルトのまま)
+                let emoji = guild_config
+                    .emoji
+                    .iter()
+                    .map(|(name, emoji)| (format!("emoji_{}", name), emoji.as_str()))
+                    .collect::<Vec<_>>();
+                let args = args
+                    .iter()
+                    .copied()
+                    .chain(emoji.iter().map(|(name, emoji)| (name.as_str(), *emoji)))
+                    .collect::<Vec<_>>();
+                self.messages()
+                    .format_with(&guild_config.templates, key, &args)
+            }
+            None => self.messages().format(key, args),
+        }
+    }
+
+    /// インタラクションなどへの応答のメッセージを作成する (サーバー内ならサーバーごとの設定を優先する)
+    fn guild_text(&self, guild_id: Option<GuildId>, key: &str, args: &[(&str, &str)]) -> String {
+        match guild_id {
+            Some(guild_id) => self.guild_message(&guild_id, key, args),
+            None => self.messages().format(key, args),
+        }
+    }
+
+    /// ボタンに付ける絵文字 (サーバーごとの設定を優先する)
+    fn button_emoji(&self, guild_id: &GuildId, name: &str) -> ReactionType {
+        let default = DEFAULT_EMOJI
+            .iter()
+            .find(|(default, _)| *default == name)
+            .map_or("", |(_, emoji)| emoji);
+        self.app_config()
+            .discord
+            .guild(guild_id)
+            .and_then(|guild_config| guild_config.emoji.get(name))
+            // 形式は設定の読み込み時に確認済み
+            .and_then(|emoji| emoji.parse().ok())
+            .unwrap_or_else(|| ReactionType::Unicode(default.to_string()))
+    }
+
+    /// VCの名前が取得できなかった場合の名前 (サーバーごとのテンプレートで上書きできる)
+    fn unknown_channel_name(&self, guild_id: &GuildId) -> String {
+        self.guild_message(guild_id, "unknown_channel", &[])
+    }
+
+    /// 参加メッセージのボタンを作成する
+    fn welcome_buttons<'a>(
+        &self,
+        c: &'a mut CreateComponents,
+        guild_id: &GuildId,
+        disabled: bool,
+    ) -> &'a mut CreateComponents {
+        c.create_action_row(|f| {
+            f.create_button(|b| {
+                b.label(self.guild_message(guild_id, "rename_button", &[]));
+                b.emoji(self.button_emoji(guild_id, "rename"));
+                b.style(ButtonStyle::Success);
+                b.custom_id("rename_button");
+                b.disabled(disabled);
+                b
+            });
+            f.create_button(|b| {
+                b.label(self.guild_message(guild_id, "claim_button", &[]));
+                b.emoji(self.button_emoji(guild_id, "claim"));
+                b.style(ButtonStyle::Secondary);
+                b.custom_id("claim_button");
+                b.disabled(disabled);
+                b
+            });
+            f.create_button(|b| {
+                b.label(self.guild_message(guild_id, "lock_button", &[]));
+                b.emoji(self.button_emoji(guild_id, "lock"));
+                b.style(ButtonStyle::Secondary);
+                b.custom_id("lock_button");
+                b.disabled(disabled);
+                b
+            });
+            f.create_button(|b| {
+                b.label(self.guild_message(guild_id, "unlock_button", &[]));
+                b.emoji(self.button_emoji(guild_id, "unlock"));
+                b.style(ButtonStyle::Secondary);
+                b.custom_id("unlock_button");
+                b.disabled(disabled);
+                b
+            });
+            f.create_button(|b| {
+                b.label(self.guild_message(guild_id, "limit_button", &[]));
+                b.emoji(self.button_emoji(guild_id, "limit"));
+                b.style(ButtonStyle::Secondary);
+                b.custom_id("limit_button");
+                b.disabled(disabled);
+                b
+            });
+            f
+        })
+    }
+
+    /// 参加メッセージのボタンを有効化/無効化する
+    async fn set_welcome_buttons_disabled(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        thread_id: &ChannelId,
+        disabled: bool,
+    ) -> Result<()> {
+        // マップから参加メッセージを取得
+        let welcome = self
+            .thread_to_welcome_message
+            .get(&(guild_id, *thread_id))
+            .map(|r| *r);
+        if let Some(welcome) = welcome {
+            api.edit_message(*thread_id, welcome, |m| {
+                m.components(|c| self.welcome_buttons(c, &guild_id, disabled));
+                m
+            })
+            .await
+            .context("参加メッセージのボタンの更新に失敗")?;
+        }
+        Ok(())
+    }
+
+    /// 退出してから猶予時間内に再参加したか判定する
+    async fn rejoined_within_cooldown(&self, vc_key: &ChannelKey, user_id: &UserId) -> bool {
+        let cooldown = chrono::Duration::seconds(self.app_config().discord.vc_join_cooldown_secs);
+        self.vc_leave_times
+            .get(vc_key)
+            .and_then(|t| t.get(user_id).copied())
+            .map(|left_at| Utc::now() - left_at < cooldown)
+            .unwrap_or(false)
+    }
+
+    /// アーカイブされたスレッドを元に戻す (元に戻した場合はtrue)
+    async fn unarchive_thread(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        thread_id: &ChannelId,
+    ) -> Result<bool> {
+        // スレッドがアーカイブされているか確認
+        let archived = api
+            .channel(*thread_id)
+            .await
+            .context("スレッドの取得に失敗")?
+            .guild()
+            .and_then(|c| c.thread_metadata)
+            .map(|m| m.archived)
+            .unwrap_or(false);
+
+        // アーカイブされていればアーカイブを解除
+        if archived {
+            api.set_thread_archived(*thread_id, false)
+                .await
+                .context("スレッドのアーカイブ解除に失敗")?;
+
+            // 解散時に無効化したボタンを戻す
+            if let Err(why) = self
+                .set_welcome_buttons_disabled(api, guild_id, thread_id, false)
+                .await
+            {
+                error!("参加メッセージのボタンの有効化に失敗: {:?}", why);
+            }
+        }
+
+        Ok(archived)
+    }
+
+    /// スレッドにメッセージを投稿する
+    /// Webhookを使う設定の場合はVCの名前で投稿する (Webhookが使えなければBotとして投稿する)
+    /// `notify` がfalseの場合はメンションしても通知しない
+    async fn send_thread_message(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        thread_id: ChannelId,
+        vc_name: &str,
+        content: String,
+        notify: bool,
+    ) -> Result<()> {
+        // Webhookを使わないビルドでは、投稿先のサーバーとVCの名前は使わない
+        #[cfg(not(feature = "webhook"))]
+        let _ = (guild_id, vc_name);
+        // VCのチャットにはスレッドを指定して投稿できないので、Botとして投稿する
+        #[cfg(feature = "webhook")]
+        if self.app_config().discord.use_webhook && !self.is_vc_chat(guild_id, thread_id) {
+            let parent_id = self.thread_channel(&guild_id)?;
+            // Webhookの名前は80文字まで
+            let username = vc_name
+                .chars()
+                .take(WEBHOOK_USERNAME_MAX_CHARS)
+                .collect::<String>();
+            match api
+                .send_webhook_message(
+                    &self.webhooks,
+                    parent_id,
+                    thread_id,
+                    &username,
+                    &content,
+                    notify,
+                )
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(why) => {
+                    // 権限が無い、Webhookが削除されたなどの場合は次回作り直す
+                    warn!("Webhookで投稿できないため、Botとして投稿します: {:?}", why);
+                    self.webhooks.forget(&parent_id);
+                }
+            }
+        }
+
+        retry("スレッドへの投稿", API_RETRY_ATTEMPTS, || {
+            api.send_message(thread_id, content.clone(), notify)
+        })
+        .await
+        .context("スレッドへの投稿に失敗")?;
+        Ok(())
+    }
+
+    /// 退出時にスレッドにメッセージを投稿する
+    async fn post_leave_message(
+        &self,
+        api: &impl DiscordApi,
+        vc_key: &ChannelKey,
+        user_id: &UserId,
+    ) -> Result<()> {
+        // マップからスレッドのチャンネルIDを取得
+        let thread_id = self.vc_to_thread.get(vc_key).map(|r| *r);
+        // スレッドが作成されていない場合は何もしない
+        let thread_id = match thread_id {
+            Some(thread_id) => thread_id,
+            None => return Ok(()),
+        };
+
+        // 退出メッセージ
+        let vc_name = self
+            .channel_name(api, vc_key.1)
+            .await
+            .unwrap_or(self.unknown_channel_name(&vc_key.0));
+        let content = self.guild_message(
+            &vc_key.0,
+            "leave",
+            &[("user", &user_id.mention().to_string())],
+        );
+        self.send_thread_message(api, vc_key.0, thread_id, &vc_name, content, false)
+            .await
+            .context("退出メッセージの送信に失敗")?;
+
+        Ok(())
+    }
+
+    /// カスタムVCから退出した時の処理
+    async fn leave_vc(&self, api: &impl DiscordApi, vc_channel: &GuildChannel, user_id: &UserId) {
+        let vc_key = (vc_channel.guild_id, vc_channel.id);
+        self.touch_link(&vc_key);
+
+        // 遅れて届いた参加の処理やスレッドの後始末と入れ違わないよう、VCをロックする
+        let _guard = self.lock_vc(&vc_key).await;
+
+        // Botの退出は通知しない
+        if !self.is_ignored_user(api, user_id) {
+            // 退出メッセージを投稿
+            if let Err(why) = self.post_leave_message(api, &vc_key, user_id).await {
+                self.report_error(
+                    api,
+                    Some(vc_key.0),
+                    "VCスレッドチャンネルへの退出メッセージの投稿に失敗",
+                    &why,
+                )
+                .await;
+            }
+
+            // 参加時刻の記録を削除
+            if let Some(mut join_times) = self.vc_join_times.get_mut(&vc_key) {
+                join_times.remove(user_id);
+            }
+
+            // 再参加の判定のために退出時刻を記録
+            self.vc_leave_times
+                .entry(vc_key)
+                .or_default()
+                .insert(*user_id, Utc::now());
+
+            // オーナーが退出した場合は他のメンバーにオーナーを引き継ぐ
+            if let Err(why) = self.transfer_owner(api, vc_channel, user_id).await {
+                self.report_error(api, Some(vc_key.0), "VCのオーナーの引き継ぎに失敗", &why)
+                    .await;
+            }
+        }
+
+        // スレッドの名前の参加人数を更新
+        if let Err(why) = self.update_thread_member_count(api, vc_channel).await {
+            self.report_error(api, Some(vc_key.0), "スレッドの参加人数の更新に失敗", &why)
+                .await;
+        }
+
+        // VCが空になったらスレッドをアーカイブ
+        if self.app_config().discord.archive_on_empty {
+            match self.count_vc_members(api, vc_channel) {
+                Some(0) => {
+                    if let Err(why) = self.archive_thread(api, &vc_key, true).await {
+                        self.report_error(
+                            api,
+                            Some(vc_key.0),
+                            "VCスレッドチャンネルのアーカイブに失敗",
+                            &why,
+                        )
+                        .await;
+                    }
+                }
+                Some(_) => {}
+                None => warn!("サーバーのキャッシュが無いため、VCの残り人数を確認できません"),
+            }
+        }
+    }
+
+    /// オーナーが退出した時に、最も長く参加しているメンバーにオーナーを引き継ぐ
+    async fn transfer_owner(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+        left_user_id: &UserId,
+    ) -> Result<()> {
+        let vc_key = (vc_channel.guild_id, vc_channel.id);
+
+        // 退出したのがオーナーでなければ何もしない
+        if self.vc_owner.get(&vc_key).map(|r| *r) != Some(*left_user_id) {
+            return Ok(());
+        }
+
+        // VCに残っているメンバーをキャッシュから取得
+        let remaining = self.vc_member_ids(api, vc_channel).unwrap_or_default();
+
+        // 最も早く参加したメンバーを選ぶ
+        let new_owner = match self.earliest_joined(&vc_key, &remaining).await {
+            Some(new_owner) => new_owner,
+            // 誰も残っていなければ引き継がない
+            None => return Ok(()),
+        };
+
+        // オーナーを更新
+        self.vc_owner.insert(vc_key, new_owner);
+
+        // スレッドに通知
+        let thread_id = self.vc_to_thread.get(&vc_key).map(|r| *r);
+        if let Some(thread_id) = thread_id {
+            api.send_message_with(thread_id, |m| {
+                m.content(self.guild_message(
+                    &vc_key.0,
+                    "owner_changed",
+                    &[("user", &new_owner.mention().to_string())],
+                ));
+                m
+            })
+            .await
+            .context("オーナー引き継ぎメッセージの送信に失敗")?;
+        }
+
+        Ok(())
+    }
+
+    /// メンバーの中から最も早くVCに参加したユーザーを選ぶ
+    /// 参加時刻の記録がないメンバー(Bot起動前から参加)を優先し、同じ場合はユーザーIDで決める
+    async fn earliest_joined(&self, vc_key: &ChannelKey, members: &[UserId]) -> Option<UserId> {
+        let join_times = self.vc_join_times.get(vc_key);
+        let join_times = join_times.as_deref();
+        members.iter().copied().min_by_key(|user_id| {
+            let joined_at = join_times.and_then(|t| t.get(user_id)).copied();
+            (joined_at, user_id.0)
+        })
+    }
+
+    /// スレッドの名前を作成する (設定されていればテンプレートに当てはめ、末尾に参加人数を付ける)
+    fn thread_name(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+        vc_name: &str,
+    ) -> String {
+        let template = self.thread_name_template(&vc_channel.guild_id);
+        if !self.app_config().discord.name_with_count {
+            return apply_name_template(template.as_deref(), vc_name);
+        }
+        // VCの名前に既に人数が付いている場合は付け直す
+        let base_name = apply_name_template(template.as_deref(), strip_member_count(vc_name));
+        match self.count_vc_members(api, vc_channel) {
+            Some(count) => format!("{} ({})", base_name, count),
+            None => base_name,
+        }
+    }
+
+    /// サーバーのスレッドの名前のテンプレート (未設定の場合はNone)
+    fn thread_name_template(&self, guild_id: &GuildId) -> Option<String> {
+        self.app_config()
+            .discord
+            .guild(guild_id)
+            .and_then(|guild_config| guild_config.thread_name_template.clone())
+    }
+
+    /// スレッドの名前からVCの名前を取り出す (参加人数とテンプレートで付けた部分を除く)
+    fn vc_name_of_thread<'a>(&self, guild_id: &GuildId, thread_name: &'a str) -> &'a str {
+        strip_name_template(
+            self.thread_name_template(guild_id).as_deref(),
+            strip_member_count(thread_name),
+        )
+    }
+
+    /// 参加/退出時にスレッドの名前の参加人数を更新する
+    async fn update_thread_member_count(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+    ) -> Result<()> {
+        if !self.app_config().discord.name_with_count {
+            return Ok(());
+        }
+        if self.skip_in_dry_run(|| format!("VC「{}」のスレッドの参加人数を更新", vc_channel.name))
+        {
+            return Ok(());
+        }
+
+        // スレッドが作成されていない場合は何もしない
+        let thread_id = self
+            .vc_to_thread
+            .get(&(vc_channel.guild_id, vc_channel.id))
+            .map(|r| *r);
+        // VCのチャットを使っている場合はVC自身なので、スレッドとしてリネームしない
+        let thread_id = match thread_id {
+            Some(thread_id) if thread_id != vc_channel.id => thread_id,
+            _ => return Ok(()),
+        };
+
+        // 名前が変わらない場合はリネームしない (レート制限の回数を無駄にしない)
+        let name = self.thread_name(api, vc_channel, &vc_channel.name);
+        if self.channel_name(api, thread_id).await.as_deref() == Some(name.as_str()) {
+            return Ok(());
+        }
+
+        // スレッドをリネーム
+        // 名前の変更は10分に2回までなので、頻繁に出入りがあっても制限が解除された時に最新の人数が反映される
+        api.rename_channel(&self.rename_queue, thread_id, name)
+            .await
+            .context("スレッドのリネームに失敗")?;
+        Ok(())
+    }
+
+    /// VC名前変更時にスレッドをリネームする
+    async fn rename_thread(&self, api: &impl DiscordApi, vc_channel: &GuildChannel) -> Result<()> {
+        if self.skip_in_dry_run(|| format!("VC「{}」のスレッドをリネーム", vc_channel.name))
+        {
+            return Ok(());
+        }
+        let vc_channel_id = &vc_channel.id;
+        // マップからスレッドのチャンネルIDを取得
+        let channel_id = self
+            .vc_to_thread
+            .get(&(vc_channel.guild_id, *vc_channel_id))
+            .map(|r| *r);
+        // スレッドが作成済みの場合 (VCのチャットを使っている場合はVC自身なのでリネームしない)
+        if let Some(thread_id) = channel_id.filter(|thread_id| thread_id != vc_channel_id) {
+            // チャンネル名を取得
+            let channel_name = self
+                .channel_name(api, *vc_channel_id)
+                .await
+                .unwrap_or(self.unknown_channel_name(&vc_channel.guild_id));
+            // スレッドをリネーム (レート制限にかかる場合は後で反映される)
+            api.rename_channel(
+                &self.rename_queue,
+                thread_id,
+                self.thread_name(api, vc_channel, &channel_name),
+            )
+            .await
+            .context("スレッドのリネームに失敗")?;
+        }
+
+        Ok(())
+    }
+
+    /// VCの名前が変わったことをスレッドに知らせる (Botで変更した場合は変更したユーザーも載せる)
+    async fn announce_rename(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+        user_id: Option<UserId>,
+    ) -> Result<()> {
+        let thread_id = match self.vc_to_thread.get(&(vc_channel.guild_id, vc_channel.id)) {
+            Some(thread_id) => *thread_id,
+            None => return Ok(()),
+        };
+        let content = match user_id {
+            Some(user_id) => self.guild_message(
+                &vc_channel.guild_id,
+                "rename_announce_by",
+                &[
+                    ("user", &user_id.mention().to_string()),
+                    ("new", &vc_channel.name),
+                ],
+            ),
+            None => self.guild_message(
+                &vc_channel.guild_id,
+                "rename_announce",
+                &[("new", &vc_channel.name)],
+            ),
+        };
+        api.send_message_with(thread_id, |m| {
+            m.content(content);
+            m.allowed_mentions(|m| m.empty_users());
+            m
+        })
+        .await
+        .context("名前の変更の案内の投稿に失敗")?;
+        Ok(())
+    }
+
+    /// VCを取得
+    async fn get_vc(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: Option<GuildId>,
+        channel_id: &ChannelId,
+    ) -> Result<GuildChannel> {
+        // サーバー外 (DMなど) ではVCは存在しない
+        let guild_id = guild_id.ok_or(anyhow::anyhow!("サーバー外のチャンネル"))?;
+        // マップからスレッドのチャンネルIDを取得
+        let vc_channel_id = self
+            .thread_to_vc
+            .get(&(guild_id, *channel_id))
+            .map(|r| *r)
+            .ok_or(anyhow::anyhow!("無効なVCチャンネル"))?;
+        match self.get_guild_channel(api, vc_channel_id).await {
+            Ok(vc_channel) => Ok(vc_channel),
+            Err(why) => {
+                // VCの削除を取りこぼしていれば、残っている対応を消す (一時的なエラーでは消さない)
+                let not_found = why
+                    .chain()
+                    .filter_map(|e| e.downcast_ref::<serenity::Error>())
+                    .any(is_not_found);
+                if not_found {
+                    let vc_key = (guild_id, vc_channel_id);
+                    let _guard = self.lock_vc(&vc_key).await;
+                    self.forget_vc(&vc_key).await;
+                }
+                Err(why)
+            }
+        }
+    }
+
+    /// VCを操作できるユーザーか判定する
+    /// 記録されたオーナーか、設定されたロールを持つユーザーか、チャンネルの管理権限を持つユーザー
+    /// 操作できない場合は、理由を伝えるメッセージのキーを返す
+    async fn check_manage_vc(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+        user_id: UserId,
+        roles: &[RoleId],
+    ) -> Result<(), &'static str> {
+        // VCを操作できるロールを持っているかどうか
+        if roles
+            .iter()
+            .any(|role| self.app_config().discord.control_roles.contains(role))
+        {
+            return Ok(());
+        }
+
+        // チャンネルの管理権限を持っているかどうか
+        match api.user_permissions(vc_channel, user_id) {
+            Ok(vc_permission) if vc_permission.manage_channels() => return Ok(()),
+            Ok(_) => {}
+            Err(why) => {
+                error!("VCチャンネルのパーミッション取得に失敗: {:?}", why);
+            }
+        }
+
+        // 記録されたオーナーかどうか
+        if self
+            .vc_owner
+            .get(&(vc_channel.guild_id, vc_channel.id))
+            .map(|r| *r)
+            != Some(user_id)
+        {
+            return Err("error_not_owner");
+        }
+
+        // 設定されていれば、VCから抜けたオーナーには操作させない
+        if self.app_config().discord.require_in_vc_for_controls {
+            let in_vc = api.user_voice_channel(vc_channel.guild_id, user_id) == Some(vc_channel.id);
+            if !in_vc {
+                return Err("error_control_not_in_vc");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// VC名前変更時にスレッドをリネームする
+    async fn button_pressed(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &MessageComponentInteraction,
+    ) -> Result<()> {
+        // VCチャンネルを取得
+        let vc_channel = match self
+            .get_vc(api, interaction.guild_id, &interaction.channel_id)
+            .await
+        {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "error_vc_closed", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+
+        // VCの権限をチェック
+        if let Err(key) = self
+            .check_manage_vc(
+                api,
+                &vc_channel,
+                interaction.user.id,
+                member_roles(&interaction.member),
+            )
+            .await
+        {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(interaction.guild_id, key, &[]),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+
+        // モーダルダイアログを開く
+        api.create_interaction_response(interaction.id, &interaction.token, |r| {
+            r.kind(InteractionResponseType::Modal)
+                .interaction_response_data(|d| {
+                    d.custom_id("rename_title");
+                    d.title(self.guild_text(interaction.guild_id, "rename_modal_title", &[]));
+                    d.components(|c| {
+                        c.create_action_row(|f| {
+                            f.create_input_text(|t| {
+                                t.custom_id("rename_text");
+                                t.label(self.guild_text(
+                                    interaction.guild_id,
+                                    "rename_modal_label",
+                                    &[],
+                                ));
+                                t.placeholder(self.guild_text(
+                                    interaction.guild_id,
+                                    "rename_modal_placeholder",
+                                    &[],
+                                ));
+                                t.style(InputTextStyle::Short);
+                                t
+                            });
+                            f
+                        });
+                        c.create_action_row(|f| {
+                            f.create_input_text(|t| {
+                                t.custom_id("rename_status");
+                                t.label(self.guild_text(
+                                    interaction.guild_id,
+                                    "rename_modal_status_label",
+                                    &[],
+                                ));
+                                t.placeholder(self.guild_text(
+                                    interaction.guild_id,
+                                    "rename_modal_status_placeholder",
+                                    &[],
+                                ));
+                                t.style(InputTextStyle::Short);
+                                // ステータスは空欄でもよい
+                                t.required(false);
+                                t
+                            });
+                            f
+                        });
+                        c
+                    });
+                    d
+                });
+            r
+        })
+        .await
+        .context("ダイアログの作成に失敗")?;
+
+        Ok(())
+    }
+
+    /// オーナーになるボタンが押された時
+    async fn claim_owner(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &MessageComponentInteraction,
+    ) -> Result<()> {
+        // VCチャンネルを取得
+        let vc_channel = match self
+            .get_vc(api, interaction.guild_id, &interaction.channel_id)
+            .await
+        {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "error_vc_closed", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+
+        // オーナーがVCに残っていればオーナーにはなれない
+        let vc_key = (vc_channel.guild_id, vc_channel.id);
+        let owner = self.vc_owner.get(&vc_key).map(|r| *r);
+        let claimed = match owner {
+            Some(owner) if self.is_in_vc(api, &vc_channel, &owner) => false,
+            _ => {
+                self.vc_owner.insert(vc_key, interaction.user.id);
+                true
+            }
+        };
+
+        // 返答
+        let content = if claimed {
+            self.guild_text(
+                interaction.guild_id,
+                "claim_done",
+                &[("user", &interaction.user.mention().to_string())],
+            )
+        } else {
+            self.guild_text(interaction.guild_id, "error_owner_exists", &[])
+        };
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// 人数制限ボタンが押された時
+    async fn limit_pressed(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &MessageComponentInteraction,
+    ) -> Result<()> {
+        // VCチャンネルを取得
+        let vc_channel = match self
+            .get_vc(api, interaction.guild_id, &interaction.channel_id)
+            .await
+        {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "error_vc_closed", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+
+        // VCの権限をチェック
+        if let Err(key) = self
+            .check_manage_vc(
+                api,
+                &vc_channel,
+                interaction.user.id,
+                member_roles(&interaction.member),
+            )
+            .await
+        {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(interaction.guild_id, key, &[]),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+
+        // モーダルダイアログを開く
+        api.create_interaction_response(interaction.id, &interaction.token, |r| {
+            r.kind(InteractionResponseType::Modal)
+                .interaction_response_data(|d| {
+                    d.custom_id("limit_title");
+                    d.title(self.guild_text(interaction.guild_id, "limit_modal_title", &[]));
+                    d.components(|c| {
+                        c.create_action_row(|f| {
+                            f.create_input_text(|t| {
+                                t.custom_id("limit_text");
+                                t.label(self.guild_text(
+                                    interaction.guild_id,
+                                    "limit_modal_label",
+                                    &[],
+                                ));
+                                t.placeholder(self.guild_text(
+                                    interaction.guild_id,
+                                    "limit_modal_placeholder",
+                                    &[],
+                                ));
+                                t.style(InputTextStyle::Short);
+                                t.max_length(2);
+                                t
+                            });
+                            f
+                        });
+                        c
+                    });
+                    d
+                });
+            r
+        })
+        .await
+        .context("ダイアログの作成に失敗")?;
+
+        Ok(())
+    }
+
+    /// 人数制限ダイアログが送信された時にVCの参加人数の上限を変更する
+    async fn set_user_limit(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ModalSubmitInteraction,
+    ) -> Result<()> {
+        // VCチャンネルを取得
+        let vc_channel = match self
+            .get_vc(api, interaction.guild_id, &interaction.channel_id)
+            .await
+        {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "error_vc_closed", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+
+        // VCの権限をチェック
+        if let Err(key) = self
+            .check_manage_vc(
+                api,
+                &vc_channel,
+                interaction.user.id,
+                member_roles(&interaction.member),
+            )
+            .await
+        {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(interaction.guild_id, key, &[]),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+
+        // 入力された人数を取得 (0は上限なし、Discordで設定できるのは99人まで)
+        let user_limit = interaction
+            .data
+            .components
+            .iter()
+            .flat_map(|c| c.components.iter())
+            .find_map(|c| match c {
+                ActionRowComponent::InputText(t) if t.custom_id == "limit_text" => {
+                    t.value.trim().parse::<u64>().ok()
+                }
+                _ => None,
+            })
+            .filter(|n| *n <= 99);
+        let user_limit = match user_limit {
+            Some(user_limit) => user_limit,
+            None => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "error_invalid_limit", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+
+        // VCの参加人数の上限を変更
+        retry(
+            "VCの参加人数の上限の変更",
+            API_RETRY_ATTEMPTS,
+            || {
+                api.edit_channel(vc_channel.id, |c| {
+                    c.user_limit(user_limit);
+                    c
+                })
+            },
+        )
+        .await
+        .context("VCの参加人数の上限の変更に失敗")?;
+
+        // スレッドにお知らせ
+        let user = interaction.user.mention().to_string();
+        let limit = user_limit.to_string();
+        let (notice_key, done_key) = if user_limit == 0 {
+            ("limit_removed_notice", "limit_removed")
+        } else {
+            ("limit_notice", "limit_done")
+        };
+        let notice = self.guild_text(
+            interaction.guild_id,
+            notice_key,
+            &[("user", &user), ("limit", &limit)],
+        );
+        if let Err(why) = api
+            .send_message_with(interaction.channel_id, |m| {
+                m.content(notice);
+                m.allowed_mentions(|m| m.empty_users());
+                m
+            })
+            .await
+        {
+            error!("人数制限のお知らせの投稿に失敗: {:?}", why);
+        }
+
+        // 返答
+        respond_ephemeral(
+            api,
+            interaction,
+            self.guild_text(interaction.guild_id, done_key, &[("limit", &limit)]),
+        )
+        .await
+        .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// ロック/ロック解除ボタンが押された時
+    async fn lock_pressed(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &MessageComponentInteraction,
+        locked: bool,
+    ) -> Result<()> {
+        // ロック/ロック解除
+        let content = self
+            .set_vc_locked(
+                api,
+                interaction.guild_id,
+                &interaction.channel_id,
+                interaction.user.id,
+                member_roles(&interaction.member),
+                locked,
+            )
+            .await;
+
+        // 返答
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// VCをロック (参加人数の上限を今の人数にする) またはロック解除 (上限なしに戻す) し、スレッドにお知らせする
+    /// ボタンとコマンドで共通の処理で、応答する内容を返す
+    async fn set_vc_locked(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: Option<GuildId>,
+        thread_id: &ChannelId,
+        user_id: UserId,
+        roles: &[RoleId],
+        locked: bool,
+    ) -> String {
+        // VCチャンネルを取得
+        let vc_channel = match self.get_vc(api, guild_id, thread_id).await {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => return self.guild_text(guild_id, "error_vc_closed", &[]),
+        };
+
+        // VCの権限をチェック
+        if let Err(key) = self.check_manage_vc(api, &vc_channel, user_id, roles).await {
+            return self.guild_text(guild_id, key, &[]);
+        }
+
+        // 参加人数の上限 (0は上限なし、Discordで設定できるのは99人まで)
+        let user_limit = if locked {
+            self.count_vc_members(api, &vc_channel)
+                .unwrap_or(0)
+                .clamp(1, 99) as u64
+        } else {
+            0
+        };
+        if let Err(why) = retry(
+            "VCの参加人数の上限の変更",
+            API_RETRY_ATTEMPTS,
+            || {
+                api.edit_channel(vc_channel.id, |c| {
+                    c.user_limit(user_limit);
+                    c
+                })
+            },
+        )
+        .await
+        {
+            error!("VCの参加人数の上限の変更に失敗: {:?}", why);
+            return self.guild_text(guild_id, "error_lock_failed", &[]);
+        }
+
+        // スレッドにお知らせ
+        let user = user_id.mention().to_string();
+        let notice = if locked {
+            self.guild_text(
+                guild_id,
+                "lock_notice",
+                &[("user", &user), ("limit", &user_limit.to_string())],
+            )
+        } else {
+            self.guild_text(guild_id, "unlock_notice", &[("user", &user)])
+        };
+        if let Err(why) = api
+            .send_message_with(*thread_id, |m| {
+                m.content(notice);
+                m.allowed_mentions(|m| m.empty_users());
+                m
+            })
+            .await
+        {
+            error!("ロックのお知らせの投稿に失敗: {:?}", why);
+        }
+
+        let key = if locked { "lock_done" } else { "unlock_done" };
+        self.guild_text(guild_id, key, &[])
+    }
+
+    /// 名前変更ダイアログの入力でVCの名前を変更する
+    async fn rename_vc(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ModalSubmitInteraction,
+    ) -> Result<()> {
+        // 入力欄の値を取得
+        let input_value = |custom_id: &str| {
+            interaction
+                .data
+                .components
+                .iter()
+                .flat_map(|c| c.components.iter())
+                .find_map(|c| match c {
+                    ActionRowComponent::InputText(t) if t.custom_id == custom_id => {
+                        Some(t.value.clone())
+                    }
+                    _ => None,
+                })
+        };
+        // VCの名前を取得
+        let name =
+            input_value("rename_text").ok_or(anyhow::anyhow!("コンポーネントが見つかりません"))?;
+        // ステータスを取得 (空欄の場合は投稿しない)
+        let status = input_value("rename_status")
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+
+        let pending = PendingRename {
+            thread_id: interaction.channel_id,
+            user_id: interaction.user.id,
+            name,
+            status,
+            requested_at: Instant::now(),
+        };
+        self.request_rename(
+            api,
+            interaction,
+            interaction.id.0,
+            interaction.guild_id,
+            member_roles(&interaction.member),
+            pending,
+        )
+        .await
+    }
+
+    /// /rename コマンドでVCの名前を変更する
+    /// スレッド内ではそのスレッドのVC、それ以外では参加中のVCを操作する
+    async fn command_rename(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // 操作するVCのスレッドを取得
+        let thread_id = interaction.guild_id.and_then(|guild_id| {
+            if self
+                .thread_to_vc
+                .contains_key(&(guild_id, interaction.channel_id))
+            {
+                Some(interaction.channel_id)
+            } else {
+                self.user_vc_thread(api, guild_id, &interaction.user.id)
+            }
+        });
+        let thread_id = match thread_id {
+            Some(thread_id) => thread_id,
+            None => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "error_not_in_vc", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+
+        // オプションの名前を取得
+        let name = interaction
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "name")
+            .and_then(|o| match &o.resolved {
+                Some(CommandDataOptionValue::String(name)) => Some(name.clone()),
+                _ => None,
+            })
+            .ok_or(anyhow::anyhow!("オプションが見つかりません: name"))?;
+
+        let pending = PendingRename {
+            thread_id,
+            user_id: interaction.user.id,
+            name,
+            status: None,
+            requested_at: Instant::now(),
+        };
+        self.request_rename(
+            api,
+            interaction,
+            interaction.id.0,
+            interaction.guild_id,
+            member_roles(&interaction.member),
+            pending,
+        )
+        .await
+    }
+
+    /// 名前の変更を受け付ける (名前変更ダイアログと /rename で共通)
+    /// VCと権限と名前を確認し、設定されていれば確認のボタンで、そうでなければすぐに名前を変更して応答する
+    async fn request_rename(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &(impl EphemeralResponse + DeferredResponse + Sync),
+        interaction_id: u64,
+        guild_id: Option<GuildId>,
+        roles: &[RoleId],
+        pending: PendingRename,
+    ) -> Result<()> {
+        // VCチャンネルを取得
+        let vc_channel = match self.get_vc(api, guild_id, &pending.thread_id).await {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(guild_id, "error_vc_closed", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+
+        // VCの権限をチェック
+        if let Err(key) = self
+            .check_manage_vc(api, &vc_channel, pending.user_id, roles)
+            .await
+        {
+            return respond_ephemeral(api, interaction, self.guild_text(guild_id, key, &[]))
+                .await
+                .context("エラー内容の応答に失敗");
+        }
+
+        // チャンネル名として使えるように整える (空の場合は変更しない)
+        let name = match sanitize_channel_name(&pending.name) {
+            Some(name) => name,
+            None => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(guild_id, "error_empty_name", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+        let pending = PendingRename { name, ..pending };
+
+        // 設定されていれば、確認のボタンを押してから名前を変更する
+        if self.app_config().discord.confirm_rename {
+            return self
+                .confirm_rename(api, interaction, interaction_id, guild_id, pending)
+                .await;
+        }
+        self.apply_rename(api, interaction, &vc_channel, pending)
+            .await
+    }
+
+    /// 変更後の名前を表示し、確認・キャンセルのボタンで応答する
+    async fn confirm_rename(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &(impl EphemeralResponse + Sync),
+        interaction_id: u64,
+        guild_id: Option<GuildId>,
+        pending: PendingRename,
+    ) -> Result<()> {
+        // 押されなかった確認は、インタラクションの期限が切れた後に破棄する
+        self.pending_renames
+            .retain(|_, p| p.requested_at.elapsed() < PENDING_RENAME_TTL);
+
+        let content = self.guild_text(guild_id, "rename_confirm", &[("name", &pending.name)]);
+        // ボタンのIDで保留中の名前の変更を区別する (名前はIDの文字数制限に収まらないことがある)
+        let pending_id = interaction_id;
+        self.pending_renames.insert(pending_id, pending);
+
+        let mut components = CreateComponents::default();
+        components.create_action_row(|a| {
+            a.create_button(|b| {
+                b.label(self.guild_text(guild_id, "rename_confirm_button", &[]));
+                b.style(ButtonStyle::Primary);
+                b.custom_id(format!("rename_confirm:{}", pending_id));
+                b
+            });
+            a.create_button(|b| {
+                b.label(self.guild_text(guild_id, "rename_cancel_button", &[]));
+                b.style(ButtonStyle::Secondary);
+                b.custom_id(format!("rename_cancel:{}", pending_id));
+                b
+            })
+        });
+        interaction
+            .create_ephemeral_response_with_components(api, content, components)
+            .await
+            .context("確認の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// 名前の変更の確認・キャンセルのボタンが押された時
+    async fn rename_confirm_pressed(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &MessageComponentInteraction,
+        pending_id: u64,
+        confirmed: bool,
+    ) -> Result<()> {
+        // 保留中の名前の変更を取り出す (押せるのは一度だけ)
+        let pending = match self.pending_renames.remove(&pending_id) {
+            Some((_, pending)) if pending.user_id == interaction.user.id => pending,
+            Some((_, pending)) => {
+                // 他の人のボタンであれば戻しておく
+                self.pending_renames.insert(pending_id, pending);
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "error_not_owner", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗");
+            }
+            None => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "rename_expired", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+
+        // キャンセルされた場合は、確認のメッセージを置き換えて終了
+        if !confirmed {
+            return api
+                .create_interaction_response(interaction.id, &interaction.token, |r| {
+                    r.kind(InteractionResponseType::UpdateMessage)
+                        .interaction_response_data(|d| {
+                            d.content(self.guild_text(
+                                interaction.guild_id,
+                                "rename_cancelled",
+                                &[],
+                            ));
+                            d.components(|c| c);
+                            d
+                        })
+                })
+                .await
+                .context("結果の応答に失敗");
+        }
+
+        // 確認している間にVCが解散した場合や、権限がなくなった場合もあるのでもう一度確認する
+        let vc_channel = match self
+            .get_vc(api, interaction.guild_id, &pending.thread_id)
+            .await
+        {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => {
+                return respond_ephemeral(
+                    api,
+                    interaction,
+                    self.guild_text(interaction.guild_id, "error_vc_closed", &[]),
+                )
+                .await
+                .context("エラー内容の応答に失敗")
+            }
+        };
+        if let Err(key) = self
+            .check_manage_vc(
+                api,
+                &vc_channel,
+                interaction.user.id,
+                member_roles(&interaction.member),
+            )
+            .await
+        {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(interaction.guild_id, key, &[]),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+
+        self.apply_rename(api, interaction, &vc_channel, pending)
+            .await
+    }
+
+    /// VCの名前を変更して結果を応答し、ステータスが入力されていればスレッドに投稿する
+    async fn apply_rename(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &(impl DeferredResponse + Sync),
+        vc_channel: &GuildChannel,
+        pending: PendingRename,
+    ) -> Result<()> {
+        let PendingRename {
+            thread_id,
+            user_id,
+            name,
+            status,
+            ..
+        } = pending;
+
+        // 名前の変更は3秒の応答期限を過ぎることがあるので、先に応答を保留する
+        // 他の人に「考え中」の表示が見えないよう、本人にだけ見える応答にする
+        interaction
+            .defer_ephemeral_response(api)
+            .await
+            .context("応答の保留に失敗")?;
+
+        // 名前の変更を知らせる時のために、変更したユーザーを覚えておく
+        let vc_key = (vc_channel.guild_id, vc_channel.id);
+        self.bot_renames
+            .insert(vc_key, (name.clone(), Some(user_id)));
+
+        // VCの名前を変更 (レート制限にかかる場合は後で反映される)
+        let future = api.rename_channel(&self.rename_queue, vc_channel.id, name.clone());
+        // レート制限の記録外でDiscordのレートリミットがかかると遅いので、タイムアウトする
+        let result = match timeout(RENAME_TIMEOUT, future).await {
+            Ok(result) => result,
+            Err(_) => {
+                return interaction
+                    .edit_deferred_response(
+                        api,
+                        self.guild_text(Some(vc_channel.guild_id), "rename_rate_limited", &[]),
+                    )
+                    .await
+                    .context("エラー内容の応答に失敗")
+            }
+        };
+        // 権限などで失敗した場合はエラーを返す
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(why) => {
+                self.bot_renames.remove(&vc_key);
+                if let Err(why) = interaction
+                    .edit_deferred_response(
+                        api,
+                        self.guild_text(Some(vc_channel.guild_id), "error_rename_failed", &[]),
+                    )
+                    .await
+                {
+                    error!("エラー内容の応答に失敗: {:?}", why);
+                }
+                return Err(why).context("VCの名前変更に失敗");
+            }
+        };
+
+        // 返答
+        // レート制限で後回しにした名前の変更は、まだ反映されていないので数えない
+        let user = user_id.mention().to_string();
+        let content = match outcome {
+            RenameOutcome::Renamed => {
+                self.renames_applied.fetch_add(1, Ordering::Relaxed);
+                self.guild_text(Some(vc_channel.guild_id), "rename_done", &[("user", &user)])
+            }
+            RenameOutcome::Deferred(wait) => {
+                // 分単位に切り上げる
+                let minutes = wait.as_secs().div_ceil(60).max(1).to_string();
+                self.guild_message(
+                    &vc_channel.guild_id,
+                    "rename_deferred",
+                    &[("user", &user), ("minutes", &minutes)],
+                )
+            }
+        };
+        interaction
+            .edit_deferred_response(api, content)
+            .await
+            .context("結果の応答に失敗")?;
+
+        // 設定されていれば、変更前後の名前をスレッドに記録する
+        if self.app_config().discord.rename_log && vc_channel.name != name {
+            let log = self.guild_message(
+                &vc_channel.guild_id,
+                "rename_log",
+                &[("user", &user), ("old", &vc_channel.name), ("new", &name)],
+            );
+            if let Err(why) = self.post_rename_log(api, thread_id, log).await {
+                error!("名前の変更の記録の投稿に失敗: {:?}", why);
+            }
+        }
+
+        // ステータスが入力されていればスレッドに投稿
+        if let Some(status) = status {
+            api.send_message_with(thread_id, |m| {
+                m.content(self.guild_message(
+                    &vc_channel.guild_id,
+                    "status_posted",
+                    &[("user", &user), ("status", &status)],
+                ));
+                m.allowed_mentions(|m| m.empty_users());
+                m
+            })
+            .await
+            .context("ステータスの投稿に失敗")?;
+        }
+
+        Ok(())
+    }
+
+    /// 名前の変更の記録をスレッドに投稿する
+    async fn post_rename_log(
+        &self,
+        api: &impl DiscordApi,
+        thread_id: ChannelId,
+        log: String,
+    ) -> Result<()> {
+        api.send_message_with(thread_id, |m| {
+            m.content(log);
+            m.allowed_mentions(|m| m.empty_users());
+            m
+        })
+        .await
+        .context("名前の変更の記録の投稿に失敗")?;
+        Ok(())
+    }
+
+    /// /thread コマンドで参加中のVCのスレッドを案内する
+    async fn command_thread(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // コマンドを実行したユーザーが参加しているVCのスレッドを取得
+        let thread_id = interaction
+            .guild_id
+            .and_then(|guild_id| self.user_vc_thread(api, guild_id, &interaction.user.id));
+
+        // 返答
+        let content = match thread_id {
+            Some(thread_id) => self.guild_text(
+                interaction.guild_id,
+                "thread_guide",
+                &[("channel", &thread_id.mention().to_string())],
+            ),
+            None => self.guild_text(interaction.guild_id, "error_not_in_vc", &[]),
+        };
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("スレッドの案内の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /help コマンドでBotの使い方を表示する
+    async fn command_help(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        respond_ephemeral(
+            api,
+            interaction,
+            self.guild_text(interaction.guild_id, "help", &[]),
+        )
+        .await
+        .context("使い方の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /voicetime コマンドでユーザーがカスタムVCにいた合計時間を表示する (ユーザーの指定がなければ自分)
+    async fn command_voicetime(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // サーバー外では使えない
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("サーバー外のチャンネル"))?;
+        // オプションのユーザーを取得
+        let user_id = interaction
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "user")
+            .and_then(|o| match &o.resolved {
+                Some(CommandDataOptionValue::User(user, _)) => Some(user.id),
+                _ => None,
+            })
+            .unwrap_or(interaction.user.id);
+
+        let content = self.guild_message(
+            &guild_id,
+            "voicetime",
+            &[
+                ("user", &user_id.mention().to_string()),
+                ("duration", &self.voice_time(guild_id, user_id).hhmmss()),
+            ],
+        );
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("通話時間の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /members コマンドでVCに参加しているメンバーを表示する
+    /// スレッド内ではそのスレッドのVC、それ以外では参加中のVCを表示する
+    async fn command_members(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // 表示するVCを取得
+        let vc_channel = match interaction.guild_id {
+            Some(guild_id) => {
+                let thread_id = if self
+                    .thread_to_vc
+                    .contains_key(&(guild_id, interaction.channel_id))
+                {
+                    Some(interaction.channel_id)
+                } else {
+                    self.user_vc_thread(api, guild_id, &interaction.user.id)
+                };
+                match thread_id {
+                    Some(thread_id) => self.get_vc(api, Some(guild_id), &thread_id).await.ok(),
+                    None => None,
+                }
+            }
+            None => None,
+        };
+
+        // 返答
+        let content = match vc_channel {
+            Some(vc_channel) => {
+                // キャッシュにメンバーがいなければメンションで表示する
+                let names = self
+                    .vc_member_ids(api, &vc_channel)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|user_id| {
+                        api.cached_member(vc_channel.guild_id, user_id)
+                            .map(|member| member.display_name().to_string())
+                            .unwrap_or(user_id.mention().to_string())
+                    })
+                    .collect::<Vec<_>>();
+                let channel = vc_channel.id.mention().to_string();
+                if names.is_empty() {
+                    self.guild_message(
+                        &vc_channel.guild_id,
+                        "members_empty",
+                        &[("channel", &channel)],
+                    )
+                } else {
+                    self.guild_message(
+                        &vc_channel.guild_id,
+                        "members",
+                        &[
+                            ("channel", &channel),
+                            ("count", &names.len().to_string()),
+                            ("members", &names.join("\n")),
+                        ],
+                    )
+                }
+            }
+            None => self.guild_text(interaction.guild_id, "error_not_in_vc", &[]),
+        };
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("メンバー一覧の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /lock, /unlock コマンドでVCをロック/ロック解除する
+    /// スレッド内ではそのスレッドのVC、それ以外では参加中のVCを操作する
+    async fn command_lock(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+        locked: bool,
+    ) -> Result<()> {
+        // 操作するVCのスレッドを取得
+        let thread_id = interaction.guild_id.and_then(|guild_id| {
+            if self
+                .thread_to_vc
+                .contains_key(&(guild_id, interaction.channel_id))
+            {
+                Some(interaction.channel_id)
+            } else {
+                self.user_vc_thread(api, guild_id, &interaction.user.id)
+            }
+        });
+
+        // 返答
+        let content = match thread_id {
+            Some(thread_id) => {
+                self.set_vc_locked(
+                    api,
+                    interaction.guild_id,
+                    &thread_id,
+                    interaction.user.id,
+                    member_roles(&interaction.member),
+                    locked,
+                )
+                .await
+            }
+            None => self.guild_text(interaction.guild_id, "error_not_in_vc", &[]),
+        };
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /transfer コマンドでVCのオーナーを他のメンバーに譲る
+    /// スレッド内ではそのスレッドのVC、それ以外では参加中のVCを操作する
+    async fn command_transfer(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // 操作するVCのスレッドを取得
+        let thread_id = interaction.guild_id.and_then(|guild_id| {
+            if self
+                .thread_to_vc
+                .contains_key(&(guild_id, interaction.channel_id))
+            {
+                Some(interaction.channel_id)
+            } else {
+                self.user_vc_thread(api, guild_id, &interaction.user.id)
+            }
+        });
+        // オプションのユーザーを取得
+        let new_owner = interaction
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "user")
+            .and_then(|o| match &o.resolved {
+                Some(CommandDataOptionValue::User(user, _)) => Some(user.id),
+                _ => None,
+            })
+            .ok_or(anyhow::anyhow!("オプションが見つかりません: user"))?;
+
+        // 返答
+        let content = match thread_id {
+            Some(thread_id) => {
+                self.transfer_requested(
+                    api,
+                    interaction.guild_id,
+                    &thread_id,
+                    interaction.user.id,
+                    member_roles(&interaction.member),
+                    new_owner,
+                )
+                .await
+            }
+            None => self.guild_text(interaction.guild_id, "error_not_in_vc", &[]),
+        };
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// VCのオーナーを、VCに参加している指定のメンバーに譲る (結果の返答を返す)
+    async fn transfer_requested(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: Option<GuildId>,
+        thread_id: &ChannelId,
+        user_id: UserId,
+        roles: &[RoleId],
+        new_owner: UserId,
+    ) -> String {
+        // VCチャンネルを取得
+        let vc_channel = match self.get_vc(api, guild_id, thread_id).await {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => return self.guild_text(guild_id, "error_vc_closed", &[]),
+        };
+
+        // VCの権限をチェック
+        if let Err(key) = self.check_manage_vc(api, &vc_channel, user_id, roles).await {
+            return self.guild_text(guild_id, key, &[]);
+        }
+
+        // VCにいないメンバーには譲れない
+        let members = self.vc_member_ids(api, &vc_channel).unwrap_or_default();
+        if !members.contains(&new_owner) {
+            return self.guild_text(guild_id, "error_transfer_not_in_vc", &[]);
+        }
+
+        // オーナーを更新 (オーナーの記録がなければ操作したユーザーから譲ったことにする)
+        let vc_key = (vc_channel.guild_id, vc_channel.id);
+        let old_owner = self.vc_owner.insert(vc_key, new_owner).unwrap_or(user_id);
+
+        // スレッドにお知らせ
+        let notice = self.guild_text(
+            guild_id,
+            "transfer_notice",
+            &[
+                ("old", &old_owner.mention().to_string()),
+                ("new", &new_owner.mention().to_string()),
+            ],
+        );
+        if let Err(why) = api
+            .send_message_with(*thread_id, |m| {
+                m.content(notice);
+                m.allowed_mentions(|m| m.empty_users());
+                m
+            })
+            .await
+        {
+            error!("オーナーの譲渡のお知らせの投稿に失敗: {:?}", why);
+        }
+
+        self.guild_text(
+            guild_id,
+            "transfer_done",
+            &[("user", &new_owner.mention().to_string())],
+        )
+    }
+
+    /// /archive コマンドで、参加中のVCのスレッドをオーナーが手動で閉じる
+    /// 他のメンバーがまだVCにいる場合は、確認のボタンを押してから閉じる
+    async fn command_archive(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // 他のメンバーがいなければすぐに閉じる
+        let content = match self
+            .archive_requested(
+                api,
+                interaction.guild_id,
+                interaction.user.id,
+                member_roles(&interaction.member),
+                false,
+            )
+            .await
+        {
+            Ok(content) => content,
+            Err(others) => {
+                // 確認のボタンを表示する
+                api.create_interaction_response(interaction.id, &interaction.token, |r| {
+                    r.kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|d| {
+                            d.content(self.guild_text(
+                                interaction.guild_id,
+                                "archive_confirm",
+                                &[("count", &others.to_string())],
+                            ));
+                            d.ephemeral(true);
+                            d.components(|c| {
+                                c.create_action_row(|a| {
+                                    a.create_button(|b| {
+                                        b.label(self.guild_text(
+                                            interaction.guild_id,
+                                            "archive_confirm_button",
+                                            &[],
+                                        ));
+                                        b.style(ButtonStyle::Danger);
+                                        b.custom_id("archive_confirm");
+                                        b
+                                    })
+                                })
+                            });
+                            d
+                        })
+                })
+                .await
+                .context("確認の応答に失敗")?;
+                return Ok(());
+            }
+        };
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /archive の確認のボタンが押された時にスレッドを閉じる
+    async fn archive_confirmed(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &MessageComponentInteraction,
+    ) -> Result<()> {
+        // 確認している間にVCを移動した場合もあるので、もう一度参加中のVCを調べる
+        let content = self
+            .archive_requested(
+                api,
+                interaction.guild_id,
+                interaction.user.id,
+                member_roles(&interaction.member),
+                true,
+            )
+            .await
+            .unwrap_or_default();
+
+        // 確認のメッセージを結果に置き換える (ボタンは消す)
+        api.create_interaction_response(interaction.id, &interaction.token, |r| {
+            r.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|d| {
+                    d.content(content);
+                    d.components(|c| c);
+                    d
+                })
+        })
+        .await
+        .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// ユーザーが参加しているVCのスレッドを閉じ、応答する内容を返す
+    /// 確認前に他のメンバーがVCにいる場合は、閉じずにその人数をErrで返す
+    async fn archive_requested(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: Option<GuildId>,
+        user_id: UserId,
+        roles: &[RoleId],
+        confirmed: bool,
+    ) -> std::result::Result<String, usize> {
+        // 参加中のVCのスレッドを取得
+        let thread_id =
+            match guild_id.and_then(|guild_id| self.user_vc_thread(api, guild_id, &user_id)) {
+                Some(thread_id) => thread_id,
+                None => return Ok(self.guild_text(guild_id, "error_not_in_vc", &[])),
+            };
+
+        // VCチャンネルを取得
+        let vc_channel = match self.get_vc(api, guild_id, &thread_id).await {
+            Ok(vc_channel) => vc_channel,
+            Err(_) => return Ok(self.guild_text(guild_id, "error_vc_closed", &[])),
+        };
+
+        // VCの権限をチェック
+        if let Err(key) = self.check_manage_vc(api, &vc_channel, user_id, roles).await {
+            return Ok(self.guild_text(guild_id, key, &[]));
+        }
+
+        // VCのチャットはアーカイブできない
+        if thread_id == vc_channel.id {
+            return Ok(self.guild_text(guild_id, "error_archive_vc_chat", &[]));
+        }
+
+        // 自分以外のメンバーがいる場合は確認する
+        let others = self
+            .vc_member_ids(api, &vc_channel)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|id| *id != user_id)
+            .count();
+        if others > 0 && !confirmed {
+            return Err(others);
+        }
+
+        // 参加・解散の処理と同時に動かないようにする
+        let _guard = self.lock_vc(&(vc_channel.guild_id, vc_channel.id)).await;
+
+        // スレッドにお知らせ (アーカイブ後は投稿すると戻ってしまうので先に行う)
+        let notice = self.guild_message(
+            &vc_channel.guild_id,
+            "archive_notice",
+            &[("user", &user_id.mention().to_string())],
+        );
+        if let Err(why) = api
+            .send_message_with(thread_id, |m| {
+                m.content(notice);
+                m.allowed_mentions(|m| m.empty_users());
+                m
+            })
+            .await
+        {
+            error!("スレッドを閉じるお知らせの投稿に失敗: {:?}", why);
+        }
+
+        // アーカイブ後は編集できないので、先にボタンを押せないようにする
+        if let Err(why) = self
+            .set_welcome_buttons_disabled(api, vc_channel.guild_id, &thread_id, true)
+            .await
+        {
+            error!("参加メッセージのボタンの無効化に失敗: {:?}", why);
+        }
+
+        // スレッドをアーカイブ (VCの対応は残すので、次に参加した時に戻る)
+        if let Err(why) = api.set_thread_archived(thread_id, true).await {
+            error!("VCスレッドチャンネルのアーカイブに失敗: {:?}", why);
+            return Ok(self.guild_text(guild_id, "error_archive_failed", &[]));
+        }
+        self.threads_archived.fetch_add(1, Ordering::Relaxed);
+
+        Ok(self.guild_text(guild_id, "archive_done", &[]))
+    }
+
+    /// ユーザーが参加しているVCのスレッドを取得する (カスタムVCでなければ見つからない)
+    fn user_vc_thread(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        user_id: &UserId,
+    ) -> Option<ChannelId> {
+        // ユーザーが参加しているVCを取得
+        let vc_channel_id = api.user_voice_channel(guild_id, *user_id)?;
+        // VCに対応するスレッドを取得
+        self.vc_to_thread
+            .get(&(guild_id, vc_channel_id))
+            .map(|r| *r)
+    }
+
+    /// /relink コマンドで、VCとスレッドを手動で対応付ける
+    /// イベントの取りこぼしなどで対応がずれた時の修復用
+    async fn command_relink(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // サーバー外では使えない
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("サーバー外のチャンネル"))?;
+
+        // オプションのチャンネルを取得
+        let option_channel = |name: &str| {
+            interaction
+                .data
+                .options
+                .iter()
+                .find(|o| o.name == name)
+                .and_then(|o| match &o.resolved {
+                    Some(CommandDataOptionValue::Channel(channel)) => Some(channel.id),
+                    _ => None,
+                })
+                .ok_or(anyhow::anyhow!("オプションが見つかりません: {}", name))
+        };
+        let vc_channel = self.get_guild_channel(api, option_channel("vc")?).await?;
+        let thread = self
+            .get_guild_channel(api, option_channel("thread")?)
+            .await?;
+
+        // カスタムVCでなければ対応付けない
+        if !self.is_custom_vc(api, &vc_channel) {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(Some(guild_id), "error_relink_not_custom_vc", &[]),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+        // スレッドを作成するチャンネルのスレッドでなければ対応付けない
+        let thread_channel = self.thread_channel(&guild_id)?;
+        if thread.parent_id != Some(thread_channel) {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(
+                    Some(guild_id),
+                    "error_relink_not_thread",
+                    &[("channel", &thread_channel.mention().to_string())],
+                ),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+
+        {
+            // 参加・解散の処理と同時に動かないようにする
+            let _vc_lock = self.lock_vc(&(guild_id, vc_channel.id)).await;
+
+            // VCとスレッドそれぞれの古い対応を解除してから対応付ける
+            self.unlink(guild_id, &vc_channel.id).await;
+            let old_vc = self.thread_to_vc.get(&(guild_id, thread.id)).map(|r| *r);
+            if let Some(old_vc) = old_vc {
+                self.unlink(guild_id, &old_vc).await;
+            }
+            self.link(guild_id, vc_channel.id, thread.id).await;
+        }
+
+        // 返答
+        respond_ephemeral(
+            api,
+            interaction,
+            self.guild_text(
+                Some(guild_id),
+                "relink_done",
+                &[
+                    ("channel", &vc_channel.mention().to_string()),
+                    ("thread", &thread.mention().to_string()),
+                ],
+            ),
+        )
+        .await
+        .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /stats コマンドでBotの動作状況を表示する
+    async fn command_stats(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // 返答
+        let content = self.guild_text(
+            interaction.guild_id,
+            "stats",
+            &[
+                (
+                    "created",
+                    &self.threads_created.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "archived",
+                    &self.threads_archived.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "renamed",
+                    &self.renames_applied.load(Ordering::Relaxed).to_string(),
+                ),
+                ("links", &self.vc_to_thread.len().to_string()),
+                (
+                    "evicted",
+                    &self.links_evicted.load(Ordering::Relaxed).to_string(),
+                ),
+            ],
+        );
+        respond_ephemeral(api, interaction, content)
+            .await
+            .context("動作状況の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /dump コマンドで、このサーバーのVC⇔スレッドの対応とオーナーをJSONファイルで送る
+    /// サーバーのログを見られなくても、スレッドが作成されない原因などを調べられるようにする
+    async fn command_dump(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // サーバー外では使えない
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("サーバー外のチャンネル"))?;
+
+        // コマンドを表示する権限はサーバー側で変更できるため、サーバーの管理権限を改めて確認する
+        if !can_manage_guild(&interaction.member) {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(Some(guild_id), "error_no_manage_guild", &[]),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+
+        // 他のサーバーの対応は含めない
+        let mut dump = serde_json::to_value(self.snapshot_state(guild_id))
+            .context("対応表のシリアライズに失敗")?;
+        dump["guild_id"] = serde_json::json!(guild_id.to_string());
+        let json = serde_json::to_vec_pretty(&dump).context("対応表のシリアライズに失敗")?;
+
+        // 返答
+        let content = self.guild_text(Some(guild_id), "dump_done", &[]);
+        api.create_interaction_response(interaction.id, &interaction.token, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| {
+                    d.content(content);
+                    d.add_file(AttachmentType::Bytes {
+                        data: json.into(),
+                        filename: format!("dump-{}.json", guild_id),
+                    });
+                    d.ephemeral(true);
+                    d
+                });
+            r
+        })
+        .await
+        .context("対応表の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// /migrate コマンドで、このサーバーのスレッドを使っているVCをVCのチャットに移す
+    /// 移し終えたVCは飛ばすので、途中で失敗しても実行し直せる
+    async fn command_migrate(
+        &self,
+        api: &impl DiscordApi,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<()> {
+        // サーバー外では使えない
+        let guild_id = interaction
+            .guild_id
+            .ok_or(anyhow::anyhow!("サーバー外のチャンネル"))?;
+
+        // コマンドを表示する権限はサーバー側で変更できるため、サーバーの管理権限を改めて確認する
+        if !can_manage_guild(&interaction.member) {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(Some(guild_id), "error_no_manage_guild", &[]),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+        // 移した後に作成されるスレッドと混ざらないよう、VCのチャットを使う設定の時のみ移す
+        if self.app_config().discord.chat_mode != ChatMode::VcText {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(Some(guild_id), "error_migrate_not_vc_text", &[]),
+            )
+            .await
+            .context("エラー内容の応答に失敗");
+        }
+        if self.skip_in_dry_run(|| format!("サーバー {} のスレッドをVCのチャットに移す", guild_id))
+        {
+            return respond_ephemeral(
+                api,
+                interaction,
+                self.guild_text(
+                    Some(guild_id),
+                    "migrate_done",
+                    &[("migrated", "0"), ("skipped", "0"), ("failed", "0")],
+                ),
+            )
+            .await
+            .context("結果の応答に失敗");
+        }
+
+        let archive_old = interaction
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "archive_old")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        // VCの数によっては時間がかかるので、先に応答を保留する
+        interaction
+            .defer_ephemeral_response(api)
+            .await
+            .context("応答の保留に失敗")?;
+
+        // このサーバーの対応を集める (マップの参照を持ったまま待機しないよう、先に集める)
+        let vc_channel_ids = self
+            .vc_to_thread
+            .iter()
+            .filter(|entry| entry.key().0 == guild_id)
+            .map(|entry| entry.key().1)
+            .collect::<Vec<_>>();
+
+        let (mut migrated, mut skipped, mut failed) = (0, 0, 0);
+        for vc_channel_id in vc_channel_ids {
+            match self
+                .migrate_to_vc_chat(api, guild_id, vc_channel_id, archive_old)
+                .await
+            {
+                Ok(true) => migrated += 1,
+                Ok(false) => skipped += 1,
+                Err(why) => {
+                    error!("VCのチャットへの移行に失敗: {:?}", why);
+                    failed += 1;
+                }
+            }
+        }
+
+        // 返答
+        interaction
+            .edit_deferred_response(
+                api,
+                self.guild_text(
+                    Some(guild_id),
+                    "migrate_done",
+                    &[
+                        ("migrated", &migrated.to_string()),
+                        ("skipped", &skipped.to_string()),
+                        ("failed", &failed.to_string()),
+                    ],
+                ),
+            )
+            .await
+            .context("結果の応答に失敗")?;
+
+        Ok(())
+    }
+
+    /// 1つのVCを、スレッドからVCのチャットに移す
+    /// 既に移してある (VC自身をスレッドとして登録している) 場合は何もせずfalseを返す
+    async fn migrate_to_vc_chat(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: GuildId,
+        vc_channel_id: ChannelId,
+        archive_old: bool,
+    ) -> Result<bool> {
+        let vc_key = (guild_id, vc_channel_id);
+        // 参加・解散の処理と同時に動かないようにする
+        let _guard = self.lock_vc(&vc_key).await;
+
+        // ロックを待つ間に解除・移行された場合は何もしない
+        let old_thread_id = match self.vc_to_thread.get(&vc_key).map(|r| *r) {
+            Some(thread_id) if thread_id != vc_channel_id => thread_id,
+            _ => return Ok(false),
+        };
+        let vc_channel = self.get_guild_channel(api, vc_channel_id).await?;
+
+        // VCのチャットに参加メッセージを投稿する (移すだけなので通知はしない)
+        let members = self.vc_member_ids(api, &vc_channel).unwrap_or_default();
+        let member_mentions = members
+            .iter()
+            .map(|m| m.mention().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let channel_name = self
+            .channel_name(api, vc_channel_id)
+            .await
+            .unwrap_or(self.unknown_channel_name(&guild_id));
+        let welcome_text = self.guild_message(
+            &guild_id,
+            "welcome",
+            &[
+                ("user", &member_mentions),
+                ("channel", &channel_name),
+                ("thread", &vc_channel_id.mention().to_string()),
+            ],
+        );
+        let owner = self
+            .vc_owner
+            .get(&vc_key)
+            .map(|r| *r)
+            .or_else(|| members.first().copied())
+            .unwrap_or_default();
+        let welcome = self
+            .send_welcome_message(
+                api,
+                guild_id,
+                vc_channel_id,
+                &owner,
+                &channel_name,
+                &member_mentions,
+                &welcome_text,
+                None,
+            )
+            .await?;
+
+        // VCのチャットの案内メッセージは古いスレッドを指しているので削除する
+        if let Some(guide_message_id) = self.vc_to_guide_message.get(&vc_key).map(|r| *r) {
+            if let Err(why) = api.delete_message(vc_channel_id, guide_message_id).await {
+                warn!("古いスレッドへの案内メッセージの削除に失敗: {:?}", why);
+            }
+        }
+
+        // 古いスレッドのボタンは押せないようにして、移ったことを知らせる
+        if let Err(why) = self
+            .set_welcome_buttons_disabled(api, guild_id, &old_thread_id, true)
+            .await
+        {
+            error!("参加メッセージのボタンの無効化に失敗: {:?}", why);
+        }
+        let notice = self.guild_text(
+            Some(guild_id),
+            "migrate_notice",
+            &[("channel", &vc_channel_id.mention().to_string())],
+        );
+        if let Err(why) = api
+            .send_message_with(old_thread_id, |m| self.message_body(m, notice))
+            .await
+        {
+            error!("VCのチャットに移ったお知らせの投稿に失敗: {:?}", why);
+        }
+        if archive_old {
+            if let Err(why) = api.set_thread_archived(old_thread_id, true).await {
+                error!("古いスレッドのアーカイブに失敗: {:?}", why);
+            } else {
+                self.threads_archived.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // VC自身をスレッドとして対応付け直す (把握しているメンバーは引き継ぐ)
+        let thread_members = self
+            .thread_members
+            .get(&(guild_id, old_thread_id))
+            .map(|r| r.clone());
+        self.unlink(guild_id, &vc_channel_id).await;
+        self.link(guild_id, vc_channel_id, vc_channel_id).await;
+        self.thread_to_welcome_message.insert(vc_key, welcome.id);
+        if let Some(thread_members) = thread_members {
+            self.thread_members.insert(vc_key, thread_members);
+        }
+        self.save_links().await;
+
+        // 参加中のメンバーの表示はVCのチャットに投稿し直す
+        if self.app_config().discord.participant_panel {
+            self.request_panel_update(&vc_key);
+        }
+
+        Ok(true)
+    }
+
+    /// スレッドの議題メッセージを後始末する
+    /// 通話の記録があればその時間と参加者、なければスレッドの作成時刻とメンバーをまとめに載せる
+    async fn finalize_agenda_message(
+        &self,
+        api: &impl DiscordApi,
+        thread_key: &ChannelKey,
+        session: Option<&VcSession>,
+    ) -> Result<bool> {
+        let thread_channel_id = &thread_key.1;
+        // 最近のメッセージを取得
+        let messages = api
+            .recent_messages(*thread_channel_id, DISBAND_MESSAGE_CHECK_LIMIT)
+            .await
+            .context("メッセージ取得に失敗")?;
+
+        // チャンネルID->議題メッセージを取得
+        // マップの参照を持ったまま待機しないよう、複製して使う
+        let message = match self.thread_to_agenda_message.get(thread_key) {
+            Some(message) => message.value().clone(),
+            None => return Ok(false),
+        };
+
+        // フォーラムの投稿は議題メッセージが最初のメッセージとしてスレッド内にある
+        let is_forum = self.is_forum(&thread_key.0);
+        // Bot (参加・退出のお知らせ等) のメッセージしかなければスレッドを削除するフラグ
+        // 取得しきれないほどメッセージがある場合は、人間の発言がなくても残す
+        let should_delete_thread = (messages.len() as u64) < DISBAND_MESSAGE_CHECK_LIMIT
+            && messages.iter().all(|m| m.author.bot);
+        // 解散時にスレッドを削除する設定の場合は、まとめが残るように議題メッセージを編集して残す
+        // フォーラムの投稿の最初のメッセージは消すと投稿が崩れるため、常に編集して残す
+        let keep_agenda_message = is_forum
+            || (!should_delete_thread
+                && self.app_config().discord.on_disband == DisbandAction::Delete);
+        // 最近のメッセージに人間のメッセージがなければ議題メッセージを削除するフラグ
+        let should_delete_agenda_message =
+            !keep_agenda_message && !messages.iter().any(|m| !m.author.bot);
+
+        // スレッドを消す予定がない場合は、通話時間等を記録する
+        if !should_delete_thread {
+            // スレッドの名前と作成時刻を取得
+            let (thread_name, thread_created_at) = match api.channel(*thread_channel_id).await? {
+                Channel::Guild(guild_channel) => {
+                    let thread_name = guild_channel.name.clone();
+                    let thread_created_at = guild_channel
+                        .thread_metadata
+                        .and_then(|m| m.create_timestamp);
+                    (thread_name, thread_created_at)
+                }
+                _ => (self.unknown_channel_name(&thread_key.0), None),
+            };
+            // 通話時間を計算
+            let duration = match session {
+                Some(session) => (Utc::now() - session.started_at).hhmmss(),
+                None => thread_created_at
+                    .map(|created_at| {
+                        let created_at = created_at.naive_utc();
+                        let now = Utc::now().naive_utc();
+                        let duration = now - created_at;
+                        duration.hhmmss()
+                    })
+                    .unwrap_or("--:--:--".to_string()),
+            };
+
+            // 参加者を取得 (通話の記録がない場合はスレッドのメンバー)
+            let members = match session {
+                Some(session) => session.participants.clone(),
+                None => {
+                    // Botを取得
+                    let bot = self
+                        .bot_user_id
+                        .lock()
+                        .await
+                        .context("自身のBotユーザーの取得に失敗")?;
+                    api.thread_member_ids(*thread_channel_id)
+                        .await
+                        .context("メンバー取得に失敗")?
+                        .into_iter()
+                        .filter(|m| *m != bot)
+                        .collect()
+                }
+            };
+
+            // 参加者リストを作成
+            let member_mentions = members
+                .iter()
+                .map(|m| m.mention().to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            // 議題メッセージを消すか
+            if should_delete_agenda_message {
+                // 議題メッセージを消す場合は新たに投稿する
+                match api
+                    .send_message_with(*thread_channel_id, |m| {
+                        m.content("");
+                        m.embed(|f| {
+                            f.title(self.guild_message(&thread_key.0, "summary_title", &[]));
+                            f.description(self.guild_message(
+                                &thread_key.0,
+                                "summary_description",
+                                &[("channel", &thread_name)],
+                            ));
+                            f.field(
+                                self.guild_message(&thread_key.0, "summary_duration", &[]),
+                                duration,
+                                true,
+                            );
+                            f.field(
+                                self.guild_message(&thread_key.0, "summary_members", &[]),
+                                member_mentions,
+                                false,
+                            );
+                            f
+                        });
+                        m.allowed_mentions(|m| m.empty_users());
+                        m
+                    })
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(why) => {
+                        // メッセージが編集できなくてもチャンネルをアーカイブしたいので、ログを出力だけしておく
+                        error!("VC解散時に議題メッセージを削除できませんでした: {:?}", why);
+                    }
+                };
+            } else {
+                // 議題メッセージを編集
+                match api
+                    .edit_message(message.channel_id, message.id, |m| {
+                        m.content("");
+                        m.embed(|f| {
+                            f.title(&thread_name);
+                            f.description(self.guild_message(
+                                &thread_key.0,
+                                "summary_description",
+                                &[("channel", &thread_name)],
+                            ));
+                            f.field(
+                                self.guild_message(&thread_key.0, "summary_duration", &[]),
+                                duration,
+                                true,
+                            );
+                            f.field(
+                                self.guild_message(&thread_key.0, "summary_members", &[]),
+                                member_mentions,
+                                false,
+                            );
+                            f
+                        });
+                        m.allowed_mentions(|m| m.empty_users());
+                        m
+                    })
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(why) => {
+                        // メッセージが編集できなくてもチャンネルをアーカイブしたいので、ログを出力だけしておく
+                        error!("VC解散時に議題メッセージを削除できませんでした: {:?}", why);
+                    }
+                };
+            }
+        }
+
+        // 最新の5件に人間のメッセージがなければ議題メッセージを削除
+        if should_delete_agenda_message {
+            // メッセージがあれば議題メッセージを削除
+            match api.delete_message(message.channel_id, message.id).await {
+                Ok(_) => {}
+                Err(why) => {
+                    // メッセージが削除できなくてもチャンネルをアーカイブしたいので、ログを出力だけしておく
+                    error!("VC解散時に議題メッセージを削除できませんでした: {:?}", why);
+                }
+            };
+        }
+
+        Ok(should_delete_thread)
+    }
+
+    /// VC解散時にスレッドをアーカイブ (会話がなければ削除) する
+    /// `rejoinable` がtrue (VCが空になっただけ) の場合は、設定に応じてスレッドの削除を保留する
+    /// 参加の処理と入れ違わないよう、呼び出し元でVCをロックしておく
+    async fn archive_thread(
+        &self,
+        api: &impl DiscordApi,
+        vc_key: &ChannelKey,
+        rejoinable: bool,
+    ) -> Result<()> {
+        let (guild_id, vc_channel_id) = vc_key;
+        // マップからスレッドのチャンネルIDを取得
+        let thread_channel_id = self.vc_to_thread.get(vc_key).map(|r| *r);
+
+        // チャンネルIDが見つけれなければ終了
+        let thread_channel_id = match thread_channel_id {
+            Some(channel_id) => channel_id,
+            None => return Ok(()),
+        };
+        let thread_key = (*guild_id, thread_channel_id);
+
+        // VCのチャットを使っている場合は、VCをアーカイブ・削除せずに対応を解除する
+        if thread_channel_id == *vc_channel_id {
+            self.release_vc_chat(api, vc_key).await;
+            return Ok(());
+        }
+
+        // 既にアーカイブされているか確認 (自動でアーカイブされた後にVCが削除された場合など)
+        let archived = match api.channel(thread_channel_id).await {
+            Ok(channel) => channel
+                .guild()
+                .and_then(|c| c.thread_metadata)
+                .is_some_and(|m| m.archived),
+            // スレッドが既に削除されていれば対応を解除するだけ
+            Err(why) if is_not_found(&why) => {
+                self.unlink(*guild_id, vc_channel_id).await;
+                return Ok(());
+            }
+            Err(why) => return Err(why).context("スレッドの取得に失敗"),
+        };
+        if archived {
+            // 後始末済み (まとめを書く議題メッセージがない) なら、もう一度アーカイブする必要はない
+            // 削除を保留している場合は、改めて削除するか判断する
+            if !self.thread_to_agenda_message.contains_key(&thread_key)
+                && !self.vc_archived_at.contains_key(vc_key)
+            {
+                return Ok(());
+            }
+            // アーカイブ中はまとめを書き込めないため、一旦アーカイブを解除する (最後にアーカイブし直す)
+            api.set_thread_archived(thread_channel_id, false)
+                .await
+                .context("スレッドのアーカイブ解除に失敗")?;
+        }
+
+        // 通話の記録を取り出す (解散したので記録は不要になる)
+        let session = self.vc_sessions.remove(vc_key).map(|(_, session)| session);
+
+        // VCで誰も喋ってなかったら議題メッセージを削除
+        let should_delete = match self
+            .finalize_agenda_message(api, &thread_key, session.as_ref())
+            .await
+        {
+            Ok(del) => del,
+            Err(why) => {
+                error!(
+                    "VCチャンネルで会話がなかったが、議題メッセージ削除に失敗: {:?}",
+                    why
+                );
+                false
+            }
+        };
+
+        // VCが解散したので退出時刻の記録は不要
+        self.vc_leave_times.remove(vc_key);
+
+        // 2件以上のメッセージがなければスレッドを削除する (設定によっては常に削除する)
+        // フォーラムの投稿はまとめが投稿内に残るため、会話があれば削除する設定でもアーカイブする
+        let delete_on_disband = self.app_config().discord.on_disband == DisbandAction::Delete
+            && !self.is_forum(guild_id);
+        let delete = should_delete || delete_on_disband;
+        // すぐに再参加された時にスレッドを作り直して案内し直さないよう、設定された時間は削除せずアーカイブしておく
+        let defer_delete =
+            delete && rejoinable && self.app_config().discord.recreate_cooldown_secs > 0;
+
+        // 議題メッセージは後始末済みなので、次回の解散時には触らない
+        // まとめを書かずに削除を保留した場合は、再参加後の解散でまとめられるよう残す
+        if !(should_delete && defer_delete) {
+            self.thread_to_agenda_message.remove(&thread_key);
+        }
+
+        if delete && !defer_delete {
+            // VCスレッドチャンネルを削除
+            api.delete_channel(thread_channel_id)
+                .await
+                .context("VCスレッドチャンネルの削除に失敗")?;
+            // スレッドが無くなったので対応を解除
+            self.unlink(*guild_id, vc_channel_id).await;
+        } else {
+            // 参加中のメンバーの表示を解散にする (アーカイブ後は編集できないので先に行う)
+            if self.app_config().discord.participant_panel {
+                self.panels_outdated.remove(vc_key);
+                if let Err(why) = self.update_panel(api, vc_key, true).await {
+                    error!("参加中のメンバーの表示の更新に失敗: {:?}", why);
+                }
+            }
+
+            // 解散したVCのボタンは押せないようにする (アーカイブ後は編集できないので先に行う)
+            if let Err(why) = self
+                .set_welcome_buttons_disabled(api, *guild_id, &thread_channel_id, true)
+                .await
+            {
+                error!("参加メッセージのボタンの無効化に失敗: {:?}", why);
+            }
+
+            // VCスレッドチャンネルをアーカイブ
+            api.set_thread_archived(thread_channel_id, true)
+                .await
+                .context("VCスレッドチャンネルのアーカイブに失敗")?;
+
+            self.threads_archived.fetch_add(1, Ordering::Relaxed);
+
+            // 再参加されないまま時間がたったら削除する
+            if defer_delete {
+                self.vc_archived_at.insert(*vc_key, Utc::now());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// VCのチャットを使っているVCが解散した時に、ボタンを押せないようにして対応を解除する
+    /// 次に参加された時は、新しい参加メッセージを投稿する
+    async fn release_vc_chat(&self, api: &impl DiscordApi, vc_key: &ChannelKey) {
+        let (guild_id, vc_channel_id) = vc_key;
+
+        // 通話の記録と退出時刻の記録は不要になる
+        self.vc_sessions.remove(vc_key);
+        self.vc_leave_times.remove(vc_key);
+
+        // 参加中のメンバーの表示を解散にする
+        if self.app_config().discord.participant_panel {
+            self.panels_outdated.remove(vc_key);
+            if let Err(why) = self.update_panel(api, vc_key, true).await {
+                error!("参加中のメンバーの表示の更新に失敗: {:?}", why);
+            }
+        }
+
+        // 解散したVCのボタンは押せないようにする (VCが削除された場合は何もしない)
+        if let Err(why) = self
+            .set_welcome_buttons_disabled(api, *guild_id, vc_channel_id, true)
+            .await
+        {
+            let deleted = why
+                .chain()
+                .filter_map(|e| e.downcast_ref::<serenity::Error>())
+                .any(is_not_found);
+            if !deleted {
+                error!("参加メッセージのボタンの無効化に失敗: {:?}", why);
+            }
+        }
+
+        self.unlink(*guild_id, vc_channel_id).await;
+    }
+
+    /// VCのチャットを使っている対応 (VC自身をスレッドとして登録したもの) かどうか
+    #[cfg(feature = "webhook")]
+    fn is_vc_chat(&self, guild_id: GuildId, thread_id: ChannelId) -> bool {
+        self.thread_to_vc.get(&(guild_id, thread_id)).map(|r| *r) == Some(thread_id)
+    }
+
+    /// カスタムVCでなくなった時 (削除・カテゴリ外への移動) にスレッドを後始末し、VCの記録を削除する
+    async fn disband_vc(&self, api: &impl DiscordApi, vc_channel: &GuildChannel) {
+        let vc_key = (vc_channel.guild_id, vc_channel.id);
+        // 参加・退出の処理と同時に動かないようにする
+        let _guard = self.lock_vc(&vc_key).await;
+
+        // VCスレッドチャンネルを後始末
+        if !self.skip_in_dry_run(|| format!("VC「{}」のスレッドを後始末", vc_channel.name))
+        {
+            if let Err(why) = self.archive_thread(api, &vc_key, false).await {
+                self.report_error(
+                    api,
+                    Some(vc_key.0),
+                    "VCスレッドチャンネルの後始末に失敗",
+                    &why,
+                )
+                .await;
+            }
+        }
+
+        // カスタムVCではなくなったので対応と記録を削除
+        self.forget_vc(&vc_key).await;
+    }
+
+    /// VCの対応と、オーナーや参加者の記録を削除する
+    async fn forget_vc(&self, vc_key: &ChannelKey) {
+        let (guild_id, vc_channel_id) = vc_key;
+        self.unlink(*guild_id, vc_channel_id).await;
+
+        self.vc_owner.remove(vc_key);
+        self.vc_join_times.remove(vc_key);
+        self.vc_leave_times.remove(vc_key);
+        self.vc_archived_at.remove(vc_key);
+        self.finish_vc_voice_times(vc_key).await;
+        self.rename_queue.forget(vc_channel_id).await;
+        self.vc_sessions.remove(vc_key);
+        self.channel_names.remove(vc_channel_id);
+        self.bot_renames.remove(vc_key);
+    }
+
+    /// カスタムVCになった時 (カテゴリ内への移動) に、参加中のメンバーがいればスレッドを作成する
+    async fn adopt_vc(&self, api: &impl DiscordApi, vc_channel: &GuildChannel) -> Result<()> {
+        // 参加中のメンバーから最も早く参加したメンバーを選ぶ (誰もいなければ次の参加時に作成する)
+        let members = self.vc_member_ids(api, vc_channel).unwrap_or_default();
+        let vc_key = (vc_channel.guild_id, vc_channel.id);
+        let user_id = match self.earliest_joined(&vc_key, &members).await {
+            Some(user_id) => user_id,
+            None => return Ok(()),
+        };
+
+        // 参加メッセージのメンションのためにメンバーを取得
+        let member = api
+            .member(vc_channel.guild_id, user_id)
+            .await
+            .context("メンバーの取得に失敗")?;
+
+        // 参加した時と同じようにスレッドを作成
+        self.create_or_mention_thread(api, vc_channel, &member)
+            .await
+    }
+
+    /// サーバーのチャンネルの更新を処理する (VCの名前の変更/カテゴリの移動)
+    async fn update_channel(
+        &self,
+        api: &impl DiscordApi,
+        old_channel: Option<GuildChannel>,
+        vc_channel: GuildChannel,
+    ) {
+        // 覚えているチャンネル名を更新
+        if self.channel_names.contains_key(&vc_channel.id) {
+            self.channel_names
+                .insert(vc_channel.id, vc_channel.name.clone());
+        }
+
+        let was_custom_vc = old_channel.as_ref().map(|c| self.is_custom_vc(api, c));
+
+        // カテゴリ外に移動された場合はスレッドを後始末する
+        if !self.is_custom_vc(api, &vc_channel) {
+            if was_custom_vc == Some(true) {
+                self.disband_vc(api, &vc_channel).await;
+            }
+            return;
+        }
+
+        // カテゴリ内に移動された場合は新しいカスタムVCとして扱う
+        if was_custom_vc == Some(false) {
+            if let Err(why) = self.adopt_vc(api, &vc_channel).await {
+                self.report_error(
+                    api,
+                    Some(vc_channel.guild_id),
+                    "VCスレッドチャンネルの作成に失敗",
+                    &why,
+                )
+                .await;
+            }
+            return;
+        }
+
+        // 名前が変わっていない場合 (権限の変更など) は無視
+        // 変更前のチャンネルがキャッシュに無い場合は判断できないのでリネームする
+        if let Some(old_channel) = &old_channel {
+            if old_channel.name == vc_channel.name {
+                return;
+            }
+        }
+
+        // VCスレッドチャンネルをリネーム (解散の処理と入れ違わないようにする)
+        let vc_key = (vc_channel.guild_id, vc_channel.id);
+        let _guard = self.lock_vc(&vc_key).await;
+        match self.rename_thread(api, &vc_channel).await {
+            Ok(_) => {}
+            Err(why) => {
+                self.report_error(
+                    api,
+                    Some(vc_channel.guild_id),
+                    "VCスレッドチャンネルのリネームに失敗",
+                    &why,
+                )
+                .await;
+            }
+        }
+
+        // Botが変更した名前であれば、変更したユーザーを取り出す
+        let bot_rename = self
+            .bot_renames
+            .remove(&vc_key)
+            .map(|(_, rename)| rename)
+            .filter(|(name, _)| *name == vc_channel.name);
+
+        // 設定されていれば、名前が変わったことをスレッドに知らせる
+        // 名前が変わったか判断できない場合と、変更の記録を投稿済みの場合は知らせない
+        let app_config = self.app_config();
+        let logged = bot_rename.is_some() && app_config.discord.rename_log;
+        if app_config.discord.rename_announce && old_channel.is_some() && !logged {
+            let user_id = bot_rename.and_then(|(_, user_id)| user_id);
+            if let Err(why) = self.announce_rename(api, &vc_channel, user_id).await {
+                error!("名前の変更の案内の投稿に失敗: {:?}", why);
+            }
+        }
+    }
+
+    /// インタラクション (ボタン・コマンド・ダイアログの送信) を処理する
+    async fn handle_interaction(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: Option<GuildId>,
+        interaction: Interaction,
+    ) {
+        // 不明なインタラクションは無視
+        match interaction {
+            Interaction::MessageComponent(interaction)
+                if interaction.data.custom_id == "rename_button" =>
+            {
+                // 名前変更チェック&反応
+                match self.button_pressed(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::MessageComponent(interaction)
+                if interaction.data.custom_id.starts_with("rename_confirm:")
+                    || interaction.data.custom_id.starts_with("rename_cancel:") =>
+            {
+                // ボタンのIDから保留中の名前の変更を探す
+                let (action, pending_id) = match interaction.data.custom_id.split_once(':') {
+                    Some((action, pending_id)) => (action, pending_id.parse().unwrap_or(0)),
+                    None => return,
+                };
+                let confirmed = action == "rename_confirm";
+                match self
+                    .rename_confirm_pressed(api, &interaction, pending_id, confirmed)
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::MessageComponent(interaction)
+                if interaction.data.custom_id == "claim_button" =>
+            {
+                // オーナーがいなければオーナーになる
+                match self.claim_owner(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::MessageComponent(interaction)
+                if interaction.data.custom_id == "lock_button"
+                    || interaction.data.custom_id == "unlock_button" =>
+            {
+                // VCをロック/ロック解除
+                let locked = interaction.data.custom_id == "lock_button";
+                match self.lock_pressed(api, &interaction, locked).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::MessageComponent(interaction)
+                if interaction.data.custom_id == "limit_button" =>
+            {
+                // 人数制限ダイアログを開く
+                match self.limit_pressed(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::MessageComponent(interaction)
+                if interaction.data.custom_id == "archive_confirm" =>
+            {
+                // 確認できたのでスレッドを閉じる
+                match self.archive_confirmed(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "archive" => {
+                // 参加中のVCのスレッドを閉じる
+                match self.command_archive(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction)
+                if interaction.data.name == "lock" || interaction.data.name == "unlock" =>
+            {
+                // VCをロック/ロック解除
+                let locked = interaction.data.name == "lock";
+                match self.command_lock(api, &interaction, locked).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "rename" => {
+                // VCの名前を変更
+                match self.command_rename(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "thread" => {
+                // 参加中のVCのスレッドを案内
+                match self.command_thread(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "members" => {
+                // VCに参加しているメンバーを表示
+                match self.command_members(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "transfer" => {
+                // VCのオーナーを譲る
+                match self.command_transfer(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction)
+                if interaction.data.name == "voicetime" =>
+            {
+                // VCにいた合計時間を表示
+                match self.command_voicetime(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "help" => {
+                // Botの使い方を表示
+                match self.command_help(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "relink" => {
+                // VCとスレッドを手動で対応付ける
+                match self.command_relink(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "migrate" => {
+                // スレッドを使っているVCをVCのチャットに移す
+                match self.command_migrate(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "dump" => {
+                // 対応表をJSONファイルで送る
+                match self.command_dump(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ApplicationCommand(interaction) if interaction.data.name == "stats" => {
+                // Botの動作状況を表示
+                match self.command_stats(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ModalSubmit(interaction)
+                if interaction.data.custom_id == "rename_title" =>
+            {
+                // テキスト入力があったらVC名前変更
+                match self.rename_vc(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            Interaction::ModalSubmit(interaction)
+                if interaction.data.custom_id == "limit_title" =>
+            {
+                // 入力された人数をVCの参加人数の上限にする
+                match self.set_user_limit(api, &interaction).await {
+                    Ok(_) => {}
+                    Err(why) => {
+                        self.report_error(api, guild_id, "インタラクションの処理に失敗", &why)
+                            .await;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// ボイスステートの更新を処理する (VCへの参加/退出/移動)
+    async fn update_voice_state(
+        &self,
+        api: &impl DiscordApi,
+        guild_id: Option<GuildId>,
+        user_id: UserId,
+        old_channel_id: Option<ChannelId>,
+        new_channel_id: Option<ChannelId>,
+        member: Option<Member>,
+    ) {
+        // 同じVCのままの場合はミュートなどの状態変更なので無視
+        if old_channel_id.is_some() && old_channel_id == new_channel_id {
+            return;
+        }
+
+        // VCの移動は「元のVCからの退出」と「移動先のVCへの参加」として扱う
+        // カスタムVCとそれ以外のVCの間の移動では、カスタムVC側の処理のみ行う
+
+        // 退出したVC (別のVCへの移動も含む)
+        let left_channel_id = old_channel_id.filter(|&id| Some(id) != new_channel_id);
+        if let Some(left_channel_id) = left_channel_id {
+            // VCにいた時間を記録 (VCが削除されていても記録する)
+            if let Some(guild_id) = guild_id {
+                self.finish_voice_time(guild_id, user_id, left_channel_id)
+                    .await;
+            }
+
+            // チャンネルを取得 (VC削除による退出の場合は取得できないので無視)
+            if let Ok(left_channel) = self.get_guild_channel(api, left_channel_id).await {
+                // カスタムVCの場合のみ退出処理
+                if self.is_custom_vc(api, &left_channel) {
+                    self.leave_vc(api, &left_channel, &user_id).await;
+                    self.request_panel_update(&(left_channel.guild_id, left_channel.id));
+                }
+            }
+        }
+
+        // チャンネルIDが存在しない場合 (退出) は無視
+        if let Some(vc_channel_id) = new_channel_id {
+            // チャンネルを取得
+            let vc_channel = match self.get_guild_channel(api, vc_channel_id).await {
+                Ok(channel) => channel,
+                Err(why) => {
+                    self.report_error(api, guild_id, "チャンネルの取得に失敗", &why)
+                        .await;
+                    return;
+                }
+            };
+
+            // カスタムVCでない場合は無視
+            if !self.is_custom_vc(api, &vc_channel) {
+                return;
+            }
+
+            // メンバー情報が含まれていない場合はユーザーIDから取得する
+            let member = match member {
+                Some(member) => member,
+                None => match api.member(vc_channel.guild_id, user_id).await {
+                    Ok(member) => member,
+                    Err(why) => {
+                        self.report_error(
+                            api,
+                            Some(vc_channel.guild_id),
+                            "VCに参加したメンバーの取得に失敗",
+                            &why,
+                        )
+                        .await;
+                        return;
+                    }
+                },
+            };
+
+            // Botの参加ではスレッドを作成しない (人間が参加した時に作成する)
+            if self.app_config().discord.ignore_bots && member.user.bot {
+                return;
+            }
+
+            // 参加時刻を記録
+            self.vc_join_times
+                .entry((vc_channel.guild_id, vc_channel_id))
+                .or_default()
+                .insert(member.user.id, Utc::now());
+            self.start_voice_time(vc_channel.guild_id, member.user.id, vc_channel_id);
+            *self
+                .weekly_stats
+                .entry(vc_channel.guild_id)
+                .or_default()
+                .vc_joins
+                .entry(vc_channel.name.clone())
+                .or_default() += 1;
+
+            // VCスレッドチャンネルを作成
+            match self
+                .create_or_mention_thread(api, &vc_channel, &member)
+                .await
+            {
+                Ok(_) => {}
+                Err(why) => {
+                    self.report_error(
+                        api,
+                        Some(vc_channel.guild_id),
+                        "VCスレッドチャンネルの作成/投稿に失敗",
+                        &why,
+                    )
+                    .await;
+                    return;
+                }
+            }
+
+            // 参加中のメンバーの表示を更新
+            self.request_panel_update(&(vc_channel.guild_id, vc_channel.id));
+
+            // スレッドの名前の参加人数を更新
+            if let Err(why) = self.update_thread_member_count(api, &vc_channel).await {
+                self.report_error(
+                    api,
+                    Some(vc_channel.guild_id),
+                    "スレッドの参加人数の更新に失敗",
+                    &why,
+                )
+                .await;
+            }
+        }
+    }
+
+    /// サーバーのチャンネルを取得する
+    /// VCの参加/退出のたびに呼ばれるので、キャッシュにある場合はAPIを呼ばない
+    async fn get_guild_channel(
+        &self,
+        api: &impl DiscordApi,
+        channel_id: ChannelId,
+    ) -> Result<GuildChannel> {
+        api.guild_channel(channel_id).await
+    }
+
+    /// チャンネル名を取得する (キャッシュに無い場合はAPIから取得)
+    /// 名前を変えた時などに何度も取得しないよう、チャンネルの更新を受信するまで覚えておく
+    async fn channel_name(&self, api: &impl DiscordApi, channel_id: ChannelId) -> Option<String> {
+        if let Some(name) = self.channel_names.get(&channel_id) {
+            return Some(name.value().clone());
+        }
+        match self.get_guild_channel(api, channel_id).await {
+            Ok(channel) => {
+                self.channel_names.insert(channel_id, channel.name.clone());
+                Some(channel.name)
+            }
+            Err(why) => {
+                warn!("チャンネル名の取得に失敗: {:?}", why);
+                None
+            }
+        }
+    }
+
+    /// ユーザーがVCに参加しているかキャッシュから判定する
+    fn is_in_vc(&self, api: &impl DiscordApi, vc_channel: &GuildChannel, user_id: &UserId) -> bool {
+        api.voice_channel_members(vc_channel.guild_id, vc_channel.id)
+            .is_some_and(|members| members.contains(user_id))
+    }
+
+    /// VCに残っているメンバー数をキャッシュから数える
+    fn count_vc_members(&self, api: &impl DiscordApi, vc_channel: &GuildChannel) -> Option<usize> {
+        self.vc_member_ids(api, vc_channel)
+            .map(|members| members.len())
+    }
+
+    /// VCに参加しているメンバー (無視するBotを除く) をキャッシュから取得する
+    fn vc_member_ids(
+        &self,
+        api: &impl DiscordApi,
+        vc_channel: &GuildChannel,
+    ) -> Option<Vec<UserId>> {
+        let members = api.voice_channel_members(vc_channel.guild_id, vc_channel.id)?;
+        Some(
+            members
+                .into_iter()
+                .filter(|user_id| !self.is_ignored_user(api, user_id))
+                .collect(),
+        )
+    }
+
+    /// 無視するユーザー (Bot) かどうか判定する
+    fn is_ignored_user(&self, api: &impl DiscordApi, user_id: &UserId) -> bool {
+        self.app_config().discord.ignore_bots
+            && api.cached_user(*user_id).map(|u| u.bot).unwrap_or(false)
+    }
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    /// 準備完了時に呼ばれる
+    async fn ready(&self, ctx: Context, data_about_bot: Ready) {
+        warn!("Bot準備完了: {}", data_about_bot.user.tag());
+
+        // Bot自身のIDを取得
+        *self.bot_user_id.lock().await = Some(data_about_bot.user.id);
+        // 定期処理で使うコンテキストを保存
+        *self.context.lock().await = Some(ctx.clone());
+
+        // スラッシュコマンドを登録
+        if let Err(why) = self.register_commands(&ctx).await {
+            self.report_error(&ctx, None, "スラッシュコマンドの登録に失敗", &why)
+                .await;
+        }
+
+        // 設定されたサーバーごとに初期化 (キャッシュが揃った時の状態合わせは、初期化が終わるまで待つ)
+        let _resync_guard = self.resync_lock.lock().await;
+        for &guild_id in self.app_config().discord.guilds.keys() {
+            // 足りない権限があれば、実際に失敗する前に知らせる
+            match self
+                .missing_permissions(&ctx, guild_id, data_about_bot.user.id)
+                .await
+            {
+                Ok(missing) if missing.is_empty() => {}
+                Ok(missing) => {
+                    let why = anyhow::anyhow!("{}", missing.join("\n"));
+                    self.report_error(
+                        &ctx,
+                        Some(guild_id),
+                        &format!("Botの権限が不足しています (サーバー: {})", guild_id),
+                        &why,
+                    )
+                    .await;
+                }
+                Err(why) => {
+                    self.report_error(&ctx, Some(guild_id), "Botの権限の確認に失敗", &why)
+                        .await
+                }
+            }
+
+            // スレッドを作成するチャンネルの種類 (テキストチャンネルかフォーラムか) を判定
+            if let Err(why) = self.detect_thread_channel_kind(&ctx, guild_id).await {
+                self.report_error(
+                    &ctx,
+                    Some(guild_id),
+                    "スレッドを作成するチャンネルの種類の判定に失敗",
+                    &why,
+                )
+                .await;
+            }
+
+            // Botが停止中に作られたVCとスレッドの対応を復元
+            match self.recover_links(&ctx, guild_id).await {
+                Ok(recovered) => warn!(
+                    "VC⇔スレッドの対応を{}件復元しました (サーバー: {})",
+                    recovered, guild_id
+                ),
+                Err(why) => {
+                    self.report_error(&ctx, Some(guild_id), "VC⇔スレッドの対応の復元に失敗", &why)
+                        .await
+                }
+            }
+        }
+    }
+
+    /// サーバーのキャッシュが揃った時に呼ばれる (再接続して受信し直した場合も呼ばれる)
+    async fn cache_ready(&self, ctx: Context, _guilds: Vec<GuildId>) {
+        let fields = self.log_fields("cache_ready", None, None, None);
+        fields.scope(self.resync(&ctx)).await
+    }
+
+    /// 切断したセッションを再開した時に呼ばれる
+    async fn resume(&self, ctx: Context, _: ResumedEvent) {
+        warn!("Discordへの接続を再開しました");
+        let fields = self.log_fields("resume", None, None, None);
+        fields.scope(self.resync(&ctx)).await
+    }
+
+    /// VCで話すボタンが押された時
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let (guild_id, channel_id, user_id) = match &interaction {
+            Interaction::ApplicationCommand(i) => (i.guild_id, Some(i.channel_id), Some(i.user.id)),
+            Interaction::MessageComponent(i) => (i.guild_id, Some(i.channel_id), Some(i.user.id)),
+            Interaction::ModalSubmit(i) => (i.guild_id, Some(i.channel_id), Some(i.user.id)),
+            _ => (None, None, None),
+        };
+        let fields = self.log_fields("interaction_create", guild_id, channel_id, user_id);
+        fields
+            .scope(async move {
+                // ドライランでは、Discordへの操作をログに出力するだけの実装で処理する
+                if self.app_config().dry_run {
+                    self.handle_interaction(&DryRunApi::new(&ctx), guild_id, interaction)
+                        .await
+                } else {
+                    self.handle_interaction(&ctx, guild_id, interaction).await
+                }
+            })
+            .await
+    }
+
+    /// VC削除時
+    async fn channel_delete(&self, ctx: Context, vc_channel: &GuildChannel) {
+        let fields = self.log_fields(
+            "channel_delete",
+            Some(vc_channel.guild_id),
+            Some(vc_channel.id),
+            None,
+        );
+        fields
+            .scope(async move {
+                // 覚えているチャンネル名を削除
+                self.channel_names.remove(&vc_channel.id);
+
+                // カスタムVCでない場合は無視
+                if !self.is_custom_vc(&ctx, vc_channel) {
+                    return;
+                }
+
+                // VCスレッドチャンネルを後始末
+                self.disband_vc(&ctx, vc_channel).await;
+            })
+            .await
+    }
+
+    /// メッセージ削除時
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        guild_id: Option<GuildId>,
+    ) {
+        let fields = self.log_fields("message_delete", guild_id, Some(channel_id), None);
+        fields
+            .scope(async move {
+                // 設定されていなければ案内メッセージを投稿し直さない
+                if !self.app_config().discord.repost_vc_guide {
+                    return;
+                }
+                let vc_key = match guild_id {
+                    Some(guild_id) => (guild_id, channel_id),
+                    None => return,
+                };
+
+                // 削除されたのが案内メッセージでなければ無視
+                if self.vc_to_guide_message.get(&vc_key).map(|r| *r) != Some(deleted_message_id) {
+                    return;
+                }
+                let thread_id = match self.vc_to_thread.get(&vc_key).map(|r| *r) {
+                    Some(thread_id) => thread_id,
+                    None => return,
+                };
+
+                if self
+                    .skip_in_dry_run(|| format!("VC {} の案内メッセージを投稿し直す", channel_id))
+                {
+                    return;
+                }
+
+                // 案内メッセージを投稿し直す
+                match self
+                    .post_vc_guide(&ctx, vc_key.0, &channel_id, &thread_id)
+                    .await
+                {
+                    Ok(guide) => {
+                        self.vc_to_guide_message.insert(vc_key, guide.id);
+                        self.save_links().await;
+                    }
+                    Err(why) => {
+                        self.report_error(
+                            &ctx,
+                            Some(vc_key.0),
+                            "VCチャットの案内メッセージの再投稿に失敗",
+                            &why,
+                        )
+                        .await
+                    }
+                }
+            })
+            .await
+    }
+
+    /// スレッド削除時
+    async fn thread_delete(&self, _ctx: Context, thread: PartialGuildChannel) {
+        // 覚えているチャンネル名を削除
+        self.channel_names.remove(&thread.id);
+
+        // マップからVCのチャンネルIDを取得
+        let vc_channel_id = self
+            .thread_to_vc
+            .get(&(thread.guild_id, thread.id))
+            .map(|r| *r);
+
+        // VCスレッドが削除された場合は対応を解除 (次にVCに参加した時に新しく作り直す)
+        if let Some(vc_channel_id) = vc_channel_id {
+            self.unlink(thread.guild_id, &vc_channel_id).await;
+        }
+    }
+
+    /// スレッド更新時
+    async fn thread_update(&self, ctx: Context, thread: GuildChannel) {
+        let fields = self.log_fields(
+            "thread_update",
+            Some(thread.guild_id),
+            Some(thread.id),
+            None,
+        );
+        fields
+            .scope(async move {
+                // 覚えているチャンネル名を更新
+                if self.channel_names.contains_key(&thread.id) {
+                    self.channel_names.insert(thread.id, thread.name.clone());
+                }
+
+                // スレッドの名前をVCに反映する設定でなければ無視
+                if !self.app_config().discord.two_way_rename {
+                    return;
+                }
+
+                // マップからVCのチャンネルIDを取得 (VCスレッドでなければ無視)
+                let vc_channel_id = self
+                    .thread_to_vc
+                    .get(&(thread.guild_id, thread.id))
+                    .map(|r| *r);
+                let vc_channel_id = match vc_channel_id {
+                    Some(vc_channel_id) => vc_channel_id,
+                    None => return,
+                };
+
+                // 参加人数やテンプレートで付けた部分を除いた名前をVCに反映する
+                let name = if self.app_config().discord.name_with_count {
+                    strip_member_count(&thread.name)
+                } else {
+                    thread.name.as_str()
+                };
+                let name = strip_name_template(
+                    self.thread_name_template(&thread.guild_id).as_deref(),
+                    name,
+                );
+
+                // 名前が同じ場合 (VC名の反映やアーカイブなど) は無視
+                // 名前を比較しないとVC→スレッド→VCと無限にリネームしてしまう
+                let old_name = self.channel_name(&ctx, vc_channel_id).await;
+                if old_name.as_deref() == Some(name) {
+                    return;
+                }
+
+                if self.skip_in_dry_run(|| format!("VC {} を「{}」にリネーム", vc_channel_id, name))
+                {
+                    return;
+                }
+
+                // VCをリネーム (レート制限にかかる場合は後で反映される)
+                let vc_key = (thread.guild_id, vc_channel_id);
+                self.bot_renames.insert(vc_key, (name.to_string(), None));
+                if let Err(why) = self
+                    .rename_queue
+                    .rename(&ctx.http, vc_channel_id, name.to_string())
+                    .await
+                {
+                    self.bot_renames.remove(&vc_key);
+                    self.report_error(&ctx, Some(thread.guild_id), "VCのリネームに失敗", &why)
+                        .await;
+                    return;
+                }
+
+                // 設定されていれば、変更前後の名前をスレッドに記録する
+                if self.app_config().discord.rename_log {
+                    let old_name = old_name.unwrap_or(self.unknown_channel_name(&thread.guild_id));
+                    let log = self.guild_message(
+                        &thread.guild_id,
+                        "rename_log_thread",
+                        &[("old", &old_name), ("new", name)],
+                    );
+                    if let Err(why) = self.post_rename_log(&ctx, thread.id, log).await {
+                        error!("名前の変更の記録の投稿に失敗: {:?}", why);
+                    }
+                }
+            })
+            .await
+    }
+
+    /// serenityが対応していないイベントの受信時
+    async fn unknown(&self, ctx: Context, name: String, raw: serde_json::Value) {
+        let fields = LogFields {
+            action: "unknown",
+            ..Default::default()
+        };
+        fields
+            .scope(async move {
+                // VCのステータスの変更以外は無視
+                if name != "VOICE_CHANNEL_STATUS_UPDATE"
+                    || !self.app_config().discord.sync_vc_status
+                {
+                    return;
+                }
+
+                // IDは文字列で送られてくる
+                let id = |key: &str| raw[key].as_str().and_then(|id| id.parse::<u64>().ok());
+                let vc_key = match (id("guild_id"), id("id")) {
+                    (Some(guild_id), Some(vc_channel_id)) => {
+                        (GuildId(guild_id), ChannelId(vc_channel_id))
+                    }
+                    _ => {
+                        warn!("VCのステータスの変更イベントを読み込めません: {}", raw);
+                        return;
+                    }
+                };
+                // ステータスが消された場合はnullになる
+                let status = raw["status"].as_str().unwrap_or_default();
+                if self.skip_in_dry_run(|| {
+                    format!("VC {} のステータス「{}」をスレッドに反映", vc_key.1, status)
+                }) {
+                    return;
+                }
+
+                // 参加・解散の処理と同時に動かないようにする
+                let _guard = self.lock_vc(&vc_key).await;
+                if let Err(why) = self.sync_vc_status(&ctx, &vc_key, status).await {
+                    self.report_error(&ctx, Some(vc_key.0), "VCのステータスの反映に失敗", &why)
+                        .await;
+                }
+            })
+            .await
+    }
+
+    /// VC名更新時
+    async fn channel_update(&self, ctx: Context, old: Option<Channel>, new: Channel) {
+        let (guild_id, channel_id) = match &new {
+            Channel::Guild(c) => (Some(c.guild_id), Some(c.id)),
+            _ => (None, None),
+        };
+        let fields = self.log_fields("channel_update", guild_id, channel_id, None);
+        fields
+            .scope(async move {
+                // チャンネルを取得
+                let vc_channel = match new.guild() {
+                    Some(guild) => guild,
+                    None => return,
+                };
+                // 変更前のチャンネル (キャッシュに無い場合はNone)
+                let old_channel = old.and_then(|c| c.guild());
+                self.update_channel(&ctx, old_channel, vc_channel).await;
+            })
+            .await
+    }
+
+    /// VCに参加/退出した時
+    async fn voice_state_update(&self, ctx: Context, old: Option<VoiceState>, new: VoiceState) {
+        let channel_id = new
+            .channel_id
+            .or_else(|| old.as_ref().and_then(|old| old.channel_id));
+        let fields = self.log_fields(
+            "voice_state_update",
+            new.guild_id,
+            channel_id,
+            Some(new.user_id),
+        );
+        let old_channel_id = old.and_then(|old| old.channel_id);
+        fields
+            .scope(async move {
+                // ドライランでは、Discordへの操作をログに出力するだけの実装で処理する
+                if self.app_config().dry_run {
+                    self.update_voice_state(
+                        &DryRunApi::new(&ctx),
+                        new.guild_id,
+                        new.user_id,
+                        old_channel_id,
+                        new.channel_id,
+                        new.member,
+                    )
+                    .await
+                } else {
+                    self.update_voice_state(
+                        &ctx,
+                        new.guild_id,
+                        new.user_id,
+                        old_channel_id,
+                        new.channel_id,
+                        new.member,
+                    )
+                    .await
+                }
+            })
+            .await
+    }
+}
+
+/// インタラクションを実行したメンバーのロール (サーバー外ではロールなし)
+fn member_roles(member: &Option<Member>) -> &[RoleId] {
+    member.as_ref().map_or(&[], |member| &member.roles)
+}
+
+/// インタラクションを実行したメンバーがサーバーの管理権限を持っているかどうか
+fn can_manage_guild(member: &Option<Member>) -> bool {
+    member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.manage_guild())
+}
+
+/// 対応表から指定したサーバーの分を、IDを文字列にして取り出す
+fn guild_entries<V: std::fmt::Display>(
+    map: &DashMap<ChannelKey, V>,
+    guild_id: GuildId,
+) -> BTreeMap<String, String> {
+    map.iter()
+        .filter(|entry| entry.key().0 == guild_id)
+        .map(|entry| (entry.key().1.to_string(), entry.value().to_string()))
+        .collect()
+}
+
+/// チャンネル名から末尾の参加人数表記 (例: "しりとり (3)") を取り除く
+fn strip_member_count(name: &str) -> &str {
+    let trimmed = name.trim_end();
+    let stripped = trimmed
+        .strip_suffix(')')
+        .and_then(|s| s.rsplit_once(" ("))
+        .filter(|(_, count)| !count.is_empty() && count.chars().all(|c| c.is_ascii_digit()))
+        .map(|(base, _)| base);
+    stripped.unwrap_or(trimmed)
+}
+
+/// VCの名前をスレッドの名前のテンプレートに当てはめる (テンプレートがなければVCの名前のまま)
+/// Discordの上限文字数を超える場合は切り詰める
+fn apply_name_template(template: Option<&str>, vc_name: &str) -> String {
+    match template {
+        Some(template) => template
+            .replace("{name}", vc_name)
+            .chars()
+            .take(CHANNEL_NAME_MAX_CHARS)
+            .collect(),
+        None => vc_name.to_string(),
+    }
+}
+
+/// スレッドの名前からテンプレートで付けた前後の部分を取り除く (テンプレートに当てはまらなければそのまま)
+fn strip_name_template<'a>(template: Option<&str>, name: &'a str) -> &'a str {
+    let stripped = template
+        .and_then(|template| template.split_once("{name}"))
+        .and_then(|(prefix, suffix)| name.strip_prefix(prefix)?.strip_suffix(suffix));
+    stripped.unwrap_or(name)
+}
+
+/// 入力された名前をチャンネル名として使えるように整える
+/// 改行を空白に置き換えて前後の空白を取り除き、Discordの上限文字数に切り詰める (空になる場合はNone)
+fn sanitize_channel_name(name: &str) -> Option<String> {
+    let name = name.replace(['\r', '\n'], " ");
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    let name = name
+        .chars()
+        .take(CHANNEL_NAME_MAX_CHARS)
+        .collect::<String>();
+    Some(name.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+    use crate::{discord_api::mock::MockApi, storage::FileStorage};
+
+    const GUILD_ID: GuildId = GuildId(100);
+    const VC_CATEGORY: ChannelId = ChannelId(200);
+    const THREAD_CHANNEL: ChannelId = ChannelId(300);
+    const VC: ChannelId = ChannelId(400);
+    const VC_KEY: ChannelKey = (GUILD_ID, VC);
+    /// カテゴリ内のもう一つのVCと、カテゴリ外のVC
+    const OTHER_VC: ChannelId = ChannelId(401);
+    const PLAIN_VC: ChannelId = ChannelId(402);
+    const ALICE: UserId = UserId(1001);
+    const BOB: UserId = UserId(1002);
+
+    /// テスト用のイベント受信リスナー (`discord` は [discord] に追加する設定)
+    fn handler(discord: &str) -> Handler {
+        handler_with_links(discord, LinkStore::default())
+    }
+
+    /// VC⇔スレッドの対応を読み込んだ状態のイベント受信リスナー
+    /// 状態ファイルはテストごとに別の一時ディレクトリに保存する
+    fn handler_with_links(discord: &str, links: LinkStore) -> Handler {
+        static TEST_COUNT: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "vc-thread-test-{}-{}",
+            std::process::id(),
+            TEST_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = format!(
+            r#"
+            state_file = "{dir}/state.json"
+            voice_time_file = "{dir}/voice_time.json"
+            [discord]
+            join_burst_window_ms = 0
+            [discord.guilds.100]
+            vc_category = "200"
+            thread_channel = "300"
+            "#,
+            dir = dir.display()
+        );
+        let app_config = config::Config::builder()
+            .add_source(config::File::from_str(&base, config::FileFormat::Toml))
+            .add_source(config::File::from_str(
+                &format!("[discord]\n{}", discord),
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .unwrap();
+        let messages = Messages::load("bot", "ja").unwrap();
+        let storage = Arc::new(FileStorage::new(&app_config));
+        Handler::with_state(
+            app_config,
+            messages,
+            storage,
+            links,
+            VoiceTimeStore::default(),
+        )
+    }
+
+    /// VCのカテゴリ・スレッドを作成するチャンネル・カスタムVCがあるサーバー
+    fn discord() -> MockApi {
+        let api = MockApi::default();
+        api.add_channel(GUILD_ID, VC_CATEGORY, ChannelType::Category, "VC", None);
+        api.add_channel(GUILD_ID, THREAD_CHANNEL, ChannelType::Text, "vc-chat", None);
+        api.add_channel(
+            GUILD_ID,
+            VC,
+            ChannelType::Voice,
+            "ゲーム部屋",
+            Some(VC_CATEGORY),
+        );
+        api
+    }
+
+    /// ユーザーをVCに参加させ、参加した時の処理をする
+    async fn join(handler: &Handler, api: &MockApi, user_id: UserId) -> Result<()> {
+        let member = api.add_member(GUILD_ID, user_id, false);
+        api.join(user_id, VC);
+        let vc_channel = api.get_channel(VC).unwrap();
+        handler
+            .create_or_mention_thread(api, &vc_channel, &member)
+            .await
+    }
+
+    /// ユーザーをVCから退出させ、退出した時の処理をする
+    async fn leave(handler: &Handler, api: &MockApi, user_id: UserId) {
+        api.leave(user_id);
+        let vc_channel = api.get_channel(VC).unwrap();
+        handler.leave_vc(api, &vc_channel, &user_id).await;
+    }
+
+    /// VCに対応するスレッド
+    fn thread_of_vc(handler: &Handler) -> Option<ChannelId> {
+        handler.vc_to_thread.get(&VC_KEY).map(|r| *r)
+    }
+
+    #[tokio::test]
+    async fn first_join_creates_and_links_thread() {
+        let handler = handler("");
+        let api = discord();
+
+        join(&handler, &api, ALICE).await.unwrap();
+
+        let thread_id = thread_of_vc(&handler).unwrap();
+        assert_eq!(api.state().threads_created, 1);
+        assert_eq!(
+            handler.thread_to_vc.get(&(GUILD_ID, thread_id)).map(|r| *r),
+            Some(VC)
+        );
+        assert_eq!(handler.vc_owner.get(&VC_KEY).map(|r| *r), Some(ALICE));
+        assert!(handler
+            .thread_to_agenda_message
+            .contains_key(&(GUILD_ID, thread_id)));
+        // 参加メッセージはボタン付きで投稿し、VCのチャットにはスレッドを案内する
+        let welcome = api.posts_in(thread_id);
+        assert_eq!(welcome.len(), 1);
+        assert!(welcome[0].has_components);
+        assert_eq!(
+            handler
+                .thread_to_welcome_message
+                .get(&(GUILD_ID, thread_id))
+                .map(|r| *r),
+            Some(welcome[0].message_id)
+        );
+        assert_eq!(api.posts_in(VC).len(), 1);
+        assert!(handler.vc_to_guide_message.contains_key(&VC_KEY));
+    }
+
+    #[tokio::test]
+    async fn dry_run_join_links_thread_without_posting() {
+        let handler = handler("");
+        let mut app_config = (*handler.app_config()).clone();
+        app_config.dry_run = true;
+        *handler.app_config.write().unwrap() = Arc::new(app_config);
+        let api = discord();
+        let member = api.add_member(GUILD_ID, ALICE, false);
+        api.join(ALICE, VC);
+        let vc_channel = api.get_channel(VC).unwrap();
+
+        handler
+            .create_or_mention_thread(&DryRunApi::new(&api), &vc_channel, &member)
+            .await
+            .unwrap();
+
+        // 参加した時と同じように対応とオーナーを記録するが、Discordには何も作成・投稿しない
+        let thread_id = thread_of_vc(&handler).unwrap();
+        assert_eq!(
+            handler.thread_to_vc.get(&(GUILD_ID, thread_id)).map(|r| *r),
+            Some(VC)
+        );
+        assert_eq!(handler.vc_owner.get(&VC_KEY).map(|r| *r), Some(ALICE));
+        assert_eq!(api.state().threads_created, 0);
+        assert!(api.state().posts.is_empty());
+        assert!(api.get_channel(thread_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn announce_in_other_channel_attaches_thread_to_anchor() {
+        let announce_channel = ChannelId(301);
+        let handler = handler("[discord.guilds.100]\nannounce_channel = \"301\"");
+        let api = discord();
+        api.add_channel(
+            GUILD_ID,
+            announce_channel,
+            ChannelType::Text,
+            "general",
+            None,
+        );
+
+        join(&handler, &api, ALICE).await.unwrap();
+
+        // 告知は告知のチャンネルに、目印はスレッドを作成するチャンネルに投稿する
+        let [announce] = api.posts_in(announce_channel).try_into().unwrap();
+        let [anchor] = api.posts_in(THREAD_CHANNEL).try_into().unwrap();
+        // スレッドは目印のメッセージに紐づけ、告知を議題メッセージにする
+        let thread_id = thread_of_vc(&handler).unwrap();
+        assert_eq!(thread_id, ChannelId(anchor.message_id.0));
+        assert_eq!(
+            handler
+                .thread_to_agenda_message
+                .get(&(GUILD_ID, thread_id))
+                .map(|r| (r.channel_id, r.id)),
+            Some((announce_channel, announce.message_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn join_to_existing_thread_posts_join_message() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+
+        join(&handler, &api, BOB).await.unwrap();
+
+        // スレッドは作り直さず、参加したメンバーだけをメンションする
+        assert_eq!(api.state().threads_created, 1);
+        assert_eq!(thread_of_vc(&handler), Some(thread_id));
+        let posts = api.posts_in(thread_id);
+        assert_eq!(posts.len(), 2);
+        assert!(posts[1].content.contains(&BOB.mention().to_string()));
+        assert!(!posts[1].content.contains(&ALICE.mention().to_string()));
+
+        // 既にスレッドのメンバーであれば、もう一度参加しても知らせない
+        join(&handler, &api, BOB).await.unwrap();
+        assert_eq!(api.posts_in(thread_id).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_joins_create_one_thread() {
+        let handler = handler("");
+        let api = discord();
+        // スレッドの作成を待っている間に、もう一人の参加を処理させる
+        api.state().thread_create_delay = Duration::from_millis(50);
+
+        let (alice, bob) = tokio::join!(join(&handler, &api, ALICE), join(&handler, &api, BOB));
+        alice.unwrap();
+        bob.unwrap();
+
+        assert_eq!(api.state().threads_created, 1);
+        assert_eq!(handler.vc_to_thread.len(), 1);
+        assert_eq!(handler.thread_to_vc.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_joins_and_leaves_keep_maps_consistent() {
+        let handler = Arc::new(handler(""));
+        let api = Arc::new(discord());
+        api.state().thread_create_delay = Duration::from_millis(10);
+        let users = (0..20).map(|i| UserId(3000 + i)).collect::<Vec<_>>();
+
+        // 全員が同時に参加する
+        let tasks = users.iter().map(|&user_id| {
+            let (handler, api) = (handler.clone(), api.clone());
+            let member = api.add_member(GUILD_ID, user_id, false);
+            api.join(user_id, VC);
+            tokio::spawn(async move {
+                handler
+                    .update_voice_state(
+                        &*api,
+                        Some(GUILD_ID),
+                        user_id,
+                        None,
+                        Some(VC),
+                        Some(member),
+                    )
+                    .await
+            })
+        });
+        for task in tasks.collect::<Vec<_>>() {
+            task.await.unwrap();
+        }
+
+        // 半分が同時に退出する
+        let (leaving, staying) = users.split_at(users.len() / 2);
+        let tasks = leaving.iter().map(|&user_id| {
+            let (handler, api) = (handler.clone(), api.clone());
+            api.leave(user_id);
+            tokio::spawn(async move {
+                handler
+                    .update_voice_state(&*api, Some(GUILD_ID), user_id, Some(VC), None, None)
+                    .await
+            })
+        });
+        for task in tasks.collect::<Vec<_>>() {
+            task.await.unwrap();
+        }
+
+        assert_eq!(api.state().threads_created, 1);
+        assert_eq!(handler.vc_to_thread.len(), 1);
+        assert_eq!(handler.thread_to_vc.len(), 1);
+        let mut joined = handler
+            .vc_join_times
+            .get(&VC_KEY)
+            .unwrap()
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        joined.sort();
+        assert_eq!(joined, staying);
+        let owner = handler.vc_owner.get(&VC_KEY).map(|r| *r).unwrap();
+        assert!(staying.contains(&owner));
+    }
+
+    #[tokio::test]
+    async fn guide_failure_still_links_thread() {
+        let handler = handler("");
+        let api = discord();
+        // VCのチャットへの案内の投稿だけ失敗させる
+        api.state().send_errors.insert(VC, 403);
+
+        join(&handler, &api, ALICE).await.unwrap();
+
+        let thread_id = thread_of_vc(&handler).unwrap();
+        assert!(api.posts_in(VC).is_empty());
+        assert_eq!(api.posts_in(thread_id).len(), 1);
+
+        // 次の参加では作成済みのスレッドを使う
+        join(&handler, &api, BOB).await.unwrap();
+        assert_eq!(api.state().threads_created, 1);
+        assert_eq!(thread_of_vc(&handler), Some(thread_id));
+    }
+
+    #[tokio::test]
+    async fn join_during_archive_reopens_thread() {
+        let handler = handler("archive_on_empty = true");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        api.say(thread_id, ALICE, "よろしく");
+        // アーカイブしている間に、次の参加を処理させる
+        api.state().archive_delay = Duration::from_millis(50);
+
+        let ((), bob) = tokio::join!(leave(&handler, &api, ALICE), join(&handler, &api, BOB));
+        bob.unwrap();
+
+        // 参加はアーカイブの後に処理され、同じスレッドを開き直す
+        assert_eq!(api.state().threads_created, 1);
+        assert_eq!(thread_of_vc(&handler), Some(thread_id));
+        assert!(!api.is_archived(thread_id));
+        let posts = api.posts_in(thread_id);
+        assert!(posts
+            .last()
+            .is_some_and(|p| p.content.contains(&BOB.mention().to_string())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_burst_invites_private_thread_members_at_once() {
+        let handler = handler(
+            r#"thread_type = "private"
+            join_burst_window_ms = 500"#,
+        );
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        let fetches = api.state().thread_member_fetches;
+        let posts = api.posts_in(thread_id).len();
+
+        let (carol, dave) = (UserId(1003), UserId(1004));
+        let (bob, carol_joined, dave_joined) = tokio::join!(
+            join(&handler, &api, BOB),
+            join(&handler, &api, carol),
+            join(&handler, &api, dave)
+        );
+        bob.unwrap();
+        carol_joined.unwrap();
+        dave_joined.unwrap();
+
+        // スレッドのメンバーの一覧は1回だけ取得し、参加メッセージも1つにまとめる
+        assert_eq!(api.state().thread_member_fetches, fetches + 1);
+        let new_posts = api.posts_in(thread_id)[posts..].to_vec();
+        assert_eq!(new_posts.len(), 1);
+        for user_id in [BOB, carol, dave] {
+            assert!(new_posts[0]
+                .content
+                .contains(&user_id.mention().to_string()));
+            assert!(api.state().thread_members[&thread_id].contains(&user_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn welcome_failure_posts_fallback_message() {
+        let handler = handler("");
+        let api = discord();
+        // ボタン付きのメッセージだけ投稿に失敗させる
+        api.state().fail_components = true;
+
+        join(&handler, &api, ALICE).await.unwrap();
+
+        // スレッドは登録し、ボタンなしの案内を投稿する
+        let thread_id = thread_of_vc(&handler).unwrap();
+        let posts = api.posts_in(thread_id);
+        let [fallback] = posts.as_slice() else {
+            panic!("参加メッセージの代わりの案内がありません: {:?}", posts);
+        };
+        assert!(!fallback.has_components);
+        assert_eq!(
+            fallback.content,
+            handler.guild_message(
+                &GUILD_ID,
+                "welcome_fallback",
+                &[("user", &ALICE.mention().to_string())]
+            )
+        );
+        assert!(!handler
+            .thread_to_welcome_message
+            .contains_key(&(GUILD_ID, thread_id)));
+    }
+
+    #[tokio::test]
+    async fn prune_vc_locks_keeps_only_held_locks() {
+        let handler = handler("");
+        let other_key = (GUILD_ID, ChannelId(401));
+        let held = handler.lock_vc(&VC_KEY).await;
+        drop(handler.lock_vc(&other_key).await);
+
+        handler.prune_vc_locks();
+
+        assert!(handler.vc_locks.contains_key(&VC_KEY));
+        assert!(!handler.vc_locks.contains_key(&other_key));
+        drop(held);
+        handler.prune_vc_locks();
+        assert!(handler.vc_locks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn owner_leave_posts_leave_message_and_transfers_owner() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        join(&handler, &api, BOB).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+
+        leave(&handler, &api, ALICE).await;
+
+        // 退出メッセージの後に、残っているメンバーにオーナーを引き継いだことを知らせる
+        assert_eq!(handler.vc_owner.get(&VC_KEY).map(|r| *r), Some(BOB));
+        let posts = api.posts_in(thread_id);
+        let [.., leave, owner_changed] = posts.as_slice() else {
+            panic!(
+                "退出メッセージとオーナーの引き継ぎの投稿がありません: {:?}",
+                posts
+            );
+        };
+        assert!(leave.content.contains(&ALICE.mention().to_string()));
+        assert!(owner_changed.content.contains(&BOB.mention().to_string()));
+        // 人が残っているのでアーカイブしない
+        assert!(!api.is_archived(thread_id));
+    }
+
+    #[tokio::test]
+    async fn solo_join_then_leave_deletes_thread() {
+        let handler = handler("archive_on_empty = true");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+
+        leave(&handler, &api, ALICE).await;
+
+        // 参加・退出のメッセージしかないスレッドは残さない
+        assert_eq!(api.state().deleted_channels, vec![thread_id]);
+        assert_eq!(thread_of_vc(&handler), None);
+        assert!(!handler.thread_to_vc.contains_key(&(GUILD_ID, thread_id)));
+    }
+
+    #[tokio::test]
+    async fn join_and_leave_notices_do_not_keep_thread() {
+        let handler = handler("archive_on_empty = true");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        join(&handler, &api, BOB).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+
+        leave(&handler, &api, ALICE).await;
+        leave(&handler, &api, BOB).await;
+
+        // 参加・退出・オーナーの引き継ぎのお知らせが何件あっても、会話がなければ削除する
+        assert!(api.posts_in(thread_id).len() > 3);
+        assert_eq!(api.state().deleted_channels, vec![thread_id]);
+    }
+
+    #[tokio::test]
+    async fn seeded_archived_thread_is_reused_on_join() {
+        let api = discord();
+        let thread_id = ChannelId(500);
+        api.add_channel(
+            GUILD_ID,
+            thread_id,
+            ChannelType::PublicThread,
+            "ゲーム部屋",
+            Some(THREAD_CHANNEL),
+        );
+        api.set_thread_archived(thread_id, true).await.unwrap();
+        let links = LinkStore {
+            links: vec![Link {
+                guild_id: GUILD_ID,
+                vc_channel_id: VC,
+                thread_id,
+                welcome_message_id: None,
+                guide_message_id: None,
+                status_message_id: None,
+                panel_message_id: None,
+            }],
+        };
+        let handler = handler_with_links("", links);
+
+        join(&handler, &api, ALICE).await.unwrap();
+
+        // 読み込んだ対応のスレッドのアーカイブを戻して使う
+        assert_eq!(api.state().threads_created, 0);
+        assert!(!api.is_archived(thread_id));
+        let snapshot = handler.snapshot_state(GUILD_ID);
+        assert_eq!(
+            snapshot.vc_to_thread,
+            BTreeMap::from([(VC.to_string(), thread_id.to_string())])
+        );
+        assert_eq!(
+            snapshot.thread_to_vc,
+            BTreeMap::from([(thread_id.to_string(), VC.to_string())])
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_state_contains_only_the_guild() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        // 他のサーバーの対応
+        let other_guild = GuildId(101);
+        handler
+            .link(other_guild, ChannelId(600), ChannelId(601))
+            .await;
+
+        let snapshot = handler.snapshot_state(GUILD_ID);
+
+        assert_eq!(
+            snapshot,
+            StateSnapshot {
+                vc_to_thread: BTreeMap::from([(VC.to_string(), thread_id.to_string())]),
+                thread_to_vc: BTreeMap::from([(thread_id.to_string(), VC.to_string())]),
+                vc_owner: BTreeMap::from([(VC.to_string(), ALICE.to_string())]),
+            }
+        );
+        assert_eq!(
+            handler.snapshot_state(other_guild).vc_to_thread,
+            BTreeMap::from([("600".to_string(), "601".to_string())])
+        );
+        // 保存した対応から同じ状態を作り直せる
+        let links = LinkStore::load(&handler.app_config().state_file).unwrap();
+        let reloaded = handler_with_links("", links);
+        assert_eq!(
+            reloaded.snapshot_state(GUILD_ID).vc_to_thread,
+            snapshot.vc_to_thread
+        );
+    }
+
+    #[tokio::test]
+    async fn archive_thread_keeps_thread_with_conversation() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        let agenda_id = handler
+            .thread_to_agenda_message
+            .get(&(GUILD_ID, thread_id))
+            .unwrap()
+            .id;
+        api.say(thread_id, ALICE, "よろしく");
+        api.say(thread_id, ALICE, "始めましょう");
+
+        handler.archive_thread(&api, &VC_KEY, false).await.unwrap();
+
+        // 会話があるのでスレッドは削除せずにアーカイブし、議題メッセージにまとめを書く
+        assert!(api.is_archived(thread_id));
+        assert!(api.state().deleted_channels.is_empty());
+        assert!(api.state().edited.contains(&agenda_id));
+        assert!(!handler
+            .thread_to_agenda_message
+            .contains_key(&(GUILD_ID, thread_id)));
+        // 対応は次の参加でアーカイブを戻すために残す
+        assert_eq!(thread_of_vc(&handler), Some(thread_id));
+    }
+
+    #[tokio::test]
+    async fn archive_thread_summarizes_auto_archived_thread() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        let agenda_id = handler
+            .thread_to_agenda_message
+            .get(&(GUILD_ID, thread_id))
+            .unwrap()
+            .id;
+        let welcome_id = api.posts_in(thread_id)[0].message_id;
+        api.say(thread_id, ALICE, "よろしく");
+        // 解散する前に自動でアーカイブされた
+        api.set_thread_archived(thread_id, true).await.unwrap();
+
+        handler.archive_thread(&api, &VC_KEY, false).await.unwrap();
+
+        // アーカイブを一旦解除してまとめを書き、ボタンを無効にしてからアーカイブし直す
+        assert!(api.state().edited.contains(&agenda_id));
+        assert!(api.state().edited.contains(&welcome_id));
+        assert!(api.is_archived(thread_id));
+        assert!(api.state().deleted_channels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn archive_thread_twice_is_noop() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        api.say(thread_id, ALICE, "よろしく");
+        handler.archive_thread(&api, &VC_KEY, false).await.unwrap();
+        let edited = api.state().edited.len();
+        let posts = api.state().posts.len();
+
+        handler.archive_thread(&api, &VC_KEY, false).await.unwrap();
+
+        assert!(api.is_archived(thread_id));
+        assert_eq!(api.state().edited.len(), edited);
+        assert_eq!(api.state().posts.len(), posts);
+        assert!(api.state().deleted_channels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bot_only_vc_does_not_create_thread() {
+        let handler = handler("");
+        let api = discord();
+        let bot = UserId(2001);
+        api.add_member(GUILD_ID, bot, true);
+        api.join(bot, VC);
+
+        // メンバー情報なしで届いた参加もAPIから取得したメンバーで判定する
+        handler
+            .update_voice_state(&api, Some(GUILD_ID), bot, None, Some(VC), None)
+            .await;
+
+        assert_eq!(thread_of_vc(&handler), None);
+        assert_eq!(api.state().threads_created, 0);
+        assert!(api.state().posts.is_empty());
+
+        // 人間が参加した時に作成する
+        let member = api.add_member(GUILD_ID, ALICE, false);
+        api.join(ALICE, VC);
+        handler
+            .update_voice_state(&api, Some(GUILD_ID), ALICE, None, Some(VC), Some(member))
+            .await;
+
+        assert!(thread_of_vc(&handler).is_some());
+        assert_eq!(handler.vc_owner.get(&VC_KEY).map(|r| *r), Some(ALICE));
+    }
+
+    #[tokio::test]
+    async fn moving_vc_across_category_archives_and_adopts_thread() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        api.say(thread_id, ALICE, "よろしく");
+
+        // カテゴリ外に移動するとスレッドをアーカイブして対応を削除する
+        let inside = api.get_channel(VC).unwrap();
+        let outside = api.add_channel(GUILD_ID, VC, ChannelType::Voice, "ゲーム部屋", None);
+        handler
+            .update_channel(&api, Some(inside.clone()), outside.clone())
+            .await;
+
+        assert!(api.is_archived(thread_id));
+        assert_eq!(thread_of_vc(&handler), None);
+        assert!(!handler.vc_owner.contains_key(&VC_KEY));
+
+        // カテゴリ内に戻すと、参加中のメンバーでスレッドを用意する
+        api.add_channel(
+            GUILD_ID,
+            VC,
+            ChannelType::Voice,
+            "ゲーム部屋",
+            Some(VC_CATEGORY),
+        );
+        handler.update_channel(&api, Some(outside), inside).await;
+
+        assert!(thread_of_vc(&handler).is_some());
+        assert_eq!(handler.vc_owner.get(&VC_KEY).map(|r| *r), Some(ALICE));
+    }
+
+    /// VCを移動した時のボイスステートの更新を処理する
+    async fn move_vc(
+        handler: &Handler,
+        api: &MockApi,
+        user_id: UserId,
+        from: Option<ChannelId>,
+        to: Option<ChannelId>,
+    ) {
+        match to {
+            Some(to) => api.join(user_id, to),
+            None => api.leave(user_id),
+        }
+        let member = api.add_member(GUILD_ID, user_id, false);
+        handler
+            .update_voice_state(api, Some(GUILD_ID), user_id, from, to, Some(member))
+            .await;
+    }
+
+    /// VCの移動を試すサーバー (VCにはALICEとBOBが参加済み)
+    async fn discord_for_moves(handler: &Handler) -> MockApi {
+        let api = discord();
+        api.add_channel(
+            GUILD_ID,
+            OTHER_VC,
+            ChannelType::Voice,
+            "作業部屋",
+            Some(VC_CATEGORY),
+        );
+        api.add_channel(GUILD_ID, PLAIN_VC, ChannelType::Voice, "ロビー", None);
+        move_vc(handler, &api, ALICE, None, Some(VC)).await;
+        move_vc(handler, &api, BOB, None, Some(VC)).await;
+        api
+    }
+
+    /// スレッドに退出メッセージが投稿されたか
+    fn has_leave_message(api: &MockApi, thread_id: ChannelId, user_id: UserId) -> bool {
+        let mention = user_id.mention().to_string();
+        api.posts_in(thread_id)
+            .iter()
+            .any(|p| p.content.contains(&mention) && p.content.contains("退出しました"))
+    }
+
+    #[tokio::test]
+    async fn move_between_custom_vcs_posts_leave_and_join() {
+        let handler = handler("");
+        let api = discord_for_moves(&handler).await;
+        let thread_id = thread_of_vc(&handler).unwrap();
+
+        move_vc(&handler, &api, ALICE, Some(VC), Some(OTHER_VC)).await;
+
+        // 元のVCのスレッドには退出、移動先のVCにはスレッドを作成して参加を投稿する
+        assert!(has_leave_message(&api, thread_id, ALICE));
+        let other_thread = handler
+            .vc_to_thread
+            .get(&(GUILD_ID, OTHER_VC))
+            .map(|r| *r)
+            .unwrap();
+        assert_ne!(other_thread, thread_id);
+        assert!(api
+            .posts_in(other_thread)
+            .iter()
+            .any(|p| p.content.contains(&ALICE.mention().to_string())));
+        assert_eq!(
+            handler.vc_owner.get(&(GUILD_ID, OTHER_VC)).map(|r| *r),
+            Some(ALICE)
+        );
+        assert_eq!(handler.vc_owner.get(&VC_KEY).map(|r| *r), Some(BOB));
+    }
+
+    #[tokio::test]
+    async fn move_to_plain_vc_posts_only_leave() {
+        let handler = handler("");
+        let api = discord_for_moves(&handler).await;
+        let thread_id = thread_of_vc(&handler).unwrap();
+
+        move_vc(&handler, &api, ALICE, Some(VC), Some(PLAIN_VC)).await;
+
+        assert!(has_leave_message(&api, thread_id, ALICE));
+        assert!(!handler.vc_to_thread.contains_key(&(GUILD_ID, PLAIN_VC)));
+        assert_eq!(api.state().threads_created, 1);
+    }
+
+    #[tokio::test]
+    async fn move_from_plain_vc_posts_only_join() {
+        let handler = handler("");
+        let api = discord_for_moves(&handler).await;
+        let thread_id = thread_of_vc(&handler).unwrap();
+        let carol = UserId(1003);
+        move_vc(&handler, &api, carol, None, Some(PLAIN_VC)).await;
+        let posts_before = api.state().posts.len();
+
+        move_vc(&handler, &api, carol, Some(PLAIN_VC), Some(VC)).await;
+
+        let posts = api.state().posts[posts_before..].to_vec();
+        assert!(!posts.is_empty());
+        assert!(posts.iter().all(|p| !p.content.contains("退出しました")));
+        assert!(api
+            .posts_in(thread_id)
+            .iter()
+            .any(|p| p.content.contains(&carol.mention().to_string())));
+    }
+
+    #[tokio::test]
+    async fn report_error_posts_only_to_the_guild_error_channel() {
+        let error_channel = ChannelId(310);
+        let handler = handler("[discord.guilds.100]\nerror_channel = \"310\"");
+        let api = discord();
+        api.add_channel(GUILD_ID, error_channel, ChannelType::Text, "errors", None);
+        let why = anyhow::anyhow!("テスト用のエラー");
+
+        handler
+            .report_error(&api, Some(GUILD_ID), "テスト", &why)
+            .await;
+        // 他のサーバーのエラーと、サーバーがわからないエラーはログのみ
+        handler
+            .report_error(&api, Some(GuildId(101)), "テスト", &why)
+            .await;
+        handler.report_error(&api, None, "テスト", &why).await;
+
+        let posts = api.state().posts.clone();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].channel_id, error_channel);
+    }
+
+    #[tokio::test]
+    async fn get_vc_forgets_deleted_vc() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        // VCの削除を取りこぼした
+        api.state().channels.remove(&VC);
+
+        assert!(handler
+            .get_vc(&api, Some(GUILD_ID), &thread_id)
+            .await
+            .is_err());
+
+        assert_eq!(thread_of_vc(&handler), None);
+        assert!(!handler.thread_to_vc.contains_key(&(GUILD_ID, thread_id)));
+        assert!(!handler.vc_owner.contains_key(&VC_KEY));
+    }
+
+    #[tokio::test]
+    async fn get_vc_keeps_link_on_transient_error() {
+        let handler = handler("");
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        api.state().channel_errors.insert(VC, 500);
+
+        assert!(handler
+            .get_vc(&api, Some(GUILD_ID), &thread_id)
+            .await
+            .is_err());
+
+        assert_eq!(thread_of_vc(&handler), Some(thread_id));
+        assert_eq!(
+            handler.thread_to_vc.get(&(GUILD_ID, thread_id)).map(|r| *r),
+            Some(VC)
+        );
+
+        // 復旧すればそのまま使える
+        api.state().channel_errors.clear();
+        let vc_channel = handler
+            .get_vc(&api, Some(GUILD_ID), &thread_id)
+            .await
+            .unwrap();
+        assert_eq!(vc_channel.id, VC);
+    }
+
+    #[tokio::test]
+    async fn thread_name_template_applies_on_create_and_rename() {
+        let handler = handler(r#"guilds.100.thread_name_template = "🎮 {name} 通話""#);
+        let api = discord();
+        join(&handler, &api, ALICE).await.unwrap();
+        let thread_id = thread_of_vc(&handler).unwrap();
+        assert!(api
+            .get_channel(thread_id)
+            .unwrap()
+            .name
+            .starts_with("🎮 ゲーム部屋 通話"));
+
+        let old = api.get_channel(VC).unwrap();
+        let new = api.add_channel(
+            GUILD_ID,
+            VC,
+            ChannelType::Voice,
+            "雑談部屋",
+            Some(VC_CATEGORY),
+        );
+        handler.update_channel(&api, Some(old), new).await;
+
+        let (renamed, name) = api.state().renames.last().cloned().unwrap();
+        assert_eq!(renamed, thread_id);
+        assert!(name.starts_with("🎮 雑談部屋 通話"));
+        // スレッドの名前からはVCの名前を取り出せる
+        assert_eq!(handler.vc_name_of_thread(&GUILD_ID, &name), "雑談部屋");
+    }
+
+    #[test]
+    fn apply_name_template_replaces_name_and_truncates() {
+        assert_eq!(apply_name_template(None, "ゲーム部屋"), "ゲーム部屋");
+        assert_eq!(
+            apply_name_template(Some("🎮 {name}"), "ゲーム部屋"),
+            "🎮 ゲーム部屋"
+        );
+        assert_eq!(
+            apply_name_template(Some("[{name}] ログ"), "雑談"),
+            "[雑談] ログ"
+        );
+        let long = "あ".repeat(CHANNEL_NAME_MAX_CHARS);
+        let name = apply_name_template(Some("🎮 {name}"), &long);
+        assert_eq!(name.chars().count(), CHANNEL_NAME_MAX_CHARS);
+        assert!(name.starts_with("🎮 "));
+    }
+
+    #[test]
+    fn strip_name_template_removes_prefix_and_suffix() {
+        assert_eq!(strip_name_template(None, "🎮 ゲーム部屋"), "🎮 ゲーム部屋");
+        assert_eq!(
+            strip_name_template(Some("🎮 {name}"), "🎮 ゲーム部屋"),
+            "ゲーム部屋"
+        );
+        assert_eq!(
+            strip_name_template(Some("[{name}] ログ"), "[雑談] ログ"),
+            "雑談"
+        );
+        // テンプレートに当てはまらない名前 (手動で変えた場合など) はそのまま
+        assert_eq!(strip_name_template(Some("🎮 {name}"), "雑談"), "雑談");
+        assert_eq!(
+            strip_name_template(Some("[{name}] ログ"), "[雑談]"),
+            "[雑談]"
+        );
+    }
+
+    #[test]
+    fn strip_member_count_removes_only_trailing_count() {
+        assert_eq!(strip_member_count("ゲーム部屋 (3)"), "ゲーム部屋");
+        assert_eq!(strip_member_count("ゲーム部屋 (12) "), "ゲーム部屋");
+        assert_eq!(strip_member_count("ゲーム部屋"), "ゲーム部屋");
+        // 数字以外や空の括弧は参加人数ではない
+        assert_eq!(strip_member_count("作業部屋 (雑談)"), "作業部屋 (雑談)");
+        assert_eq!(strip_member_count("部屋 ()"), "部屋 ()");
+        assert_eq!(strip_member_count("部屋(3)"), "部屋(3)");
+        // 最後の括弧だけを取り除く
+        assert_eq!(strip_member_count("部屋 (2) (5)"), "部屋 (2)");
+    }
+
+    #[test]
+    fn sanitize_channel_name_trims_and_rejects_empty() {
+        assert_eq!(sanitize_channel_name("  雑談  "), Some("雑談".to_string()));
+        assert_eq!(sanitize_channel_name(""), None);
+        assert_eq!(sanitize_channel_name(" \t "), None);
+        assert_eq!(sanitize_channel_name("\r\n"), None);
+    }
+
+    #[test]
+    fn sanitize_channel_name_replaces_newlines() {
+        assert_eq!(
+            sanitize_channel_name("ゲーム\n部屋\r\n2"),
+            Some("ゲーム 部屋  2".to_string())
+        );
+    }
+
+    #[test]
+    fn sanitize_channel_name_truncates_to_limit() {
+        let long = "あ".repeat(CHANNEL_NAME_MAX_CHARS + 10);
+        assert_eq!(
+            sanitize_channel_name(&long),
+            Some("あ".repeat(CHANNEL_NAME_MAX_CHARS))
+        );
+        // 切り詰めた結果の末尾の空白は取り除く
+        let name = format!("{} 部屋", "a".repeat(CHANNEL_NAME_MAX_CHARS - 1));
+        assert_eq!(
+            sanitize_channel_name(&name),
+            Some("a".repeat(CHANNEL_NAME_MAX_CHARS - 1))
+        );
+    }
 }
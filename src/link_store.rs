@@ -0,0 +1,97 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context as _, Result};
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+
+/// サーバーをまたいでチャンネルを区別するためのキー
+pub type ChannelKey = (GuildId, ChannelId);
+
+/// VC⇔スレッドの対応 (1件分)
+#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Clone)]
+pub struct Link {
+    /// サーバーID
+    pub guild_id: GuildId,
+    /// VCのチャンネルID
+    pub vc_channel_id: ChannelId,
+    /// スレッドのチャンネルID
+    pub thread_id: ChannelId,
+    /// ボタン付きの参加メッセージのID
+    #[serde(default)]
+    pub welcome_message_id: Option<MessageId>,
+    /// VCのチャットに投稿したスレッドへの案内メッセージのID
+    #[serde(default)]
+    pub guide_message_id: Option<MessageId>,
+    /// VCのステータスを載せてピン留めしたメッセージのID
+    #[serde(default)]
+    pub status_message_id: Option<MessageId>,
+    /// 参加中のメンバーを表示してピン留めしたメッセージのID
+    #[serde(default)]
+    pub panel_message_id: Option<MessageId>,
+}
+
+/// VC⇔スレッドの対応表 (ファイルに保存する)
+/// JSONのキーにはタプルを使えないため、対応の一覧として保存する
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize, PartialEq, Clone)]
+pub struct LinkStore {
+    /// VC⇔スレッドの対応の一覧
+    /// 旧形式 (サーバーIDなし) のファイルは空として読み込み、起動時の復元で対応を作り直す
+    #[serde(default)]
+    pub links: Vec<Link>,
+}
+
+impl LinkStore {
+    /// ファイルから読み込む (ファイルがない場合は空)
+    pub fn load(path: &str) -> Result<LinkStore> {
+        if !Path::new(path).exists() {
+            return Ok(LinkStore::default());
+        }
+        let json = fs::read_to_string(path).context("状態ファイルの読み込みに失敗")?;
+        let store = serde_json::from_str(&json).context("状態ファイルのパースに失敗")?;
+        Ok(store)
+    }
+
+    /// ファイルに保存する
+    /// 書き込み中に止まってもファイルが壊れないよう、一時ファイルに書き込んでから置き換える
+    pub fn save(&self, path: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).context("状態のシリアライズに失敗")?;
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, json).context("状態ファイルの書き込みに失敗")?;
+        fs::rename(&tmp_path, path).context("状態ファイルの置き換えに失敗")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("links-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let store = LinkStore {
+            links: vec![Link {
+                guild_id: GuildId(1),
+                vc_channel_id: ChannelId(2),
+                thread_id: ChannelId(3),
+                welcome_message_id: Some(MessageId(4)),
+                guide_message_id: None,
+                status_message_id: None,
+                panel_message_id: None,
+            }],
+        };
+
+        store.save(path).unwrap();
+
+        assert_eq!(LinkStore::load(path).unwrap(), store);
+        // 一時ファイルは置き換えで無くなる
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn load_missing_file_is_empty() {
+        let store = LinkStore::load("/nonexistent/links.json").unwrap();
+        assert_eq!(store, LinkStore::default());
+    }
+}
@@ -0,0 +1,84 @@
+use std::future::Future;
+
+use anyhow::{Context as _, Result};
+use log::{Log, Metadata, Record};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+
+tokio::task_local! {
+    /// 処理中のイベントの情報 (イベントごとに設定する)
+    static FIELDS: LogFields;
+}
+
+/// ログに付ける、処理中のイベントの情報
+/// JSON形式のログでは `mdc` に出力され、パターン形式のログでは `{X(guild_id)}` などで出力できる
+#[derive(Debug, Default, Clone)]
+pub struct LogFields {
+    /// 処理中のイベントの種類
+    pub action: &'static str,
+    /// サーバーID
+    pub guild_id: Option<GuildId>,
+    /// VCのチャンネルID
+    pub vc_channel_id: Option<ChannelId>,
+    /// スレッドのチャンネルID
+    pub thread_id: Option<ChannelId>,
+    /// イベントを起こしたユーザーのID
+    pub user_id: Option<UserId>,
+}
+
+impl LogFields {
+    /// この情報を付けてログを出力するように処理を実行する
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        FIELDS.scope(self, f).await
+    }
+
+    /// ログに出力する項目 (値のないものは出力しない)
+    fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = vec![("action", self.action.to_string())];
+        let ids = [
+            ("guild_id", self.guild_id.map(|id| id.0)),
+            ("vc_channel_id", self.vc_channel_id.map(|id| id.0)),
+            ("thread_id", self.thread_id.map(|id| id.0)),
+            ("user_id", self.user_id.map(|id| id.0)),
+        ];
+        for (key, id) in ids {
+            if let Some(id) = id {
+                entries.push((key, id.to_string()));
+            }
+        }
+        entries
+    }
+}
+
+/// 処理中のイベントの情報を付けてログを出力するロガー
+/// log4rsはスレッドごとの情報 (MDC) しか出力できないため、ログを出力する間だけMDCに設定する
+struct FieldsLogger(log4rs::Logger);
+
+impl Log for FieldsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // イベントの処理中でなければ何も付けない
+        let _guard = FIELDS
+            .try_with(|fields| log_mdc::extend_scoped(fields.entries()))
+            .ok();
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// ログの設定ファイルを読み込んでログを初期化する
+pub fn init(path: &str) -> Result<()> {
+    let config = log4rs::config::load_config_file(path, Default::default())?;
+    let logger = log4rs::Logger::new(config);
+    log::set_max_level(logger.max_log_level());
+    log::set_boxed_logger(Box::new(FieldsLogger(logger))).context("ロガーの登録に失敗")?;
+    Ok(())
+}
@@ -1,10 +1,29 @@
 mod app_config;
+mod config_watcher;
+mod discord_api;
 mod event_handler;
+mod link_store;
+mod log_fields;
+mod messages;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod rename_queue;
+mod respond;
+mod retry;
+mod shutdown;
+mod storage;
+mod voice_time;
+#[cfg(feature = "webhook")]
+mod webhook;
 
 use anyhow::{Context as _, Result};
 use app_config::AppConfig;
 use event_handler::Handler;
-use std::env;
+#[cfg(feature = "metrics")]
+use log::error;
+use log::warn;
+use messages::Messages;
+use std::{env, sync::Arc};
 
 use serenity::framework::standard::StandardFramework;
 use serenity::prelude::*;
@@ -14,8 +33,9 @@ use serenity::prelude::*;
 async fn main() -> Result<()> {
     let basedir = std::env::var("APP_BASEDIR").unwrap_or("bot/".to_string());
 
-    // ログを初期化
-    log4rs::init_file(format!("{}/log4rs.yml", basedir), Default::default())
+    // ログを初期化 (JSON形式で出力する場合などは、環境変数で設定ファイルを切り替える)
+    let log_config = std::env::var("APP_LOG_CONFIG").unwrap_or("log4rs.yml".to_string());
+    log_fields::init(&format!("{}/{}", basedir, log_config))
         .context("log4rsの設定ファイルの読み込みに失敗")?;
 
     // フレームワークを初期化
@@ -24,8 +44,14 @@ async fn main() -> Result<()> {
     // 設定ファイルを読み込む
     let app_config = AppConfig::load_config(&basedir).context("設定ファイルの読み込みに失敗")?;
 
+    // メッセージを読み込む
+    let messages = Messages::load(&basedir, &app_config.discord.locale)
+        .context("ロケールファイルの読み込みに失敗")?;
+
     // イベント受信リスナーを構築
-    let handler = Handler::new(app_config).context("イベント受信リスナーの構築に失敗")?;
+    // 終了時の後始末で使うため共有する
+    let handler =
+        Arc::new(Handler::new(app_config, messages).context("イベント受信リスナーの構築に失敗")?);
 
     // 環境変数のトークンを使用してDiscord APIを初期化
     let token = env::var("DISCORD_TOKEN").context("トークンが指定されていません")?;
@@ -33,16 +59,61 @@ async fn main() -> Result<()> {
         | GatewayIntents::MESSAGE_CONTENT
         | GatewayIntents::GUILD_MEMBERS;
     let mut client = Client::builder(token, intents)
-        .event_handler(handler)
+        .event_handler_arc(handler.clone())
         .framework(framework)
         .await
         .context("Botの初期化に失敗")?;
 
-    // イベント受信を開始
-    client
-        .start()
-        .await
-        .context("Bot動作中にエラーが発生しました")?;
+    // 終了時に使うため、先に取り出しておく
+    let http = client.cache_and_http.http.clone();
+    let shard_manager = client.shard_manager.clone();
+
+    // 設定ファイルが変更されたら読み込み直す
+    let config_reloader = tokio::spawn(handler.clone().run_config_reloader(basedir.clone()));
+    // 放置されたスレッドを定期的にアーカイブする
+    let inactivity_archiver = tokio::spawn(handler.clone().run_inactivity_archiver());
+    // スレッドのあるVCの数をステータスに表示する
+    let presence_updater = tokio::spawn(handler.clone().run_presence_updater());
+    // 参加中のメンバーの表示を更新する
+    let panel_updater = tokio::spawn(handler.clone().run_panel_updater());
+    // 削除を保留したスレッドを、再参加されなければ削除する
+    let deferred_deleter = tokio::spawn(handler.clone().run_deferred_deleter());
+    // 設定されていれば、週ごとの動作状況を投稿する
+    let weekly_reporter = tokio::spawn(handler.clone().run_weekly_reporter());
+    // 削除を取りこぼしたチャンネルの対応を定期的に掃除する
+    let link_sweeper = tokio::spawn(handler.clone().run_link_sweeper());
+    // 設定されていれば、監視用のメトリクスをHTTPで公開する
+    #[cfg(feature = "metrics")]
+    let metrics_server = handler.metrics_port().map(|port| {
+        let metrics_handler = handler.clone();
+        tokio::spawn(async move {
+            let render = Arc::new(move || metrics_handler.render_metrics());
+            if let Err(why) = metrics::serve(port, render).await {
+                error!("メトリクスのサーバーの起動に失敗: {:?}", why);
+            }
+        })
+    });
+
+    // イベント受信を開始 (終了シグナルを受信したら後始末して終了する)
+    tokio::select! {
+        result = client.start() => result.context("Bot動作中にエラーが発生しました")?,
+        _ = shutdown::wait_for_signal() => {
+            warn!("終了シグナルを受信しました");
+            handler.shutdown(&http).await;
+            shard_manager.lock().await.shutdown_all().await;
+        }
+    }
+    config_reloader.abort();
+    deferred_deleter.abort();
+    inactivity_archiver.abort();
+    link_sweeper.abort();
+    panel_updater.abort();
+    presence_updater.abort();
+    weekly_reporter.abort();
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
 
     Ok(())
 }
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use anyhow::{Context as _, Result};
+use config::Config;
+use log::warn;
+
+/// 絵文字の名前→デフォルトの絵文字
+/// テンプレートの `{emoji_名前}` を置換する (サーバーごとの設定で上書きできる)
+pub const DEFAULT_EMOJI: [(&str, &str); 7] = [
+    ("rename", "📝"),
+    ("claim", "👑"),
+    ("lock", "🔒"),
+    ("unlock", "🔓"),
+    ("limit", "👥"),
+    ("success", "✅"),
+    ("error", "❌"),
+];
+
+/// ユーザーに表示するメッセージ
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Messages {
+    /// キー→テンプレートのマップ
+    templates: HashMap<String, String>,
+}
+
+impl Messages {
+    /// ロケールファイルを読み込む
+    pub fn load(basedir: &str, locale: &str) -> Result<Messages> {
+        // ロケールファイルのパス
+        let path = format!("{}/locales/{}.toml", basedir, locale);
+        // ロケールファイルを読み込む
+        let templates = Config::builder()
+            .add_source(config::File::with_name(&path))
+            .build()
+            .and_then(|c| c.try_deserialize::<HashMap<String, String>>())
+            .with_context(|| format!("ロケールファイルの読み込みに失敗: {}", path))?;
+        Ok(Messages { templates })
+    }
+
+    /// テンプレートを取得する
+    pub fn get(&self, key: &str) -> String {
+        match self.templates.get(key) {
+            Some(template) => template.clone(),
+            None => {
+                // 翻訳漏れに気付けるよう、キーをそのまま表示する
+                warn!("ロケールにメッセージが定義されていません: {}", key);
+                key.to_string()
+            }
+        }
+    }
+
+    /// テンプレートを取得し、`{user}` などのプレースホルダーを置換する
+    pub fn format(&self, key: &str, args: &[(&str, &str)]) -> String {
+        render(&self.get(key), args)
+    }
+
+    /// 上書き用のテンプレートにキーがあればそちらを優先して、プレースホルダーを置換する
+    pub fn format_with(
+        &self,
+        overrides: &HashMap<String, String>,
+        key: &str,
+        args: &[(&str, &str)],
+    ) -> String {
+        match overrides.get(key) {
+            Some(template) => render(template, args),
+            None => self.format(key, args),
+        }
+    }
+}
+
+/// テンプレートの `{name}` を値に置換する
+/// 値を渡されなかった `{emoji_名前}` はデフォルトの絵文字にする
+fn render(template: &str, args: &[(&str, &str)]) -> String {
+    let text = args
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        });
+    DEFAULT_EMOJI.iter().fold(text, |text, (name, emoji)| {
+        text.replace(&format!("{{emoji_{}}}", name), emoji)
+    })
+}
@@ -0,0 +1,97 @@
+use std::{
+    convert::Infallible,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Context as _, Result};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Response, Server,
+};
+
+/// 一時的なエラーでDiscord APIの呼び出しを再試行した回数
+pub static API_RETRIES: AtomicUsize = AtomicUsize::new(0);
+/// 再試行してもDiscord APIの呼び出しに失敗した回数
+pub static API_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Prometheusのテキスト形式で出力するメトリクス
+#[derive(Debug, Default)]
+pub struct MetricsText {
+    /// 出力する文章
+    text: String,
+}
+
+impl MetricsText {
+    /// カウンター (起動してから増え続ける値) を追加する
+    pub fn counter(&mut self, name: &str, help: &str, value: usize) -> &mut Self {
+        self.metric(name, "counter", help, value)
+    }
+
+    /// ゲージ (増減する現在の値) を追加する
+    pub fn gauge(&mut self, name: &str, help: &str, value: usize) -> &mut Self {
+        self.metric(name, "gauge", help, value)
+    }
+
+    /// 出力する文章を取り出す
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.text)
+    }
+
+    /// メトリクスを1つ追加する
+    fn metric(&mut self, name: &str, kind: &str, help: &str, value: usize) -> &mut Self {
+        // Stringへの書き込みは失敗しない
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.text, "{} {}", name, value);
+        self
+    }
+}
+
+/// 指定したポートで、どのパスへのリクエストにもメトリクスを返すHTTPサーバーを動かす
+/// `render` はリクエストのたびに呼び出し、その時点の値を返す
+pub async fn serve(port: u16, render: Arc<dyn Fn() -> String + Send + Sync>) -> Result<()> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service = make_service_fn(move |_| {
+        let render = render.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_request| {
+                let body = render();
+                async move {
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                            .body(Body::from(body))
+                            .unwrap_or_default(),
+                    )
+                }
+            }))
+        }
+    });
+    Server::try_bind(&addr)
+        .with_context(|| format!("メトリクスのポートを開けません: {}", addr))?
+        .serve(make_service)
+        .await
+        .context("メトリクスのサーバーでエラーが発生しました")?;
+    Ok(())
+}
+
+/// Discord APIの呼び出しの再試行と失敗の回数を追加する
+pub fn api_metrics(metrics: &mut MetricsText) -> &mut MetricsText {
+    metrics
+        .counter(
+            "vc_thread_api_retries_total",
+            "Discord API calls retried after a transient error",
+            API_RETRIES.load(Ordering::Relaxed),
+        )
+        .counter(
+            "vc_thread_api_errors_total",
+            "Discord API calls that failed (after any retries)",
+            API_ERRORS.load(Ordering::Relaxed),
+        )
+}
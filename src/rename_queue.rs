@@ -0,0 +1,248 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use log::error;
+use serenity::{http::Http, model::id::ChannelId, prelude::Mutex};
+
+use crate::retry::retry;
+
+/// Discordがチャンネル名の変更を許可する回数
+const RENAME_LIMIT: usize = 2;
+/// Discordがチャンネル名の変更回数を数える期間
+const RENAME_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// チャンネル名の変更が一時的に失敗した時に試す最大回数
+const RETRY_ATTEMPTS: u32 = 3;
+
+/// リネームの結果
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RenameOutcome {
+    /// すぐに名前を変更した
+    Renamed,
+    /// レート制限のため、指定時間後に名前を変更する
+    Deferred(Duration),
+}
+
+/// リネームを受け付けた結果
+#[derive(Debug, PartialEq, Eq)]
+enum Reservation {
+    /// すぐに名前を変更する
+    Now,
+    /// 適用待ちの名前にした (`schedule` がtrueの場合は、適用するタイマーを動かす)
+    Later { wait: Duration, schedule: bool },
+}
+
+/// チャンネルごとのリネーム状態
+#[derive(Debug, Default)]
+struct RenameState {
+    /// 直近のリネーム時刻
+    history: VecDeque<Instant>,
+    /// 適用待ちの名前 (複数回リネームされた場合は最新の名前のみ残す)
+    pending: Option<String>,
+    /// 適用待ちの名前を変更するタイマーが動いているか
+    scheduled: bool,
+}
+
+impl RenameState {
+    /// 期間外になったリネーム時刻を削除する
+    fn expire(&mut self, now: Instant) {
+        while let Some(&renamed_at) = self.history.front() {
+            if now.duration_since(renamed_at) < RENAME_WINDOW {
+                break;
+            }
+            self.history.pop_front();
+        }
+    }
+
+    /// 次にリネームできるまでの時間
+    fn wait_time(&self, now: Instant) -> Duration {
+        match self.history.front() {
+            Some(&renamed_at) if self.history.len() >= RENAME_LIMIT => {
+                RENAME_WINDOW.saturating_sub(now.duration_since(renamed_at))
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// リネームを受け付ける
+    /// 制限内ならすぐに変更し、制限にかかるか適用待ちの名前があれば最新の名前として適用待ちにする
+    fn reserve(&mut self, now: Instant, name: String) -> Reservation {
+        self.expire(now);
+
+        let wait = self.wait_time(now);
+        if wait.is_zero() && !self.scheduled {
+            // すぐに変更できる
+            self.history.push_back(now);
+            Reservation::Now
+        } else {
+            // 後で変更する
+            self.pending = Some(name);
+            let schedule = !self.scheduled;
+            self.scheduled = true;
+            Reservation::Later { wait, schedule }
+        }
+    }
+
+    /// タイマーで適用する名前を取り出す (適用する場合はリネーム時刻として記録する)
+    fn take_pending(&mut self, now: Instant) -> Option<String> {
+        self.scheduled = false;
+        let name = self.pending.take();
+        if name.is_some() {
+            self.history.push_back(now);
+        }
+        name
+    }
+}
+
+/// レート制限内にチャンネル名の変更を収めるキュー
+#[derive(Debug, Default, Clone)]
+pub struct RenameQueue {
+    /// チャンネル→リネーム状態のマップ
+    states: Arc<Mutex<HashMap<ChannelId, RenameState>>>,
+}
+
+impl RenameQueue {
+    /// チャンネル名を変更する
+    /// レート制限にかかる場合は、制限が解除された時に最新の名前を適用する
+    pub async fn rename(
+        &self,
+        http: &Arc<Http>,
+        channel_id: ChannelId,
+        name: String,
+    ) -> Result<RenameOutcome> {
+        let reservation = self
+            .states
+            .lock()
+            .await
+            .entry(channel_id)
+            .or_default()
+            .reserve(Instant::now(), name.clone());
+
+        match reservation {
+            Reservation::Now => {
+                apply(http, channel_id, name).await?;
+                Ok(RenameOutcome::Renamed)
+            }
+            Reservation::Later { wait, schedule } => {
+                if schedule {
+                    self.schedule(http.clone(), channel_id, wait);
+                }
+                Ok(RenameOutcome::Deferred(wait))
+            }
+        }
+    }
+
+    /// 削除されたチャンネルのリネーム状態を破棄する
+    pub async fn forget(&self, channel_id: &ChannelId) {
+        self.states.lock().await.remove(channel_id);
+    }
+
+    /// 制限が解除された後に、適用待ちの名前を変更するタイマーを動かす
+    fn schedule(&self, http: Arc<Http>, channel_id: ChannelId, wait: Duration) {
+        let states = self.states.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+
+            // 適用待ちの名前を取り出す (待機中にチャンネルが削除された場合は何もしない)
+            let name = match states.lock().await.get_mut(&channel_id) {
+                Some(state) => state.take_pending(Instant::now()),
+                None => return,
+            };
+
+            if let Some(name) = name {
+                if let Err(why) = apply(&http, channel_id, name).await {
+                    error!("保留していたチャンネル名の変更に失敗: {:?}", why);
+                }
+            }
+        });
+    }
+}
+
+/// チャンネル名を変更する (VC・スレッド共通)
+async fn apply(http: &Arc<Http>, channel_id: ChannelId, name: String) -> Result<()> {
+    retry("チャンネル名の変更", RETRY_ATTEMPTS, || {
+        channel_id.edit(http, |c| {
+            c.name(&name);
+            c
+        })
+    })
+    .await
+    .context("チャンネル名の変更に失敗")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_within_limit_apply_immediately() {
+        let mut state = RenameState::default();
+        let now = Instant::now();
+
+        assert_eq!(state.reserve(now, "a".to_string()), Reservation::Now);
+        assert_eq!(state.reserve(now, "b".to_string()), Reservation::Now);
+        assert_eq!(state.pending, None);
+    }
+
+    #[test]
+    fn renames_over_limit_coalesce_into_latest_name() {
+        let mut state = RenameState::default();
+        let start = Instant::now();
+        state.reserve(start, "a".to_string());
+        state.reserve(start, "b".to_string());
+
+        // 3回目は最初のリネームが期間外になるまで待つ
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            state.reserve(later, "c".to_string()),
+            Reservation::Later {
+                wait: RENAME_WINDOW - Duration::from_secs(60),
+                schedule: true,
+            }
+        );
+        // タイマーは1つだけ動かし、最新の名前だけを残す
+        assert!(matches!(
+            state.reserve(later, "d".to_string()),
+            Reservation::Later {
+                schedule: false,
+                ..
+            }
+        ));
+        assert_eq!(state.pending.as_deref(), Some("d"));
+
+        let applied_at = start + RENAME_WINDOW;
+        assert_eq!(state.take_pending(applied_at).as_deref(), Some("d"));
+        assert!(!state.scheduled);
+    }
+
+    #[test]
+    fn pending_name_is_applied_before_new_renames() {
+        let mut state = RenameState::default();
+        let start = Instant::now();
+        state.reserve(start, "a".to_string());
+        state.reserve(start, "b".to_string());
+        state.reserve(start, "c".to_string());
+
+        // 制限が解除されても、タイマーが適用するまでは適用待ちに追加する
+        let expired = start + RENAME_WINDOW;
+        assert!(matches!(
+            state.reserve(expired, "d".to_string()),
+            Reservation::Later {
+                schedule: false,
+                ..
+            }
+        ));
+        assert_eq!(state.take_pending(expired).as_deref(), Some("d"));
+
+        // 適用した分は制限の回数に数える
+        assert_eq!(state.reserve(expired, "e".to_string()), Reservation::Now);
+        assert!(matches!(
+            state.reserve(expired, "f".to_string()),
+            Reservation::Later { schedule: true, .. }
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+use serenity::{
+    async_trait,
+    builder::CreateComponents,
+    model::application::interaction::{
+        application_command::ApplicationCommandInteraction,
+        message_component::MessageComponentInteraction, modal::ModalSubmitInteraction,
+        InteractionResponseType,
+    },
+};
+
+use crate::discord_api::DiscordApi;
+
+/// 本人にだけ見えるメッセージで応答できるインタラクション
+#[async_trait]
+pub trait EphemeralResponse {
+    /// 本人にだけ見えるメッセージで応答する
+    async fn create_ephemeral_response<A: DiscordApi>(
+        &self,
+        api: &A,
+        content: String,
+    ) -> serenity::Result<()>;
+
+    /// ボタンなどを付けた、本人にだけ見えるメッセージで応答する
+    async fn create_ephemeral_response_with_components<A: DiscordApi>(
+        &self,
+        api: &A,
+        content: String,
+        components: CreateComponents,
+    ) -> serenity::Result<()>;
+}
+
+/// 応答を保留してから、後で内容を設定できるインタラクション
+#[async_trait]
+pub trait DeferredResponse {
+    /// 本人にだけ見える応答として保留する
+    async fn defer_ephemeral_response<A: DiscordApi>(&self, api: &A) -> serenity::Result<()>;
+
+    /// 保留した応答の内容を設定する (メンションしても通知しない)
+    async fn edit_deferred_response<A: DiscordApi>(
+        &self,
+        api: &A,
+        content: String,
+    ) -> serenity::Result<()>;
+}
+
+/// インタラクションの種類ごとに同じ応答の処理を実装する
+macro_rules! impl_ephemeral_response {
+    ($($interaction:ty),*) => {
+        $(
+            #[async_trait]
+            impl EphemeralResponse for $interaction {
+                async fn create_ephemeral_response<A: DiscordApi>(
+                    &self,
+                    api: &A,
+                    content: String,
+                ) -> serenity::Result<()> {
+                    api.create_interaction_response(self.id, &self.token, |r| {
+                        r.kind(InteractionResponseType::ChannelMessageWithSource)
+                            .interaction_response_data(|d| {
+                                d.content(content);
+                                d.ephemeral(true);
+                                d
+                            });
+                        r
+                    })
+                    .await
+                }
+
+                async fn create_ephemeral_response_with_components<A: DiscordApi>(
+                    &self,
+                    api: &A,
+                    content: String,
+                    components: CreateComponents,
+                ) -> serenity::Result<()> {
+                    api.create_interaction_response(self.id, &self.token, |r| {
+                        r.kind(InteractionResponseType::ChannelMessageWithSource)
+                            .interaction_response_data(|d| {
+                                d.content(content);
+                                d.ephemeral(true);
+                                d.set_components(components);
+                                d
+                            });
+                        r
+                    })
+                    .await
+                }
+            }
+
+            #[async_trait]
+            impl DeferredResponse for $interaction {
+                async fn defer_ephemeral_response<A: DiscordApi>(
+                    &self,
+                    api: &A,
+                ) -> serenity::Result<()> {
+                    api.create_interaction_response(self.id, &self.token, |r| {
+                        r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                            .interaction_response_data(|d| d.ephemeral(true))
+                    })
+                    .await
+                }
+
+                async fn edit_deferred_response<A: DiscordApi>(
+                    &self,
+                    api: &A,
+                    content: String,
+                ) -> serenity::Result<()> {
+                    api.edit_interaction_response(&self.token, |r| {
+                        r.content(content);
+                        r.allowed_mentions(|m| m.empty_users());
+                        r
+                    })
+                    .await
+                }
+            }
+        )*
+    };
+}
+
+impl_ephemeral_response!(
+    ApplicationCommandInteraction,
+    MessageComponentInteraction,
+    ModalSubmitInteraction
+);
+
+/// 本人にだけ見えるメッセージで応答する
+pub async fn respond_ephemeral(
+    api: &impl DiscordApi,
+    interaction: &(impl EphemeralResponse + Sync),
+    content: String,
+) -> serenity::Result<()> {
+    interaction.create_ephemeral_response(api, content).await
+}
@@ -0,0 +1,151 @@
+#[cfg(feature = "metrics")]
+use std::sync::atomic::Ordering;
+use std::{future::Future, time::Duration};
+
+use log::debug;
+use serenity::{http::HttpError, Error};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{API_ERRORS, API_RETRIES};
+
+/// 最初の再試行までの待ち時間 (再試行のたびに2倍にする)
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// 一時的なエラー (Discord側の障害やレート制限、通信エラー) の場合は待ってから再試行する
+/// 権限不足などの再試行しても変わらないエラーはそのまま返す
+pub async fn retry<T, F, Fut>(name: &str, max_attempts: u32, mut op: F) -> serenity::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = serenity::Result<T>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(why) if attempt < max_attempts && is_transient(&why) => {
+                debug!(
+                    "{}に失敗したので{:?}後に再試行します ({}/{}): {:?}",
+                    name, backoff, attempt, max_attempts, why
+                );
+                #[cfg(feature = "metrics")]
+                API_RETRIES.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(why) => {
+                #[cfg(feature = "metrics")]
+                API_ERRORS.fetch_add(1, Ordering::Relaxed);
+                return Err(why);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// 再試行すれば成功する可能性があるエラーか判定する
+fn is_transient(why: &Error) -> bool {
+    match why {
+        Error::Http(http_error) => match http_error.as_ref() {
+            // 5xx (Discord側の障害) と 429 (レート制限)
+            HttpError::UnsuccessfulRequest(response) => {
+                response.status_code.is_server_error() || response.status_code.as_u16() == 429
+            }
+            // レート制限のヘッダーが壊れている場合
+            HttpError::RateLimitI64F64 | HttpError::RateLimitUtf8 => true,
+            // 通信エラー
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// 権限が足りないことを示すエラー (403) か判定する
+pub fn is_forbidden(why: &Error) -> bool {
+    match why {
+        Error::Http(http_error) => matches!(
+            http_error.as_ref(),
+            HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 403
+        ),
+        _ => false,
+    }
+}
+
+/// チャンネルなどが既に存在しないことを示すエラー (404) か判定する
+pub fn is_not_found(why: &Error) -> bool {
+    match why {
+        Error::Http(http_error) => matches!(
+            http_error.as_ref(),
+            HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 404
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::discord_api::mock::http_error;
+
+    #[test]
+    fn classifies_http_errors() {
+        for status in [500, 502, 503, 429] {
+            assert!(is_transient(&http_error(status)), "{}", status);
+        }
+        for status in [400, 403, 404] {
+            assert!(!is_transient(&http_error(status)), "{}", status);
+        }
+        assert!(is_forbidden(&http_error(403)));
+        assert!(!is_forbidden(&http_error(404)));
+        assert!(is_not_found(&http_error(404)));
+        assert!(!is_not_found(&http_error(500)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_errors_until_success() {
+        let attempts = AtomicU32::new(0);
+        let result = retry("テスト", 3, || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(http_error(502)),
+                1 => Err(http_error(429)),
+                _ => Ok(42),
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_attempts_with_backoff() {
+        let attempts = AtomicU32::new(0);
+        let started = tokio::time::Instant::now();
+        let result: serenity::Result<()> = retry("テスト", 3, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(http_error(500))
+        })
+        .await;
+
+        assert!(is_transient(&result.unwrap_err()));
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        // 500ms + 1000ms 待ってから最後の試行をする
+        assert_eq!(started.elapsed(), INITIAL_BACKOFF * 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_retry_permanent_errors() {
+        let attempts = AtomicU32::new(0);
+        let result: serenity::Result<()> = retry("テスト", 3, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(http_error(403))
+        })
+        .await;
+
+        assert!(is_forbidden(&result.unwrap_err()));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+}
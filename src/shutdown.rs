@@ -0,0 +1,26 @@
+use log::error;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// 終了シグナル (SIGINT/SIGTERM) を受信するまで待つ
+pub async fn wait_for_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(why) => {
+            // SIGTERMを受け取れなくても、Ctrl+Cでは終了できるようにする
+            error!("SIGTERMの待機に失敗: {:?}", why);
+            if let Err(why) = tokio::signal::ctrl_c().await {
+                error!("SIGINTの待機に失敗: {:?}", why);
+            }
+            return;
+        }
+    };
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(why) = result {
+                error!("SIGINTの待機に失敗: {:?}", why);
+            }
+        }
+        _ = terminate.recv() => {}
+    }
+}
@@ -0,0 +1,430 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{
+    app_config::{AppConfig, StorageKind},
+    link_store::LinkStore,
+    voice_time::VoiceTimeStore,
+};
+
+/// VC⇔スレッドの対応と通話時間の保存先
+/// 保存は別のスレッドから呼ばれることがあるので、スレッド間で共有できるようにする
+pub trait StateStorage: Send + Sync {
+    /// VC⇔スレッドの対応を読み込む (保存されていない場合は空)
+    fn load_links(&self) -> Result<LinkStore>;
+
+    /// VC⇔スレッドの対応を保存する (前回の内容は置き換える)
+    fn save_links(&self, links: &LinkStore) -> Result<()>;
+
+    /// 通話時間を読み込む (保存されていない場合は空)
+    fn load_voice_times(&self) -> Result<VoiceTimeStore>;
+
+    /// 通話時間を保存する (前回の内容は置き換える)
+    fn save_voice_times(&self, voice_times: &VoiceTimeStore) -> Result<()>;
+}
+
+/// 設定された保存先を開く
+pub fn open(app_config: &AppConfig) -> Result<Arc<dyn StateStorage>> {
+    Ok(match app_config.storage {
+        StorageKind::File => Arc::new(FileStorage::new(app_config)),
+        #[cfg(feature = "persistence-sqlite")]
+        StorageKind::Sqlite => Arc::new(sqlite_storage::SqliteStorage::open(
+            &app_config.sqlite_file,
+        )?),
+        #[cfg(feature = "persistence-redis")]
+        StorageKind::Redis => Arc::new(redis_storage::RedisStorage::open(
+            app_config.redis_url.as_deref().unwrap_or_default(),
+            &app_config.redis_key_prefix,
+        )?),
+    })
+}
+
+/// JSONファイルに保存する (デフォルト)
+pub struct FileStorage {
+    /// VC⇔スレッドの対応を保存するファイルのパス
+    state_file: String,
+    /// 通話時間を保存するファイルのパス
+    voice_time_file: String,
+}
+
+impl FileStorage {
+    /// 設定されたファイルに保存する
+    pub fn new(app_config: &AppConfig) -> Self {
+        Self {
+            state_file: app_config.state_file.clone(),
+            voice_time_file: app_config.voice_time_file.clone(),
+        }
+    }
+}
+
+impl StateStorage for FileStorage {
+    fn load_links(&self) -> Result<LinkStore> {
+        LinkStore::load(&self.state_file)
+    }
+
+    fn save_links(&self, links: &LinkStore) -> Result<()> {
+        links.save(&self.state_file)
+    }
+
+    fn load_voice_times(&self) -> Result<VoiceTimeStore> {
+        VoiceTimeStore::load(&self.voice_time_file)
+    }
+
+    fn save_voice_times(&self, voice_times: &VoiceTimeStore) -> Result<()> {
+        voice_times.save(&self.voice_time_file)
+    }
+}
+
+/// SQLiteのデータベースに保存する
+#[cfg(feature = "persistence-sqlite")]
+mod sqlite_storage {
+    use std::sync::{Mutex, PoisonError};
+
+    use anyhow::{Context as _, Result};
+    use rusqlite::{params, Connection};
+    use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
+
+    use super::StateStorage;
+    use crate::{
+        link_store::{Link, LinkStore},
+        voice_time::{VoiceTime, VoiceTimeStore},
+    };
+
+    /// テーブルがなければ作成する
+    /// SQLiteの整数は符号付きなので、IDはi64として保存する (DiscordのIDは2^63未満)
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS links (
+            guild_id INTEGER NOT NULL,
+            vc_channel_id INTEGER NOT NULL,
+            thread_id INTEGER NOT NULL,
+            welcome_message_id INTEGER,
+            guide_message_id INTEGER,
+            status_message_id INTEGER,
+            panel_message_id INTEGER,
+            PRIMARY KEY (guild_id, vc_channel_id)
+        );
+        CREATE TABLE IF NOT EXISTS voice_times (
+            guild_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            seconds INTEGER NOT NULL,
+            PRIMARY KEY (guild_id, user_id)
+        );
+    ";
+
+    pub struct SqliteStorage {
+        /// データベースへの接続 (同時に書き込まないようにロックする)
+        conn: Mutex<Connection>,
+    }
+
+    impl SqliteStorage {
+        /// データベースのファイルを開く (なければ作成する)
+        pub fn open(path: &str) -> Result<Self> {
+            let conn = Connection::open(path).context("データベースを開けませんでした")?;
+            Self::init(conn)
+        }
+
+        /// テーブルを用意する
+        fn init(conn: Connection) -> Result<Self> {
+            conn.execute_batch(SCHEMA)
+                .context("データベースのテーブルの作成に失敗")?;
+            Ok(Self {
+                conn: Mutex::new(conn),
+            })
+        }
+
+        fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+            self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    impl StateStorage for SqliteStorage {
+        fn load_links(&self) -> Result<LinkStore> {
+            let conn = self.conn();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT guild_id, vc_channel_id, thread_id, welcome_message_id,
+                        guide_message_id, status_message_id, panel_message_id FROM links",
+                )
+                .context("VC⇔スレッドの対応の読み込みに失敗")?;
+            let links = stmt
+                .query_map([], |row| {
+                    Ok(Link {
+                        guild_id: GuildId(row.get::<_, i64>(0)? as u64),
+                        vc_channel_id: ChannelId(row.get::<_, i64>(1)? as u64),
+                        thread_id: ChannelId(row.get::<_, i64>(2)? as u64),
+                        welcome_message_id: row
+                            .get::<_, Option<i64>>(3)?
+                            .map(|id| MessageId(id as u64)),
+                        guide_message_id: row
+                            .get::<_, Option<i64>>(4)?
+                            .map(|id| MessageId(id as u64)),
+                        status_message_id: row
+                            .get::<_, Option<i64>>(5)?
+                            .map(|id| MessageId(id as u64)),
+                        panel_message_id: row
+                            .get::<_, Option<i64>>(6)?
+                            .map(|id| MessageId(id as u64)),
+                    })
+                })
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                .context("VC⇔スレッドの対応の読み込みに失敗")?;
+            Ok(LinkStore { links })
+        }
+
+        fn save_links(&self, links: &LinkStore) -> Result<()> {
+            let mut conn = self.conn();
+            // 書き込み中に止まっても前回の内容が残るよう、まとめて置き換える
+            let tx = conn
+                .transaction()
+                .context("VC⇔スレッドの対応の保存に失敗")?;
+            tx.execute("DELETE FROM links", [])
+                .context("VC⇔スレッドの対応の保存に失敗")?;
+            for link in &links.links {
+                tx.execute(
+                    "INSERT INTO links VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        link.guild_id.0 as i64,
+                        link.vc_channel_id.0 as i64,
+                        link.thread_id.0 as i64,
+                        link.welcome_message_id.map(|id| id.0 as i64),
+                        link.guide_message_id.map(|id| id.0 as i64),
+                        link.status_message_id.map(|id| id.0 as i64),
+                        link.panel_message_id.map(|id| id.0 as i64),
+                    ],
+                )
+                .context("VC⇔スレッドの対応の保存に失敗")?;
+            }
+            tx.commit().context("VC⇔スレッドの対応の保存に失敗")?;
+            Ok(())
+        }
+
+        fn load_voice_times(&self) -> Result<VoiceTimeStore> {
+            let conn = self.conn();
+            let mut stmt = conn
+                .prepare("SELECT guild_id, user_id, seconds FROM voice_times")
+                .context("通話時間の読み込みに失敗")?;
+            let totals = stmt
+                .query_map([], |row| {
+                    Ok(VoiceTime {
+                        guild_id: GuildId(row.get::<_, i64>(0)? as u64),
+                        user_id: UserId(row.get::<_, i64>(1)? as u64),
+                        seconds: row.get(2)?,
+                    })
+                })
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                .context("通話時間の読み込みに失敗")?;
+            Ok(VoiceTimeStore { totals })
+        }
+
+        fn save_voice_times(&self, voice_times: &VoiceTimeStore) -> Result<()> {
+            let mut conn = self.conn();
+            let tx = conn.transaction().context("通話時間の保存に失敗")?;
+            tx.execute("DELETE FROM voice_times", [])
+                .context("通話時間の保存に失敗")?;
+            for total in &voice_times.totals {
+                tx.execute(
+                    "INSERT INTO voice_times VALUES (?1, ?2, ?3)",
+                    params![
+                        total.guild_id.0 as i64,
+                        total.user_id.0 as i64,
+                        total.seconds
+                    ],
+                )
+                .context("通話時間の保存に失敗")?;
+            }
+            tx.commit().context("通話時間の保存に失敗")?;
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn save_and_load_round_trip() {
+            let storage = SqliteStorage::init(Connection::open_in_memory().unwrap()).unwrap();
+            assert_eq!(storage.load_links().unwrap(), LinkStore::default());
+            let links = LinkStore {
+                links: vec![Link {
+                    guild_id: GuildId(1),
+                    vc_channel_id: ChannelId(2),
+                    thread_id: ChannelId(3),
+                    welcome_message_id: Some(MessageId(u64::MAX >> 1)),
+                    guide_message_id: None,
+                    status_message_id: None,
+                    panel_message_id: Some(MessageId(5)),
+                }],
+            };
+            let voice_times = VoiceTimeStore {
+                totals: vec![VoiceTime {
+                    guild_id: GuildId(1),
+                    user_id: UserId(4),
+                    seconds: 3600,
+                }],
+            };
+
+            storage.save_links(&links).unwrap();
+            storage.save_voice_times(&voice_times).unwrap();
+
+            assert_eq!(storage.load_links().unwrap(), links);
+            assert_eq!(storage.load_voice_times().unwrap(), voice_times);
+
+            // 保存し直すと前回の内容は残らない
+            storage.save_links(&LinkStore::default()).unwrap();
+            assert_eq!(storage.load_links().unwrap(), LinkStore::default());
+        }
+    }
+}
+
+/// Redisに保存する (複数のBotで同じRedisを使う場合は、キーの接頭辞を分ける)
+#[cfg(feature = "persistence-redis")]
+mod redis_storage {
+    use std::{
+        sync::{Mutex, PoisonError},
+        time::Duration,
+    };
+
+    use anyhow::{Context as _, Result};
+    use redis::{Client, Commands, Connection, RedisResult};
+
+    use super::StateStorage;
+    use crate::{link_store::LinkStore, voice_time::VoiceTimeStore};
+
+    /// Redisへの接続・読み書きを待つ時間 (Redisが応答しなくても保存で止まり続けないようにする)
+    const REDIS_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub struct RedisStorage {
+        /// Redisのクライアント (接続が切れた時に接続し直す)
+        client: Client,
+        /// Redisへの接続 (保存のたびに接続しないよう使い回す)
+        conn: Mutex<Option<Connection>>,
+        /// VC⇔スレッドの対応を保存するキー
+        links_key: String,
+        /// 通話時間を保存するキー
+        voice_times_key: String,
+    }
+
+    impl RedisStorage {
+        /// URL (`redis://host:port/db`) のRedisに保存する
+        /// 設定の誤りに起動時に気付けるよう、最初の接続はここで行う
+        pub fn open(url: &str, key_prefix: &str) -> Result<Self> {
+            let client = Client::open(url).context("RedisのURLが正しくありません")?;
+            let conn = connect(&client)?;
+            Ok(Self {
+                client,
+                conn: Mutex::new(Some(conn)),
+                links_key: format!("{}links", key_prefix),
+                voice_times_key: format!("{}voice_times", key_prefix),
+            })
+        }
+
+        /// 接続を使って操作する
+        /// 失敗した場合は接続が切れているかもしれないので、次の操作で接続し直す
+        fn with_conn<T>(&self, f: impl FnOnce(&mut Connection) -> RedisResult<T>) -> Result<T> {
+            let mut guard = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut conn = match guard.take() {
+                Some(conn) => conn,
+                None => connect(&self.client)?,
+            };
+            let value = f(&mut conn).context("Redisの操作に失敗")?;
+            *guard = Some(conn);
+            Ok(value)
+        }
+
+        /// キーに保存したJSONを読み込む (キーがない場合は空)
+        fn load<T: serde::de::DeserializeOwned + Default>(&self, key: &str) -> Result<T> {
+            let json: Option<String> = self.with_conn(|conn| conn.get(key))?;
+            match json {
+                Some(json) => serde_json::from_str(&json).context("Redisの値のパースに失敗"),
+                None => Ok(T::default()),
+            }
+        }
+
+        /// キーにJSONで保存する
+        fn save<T: serde::Serialize>(&self, key: &str, value: &T) -> Result<()> {
+            let json = serde_json::to_string(value).context("シリアライズに失敗")?;
+            self.with_conn(|conn| conn.set::<_, _, ()>(key, json))
+        }
+    }
+
+    /// Redisに接続する (応答しない場合に待ち続けないよう、待つ時間を決めておく)
+    fn connect(client: &Client) -> Result<Connection> {
+        let conn = client
+            .get_connection_with_timeout(REDIS_TIMEOUT)
+            .context("Redisへの接続に失敗")?;
+        conn.set_read_timeout(Some(REDIS_TIMEOUT))
+            .and_then(|_| conn.set_write_timeout(Some(REDIS_TIMEOUT)))
+            .context("Redisの接続の設定に失敗")?;
+        Ok(conn)
+    }
+
+    impl StateStorage for RedisStorage {
+        fn load_links(&self) -> Result<LinkStore> {
+            self.load(&self.links_key)
+                .context("VC⇔スレッドの対応の読み込みに失敗")
+        }
+
+        fn save_links(&self, links: &LinkStore) -> Result<()> {
+            self.save(&self.links_key, links)
+                .context("VC⇔スレッドの対応の保存に失敗")
+        }
+
+        fn load_voice_times(&self) -> Result<VoiceTimeStore> {
+            self.load(&self.voice_times_key)
+                .context("通話時間の読み込みに失敗")
+        }
+
+        fn save_voice_times(&self, voice_times: &VoiceTimeStore) -> Result<()> {
+            self.save(&self.voice_times_key, voice_times)
+                .context("通話時間の保存に失敗")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::id::{ChannelId, GuildId, UserId};
+
+    use super::*;
+    use crate::{link_store::Link, voice_time::VoiceTime};
+
+    #[test]
+    fn file_storage_is_default() {
+        let dir = std::env::temp_dir().join(format!("vc-thread-storage-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let app_config = AppConfig {
+            state_file: dir.join("state.json").to_string_lossy().to_string(),
+            voice_time_file: dir.join("voice_time.json").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let storage = open(&app_config).unwrap();
+        let links = LinkStore {
+            links: vec![Link {
+                guild_id: GuildId(1),
+                vc_channel_id: ChannelId(2),
+                thread_id: ChannelId(3),
+                welcome_message_id: None,
+                guide_message_id: None,
+                status_message_id: None,
+                panel_message_id: None,
+            }],
+        };
+        let voice_times = VoiceTimeStore {
+            totals: vec![VoiceTime {
+                guild_id: GuildId(1),
+                user_id: UserId(4),
+                seconds: 60,
+            }],
+        };
+
+        storage.save_links(&links).unwrap();
+        storage.save_voice_times(&voice_times).unwrap();
+
+        // 設定されたファイルに保存される
+        assert_eq!(LinkStore::load(&app_config.state_file).unwrap(), links);
+        assert_eq!(storage.load_voice_times().unwrap(), voice_times);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context as _, Result};
+use serenity::model::id::{GuildId, UserId};
+
+/// ユーザーがカスタムVCにいた合計時間 (1人分)
+#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, Clone)]
+pub struct VoiceTime {
+    /// サーバーID
+    pub guild_id: GuildId,
+    /// ユーザーID
+    pub user_id: UserId,
+    /// 合計時間 (秒)
+    pub seconds: i64,
+}
+
+/// ユーザーごとのカスタムVCにいた合計時間 (ファイルに保存する)
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize, PartialEq, Eq, Clone)]
+pub struct VoiceTimeStore {
+    /// ユーザーごとの合計時間の一覧
+    #[serde(default)]
+    pub totals: Vec<VoiceTime>,
+}
+
+impl VoiceTimeStore {
+    /// ファイルから読み込む (ファイルがない場合は空)
+    pub fn load(path: &str) -> Result<VoiceTimeStore> {
+        if !Path::new(path).exists() {
+            return Ok(VoiceTimeStore::default());
+        }
+        let json = fs::read_to_string(path).context("通話時間のファイルの読み込みに失敗")?;
+        let store = serde_json::from_str(&json).context("通話時間のファイルのパースに失敗")?;
+        Ok(store)
+    }
+
+    /// ファイルに保存する
+    /// 書き込み中に止まってもファイルが壊れないよう、一時ファイルに書き込んでから置き換える
+    pub fn save(&self, path: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).context("通話時間のシリアライズに失敗")?;
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, json).context("通話時間のファイルの書き込みに失敗")?;
+        fs::rename(&tmp_path, path).context("通話時間のファイルの置き換えに失敗")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("voice-times-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let store = VoiceTimeStore {
+            totals: vec![VoiceTime {
+                guild_id: GuildId(1),
+                user_id: UserId(2),
+                seconds: 3,
+            }],
+        };
+
+        store.save(path).unwrap();
+
+        assert_eq!(VoiceTimeStore::load(path).unwrap(), store);
+        // 一時ファイルは置き換えで無くなる
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        fs::remove_file(path).unwrap();
+    }
+}
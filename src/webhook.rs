@@ -0,0 +1,96 @@
+use anyhow::{Context as _, Result};
+use dashmap::DashMap;
+use serenity::{
+    http::Http,
+    model::{id::ChannelId, webhook::Webhook},
+};
+
+/// Botが作成するWebhookの名前 (既に作成済みのWebhookを見つけるのにも使う)
+const WEBHOOK_NAME: &str = "discord-vc-thread";
+
+/// スレッドにWebhookでメッセージを投稿する
+/// Webhookは投稿先のスレッドの親チャンネルごとに1つ作成し、使い回す
+#[derive(Debug, Default)]
+pub struct WebhookSender {
+    /// 投稿する時のアイコンのURL (未設定の場合はWebhookのデフォルト)
+    avatar_url: Option<String>,
+    /// 親チャンネル→Webhookのマップ
+    webhooks: DashMap<ChannelId, Webhook>,
+    /// Webhookの実行に使うHTTPクライアント
+    /// serenityのWebhookの実行はスレッドへの投稿に対応していないため、直接送信する
+    client: reqwest::Client,
+}
+
+impl WebhookSender {
+    /// コンストラクタ
+    pub fn new(avatar_url: Option<String>) -> Self {
+        WebhookSender {
+            avatar_url,
+            ..Default::default()
+        }
+    }
+
+    /// スレッドにメッセージを投稿する
+    /// `notify` がfalseの場合はメンションしても通知しない
+    pub async fn send(
+        &self,
+        http: &Http,
+        parent_id: ChannelId,
+        thread_id: ChannelId,
+        username: &str,
+        content: &str,
+        notify: bool,
+    ) -> Result<()> {
+        let webhook_url = self.webhook(http, parent_id).await?.url()?;
+
+        let mut body = serde_json::json!({
+            "content": content,
+            "username": username,
+        });
+        if let Some(avatar_url) = &self.avatar_url {
+            body["avatar_url"] = serde_json::json!(avatar_url);
+        }
+        if !notify {
+            body["allowed_mentions"] = serde_json::json!({ "parse": [] });
+        }
+        self.client
+            .post(webhook_url)
+            .query(&[("thread_id", thread_id.0)])
+            .json(&body)
+            .send()
+            .await
+            .context("Webhookの実行に失敗")?
+            .error_for_status()
+            .context("Webhookの実行に失敗")?;
+        Ok(())
+    }
+
+    /// 親チャンネルのWebhookを取得する (なければ作成する)
+    async fn webhook(&self, http: &Http, parent_id: ChannelId) -> Result<Webhook> {
+        if let Some(webhook) = self.webhooks.get(&parent_id) {
+            return Ok(webhook.value().clone());
+        }
+
+        // 以前作成したWebhookがあれば使う (トークンが取得できるもののみ)
+        let existing = parent_id
+            .webhooks(http)
+            .await
+            .context("Webhookの一覧の取得に失敗")?
+            .into_iter()
+            .find(|w| w.name.as_deref() == Some(WEBHOOK_NAME) && w.token.is_some());
+        let webhook = match existing {
+            Some(webhook) => webhook,
+            None => parent_id
+                .create_webhook(http, WEBHOOK_NAME)
+                .await
+                .context("Webhookの作成に失敗")?,
+        };
+        self.webhooks.insert(parent_id, webhook.clone());
+        Ok(webhook)
+    }
+
+    /// 親チャンネルのWebhookを使わないようにする (削除された場合など、次回作り直す)
+    pub fn forget(&self, parent_id: &ChannelId) {
+        self.webhooks.remove(parent_id);
+    }
+}